@@ -1,27 +1,35 @@
 use std::{
     borrow::Borrow,
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     ffi::OsString,
     hash::Hash as _,
+    path::{Path, PathBuf},
     sync::Arc,
     time::Duration,
 };
 
-use anyhow::{bail, Context as _};
+use anyhow::{anyhow, bail, Context as _};
 #[allow(unused_imports)]
 use log::debug;
 use schemars::JsonSchema;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use sha3::{Digest, Sha3_256};
 
 use crate::{
+    config_repo::{CheckoutMode, ConfigRepoConfig, ConfigRepoRevs},
     dag::{Dag, GraphNode},
+    database::remote::RemoteCacheConfig,
+    gc::GcPolicy,
+    git::{CommitHash, Worktree, WorktreeMode},
+    inputs,
     resource::{self, Pools, ResourceKey},
+    service::{ServiceConfig, ServiceScope, Services},
     test::{self, CachePolicy, TestDag, TestName},
-    util::DigestHasher,
+    util::{parse_duration, parse_size, DigestHasher},
 };
 
-#[derive(Deserialize, JsonSchema, Debug, Hash, Clone)]
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Hash, Clone)]
 #[serde(deny_unknown_fields)]
 #[serde(untagged)]
 pub enum Resource {
@@ -53,7 +61,7 @@ impl Resource {
     }
 }
 
-#[derive(Deserialize, JsonSchema, Debug, Hash, Clone)]
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Hash, Clone)]
 #[serde(deny_unknown_fields)]
 #[serde(untagged)]
 pub enum Command {
@@ -62,9 +70,10 @@ pub enum Command {
 }
 
 impl Command {
-    pub fn program(&self) -> OsString {
+    // shell is the shell configured by the user, if any.
+    pub fn program(&self, shell: Option<&str>) -> OsString {
         match self {
-            Self::Shell(_) => "bash".into(), // TODO: Figure out the user's configured shell.
+            Self::Shell(_) => shell.unwrap_or("bash").into(),
             Self::Raw(args) => args[0].clone().into(),
         }
     }
@@ -77,15 +86,111 @@ impl Command {
     }
 }
 
-#[derive(Deserialize, JsonSchema, Debug, Hash, Clone)]
+// Names of things that end up in paths need to be kept simple.
+fn check_name(kind: &str, name: &str) -> anyhow::Result<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        bail!("invalid {kind} name {name:?}, only alphanumerics, '_' and '-' are allowed");
+    }
+    Ok(())
+}
+
+// For names that end up in environment variable names, where shells can't
+// expand anything with a '-' in it.
+fn check_env_name(kind: &str, name: &str) -> anyhow::Result<()> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        bail!("invalid {kind} name {name:?}, only alphanumerics and '_' are allowed");
+    }
+    Ok(())
+}
+
+// Figure out what resources are needed to run a command.
+fn needs_resources(
+    resources: Option<&Vec<Resource>>,
+    requires_worktree: bool,
+    worktree_pool: Option<&String>,
+) -> anyhow::Result<HashMap<ResourceKey, usize>> {
+    let mut seen_resources = HashSet::new();
+    for resource in resources.unwrap_or(&vec![]) {
+        if seen_resources.contains(&resource.name()) {
+            // TODO: Need better error messages.
+            bail!("duplicate resource reference {:?}", resource.name());
+        }
+        seen_resources.insert(resource.name());
+    }
+    let mut needs_resources: HashMap<ResourceKey, usize> = resources
+        .unwrap_or(&vec![])
+        .iter()
+        .map(|r| (ResourceKey::UserToken(r.name().to_owned()), r.count()))
+        .collect();
+    match (worktree_pool, requires_worktree) {
+        (Some(pool), true) => {
+            needs_resources.insert(ResourceKey::Worktree(pool.clone()), 1);
+        }
+        (None, true) => {
+            needs_resources.insert(ResourceKey::default_worktree(), 1);
+        }
+        (Some(_), false) => bail!("worktree_pool set but requires_worktree is false"),
+        (None, false) => (),
+    }
+    Ok(needs_resources)
+}
+
+/// One step of a test with multiple steps. Each step gets its own resources
+/// (including the worktree), only while it's running.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Hash, Clone)]
 #[serde(deny_unknown_fields)]
-pub struct Test {
+pub struct Step {
+    /// The step's output is stored in steps/<name> in the result directory.
     name: String,
     command: Command,
     #[serde(default = "default_requires_worktree")]
     requires_worktree: bool,
+    worktree_pool: Option<String>,
+    resources: Option<Vec<Resource>>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Hash, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Test {
+    name: String,
+    /// Exactly one of command or steps must be set.
+    command: Option<Command>,
+    /// Commands to run in order, each only if the previous one succeeded.
+    /// Exactly one of command or steps must be set.
+    #[serde(default)]
+    steps: Vec<Step>,
+    /// Defaults to true. Only for tests with a single command, tests with
+    /// steps configure this per-step.
+    requires_worktree: Option<bool>,
+    /// Name of the worktree pool (from worktree_pools) to take the test's
+    /// worktree from. If unset, the default pool (sized by num_worktrees) is
+    /// used. Only for tests with a single command, tests with steps configure
+    /// this per-step.
+    worktree_pool: Option<String>,
     // TODO: This should only refer to resource names.
     resources: Option<Vec<Resource>>,
+    /// Names of services (from the top-level services) that the test uses.
+    /// They are started before the test runs (if they aren't already running)
+    /// and the port for each is passed in $LIMMAT_SERVICE_<name>_PORT.
+    #[serde(default)]
+    services: Vec<String>,
+    /// Globs for files outside the repository that affect the test result.
+    /// Their contents are hashed along with the test config, so results are
+    /// invalidated when they change, and in watch mode the test is re-run.
+    /// ~ and environment variables are expanded, including $LIMMAT_ORIGIN.
+    /// Relative globs are relative to the directory of the config file.
+    #[serde(default)]
+    inputs: Vec<String>,
+    /// Names of config repos (from the top-level config_repos) that the test
+    /// uses. The commit each one is at is hashed along with the test config,
+    /// and the path of a checkout of it is passed in
+    /// $LIMMAT_CONFIG_REPO_<name>.
+    #[serde(default)]
+    config_repos: Vec<String>,
     #[serde(default = "default_shutdown_grace_period")]
     /// When a job is no longer needed it's SIGTERMed. If it doesn't respond (by
     /// dying) after this duration it will then be SIGKILLed. This also affects
@@ -94,6 +199,19 @@ pub struct Test {
     shutdown_grace_period_s: u64,
     #[serde(default = "default_cache_policy")]
     cache: CachePolicy,
+    /// Only for cache = "by_tree". Share results with other repositories using
+    /// the same result database, instead of keeping each repository's results
+    /// separate. Only do this if the result really only depends on the tree,
+    /// for example for forks of a project.
+    #[serde(default)]
+    share_results: bool,
+    /// Results older than this are ignored and the test is re-run. For
+    /// example "7d", units are s, m, h, d or w.
+    cache_ttl: Option<String>,
+    /// In watch mode, re-run the test on commits that are still in the range
+    /// once their result is this old, for example "24h". Results this old are
+    /// also ignored, as if cache_ttl was set.
+    rerun_every: Option<String>,
     #[serde(default)]
     depends_on: Vec<String>,
 }
@@ -118,33 +236,174 @@ impl GraphNode for Test {
 impl Test {
     // Convert to the "real" object. other_tests is the set of other tests that
     // have already been parsed, which must include all of these test's
-    // transitive dependencies (or this will panic).
-    pub fn parse(&self, other_tests: &Dag<Arc<test::Test>>) -> anyhow::Result<test::Test> {
-        let mut seen_resources = HashSet::new();
-        for resource in self.resources.as_ref().unwrap_or(&vec![]) {
-            if seen_resources.contains(&resource.name()) {
-                // TODO: Need better error messages.
-                bail!("duplicate resource reference {:?}", resource.name());
+    // transitive dependencies (or this will panic). services is all the
+    // services defined in the config, shell is the configured shell. origin
+    // is the main repo worktree. config_repo_revs has the current commit of
+    // each config repo.
+    pub fn parse(
+        &self,
+        other_tests: &Dag<Arc<test::Test>>,
+        services: &HashMap<String, Service>,
+        shell: Option<&str>,
+        origin: &Path,
+        config_repo_revs: &ConfigRepoRevs,
+    ) -> anyhow::Result<test::Test> {
+        let steps = match (&self.command, self.steps.is_empty()) {
+            (Some(command), true) => vec![test::TestStep::single(
+                command.program(shell),
+                command.args(),
+                needs_resources(
+                    self.resources.as_ref(),
+                    self.requires_worktree.unwrap_or(true),
+                    self.worktree_pool.as_ref(),
+                )?,
+            )],
+            (None, false) => {
+                if self.requires_worktree.is_some()
+                    || self.worktree_pool.is_some()
+                    || self.resources.is_some()
+                {
+                    bail!(
+                        "test {:?} has steps, requires_worktree, worktree_pool and resources must be set on the steps instead",
+                        self.name
+                    );
+                }
+                let mut seen_steps = HashSet::new();
+                let mut steps = Vec::new();
+                for step in &self.steps {
+                    check_name("step", &step.name)?;
+                    if !seen_steps.insert(&step.name) {
+                        bail!("duplicate step {:?} in test {:?}", step.name, self.name);
+                    }
+                    steps.push(test::TestStep {
+                        name: Some(step.name.clone()),
+                        program: step.command.program(shell),
+                        args: step.command.args(),
+                        needs_resources: needs_resources(
+                            step.resources.as_ref(),
+                            step.requires_worktree,
+                            step.worktree_pool.as_ref(),
+                        )
+                        .with_context(|| format!("parsing step {:?}", step.name))?,
+                    });
+                }
+                steps
+            }
+            _ => bail!(
+                "test {:?} must have exactly one of command or steps",
+                self.name
+            ),
+        };
+
+        for name in &self.services {
+            let service = services.get(name).ok_or_else(|| {
+                anyhow!(
+                    "undefined service {name:?} referenced in test {:?}",
+                    self.name
+                )
+            })?;
+            if service.scope == ServiceScope::Worktree
+                && steps.iter().any(|s| s.worktree_pool().is_none())
+            {
+                bail!(
+                    "test {:?} uses per-worktree service {name:?} but doesn't always have a worktree",
+                    self.name
+                );
             }
-            seen_resources.insert(resource.name());
         }
-        let mut needs_resources: HashMap<ResourceKey, usize> = self
-            .resources
-            .as_ref()
-            .unwrap_or(&vec![])
-            .iter()
-            .map(|r| (ResourceKey::UserToken(r.name().to_owned()), r.count()))
-            .collect();
-        if self.requires_worktree {
-            needs_resources.insert(ResourceKey::Worktree, 1);
+
+        let cache_policy = match &self.cache {
+            CachePolicy::ByPaths(paths) => {
+                if paths.is_empty() {
+                    bail!("test {:?} has an empty by_paths cache policy", self.name);
+                }
+                let paths = paths
+                    .iter()
+                    .map(|path| {
+                        // These get looked up with git rev-parse <commit>:<path>,
+                        // which only understands paths relative to the root.
+                        let trimmed = path.trim_end_matches('/');
+                        if trimmed.is_empty() || trimmed.starts_with('/') {
+                            bail!("invalid path {path:?} in test {:?}, paths must be relative to the root of the repository", self.name);
+                        }
+                        Ok(trimmed.to_owned())
+                    })
+                    .collect::<anyhow::Result<_>>()?;
+                CachePolicy::ByPaths(paths)
+            }
+            policy => policy.clone(),
+        };
+        if self.share_results && cache_policy != CachePolicy::ByTree {
+            bail!(
+                "test {:?} sets share_results, that's only supported with cache = \"by_tree\"",
+                self.name
+            );
         }
 
+        let cache_ttl = self
+            .cache_ttl
+            .as_deref()
+            .map(parse_duration)
+            .transpose()
+            .with_context(|| format!("parsing cache_ttl for test {:?}", self.name))?;
+        let rerun_every = self
+            .rerun_every
+            .as_deref()
+            .map(parse_duration)
+            .transpose()
+            .with_context(|| format!("parsing rerun_every for test {:?}", self.name))?;
+
+        let config_repos = self
+            .config_repos
+            .iter()
+            .map(|name| {
+                let commit = config_repo_revs.get(name).ok_or_else(|| {
+                    anyhow!(
+                        "undefined config repo {name:?} referenced in test {:?}",
+                        self.name
+                    )
+                })?;
+                Ok((name.clone(), commit.clone()))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        // The TTLs are about which results to use, not how to produce them,
+        // so changing them shouldn't invalidate anything.
+        let identity = Test {
+            cache_ttl: None,
+            rerun_every: None,
+            ..self.clone()
+        };
+
         // Hash the config, also taking into account the hashes of the
-        // dependency test configs.
+        // dependency test configs, the config of the services it uses and the
+        // commits of its config repos.
         let mut hasher = DigestHasher {
             digest: Sha3_256::new(),
         };
-        self.hash(&mut hasher);
+        identity.hash(&mut hasher);
+        // Only hash this if it's set, so that the hashes didn't change when
+        // the option was added.
+        if let Some(shell) = shell {
+            shell.hash(&mut hasher);
+        }
+        for name in &self.services {
+            services[name].hash(&mut hasher);
+        }
+        let inputs = self
+            .inputs
+            .iter()
+            // Inputs from the config layers have already been made absolute
+            // (see ConfigLayers), so anything still relative comes from a
+            // commit's config, which is at the root of the repo.
+            .map(|pattern| Ok(inputs::resolve(&inputs::expand(pattern, origin)?, origin)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let input_digests = inputs::input_digests(&inputs)
+            .with_context(|| format!("hashing inputs for test {:?}", self.name))?;
+        input_digests.hash(&mut hasher);
+        for (_, commit) in &config_repos {
+            commit.hash(&mut hasher);
+        }
         for dep_name in &self.depends_on {
             other_tests
                 .node(&TestName::new(dep_name))
@@ -154,15 +413,34 @@ impl Test {
         }
         let config_hash = hasher.digest.finalize().to_vec();
         debug!("Config hash for {}: {:?}", self.name, config_hash);
+        // Everything that went into the hash, this gets stored with the
+        // results so that we don't depend on the hash not colliding, and so
+        // that we can tell the user why a result was invalidated.
+        let canonical_config = json!({
+            "test": identity,
+            "shell": shell,
+            "services": self.services.iter().map(|name| (name, &services[name])).collect::<BTreeMap<_, _>>(),
+            "inputs": input_digests,
+            "config_repos": config_repos.iter().map(|(name, commit)| (name, commit.to_string())).collect::<BTreeMap<_, _>>(),
+            "depends_on": self.depends_on.iter().map(|name| {
+                let hash = &other_tests.node(&TestName::new(name)).unwrap().config_hash;
+                (name, hash.iter().map(|b| format!("{b:02x}")).collect::<String>())
+            }).collect::<BTreeMap<_, _>>(),
+        });
 
         Ok(test::Test {
             name: TestName::new(self.name.clone()),
-            program: self.command.program(),
-            args: self.command.args(),
-            needs_resources,
+            steps,
+            services: self.services.clone(),
+            inputs,
+            config_repos,
             shutdown_grace_period: Duration::from_secs(self.shutdown_grace_period_s),
-            cache_policy: self.cache,
+            cache_policy,
+            share_results: self.share_results,
+            cache_ttl,
+            rerun_every,
             config_hash,
+            canonical_config,
             depends_on: self.depends_on.iter().map(TestName::new).collect(),
         })
     }
@@ -180,12 +458,198 @@ fn default_shutdown_grace_period() -> u64 {
     60
 }
 
+/// A long-running process that tests can use, see services on tests.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Hash, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Service {
+    /// Only alphanumerics and '_', since it goes in the name of an
+    /// environment variable.
+    name: String,
+    /// The port the service should listen on is passed in
+    /// $LIMMAT_SERVICE_PORT.
+    command: Command,
+    /// Run repeatedly after starting the service until it succeeds, tests
+    /// using the service don't start until then. If unset, the service is
+    /// considered ready as soon as it's started. Also gets
+    /// $LIMMAT_SERVICE_PORT.
+    ready_command: Option<Command>,
+    #[serde(default)]
+    scope: ServiceScope,
+    /// Give up on the service if ready_command doesn't succeed within this
+    /// many seconds.
+    #[serde(default = "default_ready_timeout")]
+    ready_timeout_s: u64,
+    /// Like shutdown_grace_period_s for tests.
+    #[serde(default = "default_shutdown_grace_period")]
+    shutdown_grace_period_s: u64,
+}
+
+fn default_ready_timeout() -> u64 {
+    60
+}
+
+/// A Git repository, other than the one being tested, that tests depend on.
+/// For example test scripts or device configs. See config_repos on tests.
+#[derive(Deserialize, JsonSchema, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigRepo {
+    name: String,
+    /// Path of a local repository, or anything Git can clone. Relative paths
+    /// are relative to the main repository. ~ and environment variables are
+    /// expanded, including $LIMMAT_ORIGIN. Remote repositories are mirrored
+    /// into Limmat's state directory and fetched when Limmat starts.
+    path: String,
+    /// The revision of the repo that tests use. Default is HEAD.
+    #[serde(default = "default_config_repo_rev")]
+    rev: String,
+    #[serde(default)]
+    checkout: CheckoutMode,
+    /// In watch mode, re-run the tests using this repo when rev changes.
+    /// Only changes to the repository on disk are noticed, remote repositories
+    /// are only fetched when Limmat starts.
+    #[serde(default)]
+    watch: bool,
+}
+
+fn default_config_repo_rev() -> String {
+    "HEAD".to_owned()
+}
+
+#[derive(Deserialize, JsonSchema, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct WorktreePool {
+    name: String,
+    /// Maximum number of worktrees in this pool.
+    count: usize,
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Gc {
+    /// Delete the oldest results until the database is at most this big, for
+    /// example "20G". Units are K, M, G or T (powers of 1024).
+    max_size: Option<String>,
+    /// Delete results older than this, for example "30d". Units are s, m, h,
+    /// d or w.
+    max_age: Option<String>,
+    /// Only keep this many of the newest results for each test.
+    keep_per_test: Option<usize>,
+    /// Never delete results for commits reachable from these revisions, for
+    /// example ["origin/master"].
+    #[serde(default)]
+    keep_reachable_from: Vec<String>,
+    /// In watch mode, collect garbage this often, for example "1h". Otherwise
+    /// garbage is only collected by `limmat db gc`.
+    interval: Option<String>,
+}
+
+impl Gc {
+    fn parse(&self) -> anyhow::Result<GcPolicy> {
+        Ok(GcPolicy {
+            max_size: self
+                .max_size
+                .as_deref()
+                .map(parse_size)
+                .transpose()
+                .context("parsing gc.max_size")?,
+            max_age: self
+                .max_age
+                .as_deref()
+                .map(parse_duration)
+                .transpose()
+                .context("parsing gc.max_age")?,
+            keep_per_test: self.keep_per_test,
+            keep_reachable_from: self.keep_reachable_from.clone(),
+        })
+    }
+}
+
+#[derive(Deserialize, JsonSchema, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RemoteCache {
+    /// Base URL of the cache, for example "http://cache.example.com:8421".
+    /// Only plain HTTP is supported. `limmat serve-cache` provides a server.
+    url: String,
+    /// Upload successful results to the cache. If false, results are only
+    /// downloaded.
+    #[serde(default = "default_upload")]
+    upload: bool,
+    /// Include artifacts in uploaded results. These can be large.
+    #[serde(default)]
+    upload_artifacts: bool,
+}
+
+fn default_upload() -> bool {
+    true
+}
+
+impl RemoteCache {
+    fn parse(&self) -> anyhow::Result<RemoteCacheConfig> {
+        RemoteCacheConfig::new(&self.url, self.upload, self.upload_artifacts)
+            .context("parsing remote_cache.url")
+    }
+}
+
+// Where the test definitions come from.
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigSource {
+    /// Use the tests from this config file for every commit.
+    #[default]
+    Worktree,
+    /// Use the tests from the limmat.toml (or .limmat.toml) in each commit
+    /// being tested. Everything else (resources, worktrees, services etc.)
+    /// still comes from this config file, and is ignored in the commit's
+    /// config. Commits with no config file don't get tested.
+    Commit,
+}
+
 #[derive(Deserialize, JsonSchema, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
+    /// Name for this project in the result database. Results are kept
+    /// separate for each project. By default the project is identified by its
+    /// root commit (the one with the lowest hash, if there are several), so
+    /// all clones of a repository share results. Setting this overrides that,
+    /// for example to let unrelated repositories share them.
+    project: Option<String>,
+    /// Where to get the test definitions from.
+    #[serde(default)]
+    config_source: ConfigSource,
     #[serde(default = "default_num_worktrees")]
     pub num_worktrees: usize,
+    /// Extra worktree pools, separate from the default one. Tests can select
+    /// one of these with worktree_pool, so that they don't have to compete
+    /// for worktrees with other tests.
+    #[serde(default)]
+    worktree_pools: Vec<WorktreePool>,
+    /// How to create the worktrees that tests run in.
+    #[serde(default)]
+    worktree_mode: WorktreeMode,
+    /// Directory (must exist) to create temporary worktrees in. Overridden by
+    /// --worktree-dir. Default is the system temporary directory.
+    worktree_dir: Option<PathBuf>,
+    /// Shell used to run commands that are specified as a string. Default is
+    /// bash. It's run with -c and the command.
+    shell: Option<String>,
+    /// If set, worktrees that haven't been used for this many seconds are
+    /// deleted. They'll be recreated when they're needed again. Worktrees are
+    /// always created on demand, up to num_worktrees.
+    worktree_idle_timeout_s: Option<u64>,
     resources: Option<Vec<Resource>>,
+    /// Long-running processes that Limmat starts when a test needs them and
+    /// keeps running (restarting them if they die) until it shuts down.
+    #[serde(default)]
+    services: Vec<Service>,
+    /// Other Git repositories that tests can use.
+    #[serde(default)]
+    config_repos: Vec<ConfigRepo>,
+    /// Deletion of old results from the result database, see `limmat db gc`.
+    #[serde(default)]
+    gc: Gc,
+    /// Share results with other machines via an HTTP server. Before running a
+    /// test, Limmat checks whether the cache has a result for it.
+    remote_cache: Option<RemoteCache>,
     // Default is just here to make testing snippets from the documentation easier.
     #[serde(default)]
     tests: Vec<Test>,
@@ -198,6 +662,60 @@ fn default_num_worktrees() -> usize {
 type ResourceTokens = HashMap<ResourceKey, Vec<String>>;
 
 impl Config {
+    // Maximum size of each worktree pool, including the default one.
+    fn parse_worktree_pools(&self) -> anyhow::Result<HashMap<String, usize>> {
+        let mut pools = HashMap::from([(
+            resource::DEFAULT_WORKTREE_POOL.to_owned(),
+            self.num_worktrees,
+        )]);
+        for pool in &self.worktree_pools {
+            // Pool names end up in paths for persistent worktrees.
+            check_name("worktree pool", &pool.name)?;
+            if pools.insert(pool.name.clone(), pool.count).is_some() {
+                bail!("duplicate worktree pool {:?}", pool.name);
+            }
+        }
+        Ok(pools)
+    }
+
+    fn parse_services(&self) -> anyhow::Result<HashMap<String, Service>> {
+        let mut services = HashMap::new();
+        for service in &self.services {
+            // Service names end up in environment variable names and paths.
+            check_env_name("service", &service.name)?;
+            if services
+                .insert(service.name.clone(), service.clone())
+                .is_some()
+            {
+                bail!("duplicate service {:?}", service.name);
+            }
+        }
+        Ok(services)
+    }
+
+    // origin is the main repo worktree, which paths can refer to.
+    pub fn parse_config_repos(&self, origin: &Path) -> anyhow::Result<Vec<ConfigRepoConfig>> {
+        let mut seen = HashSet::new();
+        let mut config_repos = Vec::new();
+        for repo in &self.config_repos {
+            // Config repo names end up in environment variable names and
+            // paths.
+            check_name("config repo", &repo.name)?;
+            if !seen.insert(&repo.name) {
+                bail!("duplicate config repo {:?}", repo.name);
+            }
+            config_repos.push(ConfigRepoConfig {
+                name: repo.name.clone(),
+                path: inputs::expand(&repo.path, origin)
+                    .with_context(|| format!("expanding path of config repo {:?}", repo.name))?,
+                rev: repo.rev.clone(),
+                checkout: repo.checkout,
+                watch: repo.watch,
+            });
+        }
+        Ok(config_repos)
+    }
+
     fn parse_resource_tokens(&self) -> ResourceTokens {
         self.resources
             .as_ref()
@@ -220,6 +738,11 @@ impl Config {
     fn parse_tests(
         &self,
         resource_tokens: &ResourceTokens,
+        worktree_pools: &HashMap<String, usize>,
+        services: &HashMap<String, Service>,
+        shell: Option<&str>,
+        origin: &Path,
+        config_repo_revs: &ConfigRepoRevs,
     ) -> anyhow::Result<Dag<Arc<test::Test>>> {
         let tests = Dag::new(self.tests.clone()).context("parsing test dependency graph")?;
         // This is beginning to be kinda cool, we can map between DAGs of
@@ -234,7 +757,13 @@ impl Config {
             .try_fold(
                 Dag::empty(),
                 |parsed_dag, test_conf| -> anyhow::Result<Dag<Arc<test::Test>>> {
-                    let new_node = Arc::new(test_conf.parse(&parsed_dag)?);
+                    let new_node = Arc::new(test_conf.parse(
+                        &parsed_dag,
+                        services,
+                        shell,
+                        origin,
+                        config_repo_revs,
+                    )?);
                     Ok(parsed_dag.with_node(new_node).unwrap())
                 },
             )
@@ -242,14 +771,25 @@ impl Config {
 
         // Check for invalid resource references.
         for test in tests.nodes() {
-            for key in test.needs_resources.keys() {
-                if let ResourceKey::UserToken(name) = key {
-                    if !resource_tokens.contains_key(key) {
-                        bail!(
-                            "undefined resource {:?} referenced in test {:?}",
-                            name,
-                            test.name
-                        );
+            for key in test.steps.iter().flat_map(|s| s.needs_resources.keys()) {
+                match key {
+                    ResourceKey::UserToken(name) => {
+                        if !resource_tokens.contains_key(key) {
+                            bail!(
+                                "undefined resource {:?} referenced in test {:?}",
+                                name,
+                                test.name
+                            );
+                        }
+                    }
+                    ResourceKey::Worktree(pool) => {
+                        if !worktree_pools.contains_key(pool) {
+                            bail!(
+                                "undefined worktree pool {:?} referenced in test {:?}",
+                                pool,
+                                test.name
+                            );
+                        }
                     }
                 }
             }
@@ -261,23 +801,85 @@ impl Config {
 
 // Messy type to try and capture a pretty arbitrary aspect of initialising the
 // pre-requisites to run jobs.
-// Construct via from. This does NOT create worktrees, that's why it has a
-// num_worktrees field to tell you how many you'll need to create and insert
-// into the pools.
+// Construct via from. This does NOT set up worktree creation, that's why it
+// has a worktree_pools field to tell you how many worktrees each pool should be
+// allowed to create.
 // The reason for this is that for some reason I decided that the num_worktrees
 // option should be ignored when running one-shot tests. This was dumb and made
 // things unnecessarily complicated.
 #[derive(Debug)]
 pub struct ParsedConfig {
-    pub num_worktrees: usize,
+    // Maximum size of each worktree pool, keyed by name. Includes the default
+    // pool.
+    pub worktree_pools: HashMap<String, usize>,
+    pub worktree_mode: WorktreeMode,
+    pub worktree_dir: Option<PathBuf>,
+    pub worktree_idle_timeout: Option<Duration>,
     pub resource_pools: Arc<Pools>,
+    pub services: Arc<Services>,
+    // The tests from this config file. In ConfigSource::Commit mode this is
+    // only used when testing the working tree.
     pub tests: TestDag,
+    // Set in ConfigSource::Commit mode.
+    pub commit_config: Option<Arc<CommitConfigParser>>,
+    pub gc_policy: GcPolicy,
+    // How often to collect garbage in watch mode, if at all.
+    pub gc_interval: Option<Duration>,
+    pub project: Option<String>,
+    pub remote_cache: Option<RemoteCacheConfig>,
 }
 
 impl ParsedConfig {
-    pub fn from(config: Config) -> anyhow::Result<Self> {
+    // origin is the main repo worktree, which input globs can refer to.
+    // config_repo_revs has the current commit of each of the config repos
+    // (see Config::parse_config_repos).
+    pub fn from(
+        config: Config,
+        origin: &Path,
+        config_repo_revs: &ConfigRepoRevs,
+    ) -> anyhow::Result<Self> {
         let resource_tokens = config.parse_resource_tokens();
-        let tests = config.parse_tests(&resource_tokens)?;
+        let worktree_pools = config.parse_worktree_pools()?;
+        let services = config.parse_services()?;
+        let shell = config.shell.as_deref();
+        if let Some(project) = &config.project {
+            // It's used as a directory name in the result database.
+            check_name("project", project)?;
+        }
+        let gc_policy = config.gc.parse()?;
+        let gc_interval = config
+            .gc
+            .interval
+            .as_deref()
+            .map(parse_duration)
+            .transpose()
+            .context("parsing gc.interval")?;
+        if gc_interval.is_some() && gc_policy.is_empty() {
+            bail!("gc.interval is set but there's nothing to collect, set max_size, max_age or keep_per_test");
+        }
+        let remote_cache = config
+            .remote_cache
+            .as_ref()
+            .map(RemoteCache::parse)
+            .transpose()?;
+        let tests = config.parse_tests(
+            &resource_tokens,
+            &worktree_pools,
+            &services,
+            shell,
+            origin,
+            config_repo_revs,
+        )?;
+        let commit_config = (config.config_source == ConfigSource::Commit).then(|| {
+            Arc::new(CommitConfigParser {
+                resource_tokens: resource_tokens.clone(),
+                worktree_pools: worktree_pools.clone(),
+                services: services.clone(),
+                shell: config.shell.clone(),
+                origin: origin.to_owned(),
+                config_repo_revs: config_repo_revs.clone(),
+            })
+        });
         let resources: HashMap<ResourceKey, Vec<resource::Resource>> = resource_tokens
             .into_iter()
             .map(|(key, tokens)| {
@@ -291,13 +893,77 @@ impl ParsedConfig {
             })
             .collect();
         Ok(Self {
-            num_worktrees: config.num_worktrees,
+            worktree_pools,
+            worktree_mode: config.worktree_mode,
+            worktree_dir: config.worktree_dir,
+            worktree_idle_timeout: config.worktree_idle_timeout_s.map(Duration::from_secs),
             resource_pools: Arc::new(Pools::new(resources)),
+            services: Arc::new(Services::new(services.into_values().map(|s| {
+                ServiceConfig {
+                    program: s.command.program(shell),
+                    args: s.command.args(),
+                    ready_command: s.ready_command.map(|c| (c.program(shell), c.args())),
+                    scope: s.scope,
+                    ready_timeout: Duration::from_secs(s.ready_timeout_s),
+                    shutdown_grace_period: Duration::from_secs(s.shutdown_grace_period_s),
+                    name: s.name,
+                }
+            }))),
             tests,
+            commit_config,
+            gc_policy,
+            gc_interval,
+            project: config.project,
+            remote_cache,
         })
     }
 }
 
+// Parses the tests out of the config file from a commit, for
+// ConfigSource::Commit. The tests can only refer to the resources etc. defined
+// in the main config.
+#[derive(Debug)]
+pub struct CommitConfigParser {
+    resource_tokens: ResourceTokens,
+    worktree_pools: HashMap<String, usize>,
+    services: HashMap<String, Service>,
+    shell: Option<String>,
+    origin: PathBuf,
+    config_repo_revs: ConfigRepoRevs,
+}
+
+impl CommitConfigParser {
+    // Paths of the config file in the commit, in order of preference.
+    const PATHS: [&str; 2] = ["limmat.toml", ".limmat.toml"];
+
+    fn parse(&self, content: &str) -> anyhow::Result<TestDag> {
+        let config: Config = toml::from_str(content).context("couldn't parse config")?;
+        config.parse_tests(
+            &self.resource_tokens,
+            &self.worktree_pools,
+            &self.services,
+            self.shell.as_deref(),
+            &self.origin,
+            &self.config_repo_revs,
+        )
+    }
+
+    // Load the tests from the config in the given commit. If there's no config
+    // there are no tests.
+    pub async fn load(&self, repo: &impl Worktree, commit: &CommitHash) -> anyhow::Result<TestDag> {
+        for path in Self::PATHS {
+            if let Some(content) = repo.show_file(commit, path).await? {
+                let content = String::from_utf8(content).context("config isn't UTF-8")?;
+                return self
+                    .parse(&content)
+                    .with_context(|| format!("parsing {path} from commit {commit}"));
+            }
+        }
+        debug!("No config in commit {commit}, not testing it");
+        Ok(Dag::empty())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use googletest::{assert_that, expect_that, prelude::*};
@@ -305,6 +971,8 @@ mod tests {
     use regex::Regex;
     use schemars::schema_for;
 
+    use crate::git::Commit;
+
     use super::*;
 
     // Poor man's replacement for google3's "generated files" feature: just check
@@ -346,7 +1014,536 @@ mod tests {
             "No TOML found in README - test bug?"
         );
         for toml in toml_blocks {
-            expect_that!(toml::from_str(toml).map(ParsedConfig::from), ok(anything()));
+            expect_that!(
+                toml::from_str::<Config>(toml).map(|c| {
+                    let config_repo_revs = fake_config_repo_revs(&c);
+                    ParsedConfig::from(c, Path::new("/fake/origin"), &config_repo_revs)
+                }),
+                ok(anything())
+            );
+        }
+    }
+
+    // Pretend all the config repos are at an arbitrary commit.
+    fn fake_config_repo_revs(config: &Config) -> ConfigRepoRevs {
+        config
+            .config_repos
+            .iter()
+            .map(|r| (r.name.clone(), Commit::arbitrary().hash))
+            .collect()
+    }
+
+    fn parse_toml(toml: &str) -> anyhow::Result<ParsedConfig> {
+        let config: Config = toml::from_str(toml)?;
+        let config_repo_revs = fake_config_repo_revs(&config);
+        ParsedConfig::from(config, Path::new("/fake/origin"), &config_repo_revs)
+    }
+
+    #[googletest::test]
+    fn test_worktree_pools() {
+        let config = parse_toml(
+            r#"
+            num_worktrees = 3
+            worktree_pools = [{ name = "kbuild", count = 1 }]
+
+            [[tests]]
+            name = "build"
+            command = "make"
+            worktree_pool = "kbuild"
+
+            [[tests]]
+            name = "fmt"
+            command = "cargo fmt --check"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.worktree_pools,
+            HashMap::from([("default".to_owned(), 3), ("kbuild".to_owned(), 1)])
+        );
+        let test = |name: &str| {
+            config.tests.node(&TestName::new(name)).unwrap().steps[0]
+                .needs_resources
+                .clone()
+        };
+        assert_eq!(
+            test("build"),
+            HashMap::from([(ResourceKey::Worktree("kbuild".to_owned()), 1)])
+        );
+        assert_eq!(
+            test("fmt"),
+            HashMap::from([(ResourceKey::default_worktree(), 1)])
+        );
+
+        for bad in [
+            // Undefined pool.
+            r#"
+            [[tests]]
+            name = "build"
+            command = "make"
+            worktree_pool = "kbuild"
+            "#,
+            // Duplicate pool.
+            r#"
+            worktree_pools = [{ name = "kbuild", count = 1 }, { name = "kbuild", count = 2 }]
+            "#,
+            // Clashes with the default pool.
+            r#"
+            worktree_pools = [{ name = "default", count = 1 }]
+            "#,
+            // Bad name.
+            r#"
+            worktree_pools = [{ name = "../foo", count = 1 }]
+            "#,
+            // Pool but no worktree.
+            r#"
+            worktree_pools = [{ name = "kbuild", count = 1 }]
+            [[tests]]
+            name = "build"
+            command = "make"
+            worktree_pool = "kbuild"
+            requires_worktree = false
+            "#,
+        ] {
+            expect_that!(parse_toml(bad), err(anything()), "{bad}");
+        }
+    }
+
+    #[googletest::test]
+    fn test_steps() {
+        let config = parse_toml(
+            r#"
+            resources = ["host"]
+
+            [[tests]]
+            name = "boot"
+            steps = [
+                { name = "build", command = "make" },
+                { name = "boot", command = "./boot.sh", requires_worktree = false, resources = ["host"] },
+            ]
+            "#,
+        )
+        .unwrap();
+        let test = config.tests.node(&TestName::new("boot")).unwrap();
+        assert_eq!(
+            test.steps
+                .iter()
+                .map(|s| (s.name.as_deref(), s.needs_resources.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    Some("build"),
+                    HashMap::from([(ResourceKey::default_worktree(), 1)])
+                ),
+                (
+                    Some("boot"),
+                    HashMap::from([(ResourceKey::UserToken("host".to_owned()), 1)])
+                ),
+            ]
+        );
+
+        for bad in [
+            // Neither command nor steps.
+            r#"
+            [[tests]]
+            name = "boot"
+            "#,
+            // Both.
+            r#"
+            [[tests]]
+            name = "boot"
+            command = "make"
+            steps = [{ name = "build", command = "make" }]
+            "#,
+            // Resources on the test instead of the steps.
+            r#"
+            [[tests]]
+            name = "boot"
+            requires_worktree = false
+            steps = [{ name = "build", command = "make" }]
+            "#,
+            // Duplicate step.
+            r#"
+            [[tests]]
+            name = "boot"
+            steps = [{ name = "build", command = "make" }, { name = "build", command = "make" }]
+            "#,
+            // Bad step name.
+            r#"
+            [[tests]]
+            name = "boot"
+            steps = [{ name = "build/1", command = "make" }]
+            "#,
+            // Undefined resource in step.
+            r#"
+            [[tests]]
+            name = "boot"
+            steps = [{ name = "build", command = "make", resources = ["host"] }]
+            "#,
+        ] {
+            expect_that!(parse_toml(bad), err(anything()), "{bad}");
+        }
+    }
+
+    #[googletest::test]
+    fn test_services() {
+        let parse = |port_arg: &str| {
+            parse_toml(&format!(
+                r#"
+                [[services]]
+                name = "db"
+                command = "run_db --port {port_arg}"
+
+                [[tests]]
+                name = "query"
+                services = ["db"]
+                command = "true"
+                "#
+            ))
+            .unwrap()
+        };
+        let config = parse("$LIMMAT_SERVICE_PORT");
+        let test = config.tests.node(&TestName::new("query")).unwrap();
+        assert_eq!(test.services, vec!["db".to_owned()]);
+        // Changing the service changes the test's config hash.
+        let other = parse("${LIMMAT_SERVICE_PORT}");
+        assert_ne!(
+            test.config_hash,
+            other
+                .tests
+                .node(&TestName::new("query"))
+                .unwrap()
+                .config_hash
+        );
+
+        for bad in [
+            // Undefined service.
+            r#"
+            [[tests]]
+            name = "query"
+            services = ["db"]
+            command = "true"
+            "#,
+            // Can't be put in an environment variable name.
+            r#"
+            [[services]]
+            name = "my-db"
+            command = "run_db"
+            "#,
+            // Duplicate service.
+            r#"
+            [[services]]
+            name = "db"
+            command = "run_db"
+
+            [[services]]
+            name = "db"
+            command = "run_db"
+            "#,
+            // Per-worktree service without a worktree.
+            r#"
+            [[services]]
+            name = "db"
+            command = "run_db"
+            scope = "worktree"
+
+            [[tests]]
+            name = "query"
+            services = ["db"]
+            requires_worktree = false
+            command = "true"
+            "#,
+        ] {
+            expect_that!(parse_toml(bad), err(anything()), "{bad}");
+        }
+    }
+
+    #[googletest::test]
+    fn test_shell() {
+        let test_program = |shell_line: &str| {
+            let config = parse_toml(&format!(
+                r#"
+                {shell_line}
+
+                [[tests]]
+                name = "foo"
+                command = "echo foo"
+                "#
+            ))
+            .unwrap();
+            let test = config.tests.node(&TestName::new("foo")).unwrap().clone();
+            (test.steps[0].program.clone(), test.config_hash.clone())
+        };
+        let (default_program, default_hash) = test_program("");
+        let (zsh_program, zsh_hash) = test_program(r#"shell = "zsh""#);
+        assert_eq!(default_program, OsString::from("bash"));
+        assert_eq!(zsh_program, OsString::from("zsh"));
+        assert_ne!(default_hash, zsh_hash);
+    }
+
+    #[googletest::test]
+    fn test_cache_by_paths() {
+        let config = parse_toml(
+            r#"
+            [[tests]]
+            name = "build"
+            command = "make"
+            cache = { by_paths = ["arch/x86/", "kernel"] }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config
+                .tests
+                .node(&TestName::new("build"))
+                .unwrap()
+                .cache_policy,
+            CachePolicy::ByPaths(vec!["arch/x86".into(), "kernel".into()])
+        );
+
+        for bad_paths in ["[]", r#"["/kernel"]"#, r#"["/"]"#] {
+            expect_that!(
+                parse_toml(&format!(
+                    r#"
+                    [[tests]]
+                    name = "build"
+                    command = "make"
+                    cache = {{ by_paths = {bad_paths} }}
+                    "#
+                )),
+                err(anything()),
+                "{bad_paths}"
+            );
+        }
+    }
+
+    #[googletest::test]
+    fn test_result_ttl() {
+        let config = parse_toml(
+            r#"
+            [[tests]]
+            name = "build"
+            command = "make"
+            cache_ttl = "7d"
+            rerun_every = "90m"
+            "#,
+        )
+        .unwrap();
+        let test = config.tests.node(&TestName::new("build")).unwrap();
+        expect_that!(
+            test.cache_ttl,
+            some(eq(Duration::from_secs(7 * 24 * 60 * 60)))
+        );
+        expect_that!(test.rerun_every, some(eq(Duration::from_secs(90 * 60))));
+        expect_that!(
+            test.max_result_age(),
+            some(eq(Duration::from_secs(90 * 60)))
+        );
+
+        for bad_duration in [
+            "7",
+            "d",
+            "0h",
+            "1.5h",
+            "-1s",
+            "3 days",
+            "99999999999999999w",
+        ] {
+            expect_that!(
+                parse_toml(&format!(
+                    r#"
+                    [[tests]]
+                    name = "build"
+                    command = "make"
+                    cache_ttl = "{bad_duration}"
+                    "#
+                )),
+                err(anything()),
+                "{bad_duration}"
+            );
+        }
+    }
+
+    #[googletest::test]
+    fn test_result_ttl_not_hashed() {
+        let parse = |ttl: &str| {
+            let config = parse_toml(&format!(
+                r#"
+                [[tests]]
+                name = "build"
+                command = "make"
+                {ttl}
+                "#
+            ))
+            .unwrap();
+            let test = config.tests.node(&TestName::new("build")).unwrap().clone();
+            (test.config_hash.clone(), test.canonical_config.clone())
+        };
+        let without = parse("");
+        expect_that!(parse(r#"cache_ttl = "7d""#), eq(&without));
+        expect_that!(parse(r#"cache_ttl = "1h""#), eq(&without));
+        expect_that!(parse(r#"rerun_every = "90m""#), eq(&without));
+    }
+
+    #[googletest::test]
+    fn test_gc() {
+        let config = parse_toml(
+            r#"
+            [gc]
+            max_size = "20G"
+            max_age = "4w"
+            keep_reachable_from = ["origin/master"]
+            interval = "1h"
+            "#,
+        )
+        .unwrap();
+        expect_that!(
+            config.gc_policy,
+            eq(&GcPolicy {
+                max_size: Some(20 << 30),
+                max_age: Some(Duration::from_secs(4 * 7 * 24 * 60 * 60)),
+                keep_per_test: None,
+                keep_reachable_from: vec!["origin/master".into()],
+            })
+        );
+        expect_that!(config.gc_interval, some(eq(Duration::from_secs(60 * 60))));
+
+        for bad_gc in [
+            r#"max_size = "20GB""#,
+            r#"max_size = "1.5G""#,
+            r#"max_age = "forever""#,
+            // Nothing to collect.
+            r#"interval = "1h""#,
+        ] {
+            expect_that!(
+                parse_toml(&format!("[gc]\n{bad_gc}")),
+                err(anything()),
+                "{bad_gc}"
+            );
         }
     }
+
+    #[googletest::test]
+    fn test_remote_cache() {
+        let config = parse_toml(
+            r#"
+            [remote_cache]
+            url = "http://cache.example.com:8421/limmat"
+            "#,
+        )
+        .unwrap();
+        expect_that!(
+            config.remote_cache,
+            some(eq(&RemoteCacheConfig::new(
+                "http://cache.example.com:8421/limmat",
+                true,
+                false
+            )
+            .unwrap()))
+        );
+
+        for bad_url in ["https://cache.example.com", "cache.example.com", "data:foo"] {
+            expect_that!(
+                parse_toml(&format!("[remote_cache]\nurl = {bad_url:?}")),
+                err(anything()),
+                "{bad_url}"
+            );
+        }
+    }
+
+    #[googletest::test]
+    fn test_result_sharing() {
+        let config = parse_toml(
+            r#"
+            project = "my-project"
+            [[tests]]
+            name = "build"
+            command = "make"
+            cache = "by_tree"
+            share_results = true
+            "#,
+        )
+        .unwrap();
+        expect_that!(config.project, some(eq("my-project")));
+        let test = config.tests.node(&TestName::new("build")).unwrap();
+        expect_that!(test.share_results, eq(true));
+
+        expect_that!(
+            parse_toml(
+                r#"
+                [[tests]]
+                name = "build"
+                command = "make"
+                share_results = true
+                "#
+            ),
+            err(anything())
+        );
+        expect_that!(parse_toml(r#"project = "../oops""#), err(anything()));
+    }
+
+    #[googletest::test]
+    fn test_config_repos() {
+        let toml = r#"
+            [[config_repos]]
+            name = "scripts"
+            path = "$LIMMAT_ORIGIN/../scripts"
+            checkout = "exclusive"
+
+            [[tests]]
+            name = "foo"
+            config_repos = ["scripts"]
+            command = "$LIMMAT_CONFIG_REPO_scripts/run.sh"
+            "#;
+        let config: Config = toml::from_str(toml).unwrap();
+        let repos = config.parse_config_repos(Path::new("/my/repo")).unwrap();
+        assert_eq!(repos.len(), 1);
+        assert_eq!(repos[0].path, "/my/repo/../scripts");
+        assert_eq!(repos[0].rev, "HEAD");
+        assert_eq!(repos[0].checkout, CheckoutMode::Exclusive);
+
+        // The commit of the config repo is part of the test's config hash.
+        let parse = |commit: &str| {
+            let config: Config = toml::from_str(toml).unwrap();
+            let revs = HashMap::from([("scripts".to_owned(), CommitHash::new(commit))]);
+            let config = ParsedConfig::from(config, Path::new("/my/repo"), &revs).unwrap();
+            config.tests.node(&TestName::new("foo")).unwrap().clone()
+        };
+        let test = parse("aaaa");
+        assert_eq!(
+            test.config_repos,
+            vec![("scripts".to_owned(), CommitHash::new("aaaa"))]
+        );
+        assert_ne!(test.config_hash, parse("bbbb").config_hash);
+
+        // Undefined config repo.
+        expect_that!(
+            parse_toml(
+                r#"
+                [[tests]]
+                name = "foo"
+                config_repos = ["scripts"]
+                command = "true"
+                "#
+            ),
+            err(anything())
+        );
+        // Duplicate config repo.
+        let config: Config = toml::from_str(
+            r#"
+            [[config_repos]]
+            name = "scripts"
+            path = "/foo"
+
+            [[config_repos]]
+            name = "scripts"
+            path = "/bar"
+            "#,
+        )
+        .unwrap();
+        expect_that!(
+            config.parse_config_repos(Path::new("/my/repo")),
+            err(anything())
+        );
+    }
 }
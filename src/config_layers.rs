@@ -0,0 +1,494 @@
+// The config is merged from several files ("layers"): the host config
+// describes the machine (resources, worktrees etc), the repo config describes
+// the tests, and the local config can override either for a single checkout.
+// Later layers override earlier ones, except that the repo config can't
+// override the settings in HOST_KEYS if the host config has them: those are
+// about the machine, which whoever wrote the repo config doesn't know about.
+// Top-level keys are simply replaced,
+// except for the lists of named things (tests, resources etc), where entries
+// are replaced by name and new entries are appended. Entries are replaced
+// wholesale, there's no merging of the fields within a test.
+
+use std::{
+    collections::HashMap,
+    env,
+    fmt::{self, Display},
+    fs,
+    path::{self, Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context as _};
+#[allow(unused_imports)]
+use log::{debug, warn};
+use toml::{Table, Value};
+
+use crate::{config::Config, inputs};
+
+// Top-level keys of the config that are lists of things with names.
+const NAMED_LISTS: [&str; 5] = [
+    "worktree_pools",
+    "resources",
+    "services",
+    "config_repos",
+    "tests",
+];
+
+// Settings that belong to the host config. Named list entries are checked
+// individually, so the repo config can still add its own resources.
+const HOST_KEYS: [&str; 5] = [
+    "num_worktrees",
+    "worktree_pools",
+    "worktree_dir",
+    "shell",
+    "resources",
+];
+
+// Name of the local override file, which lives next to the repo config.
+const LOCAL_CONFIG_NAME: &str = "limmat.local.toml";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayerKind {
+    Host,
+    Repo,
+    Local,
+}
+
+impl Display for LayerKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Host => "host",
+                Self::Repo => "repo",
+                Self::Local => "local",
+            }
+        )
+    }
+}
+
+#[derive(Debug)]
+struct Layer {
+    kind: LayerKind,
+    path: PathBuf,
+}
+
+impl Display for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} config {}", self.kind, self.path.display())
+    }
+}
+
+// Identifies a value in the merged config, for tracking where it came from.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum ValueId {
+    Key(String),
+    // An entry in one of the NAMED_LISTS.
+    Entry { list: String, name: String },
+}
+
+#[derive(Debug)]
+pub struct ConfigLayers {
+    layers: Vec<Layer>,
+    merged: Table,
+    // Index into layers.
+    origins: HashMap<ValueId, usize>,
+}
+
+// The name of an entry in one of the NAMED_LISTS. Resources can just be a
+// string, which is its name.
+fn entry_name(list: &str, entry: &Value) -> anyhow::Result<String> {
+    match entry {
+        Value::String(name) if list == "resources" => Ok(name.clone()),
+        Value::Table(table) => match table.get("name") {
+            Some(Value::String(name)) => Ok(name.clone()),
+            _ => Err(anyhow!("entry in {list} has no name")),
+        },
+        _ => Err(anyhow!("invalid entry in {list}")),
+    }
+}
+
+// Relative input globs are relative to the config file they're in, so make them
+// absolute while we still know which file that is. Ones starting with ~ or a
+// variable only become paths once they're expanded, leave those alone.
+fn resolve_inputs(table: &mut Table, dir: &Path) {
+    let Some(Value::Array(tests)) = table.get_mut("tests") else {
+        return;
+    };
+    for test in tests {
+        let Some(Value::Array(patterns)) = test.get_mut("inputs") else {
+            continue;
+        };
+        for pattern in patterns {
+            if let Value::String(pattern) = pattern {
+                if !pattern.starts_with(['~', '$']) {
+                    *pattern = inputs::resolve(pattern, dir);
+                }
+            }
+        }
+    }
+}
+
+impl ConfigLayers {
+    // Path of the host config if the user didn't specify one. Unlike when the
+    // user specifies it explicitly, it's fine for this not to exist.
+    pub fn default_host_config() -> Option<PathBuf> {
+        directories::ProjectDirs::from("", "", "limmat")
+            .map(|dirs| dirs.config_dir().join("config.toml"))
+    }
+
+    // Find and load all the layers. host_config_arg and repo_config are the
+    // paths specified by the user (or found by searching, for the repo config),
+    // those must exist. The local config is looked for next to the repo config.
+    pub fn load(host_config_arg: &Option<PathBuf>, repo_config: &Path) -> anyhow::Result<Self> {
+        let host_config = match host_config_arg
+            .clone()
+            .or_else(|| env::var_os("LIMMAT_HOST_CONFIG").map(PathBuf::from))
+        {
+            Some(path) => Some(path),
+            None => Self::default_host_config().filter(|p| p.exists()),
+        };
+        let local_config = repo_config.with_file_name(LOCAL_CONFIG_NAME);
+
+        let mut layers = Vec::new();
+        if let Some(path) = host_config {
+            layers.push((LayerKind::Host, path));
+        }
+        layers.push((LayerKind::Repo, repo_config.to_owned()));
+        if local_config.exists() {
+            layers.push((LayerKind::Local, local_config));
+        }
+
+        let mut zelf = Self {
+            layers: Vec::new(),
+            merged: Table::new(),
+            origins: HashMap::new(),
+        };
+        for (kind, path) in layers {
+            let layer = Layer { kind, path };
+            let content = fs::read_to_string(&layer.path)
+                .with_context(|| format!("couldn't read {layer}"))?;
+            debug!("{layer}:\n{content}");
+            zelf.add_layer(layer, &content)?;
+        }
+        Ok(zelf)
+    }
+
+    fn add_layer(&mut self, layer: Layer, content: &str) -> anyhow::Result<()> {
+        // Check each layer is valid on its own, so that errors point at the
+        // file that caused them.
+        toml::from_str::<Config>(content).with_context(|| format!("couldn't parse {layer}"))?;
+        let mut table: Table = toml::from_str(content).unwrap();
+        let dir =
+            path::absolute(&layer.path).with_context(|| format!("finding directory of {layer}"))?;
+        resolve_inputs(&mut table, dir.parent().unwrap_or(Path::new("/")));
+
+        let layer_idx = self.layers.len();
+        let kind = layer.kind;
+        self.layers.push(layer);
+        for (key, value) in table {
+            let host_owned = kind == LayerKind::Repo && HOST_KEYS.contains(&key.as_str());
+            if !NAMED_LISTS.contains(&key.as_str()) {
+                let id = ValueId::Key(key.clone());
+                if host_owned && self.set_by_host(&id) {
+                    warn!(
+                        "Ignoring {key} from {}, it's set in the host config",
+                        self.layers[layer_idx]
+                    );
+                    continue;
+                }
+                self.origins.insert(ValueId::Key(key.clone()), layer_idx);
+                self.merged.insert(key, value);
+                continue;
+            }
+            let Value::Array(new_entries) = value else {
+                // from_str::<Config> should have caught this.
+                unreachable!("{key} isn't a list");
+            };
+            let Value::Array(entries) = self
+                .merged
+                .entry(key.clone())
+                .or_insert_with(|| Value::Array(Vec::new()))
+            else {
+                unreachable!("{key} isn't a list");
+            };
+            for new_entry in new_entries {
+                let name = entry_name(&key, &new_entry)?;
+                let id = ValueId::Entry {
+                    list: key.clone(),
+                    name: name.clone(),
+                };
+                if host_owned
+                    && self
+                        .origins
+                        .get(&id)
+                        .is_some_and(|&i| self.layers[i].kind == LayerKind::Host)
+                {
+                    warn!(
+                        "Ignoring {key} entry {name:?} from {}, it's set in the host config",
+                        self.layers[layer_idx]
+                    );
+                    continue;
+                }
+                match entries
+                    .iter_mut()
+                    .find(|e| entry_name(&key, e).is_ok_and(|n| n == name))
+                {
+                    Some(entry) => *entry = new_entry,
+                    None => entries.push(new_entry),
+                }
+                self.origins.insert(id, layer_idx);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn config(&self) -> anyhow::Result<Config> {
+        // The layers are checked individually but they might still conflict.
+        Value::Table(self.merged.clone())
+            .try_into()
+            .context("couldn't parse merged config")
+    }
+
+    fn set_by_host(&self, id: &ValueId) -> bool {
+        self.origins
+            .get(id)
+            .is_some_and(|&i| self.layers[i].kind == LayerKind::Host)
+    }
+
+    fn origin(&self, id: &ValueId) -> &Layer {
+        &self.layers[self.origins[id]]
+    }
+
+    // The merged config as TOML. If show_origin, each value is preceded by a
+    // comment saying which file it came from.
+    pub fn show(&self, show_origin: bool) -> String {
+        if !show_origin {
+            return toml::to_string(&self.merged).expect("couldn't serialize config");
+        }
+
+        // TOML requires plain values to come before arrays of tables, so we
+        // build those up separately.
+        let mut values = String::new();
+        let mut tables = String::new();
+        for (key, value) in &self.merged {
+            let Some(entries) = value
+                .as_array()
+                .filter(|_| NAMED_LISTS.contains(&key.as_str()))
+            else {
+                let origin = self.origin(&ValueId::Key(key.clone()));
+                values.push_str(&format!("# From {origin}\n{key} = {value}\n"));
+                continue;
+            };
+            let entry_origin = |entry| {
+                self.origin(&ValueId::Entry {
+                    list: key.clone(),
+                    name: entry_name(key, entry).unwrap(),
+                })
+            };
+            if entries.iter().all(|e| e.is_table()) {
+                for entry in entries {
+                    let mut table = Table::new();
+                    table.insert(key.clone(), Value::Array(vec![entry.clone()]));
+                    tables.push_str(&format!(
+                        "# From {}\n{}\n",
+                        entry_origin(entry),
+                        toml::to_string(&table).expect("couldn't serialize config")
+                    ));
+                }
+            } else {
+                values.push_str(&format!("{key} = [\n"));
+                for entry in entries {
+                    values.push_str(&format!("    {entry}, # From {}\n", entry_origin(entry)));
+                }
+                values.push_str("]\n");
+            }
+        }
+        format!("{values}\n{tables}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use googletest::{expect_that, prelude::*};
+    use pretty_assertions::assert_eq;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn write_layers(host: &str, repo: &str, local: Option<&str>) -> (TempDir, ConfigLayers) {
+        let dir = TempDir::new().unwrap();
+        let host_path = dir.path().join("host.toml");
+        let repo_path = dir.path().join("limmat.toml");
+        fs::write(&host_path, host).unwrap();
+        fs::write(&repo_path, repo).unwrap();
+        if let Some(local) = local {
+            fs::write(dir.path().join(LOCAL_CONFIG_NAME), local).unwrap();
+        }
+        let layers = ConfigLayers::load(&Some(host_path), &repo_path).unwrap();
+        (dir, layers)
+    }
+
+    #[googletest::test]
+    fn test_merge() {
+        let (dir, layers) = write_layers(
+            r#"
+            num_worktrees = 2
+            shell = "zsh"
+            resources = ["gpu"]
+            "#,
+            r#"
+            num_worktrees = 4
+            resources = [{ name = "gpu", count = 2 }]
+
+            [[tests]]
+            name = "build"
+            command = "make"
+
+            [[tests]]
+            name = "test"
+            command = "make test"
+            "#,
+            Some(
+                r#"
+                num_worktrees = 1
+
+                [[tests]]
+                name = "test"
+                command = "make test-quick"
+                "#,
+            ),
+        );
+        let merged: Table = toml::from_str(&layers.show(false)).unwrap();
+        let want: Table = toml::from_str(
+            r#"
+            num_worktrees = 1
+            shell = "zsh"
+            resources = ["gpu"]
+
+            [[tests]]
+            name = "build"
+            command = "make"
+
+            [[tests]]
+            name = "test"
+            command = "make test-quick"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(merged, want);
+        expect_that!(layers.config(), ok(anything()));
+
+        // The annotated version should be the same config.
+        let shown = layers.show(true);
+        assert_eq!(toml::from_str::<Table>(&shown).unwrap(), want);
+        let host_path = dir.path().join("host.toml");
+        let local_path = dir.path().join(LOCAL_CONFIG_NAME);
+        for want_line in [
+            format!(
+                "# From local config {}\nnum_worktrees = 1",
+                local_path.display()
+            ),
+            format!(
+                "# From host config {}\nshell = \"zsh\"",
+                host_path.display()
+            ),
+            format!("# From local config {}\n[[tests]]", local_path.display()),
+        ] {
+            expect_that!(shown, contains_substring(want_line));
+        }
+    }
+
+    #[googletest::test]
+    fn test_host_keys() {
+        let (_dir, layers) = write_layers(
+            r#"
+            num_worktrees = 2
+            resources = [{ name = "gpu", count = 1 }]
+            "#,
+            r#"
+            num_worktrees = 4
+            shell = "sh"
+            resources = [{ name = "gpu", count = 8 }, { name = "db", count = 1 }]
+            "#,
+            None,
+        );
+        // The repo config can set host settings that the host config doesn't
+        // have, but not override the ones it does.
+        let merged: Table = toml::from_str(&layers.show(false)).unwrap();
+        let want: Table = toml::from_str(
+            r#"
+            num_worktrees = 2
+            shell = "sh"
+            resources = [{ name = "gpu", count = 1 }, { name = "db", count = 1 }]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(merged, want);
+    }
+
+    #[googletest::test]
+    fn test_relative_inputs() {
+        let dir = TempDir::new().unwrap();
+        let dir_path = dir.path().canonicalize().unwrap();
+        let host_path = dir_path.join("host").join("host.toml");
+        let repo_path = dir_path.join("limmat.toml");
+        fs::create_dir(host_path.parent().unwrap()).unwrap();
+        fs::write(
+            &host_path,
+            r#"
+            [[tests]]
+            name = "host"
+            command = "true"
+            inputs = ["*.config"]
+            "#,
+        )
+        .unwrap();
+        fs::write(
+            &repo_path,
+            r#"
+            [[tests]]
+            name = "repo"
+            command = "true"
+            inputs = ["tools/run.sh", "/etc/foo", "~/foo", "$LIMMAT_ORIGIN/foo"]
+            "#,
+        )
+        .unwrap();
+        let layers = ConfigLayers::load(&Some(host_path), &repo_path).unwrap();
+        let merged: Table = toml::from_str(&layers.show(false)).unwrap();
+        let inputs = |i: usize| -> Vec<&str> {
+            merged["tests"][i]["inputs"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|v| v.as_str().unwrap())
+                .collect()
+        };
+        let dir_path = dir_path.to_str().unwrap();
+        assert_eq!(inputs(0), vec![format!("{dir_path}/host/*.config")]);
+        assert_eq!(
+            inputs(1),
+            vec![
+                format!("{dir_path}/tools/run.sh"),
+                "/etc/foo".to_owned(),
+                "~/foo".to_owned(),
+                "$LIMMAT_ORIGIN/foo".to_owned(),
+            ]
+        );
+    }
+
+    #[googletest::test]
+    fn test_bad_layer() {
+        let dir = TempDir::new().unwrap();
+        let host_path = dir.path().join("host.toml");
+        let repo_path = dir.path().join("limmat.toml");
+        fs::write(&host_path, "num_worktrees = \"lots\"").unwrap();
+        fs::write(&repo_path, "").unwrap();
+        let err = ConfigLayers::load(&Some(host_path.clone()), &repo_path).unwrap_err();
+        // The error should say which file is broken.
+        expect_that!(
+            format!("{err:#}"),
+            contains_substring(host_path.display().to_string())
+        );
+    }
+}
@@ -0,0 +1,519 @@
+// Config repos are Git repositories other than the one being tested, that
+// tests depend on (test scripts, device configs etc). Each one is resolved to
+// a commit, which is hashed into the config of the tests that use it. The
+// tests get a checkout of that commit.
+
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::{create_dir_all, OpenOptions},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    time::Instant,
+};
+
+use anyhow::{anyhow, Context as _};
+use futures::{future::join_all, stream, Stream, StreamExt as _};
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+use parking_lot::Mutex;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use sha3::{Digest, Sha3_256};
+use tempfile::TempDir;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    flock::ExclusiveFlock,
+    git::{CommitHash, PersistentWorktree, TempWorktree, Worktree},
+    process::CommandExt as _,
+};
+
+// How tests get a checkout of a config repo.
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy, Default, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckoutMode {
+    /// All tests using the same commit of the repo share one checkout, so
+    /// they mustn't modify it.
+    #[default]
+    Shared,
+    /// Each test job gets its own checkout, which is deleted when the job
+    /// finishes.
+    Exclusive,
+}
+
+// How to find a config repo. Construct via config::ConfigRepo.
+#[derive(Debug, Clone)]
+pub struct ConfigRepoConfig {
+    pub name: String,
+    // Local path or anything Git can clone, with variables already expanded.
+    pub path: String,
+    pub rev: String,
+    pub checkout: CheckoutMode,
+    pub watch: bool,
+}
+
+// The commit each config repo is at, keyed by name.
+pub type ConfigRepoRevs = HashMap<String, CommitHash>;
+
+#[derive(Debug)]
+struct ConfigRepo {
+    config: ConfigRepoConfig,
+    // Either the user's own repo, or our mirror of a remote one.
+    repo: PersistentWorktree,
+    // For watch_refs, this just produces the commit rev is at.
+    watch_spec: OsString,
+}
+
+// How many shared checkouts that no job is using to keep around, in case
+// they're needed again.
+const MAX_IDLE_SHARED: usize = 4;
+
+type SharedCheckouts = Mutex<HashMap<(String, CommitHash), SharedCheckout>>;
+
+#[derive(Debug)]
+pub struct ConfigRepos {
+    repos: HashMap<String, ConfigRepo>,
+    // Shared checkouts, created on demand and kept while jobs are using them.
+    // Once too many of them are idle, the least recently used get deleted.
+    shared: Arc<SharedCheckouts>,
+    max_idle_shared: usize,
+}
+
+#[derive(Debug)]
+struct SharedCheckout {
+    // Held while checking out, None until that's succeeded.
+    worktree: Arc<tokio::sync::Mutex<Option<TempWorktree>>>,
+    // Number of SharedGuards for it.
+    users: usize,
+    last_used: Instant,
+}
+
+// A shared checkout that's in use, it won't be deleted while this exists.
+#[derive(Debug)]
+pub struct SharedGuard {
+    shared: Arc<SharedCheckouts>,
+    key: (String, CommitHash),
+    path: PathBuf,
+}
+
+impl Drop for SharedGuard {
+    fn drop(&mut self) {
+        // After shutdown it's gone already.
+        if let Some(checkout) = self.shared.lock().get_mut(&self.key) {
+            checkout.users -= 1;
+            checkout.last_used = Instant::now();
+        }
+    }
+}
+
+// A checkout of a config repo for a job. Call cleanup when the job is done.
+#[derive(Debug)]
+pub enum ConfigRepoCheckout {
+    Shared(SharedGuard),
+    Exclusive(TempWorktree),
+}
+
+impl ConfigRepoCheckout {
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::Shared(guard) => &guard.path,
+            Self::Exclusive(worktree) => worktree.path(),
+        }
+    }
+
+    pub async fn cleanup(self) {
+        if let Self::Exclusive(worktree) = self {
+            worktree.cleanup().await;
+        }
+    }
+}
+
+// Get our mirror of a remote repo, updating it if fetch is set. Mirrors live
+// in the state directory so they can be reused by later Limmat instances.
+async fn mirror(state_dir: &Path, url: &str, fetch: bool) -> anyhow::Result<PathBuf> {
+    let mirrors_dir = state_dir.join("config_repos");
+    create_dir_all(&mirrors_dir).context("creating config repo mirror dir")?;
+    let digest = Sha3_256::digest(url.as_bytes());
+    let dir = mirrors_dir.join(&format!("{digest:x}")[..16]);
+    // Don't trip over other Limmats updating the same mirror.
+    let lock_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dir.with_extension("lock"))
+        .context("opening config repo mirror lock")?;
+    let _lock = ExclusiveFlock::new(lock_file).await?;
+
+    let repo = PersistentWorktree { path: dir.clone() };
+    if dir.exists() {
+        if !fetch {
+            return Ok(dir);
+        }
+        debug!("Fetching {url} into {dir:?}");
+        // If we can't fetch we can still test against what we had before.
+        if let Err(e) = repo.git(["fetch", "--quiet", "--prune"]).execute().await {
+            warn!("Couldn't fetch config repo {url}, using existing mirror: {e:#}");
+        }
+    } else {
+        info!("Cloning config repo {url} into {dir:?}");
+        repo.git(["clone", "--quiet", "--mirror", url])
+            .arg(&dir)
+            // The mirror dir doesn't exist yet.
+            .current_dir(&mirrors_dir)
+            .execute()
+            .await
+            .with_context(|| format!("cloning config repo {url}"))?;
+    }
+    Ok(dir)
+}
+
+impl ConfigRepos {
+    // Find all the config repos, cloning the remote ones if we don't have them
+    // yet. If fetch is set, the ones we do have get updated; that's only
+    // worth the wait for commands that run tests. Relative paths are relative
+    // to origin, the main repo worktree.
+    pub async fn open(
+        configs: impl IntoIterator<Item = ConfigRepoConfig>,
+        origin: &Path,
+        state_dir: &Path,
+        fetch: bool,
+    ) -> anyhow::Result<Self> {
+        let mut repos = HashMap::new();
+        for config in configs {
+            let local_path = origin.join(&config.path);
+            let path = if local_path.exists() {
+                local_path
+            } else {
+                mirror(state_dir, &config.path, fetch).await?
+            };
+            repos.insert(
+                config.name.clone(),
+                ConfigRepo {
+                    watch_spec: format!("{}^!", config.rev).into(),
+                    repo: PersistentWorktree { path },
+                    config,
+                },
+            );
+        }
+        Ok(Self {
+            repos,
+            shared: Arc::new(Mutex::new(HashMap::new())),
+            max_idle_shared: MAX_IDLE_SHARED,
+        })
+    }
+
+    #[cfg(test)]
+    fn with_max_idle_shared(mut self, max_idle_shared: usize) -> Self {
+        self.max_idle_shared = max_idle_shared;
+        self
+    }
+
+    // Look up the commits the repos are currently at.
+    pub async fn resolve(&self) -> anyhow::Result<ConfigRepoRevs> {
+        let mut revs = HashMap::new();
+        for (name, repo) in &self.repos {
+            let commit = repo
+                .repo
+                .rev_parse(&repo.config.rev)
+                .await
+                .with_context(|| format!("looking up revision of config repo {name:?}"))?
+                .ok_or_else(|| {
+                    anyhow!("no revision {:?} in config repo {name:?}", repo.config.rev)
+                })?;
+            revs.insert(name.clone(), commit.hash);
+        }
+        Ok(revs)
+    }
+
+    // Produces an item whenever the rev of one of the repos with watch set
+    // might have changed. Never terminates.
+    pub fn changes(&self) -> anyhow::Result<impl Stream<Item = anyhow::Result<()>> + Send + '_> {
+        let mut streams: Vec<Pin<Box<dyn Stream<Item = anyhow::Result<()>> + Send + '_>>> =
+            Vec::new();
+        for repo in self.repos.values().filter(|r| r.config.watch) {
+            let changes = repo
+                .repo
+                .watch_refs(&repo.watch_spec)
+                .with_context(|| format!("watching config repo {:?}", repo.config.name))?
+                // The first item is just the initial state.
+                .skip(1)
+                .map(|revs| revs.map(|_| ()));
+            streams.push(Box::pin(changes));
+        }
+        Ok(stream::select_all(streams).chain(stream::pending()))
+    }
+
+    // Check out the named repo at the given commit for a job.
+    pub async fn checkout(
+        &self,
+        ct: &CancellationToken,
+        name: &str,
+        commit: &CommitHash,
+    ) -> anyhow::Result<ConfigRepoCheckout> {
+        let repo = self
+            .repos
+            .get(name)
+            .ok_or_else(|| anyhow!("no such config repo {name:?}"))?;
+        match repo.config.checkout {
+            CheckoutMode::Shared => {
+                let key = (name.to_owned(), commit.clone());
+                let worktree = {
+                    let mut shared = self.shared.lock();
+                    let checkout = shared.entry(key.clone()).or_insert_with(|| SharedCheckout {
+                        worktree: Arc::new(tokio::sync::Mutex::new(None)),
+                        users: 0,
+                        last_used: Instant::now(),
+                    });
+                    checkout.users += 1;
+                    let worktree = checkout.worktree.clone();
+                    self.evict(&mut shared);
+                    worktree
+                };
+                let mut guard = SharedGuard {
+                    shared: self.shared.clone(),
+                    key,
+                    path: PathBuf::new(),
+                };
+                // Only this checkout's lock is held while checking out, other
+                // repos and commits can be checked out in parallel.
+                let mut worktree = worktree.lock().await;
+                if worktree.is_none() {
+                    *worktree = Some(repo.checkout(ct, commit).await?);
+                }
+                guard.path = worktree.as_ref().unwrap().path().to_owned();
+                Ok(ConfigRepoCheckout::Shared(guard))
+            }
+            CheckoutMode::Exclusive => Ok(ConfigRepoCheckout::Exclusive(
+                repo.checkout(ct, commit).await?,
+            )),
+        }
+    }
+
+    // Delete the least recently used shared checkouts that aren't in use, if
+    // there are too many.
+    fn evict(&self, shared: &mut HashMap<(String, CommitHash), SharedCheckout>) {
+        let mut idle: Vec<((String, CommitHash), Instant)> = shared
+            .iter()
+            .filter(|(_, checkout)| checkout.users == 0)
+            .map(|(key, checkout)| (key.clone(), checkout.last_used))
+            .collect();
+        if idle.len() <= self.max_idle_shared {
+            return;
+        }
+        idle.sort_by_key(|(_, last_used)| *last_used);
+        for (key, _) in &idle[..idle.len() - self.max_idle_shared] {
+            let checkout = shared.remove(key).unwrap();
+            // Nobody's using it so nobody can be holding the lock.
+            let worktree = checkout.worktree.try_lock().unwrap().take();
+            if let Some(worktree) = worktree {
+                debug!("Deleting checkout of config repo {:?} at {}", key.0, key.1);
+                tokio::spawn(worktree.cleanup());
+            }
+        }
+    }
+
+    // Delete the shared checkouts.
+    pub async fn shutdown(&self) {
+        let shared: Vec<_> = self
+            .shared
+            .lock()
+            .drain()
+            .filter_map(|(_, checkout)| checkout.worktree.try_lock().ok()?.take())
+            .collect();
+        join_all(shared.into_iter().map(|w| w.cleanup())).await;
+    }
+}
+
+impl ConfigRepo {
+    async fn checkout(
+        &self,
+        ct: &CancellationToken,
+        commit: &CommitHash,
+    ) -> anyhow::Result<TempWorktree> {
+        let name = &self.config.name;
+        debug!("Checking out config repo {name:?} at {commit}");
+        // A clone means we don't leave worktrees registered in the user's
+        // repo.
+        let temp_dir = TempDir::with_prefix(format!("limmat-config-repo-{name}-"))
+            .context("creating temp dir for config repo")?;
+        let worktree = TempWorktree::new_clone(ct, &self.repo, temp_dir)
+            .await
+            .with_context(|| format!("cloning config repo {name:?}"))?;
+        if let Err(e) = worktree.checkout(commit).await {
+            worktree.cleanup().await;
+            return Err(e.context(format!("checking out config repo {name:?}")));
+        }
+        Ok(worktree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt as _;
+    use googletest::{expect_that, prelude::*};
+    use std::time::Duration;
+
+    use tempfile::TempDir;
+    use tokio::{join, time::sleep};
+
+    use crate::{
+        git::test_utils::{TempRepo, WorktreeExt as _},
+        test_utils::timeout_5s,
+    };
+
+    use super::*;
+
+    fn config(name: &str, path: &Path, checkout: CheckoutMode) -> ConfigRepoConfig {
+        ConfigRepoConfig {
+            name: name.to_owned(),
+            path: path.to_string_lossy().into_owned(),
+            rev: "HEAD".to_owned(),
+            checkout,
+            watch: true,
+        }
+    }
+
+    #[googletest::test]
+    #[test_log::test(tokio::test)]
+    async fn test_config_repos() {
+        let repo = TempRepo::new().await.unwrap();
+        let commit1 = repo.commit("one").await.unwrap();
+        let state_dir = TempDir::new().unwrap();
+        let origin = TempDir::new().unwrap();
+        let repos = ConfigRepos::open(
+            [
+                config("shared", repo.path(), CheckoutMode::Shared),
+                config("exclusive", repo.path(), CheckoutMode::Exclusive),
+            ],
+            origin.path(),
+            state_dir.path(),
+            true,
+        )
+        .await
+        .unwrap();
+        let revs = repos.resolve().await.unwrap();
+        expect_that!(revs["shared"], eq(&commit1.hash));
+        expect_that!(revs["exclusive"], eq(&commit1.hash));
+
+        let ct = CancellationToken::new();
+        let shared1 = repos.checkout(&ct, "shared", &commit1.hash).await.unwrap();
+        let shared2 = repos.checkout(&ct, "shared", &commit1.hash).await.unwrap();
+        expect_that!(shared1.path(), eq(shared2.path()));
+        let exclusive1 = repos
+            .checkout(&ct, "exclusive", &commit1.hash)
+            .await
+            .unwrap();
+        let exclusive2 = repos
+            .checkout(&ct, "exclusive", &commit1.hash)
+            .await
+            .unwrap();
+        expect_that!(exclusive1.path(), not(eq(exclusive2.path())));
+        let exclusive_path = exclusive1.path().to_owned();
+        expect_that!(exclusive_path.exists(), eq(true));
+        exclusive1.cleanup().await;
+        exclusive2.cleanup().await;
+        expect_that!(exclusive_path.exists(), eq(false));
+
+        // Moving the repo on should be noticed.
+        let mut changes = Box::pin(repos.changes().unwrap());
+        let (change, commit2) = join!(timeout_5s(changes.next()), async {
+            // The watch only gets set up once we start polling, give it a
+            // chance.
+            sleep(Duration::from_millis(500)).await;
+            repo.commit("two").await.unwrap()
+        });
+        change
+            .expect("didn't notice config repo change")
+            .unwrap()
+            .unwrap();
+        expect_that!(repos.resolve().await.unwrap()["shared"], eq(&commit2.hash));
+        let shared3 = repos.checkout(&ct, "shared", &commit2.hash).await.unwrap();
+        expect_that!(shared3.path(), not(eq(shared1.path())));
+
+        let shared_path = shared1.path().to_owned();
+        repos.shutdown().await;
+        expect_that!(shared_path.exists(), eq(false));
+    }
+
+    #[googletest::test]
+    #[test_log::test(tokio::test)]
+    async fn test_config_repo_shared_evicted() {
+        let repo = TempRepo::new().await.unwrap();
+        let mut commits = Vec::new();
+        for i in 0..3 {
+            commits.push(repo.commit(format!("{i}")).await.unwrap());
+        }
+        let state_dir = TempDir::new().unwrap();
+        let origin = TempDir::new().unwrap();
+        let repos = ConfigRepos::open(
+            [config("shared", repo.path(), CheckoutMode::Shared)],
+            origin.path(),
+            state_dir.path(),
+            true,
+        )
+        .await
+        .unwrap()
+        .with_max_idle_shared(1);
+        let ct = CancellationToken::new();
+
+        // Once it's not in use and there are too many others, the oldest
+        // checkout gets deleted.
+        let mut paths = Vec::new();
+        for commit in &commits {
+            let checkout = repos.checkout(&ct, "shared", &commit.hash).await.unwrap();
+            paths.push(checkout.path().to_owned());
+            checkout.cleanup().await;
+        }
+        timeout_5s(async {
+            while paths[0].exists() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("idle checkout not deleted");
+        expect_that!(paths[1].exists(), eq(true));
+        expect_that!(paths[2].exists(), eq(true));
+
+        // It can still be checked out again.
+        let checkout = repos
+            .checkout(&ct, "shared", &commits[0].hash)
+            .await
+            .unwrap();
+        expect_that!(checkout.path().exists(), eq(true));
+        checkout.cleanup().await;
+        repos.shutdown().await;
+    }
+
+    #[googletest::test]
+    #[test_log::test(tokio::test)]
+    async fn test_config_repo_mirror() {
+        let repo = TempRepo::new().await.unwrap();
+        let commit1 = repo.commit("one").await.unwrap();
+        let state_dir = TempDir::new().unwrap();
+        let origin = TempDir::new().unwrap();
+        // A file:// URL doesn't exist as a local path so it gets mirrored.
+        let url = format!("file://{}", repo.path().display());
+        let (repo, url, origin, state_dir) = (&repo, &url, &origin, &state_dir);
+        let open = |fetch| async move {
+            let mut config = config("remote", repo.path(), CheckoutMode::Shared);
+            config.path = url.clone();
+            ConfigRepos::open([config], origin.path(), state_dir.path(), fetch)
+                .await
+                .unwrap()
+        };
+        // It has to be cloned even if we aren't fetching.
+        let repos = open(false).await;
+        expect_that!(repos.resolve().await.unwrap()["remote"], eq(&commit1.hash));
+
+        // Reopening should fetch new commits, if asked to.
+        let commit2 = repo.commit("two").await.unwrap();
+        expect_that!(repos.resolve().await.unwrap()["remote"], eq(&commit1.hash));
+        let repos = open(false).await;
+        expect_that!(repos.resolve().await.unwrap()["remote"], eq(&commit1.hash));
+        let repos = open(true).await;
+        expect_that!(repos.resolve().await.unwrap()["remote"], eq(&commit2.hash));
+    }
+}
@@ -20,7 +20,7 @@ pub trait GraphNode {
 
 // Ajacency-list for a directed acyclic graph, where nodes are identified
 // with a usize.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Dag<G: GraphNode> {
     nodes: Vec<G>,
     // maps ids that nodes know about themselves to their index in `nodes`.
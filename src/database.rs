@@ -1,35 +1,294 @@
 use std::{
-    fs::{create_dir, create_dir_all, File, OpenOptions},
-    io::ErrorKind::AlreadyExists,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::{self, Display},
+    fs::{self, create_dir, create_dir_all, File, OpenOptions},
+    io::{
+        ErrorKind::{AlreadyExists, NotFound},
+        Write as _,
+    },
     path::{Path, PathBuf},
     process::Stdio,
+    sync::Arc,
+    time::{Duration, SystemTime},
 };
 
 use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use futures::{
+    channel::mpsc,
+    future::{BoxFuture, FutureExt as _},
+    StreamExt as _,
+};
 #[allow(unused_imports)]
-use log::debug;
+use log::{debug, info, warn};
+use nix::sys::utsname::uname;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher as _};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 #[cfg(test)]
 use tempfile::NamedTempFile;
 
 use crate::{
     flock::{ExclusiveFlock, SharedFlock},
-    git::Hash,
-    test::{ConfigHash, ExitCode, TestCase, TestName, TestResult},
+    git::{CommitHash, Worktree},
+    test::{CachePolicy, ConfigHash, ExitCode, TestCase, TestResult},
     util::IoResultExt as _,
 };
 
+pub mod bundle;
+pub mod remote;
+mod sqlite;
+
+// Results that aren't specific to a repository, see Test::share_results.
+const SHARED_DIR: &str = "shared";
+// Results from before they were separated by repository, waiting for
+// migrate_legacy to move them into the right namespace.
+const LEGACY_DIR: &str = "legacy";
+const BACKUPS_DIR: &str = "backups";
+// Where db fsck --repair puts broken entries.
+const QUARANTINE_DIR: &str = "quarantine";
+// Entries get moved in here before they're deleted, see remove_locked_entry.
+const TRASH_DIR: &str = "trash";
+// A file in here gets touched whenever a result is forgotten, so that
+// running instances can notice, see ForgetWatcher.
+const EVENTS_DIR: &str = "events";
+// Created in an entry by Database::forget if it couldn't delete it straight
+// away. The result is ignored, and whoever runs the test next gets rid of it.
+const FORGOTTEN: &str = "forgotten";
+
+// Version of the layout of the database, stored in the version file in the
+// base dir:
+//
+// 1. No version file, results weren't separated by repository.
+// 2. Results are separated by repository.
+// 3. Each entry is locked via its own lock file instead of result.json, so
+//    that results can be written atomically. The files on disk are the same
+//    but versions that only know about 2 wouldn't respect the new locks, so
+//    this stops them using the database at the same time.
+const VERSION: u32 = 3;
+
+// MIGRATIONS[0] takes the database from version 1 to 2, and so on. None means
+// nothing on disk needs to change, just the version number. The others run
+// after the database has been backed up with hard links, so they mustn't
+// modify existing files in place, only move, delete or replace them.
+type Migration = fn(&Path) -> Result<()>;
+const MIGRATIONS: [Option<Migration>; VERSION as usize - 1] = [Some(migrate_v1), None];
+
 // Result database similar to the design described in
 // https://github.com/bjackman/git-brisect?tab=readme-ov-file#the-result-directory
-// TODO: Actually we should probably separate it by the repo lol. But how?
+// Results are separated by repository, each Database object only deals with
+// one repository's namespace (plus the results shared between repositories).
+// The results, logs and artifacts are always stored in the directory, the
+// backend (see ResultStore) decides how they're found.
 pub struct Database {
     pub base_dir: PathBuf,
+    dir: Arc<DirStore>,
+    store: Arc<dyn ResultStore>,
+    // Everyone using the database holds this, so it doesn't get migrated
+    // under them.
+    _lock: SharedFlock,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Backend {
+    // Just the directory, finding results means scanning it.
+    #[default]
+    Directory,
+    // The directory plus an SQLite index of the results in it.
+    Sqlite,
+}
+
+// The operations that differ between backends. Whatever the backend, the
+// locking is done with flocks on the entries in the directory, so different
+// processes can use different backends on the same database (although entries
+// stored by the directory backend won't be in the index until it's rebuilt,
+// see reindex).
+pub trait ResultStore: Send + Sync {
+    // Either get or create a result in the database. If there's a test running,
+    // this blocks until it's done.
+    fn lookup<'a>(&'a self, test_case: &'a TestCase) -> BoxFuture<'a, Result<LookupResult>>;
+
+    // Like lookup, but if there's no usable result just return None instead
+    // of creating an entry for you to run the test in.
+    fn find<'a>(&'a self, test_case: &'a TestCase) -> BoxFuture<'a, Result<Option<DatabaseEntry>>>;
+
+    // Figure out whether lookup would find a result, and if not why not. This
+    // doesn't wait for any locks so if a test is starting or finishing the
+    // answer might be out of date as soon as you get it.
+    fn explain(&self, test_case: &TestCase) -> Result<Explanation>;
+
+    // Entries stored in the database for this repository that match the
+    // query, including results shared with other repositories and entries for
+    // tests that didn't produce a result. Like explain, this doesn't take any
+    // locks.
+    fn entries(&self, query: &EntryQuery) -> Result<Vec<StoredEntry>>;
+
+    // Delete an entry, unless someone is using it (i.e. reading the result or
+    // running the test). Returns whether it's gone.
+    fn delete(&self, entry: &StoredEntry) -> Result<bool>;
+
+    // Pick up entries that were added to the directory behind the store's
+    // back.
+    fn reindex(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// The directory backend, this is also where everything about the directory
+// layout lives.
+struct DirStore {
+    base_dir: PathBuf,
+    // Relative to base_dir, see Database::create_or_open.
+    namespace: PathBuf,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 struct TestResultEntry {
     config_hash: ConfigHash,
+    // The full config that config_hash was computed from. Entries written by
+    // older versions don't have this.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    config: Option<serde_json::Value>,
     result: TestResult,
+    // Also missing from entries written by older versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    finished_at: Option<SystemTime>,
+    #[serde(default)]
+    metadata: RunMetadata,
+}
+
+// Information about how a result was produced. This doesn't affect caching,
+// it's just for humans. Everything is optional because older versions didn't
+// record any of it.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunMetadata {
+    // The commit that was tested. For results cached by tree or by paths,
+    // this is just the first commit the result was produced for.
+    pub commit: Option<String>,
+    pub started_at: Option<SystemTime>,
+    pub duration: Option<Duration>,
+    pub hostname: Option<String>,
+    pub limmat_version: Option<String>,
+    #[serde(default)]
+    pub steps: Vec<StepMetadata>,
+    // Set if the result was produced elsewhere and brought in with db import,
+    // to the host that exported it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub imported_from: Option<String>,
+}
+
+impl RunMetadata {
+    fn start(commit: Option<&CommitHash>) -> Self {
+        Self {
+            commit: commit.map(|c| c.to_string()),
+            started_at: Some(SystemTime::now()),
+            duration: None,
+            hostname: hostname(),
+            limmat_version: Some(env!("CARGO_PKG_VERSION").to_owned()),
+            steps: Vec::new(),
+            imported_from: None,
+        }
+    }
+}
+
+fn hostname() -> Option<String> {
+    uname()
+        .map(|u| u.nodename().to_string_lossy().into_owned())
+        .ok()
+}
+
+// Tests without named steps still get one of these.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct StepMetadata {
+    pub name: Option<String>,
+    pub started_at: SystemTime,
+    pub duration: Option<Duration>,
+    // Only set if the step had its own worktree.
+    pub worktree: Option<PathBuf>,
+    // Tokens of the user-configured resources the step held.
+    pub resources: BTreeMap<String, Vec<String>>,
+    // Set if the step was killed by a signal. Results aren't stored in that
+    // case, but this still ends up in the error record.
+    pub signal: Option<i32>,
+}
+
+// Stored in place of a result when the test didn't produce one.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+struct ErrorEntry {
+    error: String,
+    metadata: RunMetadata,
+}
+
+impl TestResultEntry {
+    fn age(&self) -> Option<Duration> {
+        // If the clock went backwards, just say it's brand new.
+        self.finished_at
+            .map(|t| t.elapsed().unwrap_or(Duration::ZERO))
+    }
+
+    // Is this result too old to be used for this test case? If we don't know
+    // how old it is, assume it's too old.
+    fn expired(&self, test_case: &TestCase) -> bool {
+        test_case
+            .test
+            .max_result_age()
+            .is_some_and(|max_age| self.age().map_or(true, |age| age >= max_age))
+    }
+
+    // Was this result produced by the same config as the test case has now?
+    // For entries without a stored config we can only go by the hash.
+    fn config_matches(&self, test_case: &TestCase) -> bool {
+        self.config_hash == test_case.test.config_hash
+            && self
+                .config
+                .as_ref()
+                .map_or(true, |config| *config == test_case.test.canonical_config)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    // The result JSON is empty or can't be parsed.
+    CorruptResult(String),
+    // There's a result but some of its logs (relative to the entry) are
+    // missing.
+    MissingLogs(Vec<PathBuf>),
+    // There's neither a result nor an error, but no test is running.
+    NoResult,
+    // The result is from a config that none of the tests have.
+    UnknownConfig,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::CorruptResult(error) => write!(f, "corrupt result JSON ({error})"),
+            Self::MissingLogs(paths) => write!(
+                f,
+                "missing {}",
+                paths
+                    .iter()
+                    .map(|p| p.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Self::NoResult => write!(f, "no result or error"),
+            Self::UnknownConfig => write!(f, "result from unknown config"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FsckIssue {
+    pub path: PathBuf,
+    pub problem: Problem,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Repair {
+    Delete,
+    // Move into the quarantine dir in the database, for a human to look at.
+    Quarantine,
 }
 
 pub enum LookupResult {
@@ -39,114 +298,1177 @@ pub enum LookupResult {
     YouRunIt(DatabaseOutput),
 }
 
-// "Database" which is really just a directory. Entries are flocked via their main JSON file.
-// I am not really sure if this flocking is safe if you open the same entry
-// twice within the same process:
-// https://stackoverflow.com/questions/79266574/is-flock-per-ofd-or-per-process-per-file
-// For now I am just gonna assume flock has the most helpful semantics among the
-// range of ambiguity and hope it's fine.
+// What the database has to say about a test case, for explaining to the user
+// whether it will be re-run.
+pub enum Explanation {
+    CacheDisabled,
+    NoResult,
+    // The test was started but it didn't produce a result, either because of
+    // an error or because Limmat was killed. In the latter case there's no
+    // error message.
+    Error { message: Option<String> },
+    // There's a result and it's still valid.
+    Cached(TestResult),
+    // There's a result but it's too old. The age is None for results written
+    // by older versions.
+    Expired { age: Option<Duration> },
+    // There's a result but it was produced by a different config. The stored
+    // config is None for results written by older versions.
+    ConfigChanged { stored: Option<serde_json::Value> },
+    // There's a result but someone asked for it to be forgotten, it's waiting
+    // to be re-run.
+    Forgotten,
+    // The test is being run right now, whatever was there before.
+    Running,
+}
+
+pub enum ForgetOutcome {
+    // There was nothing to forget.
+    NotFound,
+    Deleted,
+    // Someone's using the result, so it's been marked as forgotten instead.
+    // It'll be replaced when the test is re-run.
+    Marked,
+}
+
+// Which entries to get from ResultStore::entries. The default matches
+// everything.
+#[derive(Debug, Clone, Default)]
+pub struct EntryQuery {
+    pub test: Option<String>,
+    // Only entries for these commits.
+    pub commits: Option<HashSet<CommitHash>>,
+    // Only entries that finished at or after this.
+    pub since: Option<SystemTime>,
+    pub outcome: Option<Outcome>,
+}
+
+impl EntryQuery {
+    fn matches(&self, entry: &StoredEntry) -> bool {
+        self.test.as_ref().map_or(true, |t| *t == entry.test_name)
+            && self.commits.as_ref().map_or(true, |commits| {
+                entry.commit.as_ref().is_some_and(|c| commits.contains(c))
+            })
+            && self.since.map_or(true, |since| entry.finished_at >= since)
+            && self.outcome.map_or(true, |o| o == entry.outcome())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Outcome {
+    Success,
+    Failure,
+    // There's no result, the test didn't finish or it hit an error.
+    Error,
+}
+
 impl Database {
-    pub fn create_or_open(base_dir: &Path) -> anyhow::Result<Self> {
+    // namespace is relative to base_dir, see project_namespace and
+    // root_commit_namespace. If the database was created by an older version
+    // of Limmat, it gets backed up and migrated to the current layout. That
+    // fails if anyone else is using it.
+    pub async fn create_or_open(
+        base_dir: &Path,
+        namespace: impl AsRef<Path>,
+        backend: Backend,
+    ) -> anyhow::Result<Self> {
         create_dir_all(base_dir).context(format!(
             "creating result database dir at {}",
             base_dir.display()
         ))?;
+        let open_lock = || {
+            let path = base_dir.join("lock");
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)
+                .with_context(|| format!("opening {}", path.display()))
+        };
+        let mut lock = SharedFlock::new(open_lock()?)
+            .await
+            .context("locking result database")?;
+        let version = read_version(base_dir)?;
+        if version > VERSION {
+            bail!(
+                "result database at {} was written by a newer version of Limmat \
+                 (layout version {version}, this version only supports up to {VERSION})",
+                base_dir.display()
+            );
+        }
+        if version < VERSION {
+            drop(lock);
+            let Some(exclusive) = ExclusiveFlock::try_new(open_lock()?)? else {
+                bail!(
+                    "result database at {} needs to be upgraded but another instance of \
+                     Limmat is using it, stop it and try again",
+                    base_dir.display()
+                );
+            };
+            // Someone else might have done it while we weren't holding the
+            // lock.
+            migrate(base_dir, read_version(base_dir)?)
+                .with_context(|| format!("upgrading result database at {}", base_dir.display()))?;
+            lock = exclusive
+                .downgrade()
+                .await
+                .context("downgrading result database lock")?;
+        }
+        let namespace = namespace.as_ref().to_owned();
+        create_dir_all(base_dir.join(EVENTS_DIR)).context("creating events dir")?;
+        create_dir_all(base_dir.join(&namespace)).context(format!(
+            "creating result database dir at {}",
+            base_dir.display()
+        ))?;
+        let dir = Arc::new(DirStore {
+            base_dir: base_dir.to_owned(),
+            namespace,
+        });
+        let store: Arc<dyn ResultStore> = match backend {
+            Backend::Directory => dir.clone(),
+            Backend::Sqlite => {
+                Arc::new(sqlite::SqliteStore::open(dir.clone()).context("opening result index")?)
+            }
+        };
         Ok(Self {
             base_dir: base_dir.to_owned(),
+            dir,
+            store,
+            _lock: lock,
         })
     }
 
-    pub fn result_relpath(test_case: &TestCase) -> PathBuf {
-        Path::new(test_case.storage_hash()).join(&test_case.test.name)
+    // Look for results in a remote cache before running tests, and upload
+    // them there afterwards. See remote::RemoteStore.
+    pub fn with_remote_cache(mut self, config: remote::RemoteCacheConfig) -> Result<Self> {
+        self.store = Arc::new(remote::RemoteStore::new(self.store, config)?);
+        Ok(self)
     }
 
-    fn result_path(&self, hash: &Hash, test_name: &TestName) -> PathBuf {
-        self.base_dir.join::<&str>(hash.as_ref()).join(test_name)
+    // Where results for a repository go, relative to the base dir. If the user
+    // configured a project name, that's used so that unrelated repositories
+    // can share results. Otherwise it's the root commit, so clones of the same
+    // repository share them anyway.
+    pub fn project_namespace(project: &str) -> PathBuf {
+        Path::new("projects").join(project)
     }
 
-    // Either get or create a result in the database. If there's a test running,
-    // this blocks until it's done.
+    pub fn root_commit_namespace(root_commit: &CommitHash) -> PathBuf {
+        Path::new("repos").join(root_commit.to_string())
+    }
+
+    // Until a repository has any commits there's no root commit to identify it
+    // by, so it gets a namespace of its own, identified by repo_id (anything
+    // that's unique to that repository on this host). Once it has commits,
+    // adopt_unborn moves the results into the real namespace.
+    pub fn unborn_namespace(repo_id: &str) -> PathBuf {
+        Path::new("unborn").join(repo_id)
+    }
+
+    // Relative to the base dir.
+    pub fn result_relpath(&self, test_case: &TestCase) -> PathBuf {
+        self.dir.result_relpath(test_case)
+    }
+
+    // Directory containing the stdout and stderr of a step, relative to the
+    // result directory. Tests with only a single command don't have named
+    // steps, their output goes directly in the result directory.
+    pub fn step_relpath(step: Option<&str>) -> PathBuf {
+        match step {
+            Some(name) => Path::new("steps").join(name),
+            None => PathBuf::new(),
+        }
+    }
+
+    // See ResultStore for these.
+
     pub async fn lookup(&self, test_case: &TestCase) -> Result<LookupResult> {
-        let result_dir = self.result_path(test_case.storage_hash(), &test_case.test.name);
-        create_dir_all(&result_dir)
-            .with_context(|| format!("creating commit result dir at {}", result_dir.display()))?;
-        let json_path = result_dir.join("result.json");
+        self.store.lookup(test_case).await
+    }
 
-        let parse_result = |json: &str| -> Option<TestResultEntry> {
-            // Manually ignore empty JSON to avoid log spam.
-            if json.is_empty() {
-                return None;
-            }
-            match serde_json::from_str::<TestResultEntry>(json) {
-                Ok(test_result) => {
-                    // Has the configuration changed? if not we need to rerun regardless.
-                    if test_result.config_hash == test_case.test.config_hash {
-                        // Was the test configured to accept cached results?
-                        if test_case.cache_hash.is_some() {
-                            // Cool, we're done.
-                            return Some(test_result);
-                        }
-                    }
-                }
-                Err(e) => {
-                    // This probably just means limmat got killed before we finished
-                    // writing the result.
-                    debug!(
-                        "Error reading result JSON from {}: {e} - JSON\n{:?}",
-                        json_path.display(),
-                        json,
-                    );
-                }
+    pub async fn find(&self, test_case: &TestCase) -> Result<Option<DatabaseEntry>> {
+        self.store.find(test_case).await
+    }
+
+    pub fn explain(&self, test_case: &TestCase) -> Result<Explanation> {
+        self.store.explain(test_case)
+    }
+
+    pub fn entries(&self, query: &EntryQuery) -> Result<Vec<StoredEntry>> {
+        self.store.entries(query)
+    }
+
+    pub fn delete(&self, entry: &StoredEntry) -> Result<bool> {
+        self.store.delete(entry)
+    }
+
+    pub fn reindex(&self) -> Result<()> {
+        self.store.reindex()
+    }
+
+    // Get rid of the result for the test case so it gets re-run. If it can't
+    // be deleted because it's in use, it's marked so that it gets ignored, and
+    // anyone using it is notified via ForgetWatcher. If a test is running for
+    // the test case, its result is kept.
+    pub fn forget(&self, test_case: &TestCase) -> Result<ForgetOutcome> {
+        let outcome = self.dir.forget(test_case)?;
+        if matches!(outcome, ForgetOutcome::Deleted) {
+            self.store.reindex()?;
+        }
+        Ok(outcome)
+    }
+
+    // Get notified when some other process forgets results.
+    pub fn forget_watcher(&self) -> Result<ForgetWatcher> {
+        ForgetWatcher::new(&self.base_dir.join(EVENTS_DIR))
+    }
+
+    // Check this repository's entries for damage, e.g. from crashes with older
+    // versions of Limmat that didn't write results atomically. Entries that are
+    // in use are skipped. If known_configs is set (test name to config hashes)
+    // results from other configs are reported too. If repair is set, the
+    // entries with problems are removed.
+    pub fn fsck(
+        &self,
+        known_configs: Option<&HashMap<String, HashSet<ConfigHash>>>,
+        repair: Option<Repair>,
+    ) -> Result<Vec<FsckIssue>> {
+        let issues = self.dir.fsck(known_configs, repair)?;
+        if repair.is_some() && !issues.is_empty() {
+            self.store.reindex()?;
+        }
+        Ok(issues)
+    }
+
+    // Move results from the old layout, from before results were separated by
+    // repository (see migrate_v1), into this repository's namespace. Results
+    // are only moved if the commit (or tree) they were for exists in repo; the
+    // rest presumably belong to other repositories and get moved when Limmat
+    // runs there. Returns how many were moved.
+    pub async fn migrate_legacy(&self, repo: &impl Worktree) -> Result<usize> {
+        let moved = self.dir.migrate_legacy(repo).await?;
+        if moved != 0 {
+            self.store.reindex()?;
+        }
+        Ok(moved)
+    }
+
+    // Move results that were stored before the repository had any commits
+    // (see unborn_namespace) into this namespace. Returns how many were
+    // moved.
+    pub fn adopt_unborn(&self, repo_id: &str) -> Result<usize> {
+        let moved = self.dir.adopt(&Self::unborn_namespace(repo_id))?;
+        if moved != 0 {
+            self.store.reindex()?;
+        }
+        Ok(moved)
+    }
+}
+
+// "Database" which is really just a directory. Each entry has a lock file that
+// gets flocked, the result JSON is written atomically by replacing it, so it
+// can't be flocked itself.
+// I am not really sure if this flocking is safe if you open the same entry
+// twice within the same process:
+// https://stackoverflow.com/questions/79266574/is-flock-per-ofd-or-per-process-per-file
+// For now I am just gonna assume flock has the most helpful semantics among the
+// range of ambiguity and hope it's fine.
+impl DirStore {
+    fn result_relpath(&self, test_case: &TestCase) -> PathBuf {
+        let hash = Path::new(test_case.storage_hash());
+        let hash_dir = match (&test_case.test.cache_policy, &test_case.cache_hash) {
+            // These aren't Git object IDs so keep them separate, just to avoid
+            // confusing humans.
+            (CachePolicy::ByPaths(_), Some(_)) => self.namespace.join("by_paths").join(hash),
+            (CachePolicy::ByTree, Some(_)) if test_case.test.share_results => {
+                Path::new(SHARED_DIR).join(hash)
             }
-            None
+            _ => self.namespace.join(hash),
         };
+        hash_dir.join(&test_case.test.name)
+    }
+
+    fn result_path(&self, test_case: &TestCase) -> PathBuf {
+        self.base_dir.join(self.result_relpath(test_case))
+    }
+
+    async fn lookup(&self, test_case: &TestCase) -> Result<LookupResult> {
+        let result_dir = self.result_path(test_case);
+        let json_path = result_dir.join("result.json");
+        let lock_path = result_dir.join("lock");
+
+        let read_result = || read_usable_result(&json_path, test_case);
 
         // Don't block forever.
         for _ in 0..5 {
-            let json_file = OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .truncate(false)
-                .open(result_dir.join("result.json"))
-                .context("opening result JSON")?;
-            let flock = SharedFlock::new(json_file)
+            // The entry might get garbage collected or migrated at any point
+            // where we don't hold the lock, so all of this needs to be retried
+            // if that happens.
+            create_dir_all(&result_dir).with_context(|| {
+                format!("creating commit result dir at {}", result_dir.display())
+            })?;
+            let lock_file = match open_entry_lock(&result_dir) {
+                Ok(f) => f,
+                Err(e) if e.kind() == NotFound => continue,
+                Err(e) => return Err(e).context("opening database entry lock"),
+            };
+            let flock = SharedFlock::new(lock_file)
                 .await
-                .context("locking JSON file for reading")?;
+                .context("locking database entry for reading")?;
+            if !flock.is_at(&lock_path)? {
+                continue;
+            }
 
-            if let Some(test_result) = parse_result(flock.content()) {
+            if let Some(test_result) = read_result()? {
                 return Ok(LookupResult::FoundResult(DatabaseEntry {
                     base_path: result_dir.clone(),
                     result: test_result,
-                    _json_flock: flock,
+                    _flock: flock,
                     #[cfg(test)]
                     _tempfile: None,
                 }));
             }
 
             // Seems we have to run the test. For that we'll need an exclusive lock.
-            let flock = flock.upgrade().await.context("upgrading JSON file lock")?;
+            let flock = flock
+                .upgrade()
+                .await
+                .context("upgrading database entry lock")?;
+            if !flock.is_at(&lock_path)? {
+                continue;
+            }
 
             // But, that upgrade wasn't atomic, someone else might have jumped
             // in and run the test. Check if that's the case...
-            if parse_result(flock.content()).is_some() {
+            if read_result()?.is_some() {
                 // OK great someone ran the test, so we just wanna return the result. But for that
                 // we need to downgrade the lock to a shared lock, which is also not atomic. At the
                 // time of writing, this is harmless: we know the test case is cacheable (otherwise
-                // parse_result never returns Some) so if someone else gets the lock during the
-                // downgrade, they aren't gonna re-run the test. But, we want the flexibility to
-                // later implement at-will re-runs of tests, and more importantly deletion of test
-                // results. So we downgrade the lock by just going back around this loop.
+                // read_usable_result never returns Some) so if someone else gets the lock during the
+                // downgrade, they aren't gonna re-run the test. But they might garbage collect it,
+                // or forget it so the test gets re-run (see Database::forget). So we downgrade the
+                // lock by just going back around this loop.
                 continue;
             }
 
+            // If the result was forgotten, it's up to us to get rid of it.
+            // Otherwise if the test doesn't produce a new one, it would come
+            // back.
+            if result_dir.join(FORGOTTEN).exists() {
+                fs::remove_file(result_dir.join("result.json"))
+                    .ignore(NotFound)
+                    .context("removing forgotten result")?;
+                fs::remove_file(result_dir.join(FORGOTTEN)).context("removing forgotten marker")?;
+            }
+
             return Ok(LookupResult::YouRunIt(
-                DatabaseOutput::new(result_dir, test_case.test.config_hash.clone(), flock)
-                    .context("creating database entry")?,
+                DatabaseOutput::new(
+                    result_dir,
+                    test_case.test.config_hash.clone(),
+                    Some(test_case.test.canonical_config.clone()),
+                    &test_case.commit_hash,
+                    flock,
+                )
+                .context("creating database entry")?,
             ));
         }
         bail!("too much database contention, something fishy going on")
     }
+
+    async fn find(&self, test_case: &TestCase) -> Result<Option<DatabaseEntry>> {
+        let result_dir = self.result_path(test_case);
+        let lock_path = result_dir.join("lock");
+        for _ in 0..5 {
+            // Unlike lookup, don't create the entry directory. The lock file
+            // does get created if the entry exists, that's harmless.
+            let lock_file = match open_entry_lock(&result_dir) {
+                Ok(f) => f,
+                Err(e) if e.kind() == NotFound => return Ok(None),
+                Err(e) => return Err(e).context("opening database entry lock"),
+            };
+            let flock = SharedFlock::new(lock_file)
+                .await
+                .context("locking database entry for reading")?;
+            if !flock.is_at(&lock_path)? {
+                continue;
+            }
+            let result = read_usable_result(&result_dir.join("result.json"), test_case)?;
+            return Ok(result.map(|result| DatabaseEntry {
+                base_path: result_dir.clone(),
+                result,
+                _flock: flock,
+                #[cfg(test)]
+                _tempfile: None,
+            }));
+        }
+        bail!("too much database contention, something fishy going on")
+    }
+
+    fn explain(&self, test_case: &TestCase) -> Result<Explanation> {
+        if test_case.cache_hash.is_none() {
+            return Ok(Explanation::CacheDisabled);
+        }
+        let result_dir = self.result_path(test_case);
+        // Whoever's running the test holds the lock exclusively until it's
+        // done, so without this a running test would look like an error.
+        match File::open(result_dir.join("lock")) {
+            Ok(file) => {
+                if SharedFlock::try_new(file)?.is_none() {
+                    return Ok(Explanation::Running);
+                }
+            }
+            Err(e) if e.kind() == NotFound => (),
+            Err(e) => return Err(e).context("opening entry lock"),
+        }
+        let json_path = result_dir.join("result.json");
+        let json = match fs::read_to_string(&json_path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == NotFound => String::new(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("reading {}", json_path.display()));
+            }
+        };
+        // Older versions created empty JSON files for entries without results.
+        if json.is_empty() {
+            // lookup creates the entry, but the artifacts dir only gets
+            // created once the first step starts. Jobs can also fail before
+            // that, e.g. if the worktree couldn't be set up.
+            if result_dir.join("artifacts").exists() || result_dir.join("error.json").exists() {
+                let message = fs::read_to_string(result_dir.join("error.json"))
+                    .ok()
+                    .and_then(|json| serde_json::from_str::<ErrorEntry>(&json).ok())
+                    .map(|entry| entry.error);
+                return Ok(Explanation::Error { message });
+            }
+            return Ok(Explanation::NoResult);
+        }
+        // lookup ignores corrupted entries, so we do too.
+        let Ok(entry) = serde_json::from_str::<TestResultEntry>(&json) else {
+            return Ok(Explanation::NoResult);
+        };
+        if result_dir.join(FORGOTTEN).exists() {
+            Ok(Explanation::Forgotten)
+        } else if !entry.config_matches(test_case) {
+            Ok(Explanation::ConfigChanged {
+                stored: entry.config,
+            })
+        } else if entry.expired(test_case) {
+            Ok(Explanation::Expired { age: entry.age() })
+        } else {
+            Ok(Explanation::Cached(entry.result))
+        }
+    }
+
+    // All the directories that might be entries visible to this repository,
+    // and whether they're for by_paths results.
+    fn entry_dirs(&self) -> Result<Vec<(PathBuf, bool)>> {
+        let mut dirs = Vec::new();
+        let namespace_dir = self.base_dir.join(&self.namespace);
+        let by_paths_dir = namespace_dir.join("by_paths");
+        let shared_dir = self.base_dir.join(SHARED_DIR);
+        for (parent, by_paths) in [
+            (&namespace_dir, false),
+            (&by_paths_dir, true),
+            (&shared_dir, false),
+        ] {
+            for hash_dir in subdirs(parent)? {
+                if !by_paths && hash_dir == by_paths_dir {
+                    continue;
+                }
+                for result_dir in subdirs(&hash_dir)? {
+                    dirs.push((result_dir, by_paths));
+                }
+            }
+        }
+        Ok(dirs)
+    }
+
+    fn entries(&self, query: &EntryQuery) -> Result<Vec<StoredEntry>> {
+        let mut entries = Vec::new();
+        for (result_dir, by_paths) in self.entry_dirs()? {
+            if let Some(entry) = StoredEntry::read(result_dir, by_paths)? {
+                if query.matches(&entry) {
+                    entries.push(entry);
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    fn delete(&self, entry: &StoredEntry) -> Result<bool> {
+        let lock_path = entry.path.join("lock");
+        let lock_file = match open_entry_lock(&entry.path) {
+            Ok(f) => f,
+            // Someone else got there first.
+            Err(e) if e.kind() == NotFound => return Ok(true),
+            Err(e) => {
+                return Err(e).with_context(|| format!("opening {}", lock_path.display()));
+            }
+        };
+        let Some(flock) = ExclusiveFlock::try_new(lock_file)? else {
+            return Ok(false);
+        };
+        if !flock.is_at(&lock_path)? {
+            // Someone else deleted it, the entry there now (if any) isn't the
+            // one we were asked about.
+            return Ok(true);
+        }
+        remove_locked_entry(&self.base_dir, &entry.path)?;
+        Ok(true)
+    }
+
+    // See Database::forget.
+    fn forget(&self, test_case: &TestCase) -> Result<ForgetOutcome> {
+        let path = self.result_path(test_case);
+        let lock_path = path.join("lock");
+        let lock_file = match open_entry_lock(&path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == NotFound => return Ok(ForgetOutcome::NotFound),
+            Err(e) => {
+                return Err(e).with_context(|| format!("opening {}", lock_path.display()));
+            }
+        };
+        if let Some(flock) = ExclusiveFlock::try_new(lock_file)? {
+            if !flock.is_at(&lock_path)? {
+                // Someone else deleted it.
+                return Ok(ForgetOutcome::NotFound);
+            }
+            remove_locked_entry(&self.base_dir, &path)?;
+            return Ok(ForgetOutcome::Deleted);
+        }
+        // Someone's reading the result (e.g. a watch process showing it in the
+        // UI) or running the test. Either way we can't delete it, so mark it
+        // and tell them about it.
+        match File::create(path.join(FORGOTTEN)) {
+            Ok(_) => (),
+            // Got deleted in the meantime.
+            Err(e) if e.kind() == NotFound => return Ok(ForgetOutcome::Deleted),
+            Err(e) => return Err(e).context("marking result as forgotten"),
+        }
+        // ForgetWatcher just needs to see something change, so touch the
+        // event file. Other processes might be doing the same thing at the
+        // same time, that's fine.
+        let event_path = self.base_dir.join(EVENTS_DIR).join("forgotten");
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&event_path)
+            .and_then(|f| f.set_modified(SystemTime::now()))
+            .with_context(|| format!("touching {}", event_path.display()))?;
+        Ok(ForgetOutcome::Marked)
+    }
+
+    // See Database::fsck.
+    fn fsck(
+        &self,
+        known_configs: Option<&HashMap<String, HashSet<ConfigHash>>>,
+        repair: Option<Repair>,
+    ) -> Result<Vec<FsckIssue>> {
+        let quarantine_dir = self.base_dir.join(QUARANTINE_DIR).join(
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs())
+                .to_string(),
+        );
+        let mut issues = Vec::new();
+        for (path, _) in self.entry_dirs()? {
+            let lock_path = path.join("lock");
+            let lock_file = match open_entry_lock(&path) {
+                Ok(f) => f,
+                // Deleted while we were looking.
+                Err(e) if e.kind() == NotFound => continue,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("opening {}", lock_path.display()));
+                }
+            };
+            // If someone has it locked, a test is running or someone's reading
+            // the result, either way it's not broken.
+            let Some(flock) = ExclusiveFlock::try_new(lock_file)? else {
+                continue;
+            };
+            if !flock.is_at(&lock_path)? {
+                continue;
+            }
+            let Some(problem) = check_entry(&path, known_configs)? else {
+                continue;
+            };
+            match repair {
+                None => (),
+                Some(Repair::Delete) => remove_locked_entry(&self.base_dir, &path)?,
+                Some(Repair::Quarantine) => {
+                    let dest = quarantine_dir.join(path.strip_prefix(&self.base_dir).unwrap());
+                    let parent = dest.parent().unwrap();
+                    create_dir_all(parent)
+                        .with_context(|| format!("creating {}", parent.display()))?;
+                    // Anyone waiting for the lock will notice it isn't there
+                    // any more and go round again in lookup.
+                    fs::rename(&path, &dest).with_context(|| {
+                        format!("moving {} to {}", path.display(), dest.display())
+                    })?;
+                    if let Some(parent) = path.parent() {
+                        let _ = fs::remove_dir(parent);
+                    }
+                }
+            }
+            issues.push(FsckIssue { path, problem });
+        }
+        Ok(issues)
+    }
+
+    // See Database::migrate_legacy.
+    async fn migrate_legacy(&self, repo: &impl Worktree) -> Result<usize> {
+        let legacy_dir = self.base_dir.join(LEGACY_DIR);
+        let mut candidates = Vec::new();
+        for (hash_dir, by_paths) in hash_dirs(&legacy_dir)? {
+            for result_dir in subdirs(&hash_dir)? {
+                // For by_paths results the directory isn't named after a
+                // Git object, so only results with recorded commits can be
+                // migrated.
+                if let Some(entry) = StoredEntry::read(result_dir, by_paths)? {
+                    if let Some(commit) = &entry.commit {
+                        candidates.push((entry.clone(), commit.to_string()));
+                    }
+                }
+            }
+        }
+        if candidates.is_empty() {
+            return Ok(0);
+        }
+        let ids: Vec<String> = candidates.iter().map(|(_, id)| id.clone()).collect();
+        let ours = repo.existing_objects(&ids).await?;
+        let mut moved = 0;
+        for (entry, id) in candidates {
+            if !ours.contains(&id) {
+                continue;
+            }
+            let relpath = entry.path.strip_prefix(&legacy_dir).unwrap();
+            if self.move_entry(&entry, &self.base_dir.join(&self.namespace).join(relpath))? {
+                moved += 1;
+            }
+        }
+        remove_empty_hash_dirs(&legacy_dir);
+        Ok(moved)
+    }
+
+    // See Database::adopt_unborn. Everything in there belongs to this
+    // repository, so unlike migrate_legacy there's no need to check.
+    fn adopt(&self, from: &Path) -> Result<usize> {
+        let from_dir = self.base_dir.join(from);
+        let mut moved = 0;
+        for (hash_dir, by_paths) in hash_dirs(&from_dir)? {
+            for result_dir in subdirs(&hash_dir)? {
+                let Some(entry) = StoredEntry::read(result_dir, by_paths)? else {
+                    continue;
+                };
+                let relpath = entry.path.strip_prefix(&from_dir).unwrap();
+                if self.move_entry(&entry, &self.base_dir.join(&self.namespace).join(relpath))? {
+                    moved += 1;
+                }
+            }
+        }
+        remove_empty_hash_dirs(&from_dir);
+        let _ = fs::remove_dir(from_dir.parent().unwrap());
+        Ok(moved)
+    }
+
+    // Move an entry unless someone is using it (for legacy entries, presumably
+    // an older version of Limmat). Returns whether it's gone from the old
+    // location.
+    fn move_entry(&self, entry: &StoredEntry, dest: &Path) -> Result<bool> {
+        let lock_path = entry.path.join("lock");
+        let lock_file = match open_entry_lock(&entry.path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == NotFound => return Ok(false),
+            Err(e) => {
+                return Err(e).with_context(|| format!("opening {}", lock_path.display()));
+            }
+        };
+        let Some(flock) = ExclusiveFlock::try_new(lock_file)? else {
+            return Ok(false);
+        };
+        if !flock.is_at(&lock_path)? {
+            return Ok(false);
+        }
+        if dest.exists() {
+            // The test has already been run again since the entry was stored
+            // there, so the old result is redundant.
+            drop(flock);
+            return self.delete(entry);
+        }
+        let parent = dest.parent().unwrap();
+        create_dir_all(parent).with_context(|| format!("creating {}", parent.display()))?;
+        // Anyone waiting for the lock will notice it isn't there any more and
+        // go round again in lookup.
+        fs::rename(&entry.path, dest)
+            .with_context(|| format!("moving {} to {}", entry.path.display(), dest.display()))?;
+        Ok(true)
+    }
+}
+
+impl ResultStore for DirStore {
+    fn lookup<'a>(&'a self, test_case: &'a TestCase) -> BoxFuture<'a, Result<LookupResult>> {
+        DirStore::lookup(self, test_case).boxed()
+    }
+
+    fn find<'a>(&'a self, test_case: &'a TestCase) -> BoxFuture<'a, Result<Option<DatabaseEntry>>> {
+        DirStore::find(self, test_case).boxed()
+    }
+
+    fn explain(&self, test_case: &TestCase) -> Result<Explanation> {
+        DirStore::explain(self, test_case)
+    }
+
+    fn entries(&self, query: &EntryQuery) -> Result<Vec<StoredEntry>> {
+        DirStore::entries(self, query)
+    }
+
+    fn delete(&self, entry: &StoredEntry) -> Result<bool> {
+        DirStore::delete(self, entry)
+    }
+}
+
+// The directories named after hashes in a namespace, and whether they're for
+// by_paths results. In version 1 of the database, results were all directly in
+// the base dir in this layout.
+fn hash_dirs(base_dir: &Path) -> Result<Vec<(PathBuf, bool)>> {
+    let is_hash = |dir: &PathBuf| {
+        dir.file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| n.len() >= 40 && n.chars().all(|c| c.is_ascii_hexdigit()))
+    };
+    let mut dirs = Vec::new();
+    for (parent, by_paths) in [
+        (base_dir.to_owned(), false),
+        (base_dir.join("by_paths"), true),
+    ] {
+        for dir in subdirs(&parent)? {
+            if is_hash(&dir) {
+                dirs.push((dir, by_paths));
+            }
+        }
+    }
+    Ok(dirs)
+}
+
+// Clean up after moving the entries out of a namespace. Anything that isn't
+// empty (i.e. entries that were in use) is left alone.
+fn remove_empty_hash_dirs(dir: &Path) {
+    if let Ok(dirs) = hash_dirs(dir) {
+        for (hash_dir, _) in dirs {
+            let _ = fs::remove_dir(hash_dir);
+        }
+    }
+    let _ = fs::remove_dir(dir.join("by_paths"));
+    let _ = fs::remove_dir(dir);
+}
+
+// Delete the entry at path, the caller must hold its lock exclusively.
+fn remove_locked_entry(base_dir: &Path, path: &Path) -> Result<()> {
+    // As soon as the lock file is gone from path, lookup can create a new
+    // entry there and start running the test. So get the whole entry out of
+    // the way while we still hold the lock, then delete it at our leisure.
+    // Anyone waiting for the lock will notice it isn't there any more and go
+    // round again in lookup.
+    let trash_dir = base_dir.join(TRASH_DIR);
+    create_dir_all(&trash_dir).with_context(|| format!("creating {}", trash_dir.display()))?;
+    let trash = tempfile::Builder::new()
+        .prefix("entry-")
+        .tempdir_in(&trash_dir)
+        .context("creating trash dir")?;
+    let dest = trash.path().join("entry");
+    fs::rename(path, &dest)
+        .with_context(|| format!("moving {} to {}", path.display(), dest.display()))?;
+    trash
+        .close()
+        .with_context(|| format!("removing {}", dest.display()))?;
+    // Clean up the hash dir if this was the last test in it. If it isn't
+    // empty this fails, which is fine.
+    if let Some(parent) = path.parent() {
+        let _ = fs::remove_dir(parent);
+    }
+    Ok(())
+}
+
+// Figure out what's wrong with the entry at path, if anything. The caller must
+// hold its lock, so there's no test running.
+fn check_entry(
+    path: &Path,
+    known_configs: Option<&HashMap<String, HashSet<ConfigHash>>>,
+) -> Result<Option<Problem>> {
+    let json_path = path.join("result.json");
+    let json = match fs::read_to_string(&json_path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == NotFound => {
+            // Tests that didn't produce a result leave an error record, it's
+            // only a problem if there's nothing at all.
+            return Ok((!path.join("error.json").exists()).then_some(Problem::NoResult));
+        }
+        Err(e) => return Err(e).with_context(|| format!("reading {}", json_path.display())),
+    };
+    let result = match serde_json::from_str::<TestResultEntry>(&json) {
+        Ok(result) => result,
+        Err(_) if json.is_empty() => return Ok(Some(Problem::CorruptResult("empty".to_owned()))),
+        Err(e) => return Ok(Some(Problem::CorruptResult(e.to_string()))),
+    };
+    // Entries from before steps were recorded only had the one set of logs.
+    let steps: Vec<Option<&str>> = if result.metadata.steps.is_empty() {
+        vec![None]
+    } else {
+        result
+            .metadata
+            .steps
+            .iter()
+            .map(|s| s.name.as_deref())
+            .collect()
+    };
+    let mut missing = Vec::new();
+    for step in steps {
+        for name in ["stdout.txt", "stderr.txt"] {
+            let relpath = Database::step_relpath(step).join(name);
+            if !path.join(&relpath).exists() {
+                missing.push(relpath);
+            }
+        }
+    }
+    if !missing.is_empty() {
+        return Ok(Some(Problem::MissingLogs(missing)));
+    }
+    if let Some(known_configs) = known_configs {
+        let test_name = path.file_name().unwrap().to_string_lossy();
+        if !known_configs
+            .get(test_name.as_ref())
+            .is_some_and(|hashes| hashes.contains(&result.config_hash))
+        {
+            return Ok(Some(Problem::UnknownConfig));
+        }
+    }
+    Ok(None)
+}
+
+// Read the result JSON at json_path, if it's a result that can be used for the
+// test case.
+fn read_usable_result(json_path: &Path, test_case: &TestCase) -> Result<Option<TestResultEntry>> {
+    let json = match fs::read_to_string(json_path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", json_path.display())),
+    };
+    // Manually ignore empty JSON to avoid log spam.
+    if json.is_empty() || json_path.with_file_name(FORGOTTEN).exists() {
+        return Ok(None);
+    }
+    match serde_json::from_str::<TestResultEntry>(&json) {
+        Ok(test_result) => {
+            // Has the configuration changed? if not we need to rerun regardless.
+            if test_result.config_matches(test_case) && !test_result.expired(test_case) {
+                // Was the test configured to accept cached results?
+                if test_case.cache_hash.is_some() {
+                    // Cool, we're done.
+                    return Ok(Some(test_result));
+                }
+            }
+        }
+        Err(e) => {
+            // Results are written atomically so this shouldn't happen
+            // unless someone messed with the database.
+            debug!(
+                "Error reading result JSON from {}: {e} - JSON\n{:?}",
+                json_path.display(),
+                json,
+            );
+        }
+    }
+    Ok(None)
+}
+
+// Databases without a version file are version 1.
+fn read_version(base_dir: &Path) -> Result<u32> {
+    let path = base_dir.join("version");
+    let content = match fs::read_to_string(&path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == NotFound => return Ok(1),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+    };
+    match content.trim().parse() {
+        Ok(version) if version >= 1 => Ok(version),
+        _ => bail!("invalid database version {content:?} in {}", path.display()),
+    }
+}
+
+fn write_version(base_dir: &Path, version: u32) -> Result<()> {
+    write_atomic(&base_dir.join("version"), format!("{version}\n").as_bytes())
+}
+
+// Replace the file at path, such that even if we crash or the power goes out,
+// it has either the old content or the new content.
+fn write_atomic(path: &Path, content: &[u8]) -> Result<()> {
+    let mut tmp_name = path.file_name().unwrap().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    let mut file =
+        File::create(&tmp_path).with_context(|| format!("creating {}", tmp_path.display()))?;
+    file.write_all(content)
+        .and_then(|_| file.sync_all())
+        .with_context(|| format!("writing {}", tmp_path.display()))?;
+    fs::rename(&tmp_path, path)
+        .with_context(|| format!("moving {} to {}", tmp_path.display(), path.display()))?;
+    sync_dir(path.parent().unwrap())
+}
+
+// Make the directory entries in dir durable.
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)
+        .and_then(|f| f.sync_all())
+        .with_context(|| format!("syncing {}", dir.display()))
+}
+
+// Open the file that's flocked to lock the entry in result_dir.
+fn open_entry_lock(result_dir: &Path) -> std::io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(result_dir.join("lock"))
+}
+
+// Bring the database up to the current version. Caller must hold the lock
+// exclusively.
+fn migrate(base_dir: &Path, from: u32) -> Result<()> {
+    if from == VERSION {
+        return Ok(());
+    }
+    // Don't bother backing up a brand new database.
+    let mut contents = fs::read_dir(base_dir).context("reading database dir")?;
+    if contents.all(|d| d.is_ok_and(|d| d.file_name() == "lock")) {
+        return write_version(base_dir, VERSION);
+    }
+    let pending = &MIGRATIONS[from as usize - 1..];
+    if pending.iter().all(Option::is_none) {
+        // Nothing to back up.
+        info!("Upgrading result database to version {VERSION}");
+        return write_version(base_dir, VERSION);
+    }
+    let backup_dir = base_dir.join(BACKUPS_DIR).join(format!("v{from}"));
+    if backup_dir.exists() {
+        // A previous attempt must have been interrupted, the backup from then
+        // is older so keep that one.
+        info!(
+            "Result database backup already exists at {}",
+            backup_dir.display()
+        );
+    } else {
+        info!(
+            "Backing up result database to {} before upgrading it",
+            backup_dir.display()
+        );
+        let tmp_dir = base_dir.join(BACKUPS_DIR).join(format!("v{from}.tmp"));
+        fs::remove_dir_all(&tmp_dir)
+            .ignore(NotFound)
+            .with_context(|| format!("removing {}", tmp_dir.display()))?;
+        create_dir_all(&tmp_dir).with_context(|| format!("creating {}", tmp_dir.display()))?;
+        for dirent in fs::read_dir(base_dir).context("reading database dir")? {
+            let dirent = dirent.context("reading database dir")?;
+            // The index gets modified in place, and it can be rebuilt anyway.
+            let name = dirent.file_name();
+            if [BACKUPS_DIR, TRASH_DIR, "lock"].iter().any(|n| name == *n)
+                || name.to_string_lossy().starts_with("index.sqlite")
+            {
+                continue;
+            }
+            link_tree(&dirent.path(), &tmp_dir.join(dirent.file_name()))?;
+        }
+        fs::rename(&tmp_dir, &backup_dir)
+            .with_context(|| format!("moving backup to {}", backup_dir.display()))?;
+    }
+    for (i, migration) in pending.iter().enumerate() {
+        let to = from + i as u32 + 1;
+        info!("Upgrading result database to version {to}");
+        if let Some(migration) = migration {
+            migration(base_dir)?;
+        }
+        write_version(base_dir, to)?;
+    }
+    Ok(())
+}
+
+// Copy a directory tree, hard-linking the files so it doesn't take up any
+// space.
+fn link_tree(src: &Path, dest: &Path) -> Result<()> {
+    let file_type = fs::symlink_metadata(src)
+        .with_context(|| format!("statting {}", src.display()))?
+        .file_type();
+    if file_type.is_dir() {
+        create_dir(dest).with_context(|| format!("creating {}", dest.display()))?;
+        for dirent in fs::read_dir(src).with_context(|| format!("reading {}", src.display()))? {
+            let dirent = dirent.with_context(|| format!("reading {}", src.display()))?;
+            link_tree(&dirent.path(), &dest.join(dirent.file_name()))?;
+        }
+    } else if file_type.is_symlink() {
+        let target = fs::read_link(src).with_context(|| format!("reading {}", src.display()))?;
+        std::os::unix::fs::symlink(target, dest)
+            .with_context(|| format!("creating {}", dest.display()))?;
+    } else if file_type.is_file() {
+        fs::hard_link(src, dest)
+            .with_context(|| format!("linking {} to {}", src.display(), dest.display()))?;
+    }
+    Ok(())
+}
+
+// Version 2 separated results by repository. We can't tell which repository
+// the old ones belong to without a Git repo to look in, so they get set aside
+// and migrate_legacy moves them into the right namespace later.
+fn migrate_v1(base_dir: &Path) -> Result<()> {
+    let legacy_dir = base_dir.join(LEGACY_DIR);
+    for (hash_dir, by_paths) in hash_dirs(base_dir)? {
+        // Older versions of Limmat don't know about the database lock, so
+        // check the entries aren't in use. Hang on to the locks until they've
+        // been moved.
+        let mut locks = Vec::new();
+        for result_dir in subdirs(&hash_dir)? {
+            let json_path = result_dir.join("result.json");
+            let json_file = match OpenOptions::new().read(true).write(true).open(&json_path) {
+                Ok(f) => f,
+                Err(e) if e.kind() == NotFound => continue,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("opening {}", json_path.display()));
+                }
+            };
+            match ExclusiveFlock::try_new(json_file)? {
+                Some(flock) => locks.push(flock),
+                None => bail!(
+                    "{} is in use, probably by an older version of Limmat, stop it and try again",
+                    result_dir.display()
+                ),
+            }
+        }
+        let dest = if by_paths {
+            legacy_dir.join("by_paths")
+        } else {
+            legacy_dir.clone()
+        }
+        .join(hash_dir.file_name().unwrap());
+        let parent = dest.parent().unwrap();
+        create_dir_all(parent).with_context(|| format!("creating {}", parent.display()))?;
+        fs::rename(&hash_dir, &dest)
+            .with_context(|| format!("moving {} to {}", hash_dir.display(), dest.display()))?;
+    }
+    let _ = fs::remove_dir(base_dir.join("by_paths"));
+    Ok(())
+}
+
+// Paths of the directories in dir. Doesn't fail if dir doesn't exist.
+fn subdirs(dir: &Path) -> Result<Vec<PathBuf>> {
+    let read_dir = match fs::read_dir(dir) {
+        Ok(r) => r,
+        Err(e) if e.kind() == NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", dir.display())),
+    };
+    let mut dirs = Vec::new();
+    for dirent in read_dir {
+        let dirent = dirent.with_context(|| format!("reading {}", dir.display()))?;
+        // Things can get deleted under us, just skip them.
+        if dirent.file_type().is_ok_and(|t| t.is_dir()) {
+            dirs.push(dirent.path());
+        }
+    }
+    Ok(dirs)
+}
+
+// Total size of the files under path, not following symlinks.
+fn disk_usage(path: &Path) -> u64 {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    fs::read_dir(path)
+        .map(|r| r.flatten().map(|d| disk_usage(&d.path())).sum())
+        .unwrap_or(0)
+}
+
+// An entry found by scanning the database directory, for garbage collection.
+#[derive(Debug, Clone)]
+pub struct StoredEntry {
+    pub path: PathBuf,
+    pub test_name: String,
+    // Bytes on disk including all the output and artifacts.
+    pub size: u64,
+    // When the result was stored. For older entries and ones with no result
+    // this comes from the filesystem.
+    pub finished_at: SystemTime,
+    // None if we can't tell what commit the result was for.
+    pub commit: Option<CommitHash>,
+    // None if there's no result.
+    pub exit_code: Option<ExitCode>,
+}
+
+impl StoredEntry {
+    // Returns None if the directory doesn't look like a result entry.
+    fn read(path: PathBuf, by_paths: bool) -> Result<Option<Self>> {
+        // Entries that don't have a result yet only have the lock file. Older
+        // versions didn't have that but always had the JSON file.
+        let mut mtime = None;
+        for name in ["result.json", "lock"] {
+            let file_path = path.join(name);
+            match fs::metadata(&file_path) {
+                Ok(metadata) => {
+                    mtime = metadata.modified().ok();
+                    break;
+                }
+                Err(e) if e.kind() == NotFound => (),
+                Err(e) => {
+                    return Err(e).with_context(|| format!("statting {}", file_path.display()));
+                }
+            }
+        }
+        // Otherwise it probably got deleted while we were looking at it.
+        let Some(mtime) = mtime else {
+            return Ok(None);
+        };
+        let result = fs::read_to_string(path.join("result.json"))
+            .ok()
+            .and_then(|json| serde_json::from_str::<TestResultEntry>(&json).ok());
+        let metadata = match &result {
+            Some(entry) => Some(entry.metadata.clone()),
+            None => fs::read_to_string(path.join("error.json"))
+                .ok()
+                .and_then(|json| serde_json::from_str::<ErrorEntry>(&json).ok())
+                .map(|entry| entry.metadata),
+        };
+        // Before the commit was recorded, it was the directory name for
+        // results that weren't cached by tree or by paths.
+        let commit = metadata.and_then(|m| m.commit).or_else(|| {
+            (!by_paths)
+                .then(|| path.parent()?.file_name()?.to_str().map(str::to_owned))
+                .flatten()
+        });
+        Ok(Some(Self {
+            test_name: path
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            size: disk_usage(&path),
+            exit_code: result.as_ref().map(|r| r.result.exit_code),
+            finished_at: result.and_then(|r| r.finished_at).unwrap_or(mtime),
+            commit: commit.map(CommitHash::new),
+            path,
+        }))
+    }
+
+    pub fn outcome(&self) -> Outcome {
+        match self.exit_code {
+            Some(0) => Outcome::Success,
+            Some(_) => Outcome::Failure,
+            None => Outcome::Error,
+        }
+    }
+
+    pub fn age(&self) -> Duration {
+        self.finished_at.elapsed().unwrap_or(Duration::ZERO)
+    }
 }
 
 // Existing entry in the database. Until you drop this object, the entry is read-locked, meaning
@@ -155,7 +1477,7 @@ impl Database {
 pub struct DatabaseEntry {
     base_path: PathBuf,
     result: TestResultEntry,
-    _json_flock: SharedFlock,
+    _flock: SharedFlock,
     #[cfg(test)]
     _tempfile: Option<NamedTempFile>,
 }
@@ -169,12 +1491,61 @@ impl DatabaseEntry {
         self.result.result.exit_code
     }
 
-    pub fn stdout_path(&self) -> PathBuf {
-        self.base_path.join("stdout.txt")
+    // How long ago the result was produced, if known.
+    pub fn age(&self) -> Option<Duration> {
+        self.result.age()
     }
 
-    pub fn stderr_path(&self) -> PathBuf {
-        self.base_path.join("stderr.txt")
+    pub fn metadata(&self) -> &RunMetadata {
+        &self.result.metadata
+    }
+
+    // Has someone asked for this result to be forgotten? If so you should drop
+    // this so that the test can be re-run.
+    pub fn forgotten(&self) -> bool {
+        self.base_path.join(FORGOTTEN).exists()
+    }
+
+    // Everything we know about the result, for scripts to consume. This is
+    // separate from the stored format so that it can use friendlier
+    // representations: times are RFC 3339 and durations are in seconds.
+    pub fn to_json(&self) -> serde_json::Value {
+        let timestamp = |t: SystemTime| humantime::format_rfc3339_millis(t).to_string();
+        let metadata = &self.result.metadata;
+        json!({
+            "result": self.result.result,
+            "finished_at": self.result.finished_at.map(timestamp),
+            "metadata": {
+                "commit": metadata.commit,
+                "started_at": metadata.started_at.map(timestamp),
+                "duration_s": metadata.duration.map(|d| d.as_secs_f64()),
+                "hostname": metadata.hostname,
+                "limmat_version": metadata.limmat_version,
+                "imported_from": metadata.imported_from,
+                "steps": metadata.steps.iter().map(|step| json!({
+                    "name": step.name,
+                    "started_at": timestamp(step.started_at),
+                    "duration_s": step.duration.map(|d| d.as_secs_f64()),
+                    "worktree": step.worktree,
+                    "resources": step.resources,
+                    "signal": step.signal,
+                })).collect::<Vec<_>>(),
+            },
+            "result_dir": self.base_path,
+            "artifacts_dir": self.artifacts_dir(),
+        })
+    }
+
+    pub fn stdout_path(&self, step: Option<&str>) -> PathBuf {
+        self.base_path
+            .join(Database::step_relpath(step))
+            .join("stdout.txt")
+    }
+
+    pub fn stderr_path(&self, step: Option<&str>) -> PathBuf {
+        self.base_path
+            .join(Database::step_relpath(step))
+            .join("stderr.txt")
     }
 
     pub fn artifacts_dir(&self) -> PathBuf {
@@ -188,124 +1559,227 @@ impl DatabaseEntry {
             base_path: "".into(),
             result: TestResultEntry {
                 config_hash: b"FAKE CONFIG HASH".into(),
+                config: None,
                 result,
+                finished_at: None,
+                metadata: RunMetadata::default(),
             },
-            _json_flock: SharedFlock::new(tempfile.reopen().unwrap()).await.unwrap(),
+            _flock: SharedFlock::new(tempfile.reopen().unwrap()).await.unwrap(),
             _tempfile: Some(tempfile),
         }
     }
 }
 
+// Notifies about results being forgotten by other processes using the
+// database, see Database::forget.
+pub struct ForgetWatcher {
+    _watcher: RecommendedWatcher,
+    rx: mpsc::UnboundedReceiver<notify::Result<Event>>,
+}
+
+impl ForgetWatcher {
+    fn new(events_dir: &Path) -> Result<Self> {
+        let (tx, rx) = mpsc::unbounded();
+        let mut watcher = RecommendedWatcher::new(
+            move |res| {
+                // Receiver dropped means we're shutting down.
+                let _ = tx.unbounded_send(res);
+            },
+            notify::Config::default(),
+        )?;
+        watcher
+            .watch(events_dir, RecursiveMode::NonRecursive)
+            .with_context(|| format!("watching {}", events_dir.display()))?;
+        Ok(Self {
+            _watcher: watcher,
+            rx,
+        })
+    }
+
+    // Completes when results might have been forgotten. Check
+    // DatabaseEntry::forgotten to find out which. Cancel-safe.
+    pub async fn changed(&mut self) {
+        loop {
+            match self.rx.next().await.expect("forget watcher channel closed") {
+                Ok(event) if !matches!(event.kind, EventKind::Access(_)) => return,
+                Ok(_) => (),
+                Err(e) => warn!("Error watching for forgotten results: {e}"),
+            }
+        }
+    }
+}
+
+// See DatabaseOutput::on_result.
+type ResultHook = Box<dyn FnOnce(&TestResultEntry) -> BoxFuture<'static, ()> + Send>;
+
 // Output for an individual test job, which may or may not be stored into the
 // database depending on where it came from. If it is, it ncludes an exclusive
 // lock on the database entry, nobody can read the result or run the test case
 // until you drop this object.
 pub struct DatabaseOutput {
-    base_dir: PathBuf,      // Must exist.
-    artifacts_dir: PathBuf, // This too.
+    base_dir: PathBuf, // Must exist.
+    // Only created once the first step starts, so that explain can tell apart
+    // jobs that never got going.
+    artifacts_dir: PathBuf,
     // TODO: this is a mess, probably instead we should use a trait object of some kind. This was
     // done this way in part to avoid polluting the code with a trait object but
     // maybe it can be done cleanly specifically within the database module.
-    stdout_opened: bool,
-    provided_stdout: Option<Stdio>,
-    stderr_opened: bool,
-    provided_stderr: Option<Stdio>,
+    // If set, this provides the stdout and stderr for every step instead of
+    // files in the database.
+    provided_output: Option<fn() -> Stdio>,
     status_written: bool,
     config_hash: ConfigHash,
-    json_flock: ExclusiveFlock,
+    config: Option<serde_json::Value>,
+    metadata: RunMetadata,
+    // Paths of the stdout and stderr files, which get synced before the
+    // result is stored.
+    output_paths: Vec<PathBuf>,
+    // Called once the result has been stored, with the entry read-locked. The
+    // job waits for these, so anything slow should be done in the background.
+    // See sqlite::SqliteStore and remote::RemoteStore.
+    on_result: Vec<ResultHook>,
+    flock: ExclusiveFlock,
 }
 
 impl DatabaseOutput {
     fn new(
         base_dir: PathBuf, // Must exist.
         config_hash: ConfigHash,
-        json_flock: ExclusiveFlock,
+        config: Option<serde_json::Value>,
+        commit: &CommitHash,
+        flock: ExclusiveFlock,
     ) -> anyhow::Result<Self> {
         debug!("Creating database entry at {base_dir:?}");
-        let artifacts_dir = base_dir.join("artifacts").to_owned();
-        create_dir(&artifacts_dir)
-            .ignore(AlreadyExists)
-            .context("creating artifacts dir")?;
         Ok(Self {
-            artifacts_dir,
+            artifacts_dir: base_dir.join("artifacts"),
             base_dir,
-            stdout_opened: false,
-            provided_stdout: None,
-            stderr_opened: false,
-            provided_stderr: None,
+            provided_output: None,
             status_written: false,
             config_hash,
-            json_flock,
+            config,
+            metadata: RunMetadata::start(Some(commit)),
+            output_paths: Vec::new(),
+            on_result: Vec::new(),
+            flock,
         })
     }
 
     // Create a "DatabaseOutput" that is not actually in the database, this can be used for
     // storing "ephemeral" results (not in the sense that we destroy them
     // ourselves, just in the sense that we don't really look after them and the
-    // user is likely to delete them later). base_dir must exist.
-    pub async fn ephemeral(
-        base_dir: PathBuf,
-        stdout: Stdio,
-        stderr: Stdio,
-    ) -> anyhow::Result<Self> {
+    // user is likely to delete them later). base_dir must exist. output is
+    // called to get the stdout and stderr for each step.
+    pub async fn ephemeral(base_dir: PathBuf, output: fn() -> Stdio) -> anyhow::Result<Self> {
         let artifacts_dir = base_dir.join("artifacts").to_owned();
         create_dir(&artifacts_dir).context("creating artifacts dir")?;
-        let json_file = OpenOptions::new()
+        let lock_file = OpenOptions::new()
             .read(true)
             .write(true)
             .create_new(true)
-            .open(base_dir.join("result.json"))
-            .context("creating ephemeral result JSON")?;
+            .open(base_dir.join("lock"))
+            .context("creating ephemeral result lock")?;
         Ok(Self {
             base_dir,
             artifacts_dir,
-            stdout_opened: false,
-            provided_stdout: Some(stdout),
-            stderr_opened: false,
-            provided_stderr: Some(stderr),
+            provided_output: Some(output),
             status_written: false,
             config_hash: vec![],
+            config: None,
+            metadata: RunMetadata::start(None),
+            output_paths: Vec::new(),
+            on_result: Vec::new(),
             // Note the locking is unnecessary in the ephemeral case but it's
             // just easier to do it anyway.
-            json_flock: ExclusiveFlock::new(json_file)
+            flock: ExclusiveFlock::new(lock_file)
                 .await
-                .context("locking ephemeral JSON result")?,
+                .context("locking ephemeral result")?,
         })
     }
 
-    fn stdout_file(&mut self) -> anyhow::Result<File> {
-        let path = self.base_dir.join("stdout.txt");
-        File::create(&path).with_context(|| format!("creating {}", path.display()))
+    fn output_file(&mut self, step: Option<&str>, filename: &str) -> anyhow::Result<File> {
+        let dir = self.base_dir.join(Database::step_relpath(step));
+        create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+        let path = dir.join(filename);
+        // If the test was run before, this might be hard-linked into a backup
+        // (see migrate), so replace it rather than truncating it.
+        fs::remove_file(&path)
+            .ignore(NotFound)
+            .with_context(|| format!("removing {}", path.display()))?;
+        let file = File::create(&path).with_context(|| format!("creating {}", path.display()))?;
+        self.output_paths.push(path);
+        Ok(file)
+    }
+
+    fn stdout_file(&mut self, step: Option<&str>) -> anyhow::Result<File> {
+        self.output_file(step, "stdout.txt")
     }
 
-    fn stderr_file(&mut self) -> anyhow::Result<File> {
-        let path = self.base_dir.join("stderr.txt");
-        File::create(&path).with_context(|| format!("creating {}", path.display()))
+    fn stderr_file(&mut self, step: Option<&str>) -> anyhow::Result<File> {
+        self.output_file(step, "stderr.txt")
     }
 
-    pub fn stdout(&mut self) -> Result<Stdio> {
-        assert!(!self.stdout_opened);
-        self.stdout_opened = true;
-        if let Some(stdout) = self.provided_stdout.take() {
-            return Ok(stdout);
+    pub fn stdout(&mut self, step: Option<&str>) -> Result<Stdio> {
+        if let Some(output) = self.provided_output {
+            return Ok(output());
         }
-        Ok(self.stdout_file()?.into())
+        Ok(self.stdout_file(step)?.into())
     }
 
-    pub fn stderr(&mut self) -> Result<Stdio> {
-        assert!(!self.stderr_opened);
-        self.stderr_opened = true;
-        if let Some(stderr) = self.provided_stderr.take() {
-            return Ok(stderr);
+    pub fn stderr(&mut self, step: Option<&str>) -> Result<Stdio> {
+        if let Some(output) = self.provided_output {
+            return Ok(output());
         }
-        Ok(self.stderr_file()?.into())
+        Ok(self.stderr_file(step)?.into())
+    }
+
+    // Record that a step is starting, for the metadata. This also creates the
+    // artifacts dir.
+    pub fn start_step(
+        &mut self,
+        name: Option<&str>,
+        worktree: Option<&Path>,
+        resources: BTreeMap<String, Vec<String>>,
+    ) -> anyhow::Result<()> {
+        create_dir(&self.artifacts_dir)
+            .ignore(AlreadyExists)
+            .context("creating artifacts dir")?;
+        self.metadata.steps.push(StepMetadata {
+            name: name.map(str::to_owned),
+            started_at: SystemTime::now(),
+            duration: None,
+            worktree: worktree.map(Path::to_owned),
+            resources,
+            signal: None,
+        });
+        Ok(())
+    }
+
+    // Record how the step from the last start_step call ended.
+    pub fn finish_step(&mut self, signal: Option<i32>) {
+        if let Some(step) = self.metadata.steps.last_mut() {
+            step.duration = step.started_at.elapsed().ok();
+            step.signal = signal;
+        }
+    }
+
+    // Record that the test didn't produce a result. The entry stays empty so
+    // the test will be run again, this is just to tell the user what happened.
+    pub fn set_error(&mut self, error: &str) -> anyhow::Result<()> {
+        let entry = ErrorEntry {
+            error: error.to_owned(),
+            metadata: self.metadata.clone(),
+        };
+        write_atomic(
+            &self.base_dir.join("error.json"),
+            &serde_json::to_vec(&entry).expect("failed to serialize ErrorEntry"),
+        )
+        .context("writing error JSON")
     }
 
     // Set the result and return the created entry. Unfortunately because flock
     // downgrades are non-atomic, it's possible for this to fail as someone can
-    // grab the lock while we are downgrading and delete the entry. (At time of
-    // writing there is no logic to delete entries but I plan to implement this).
-    // I'm hopeful that this won't really happen in practice, hopefully flock
+    // grab the lock while we are downgrading and delete the entry (see
+    // Database::delete). I'm hopeful that this won't really happen in practice, hopefully flock
     // implementations treat shared locks as having higher-priority, so the
     // downgrade rarely gets "beaten" by an exclusive lock. (Also, it should be
     // rare that we delete an entry when there's a test running that depends on
@@ -313,24 +1787,64 @@ impl DatabaseOutput {
     pub async fn set_result(mut self, result: &TestResult) -> anyhow::Result<DatabaseEntry> {
         assert!(!self.status_written);
         self.status_written = true;
+        // Results always come with an artifacts dir, even if there were no
+        // steps.
+        create_dir(&self.artifacts_dir)
+            .ignore(AlreadyExists)
+            .context("creating artifacts dir")?;
         let entry = TestResultEntry {
             config_hash: self.config_hash.clone(),
+            config: self.config.clone(),
             result: result.clone(),
+            finished_at: Some(SystemTime::now()),
+            metadata: RunMetadata {
+                duration: self.metadata.started_at.and_then(|t| t.elapsed().ok()),
+                ..self.metadata.clone()
+            },
         };
-        self.json_flock
-            .set_content(&serde_json::to_vec(&entry).expect("failed to serialize TestStatus"))
-            .context("writing JSON result")?;
+        self.commit(entry).await
+    }
+
+    // Store the result entry and downgrade the lock.
+    async fn commit(self, entry: TestResultEntry) -> anyhow::Result<DatabaseEntry> {
+        // Make sure the output is on disk before the result, so a result
+        // never points at truncated logs.
+        let mut dirs = HashSet::new();
+        for path in &self.output_paths {
+            File::open(path)
+                .and_then(|f| f.sync_all())
+                .with_context(|| format!("syncing {}", path.display()))?;
+            dirs.insert(path.parent().unwrap());
+        }
+        for dir in dirs {
+            sync_dir(dir)?;
+        }
+        write_atomic(
+            &self.base_dir.join("result.json"),
+            &serde_json::to_vec(&entry).expect("failed to serialize TestStatus"),
+        )
+        .context("writing JSON result")?;
+        // Any error from a previous attempt isn't interesting any more.
+        fs::remove_file(self.base_dir.join("error.json"))
+            .ignore(NotFound)
+            .context("removing stale error JSON")?;
+        // If someone tried to forget the result while we were running, the
+        // result they wanted to forget is gone now.
+        fs::remove_file(self.base_dir.join(FORGOTTEN))
+            .ignore(NotFound)
+            .context("removing forgotten marker")?;
+        let flock = self
+            .flock
+            .downgrade()
+            .await
+            .context("downgrading database entry lock")?;
+        for on_result in self.on_result {
+            on_result(&entry).await;
+        }
         Ok(DatabaseEntry {
             base_path: self.base_dir,
-            result: TestResultEntry {
-                config_hash: self.config_hash,
-                result: result.clone(),
-            },
-            _json_flock: self
-                .json_flock
-                .downgrade()
-                .await
-                .context("downgrading result JSON flock")?,
+            result: entry,
+            _flock: flock,
             #[cfg(test)]
             _tempfile: None,
         })
@@ -346,39 +1860,55 @@ mod tests {
     use std::{io::Write as _, sync::Arc};
 
     use tempfile::TempDir;
+    use tokio::time::timeout;
 
-    use crate::{git::Commit, test::Test};
+    use crate::{
+        git::{
+            test_utils::{TempRepo, WorktreeExt as _},
+            Commit,
+        },
+        test::{Test, TestName},
+    };
 
     use super::*;
 
     #[test_log::test(tokio::test)]
     async fn test_corrupted_result() {
         let db_dir = TempDir::new().unwrap();
-        let db = Database::create_or_open(db_dir.path()).unwrap();
+        let db = Database::create_or_open(db_dir.path(), "repo", Backend::Directory)
+            .await
+            .unwrap();
 
-        // Setup: Create a corrupted database entry. This simulates Limmat
-        // getting killed in the middle of writing.
+        // Setup: Create a corrupted database entry. Results are written
+        // atomically so this shouldn't happen, but older versions could leave
+        // entries like this if they got killed in the middle of writing.
         // Best way to make sure we are corrupting data that the database will
         // really is to have the database write it in the first place.
-        let test_case = TestCase::new(Commit::arbitrary(), Arc::new(Test::arbitrary()));
+        let repo = TempRepo::new().await.unwrap();
+        let test_case = TestCase::new(&repo, Commit::arbitrary(), Arc::new(Test::arbitrary()))
+            .await
+            .unwrap();
         let json_path = {
             let mut output = match db.lookup(&test_case).await.unwrap() {
                 LookupResult::FoundResult(_) => panic!("Found result in empty database"),
                 LookupResult::YouRunIt(output) => output,
             };
             output
-                .stderr_file()
+                .stderr_file(None)
                 .unwrap()
                 .write_all(b"hello stderr\n")
                 .unwrap();
             output
-                .stdout_file()
+                .stdout_file(None)
                 .unwrap()
                 .write_all(b"hello stdout\n")
                 .unwrap();
             let json_path = output.base_dir.join("result.json");
             output
-                .set_result(&TestResult { exit_code: 1 })
+                .set_result(&TestResult {
+                    exit_code: 1,
+                    failed_step: None,
+                })
                 .await
                 .unwrap();
             json_path
@@ -401,7 +1931,10 @@ mod tests {
                 LookupResult::YouRunIt(output) => output,
             };
             output
-                .set_result(&TestResult { exit_code: 2 })
+                .set_result(&TestResult {
+                    exit_code: 2,
+                    failed_step: None,
+                })
                 .await
                 .unwrap();
         }
@@ -411,6 +1944,500 @@ mod tests {
             LookupResult::YouRunIt(_) => panic!("no JSON found after DB corruption"),
         };
     }
+
+    #[test_log::test(tokio::test)]
+    async fn test_interrupted_write() {
+        let db_dir = TempDir::new().unwrap();
+        let db = Database::create_or_open(db_dir.path(), "repo", Backend::Directory)
+            .await
+            .unwrap();
+        let repo = TempRepo::new().await.unwrap();
+        let test_case = TestCase::new(&repo, Commit::arbitrary(), Arc::new(Test::arbitrary()))
+            .await
+            .unwrap();
+        let set_result = |output: DatabaseOutput, exit_code| async move {
+            output
+                .set_result(&TestResult {
+                    exit_code,
+                    failed_step: None,
+                })
+                .await
+                .unwrap()
+        };
+
+        // Job got cancelled before it started running, e.g. while waiting
+        // for a worktree. That's not an error.
+        match db.lookup(&test_case).await.unwrap() {
+            LookupResult::FoundResult(_) => panic!("Found result in empty database"),
+            LookupResult::YouRunIt(output) => drop(output),
+        };
+        assert!(matches!(
+            db.explain(&test_case).unwrap(),
+            Explanation::NoResult
+        ));
+
+        let result_dir = match db.lookup(&test_case).await.unwrap() {
+            LookupResult::FoundResult(_) => panic!("Found result after cancelled job"),
+            LookupResult::YouRunIt(mut output) => {
+                output.start_step(None, None, BTreeMap::new()).unwrap();
+                output.stdout_file(None).unwrap();
+                let result_dir = output.base_dir.clone();
+                // Simulate getting killed half way through writing the
+                // result.
+                fs::write(result_dir.join("result.json.tmp"), b"{\"config_ha").unwrap();
+                drop(output);
+                result_dir
+            }
+        };
+        assert!(!result_dir.join("result.json").exists());
+        assert!(matches!(
+            db.explain(&test_case).unwrap(),
+            Explanation::Error { message: None }
+        ));
+
+        // It should just get re-run.
+        match db.lookup(&test_case).await.unwrap() {
+            LookupResult::FoundResult(e) => panic!("found result after interrupted write {e:?}"),
+            LookupResult::YouRunIt(output) => set_result(output, 3).await,
+        };
+        assert!(!result_dir.join("result.json.tmp").exists());
+        match db.lookup(&test_case).await.unwrap() {
+            LookupResult::FoundResult(entry) => assert_eq!(entry.exit_code(), 3),
+            LookupResult::YouRunIt(_) => panic!("result not found after re-run"),
+        };
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_config_hash_collision() {
+        let db_dir = TempDir::new().unwrap();
+        let db = Database::create_or_open(db_dir.path(), "repo", Backend::Directory)
+            .await
+            .unwrap();
+        let repo = TempRepo::new().await.unwrap();
+        let test_case = |config: &str| {
+            let test = Test {
+                canonical_config: serde_json::json!({ "command": config }),
+                ..Test::arbitrary()
+            };
+            TestCase::new(&repo, Commit::arbitrary(), Arc::new(test))
+        };
+
+        let orig = test_case("foo").await.unwrap();
+        match db.lookup(&orig).await.unwrap() {
+            LookupResult::FoundResult(_) => panic!("Found result in empty database"),
+            LookupResult::YouRunIt(output) => output
+                .set_result(&TestResult {
+                    exit_code: 0,
+                    failed_step: None,
+                })
+                .await
+                .unwrap(),
+        };
+        assert!(matches!(
+            db.lookup(&orig).await.unwrap(),
+            LookupResult::FoundResult(_)
+        ));
+
+        // Same hash but a different config, that shouldn't get the result.
+        let collided = test_case("bar").await.unwrap();
+        assert_eq!(collided.test.config_hash, orig.test.config_hash);
+        assert!(matches!(
+            db.explain(&collided).unwrap(),
+            Explanation::ConfigChanged { stored: Some(_) }
+        ));
+        assert!(matches!(
+            db.lookup(&collided).await.unwrap(),
+            LookupResult::YouRunIt(_)
+        ));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_fsck() {
+        let db_dir = TempDir::new().unwrap();
+        let db = Database::create_or_open(db_dir.path(), "repo", Backend::Directory)
+            .await
+            .unwrap();
+        let repo = TempRepo::new().await.unwrap();
+        let test_case = |name: &str, config_hash: ConfigHash| {
+            let test = Test {
+                name: TestName::new(name),
+                config_hash,
+                ..Test::arbitrary()
+            };
+            TestCase::new(&repo, Commit::arbitrary(), Arc::new(test))
+        };
+        let db = &db;
+        let output = |test_case: TestCase| async move {
+            match db.lookup(&test_case).await.unwrap() {
+                LookupResult::FoundResult(_) => panic!("Found result in empty database"),
+                LookupResult::YouRunIt(output) => output,
+            }
+        };
+        let store = |name: &'static str, config_hash, logs: bool| async move {
+            let mut output = output(test_case(name, config_hash).await.unwrap()).await;
+            if logs {
+                output.stdout_file(None).unwrap();
+                output.stderr_file(None).unwrap();
+            }
+            output
+                .set_result(&TestResult {
+                    exit_code: 0,
+                    failed_step: None,
+                })
+                .await
+                .unwrap()
+                .base_path
+        };
+
+        store("good", vec![1], true).await;
+        let corrupt = store("corrupt", vec![1], true).await;
+        fs::write(corrupt.join("result.json"), b"").unwrap();
+        let missing_logs = store("missing_logs", vec![1], false).await;
+        let unknown_config = store("unknown_config", vec![2], true).await;
+        // Test got killed, there's just the lock.
+        let no_result = output(test_case("no_result", vec![1]).await.unwrap())
+            .await
+            .base_dir
+            .clone();
+        // These are fine.
+        let mut errored = output(test_case("errored", vec![1]).await.unwrap()).await;
+        errored.set_error("oh no").unwrap();
+        drop(errored);
+        let _running = output(test_case("running", vec![1]).await.unwrap()).await;
+
+        let known_configs: HashMap<String, HashSet<ConfigHash>> = [
+            "good",
+            "corrupt",
+            "missing_logs",
+            "unknown_config",
+            "no_result",
+            "errored",
+            "running",
+        ]
+        .into_iter()
+        .map(|name| (name.to_owned(), [vec![1]].into()))
+        .collect();
+        let expected = HashMap::from([
+            (corrupt.clone(), Problem::CorruptResult("empty".to_owned())),
+            (
+                missing_logs.clone(),
+                Problem::MissingLogs(vec!["stdout.txt".into(), "stderr.txt".into()]),
+            ),
+            (unknown_config.clone(), Problem::UnknownConfig),
+            (no_result.clone(), Problem::NoResult),
+        ]);
+        let issues = |repair| -> HashMap<PathBuf, Problem> {
+            db.fsck(Some(&known_configs), repair)
+                .unwrap()
+                .into_iter()
+                .map(|issue| (issue.path, issue.problem))
+                .collect()
+        };
+        assert_eq!(issues(None), expected);
+        // Without the configs, that one's fine.
+        assert_eq!(db.fsck(None, None).unwrap().len(), expected.len() - 1);
+
+        assert_eq!(issues(Some(Repair::Quarantine)), expected);
+        assert_eq!(issues(None), HashMap::new());
+        for path in expected.keys() {
+            assert!(!path.exists(), "{path:?} still exists");
+        }
+        assert!(subdirs(&db_dir.path().join(QUARANTINE_DIR))
+            .unwrap()
+            .into_iter()
+            .next()
+            .is_some_and(|dir| dir.join("repo").exists()));
+        assert!(db
+            .find(&test_case("good", vec![1]).await.unwrap())
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_forget() {
+        let db_dir = TempDir::new().unwrap();
+        let db = Database::create_or_open(db_dir.path(), "repo", Backend::Directory)
+            .await
+            .unwrap();
+        let mut watcher = db.forget_watcher().unwrap();
+        let repo = TempRepo::new().await.unwrap();
+        let test_case = TestCase::new(&repo, Commit::arbitrary(), Arc::new(Test::arbitrary()))
+            .await
+            .unwrap();
+        let run = |exit_code| {
+            let db = &db;
+            let test_case = &test_case;
+            async move {
+                let LookupResult::YouRunIt(output) = db.lookup(test_case).await.unwrap() else {
+                    panic!("found result, expected to run test");
+                };
+                output
+                    .set_result(&TestResult {
+                        exit_code,
+                        failed_step: None,
+                    })
+                    .await
+                    .unwrap()
+            }
+        };
+
+        assert!(matches!(
+            db.forget(&test_case).unwrap(),
+            ForgetOutcome::NotFound
+        ));
+
+        // Nobody's using it, it just gets deleted.
+        drop(run(1).await);
+        assert!(matches!(
+            db.forget(&test_case).unwrap(),
+            ForgetOutcome::Deleted
+        ));
+
+        // If it's in use, the user should get told about it and drop it.
+        let entry = run(2).await;
+        assert!(!entry.forgotten());
+        assert!(matches!(
+            db.forget(&test_case).unwrap(),
+            ForgetOutcome::Marked
+        ));
+        timeout(Duration::from_secs(5), watcher.changed())
+            .await
+            .expect("no notification for forgotten result");
+        assert!(entry.forgotten());
+        assert!(matches!(
+            db.explain(&test_case).unwrap(),
+            Explanation::Forgotten
+        ));
+        assert!(db.find(&test_case).await.unwrap().is_none());
+        drop(entry);
+
+        // If the re-run doesn't produce a result, the old one doesn't come
+        // back.
+        let LookupResult::YouRunIt(output) = db.lookup(&test_case).await.unwrap() else {
+            panic!("found forgotten result");
+        };
+        assert!(matches!(
+            db.explain(&test_case).unwrap(),
+            Explanation::Running
+        ));
+        drop(output);
+        assert!(matches!(
+            db.explain(&test_case).unwrap(),
+            Explanation::Error { .. }
+        ));
+
+        // Results from runs that were going on while they were forgotten are
+        // kept.
+        let LookupResult::YouRunIt(output) = db.lookup(&test_case).await.unwrap() else {
+            panic!("found result, expected to run test");
+        };
+        // The event file already exists this time, the watcher should still
+        // notice.
+        let mut watcher = db.forget_watcher().unwrap();
+        assert!(matches!(
+            db.forget(&test_case).unwrap(),
+            ForgetOutcome::Marked
+        ));
+        timeout(Duration::from_secs(5), watcher.changed())
+            .await
+            .expect("no notification for forgotten result");
+        let entry = output
+            .set_result(&TestResult {
+                exit_code: 3,
+                failed_step: None,
+            })
+            .await
+            .unwrap();
+        assert!(!entry.forgotten());
+        drop(entry);
+        match db.lookup(&test_case).await.unwrap() {
+            LookupResult::FoundResult(entry) => assert_eq!(entry.exit_code(), 3),
+            LookupResult::YouRunIt(_) => panic!("result from re-run got dropped"),
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_expired_result() {
+        let db_dir = TempDir::new().unwrap();
+        let db = Database::create_or_open(db_dir.path(), "repo", Backend::Directory)
+            .await
+            .unwrap();
+        let repo = TempRepo::new().await.unwrap();
+        let test_case = TestCase::new(
+            &repo,
+            Commit::arbitrary(),
+            Arc::new(Test {
+                cache_ttl: Some(Duration::from_millis(500)),
+                ..Test::arbitrary()
+            }),
+        )
+        .await
+        .unwrap();
+
+        match db.lookup(&test_case).await.unwrap() {
+            LookupResult::FoundResult(_) => panic!("Found result in empty database"),
+            LookupResult::YouRunIt(output) => output
+                .set_result(&TestResult {
+                    exit_code: 0,
+                    failed_step: None,
+                })
+                .await
+                .unwrap(),
+        };
+        assert!(matches!(
+            db.explain(&test_case).unwrap(),
+            Explanation::Cached(_)
+        ));
+        assert!(matches!(
+            db.lookup(&test_case).await.unwrap(),
+            LookupResult::FoundResult(_)
+        ));
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(matches!(
+            db.explain(&test_case).unwrap(),
+            Explanation::Expired { age: Some(_) }
+        ));
+        assert!(matches!(
+            db.lookup(&test_case).await.unwrap(),
+            LookupResult::YouRunIt(_)
+        ));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_migrate_legacy() {
+        let db_dir = TempDir::new().unwrap();
+        // This gives the old layout, as it is after migrate_v1.
+        let legacy_db = Database::create_or_open(db_dir.path(), LEGACY_DIR, Backend::Directory)
+            .await
+            .unwrap();
+        let repo = TempRepo::new().await.unwrap();
+        let test = Arc::new(Test::arbitrary());
+        let test_case = |commit: Commit| TestCase::new(&repo, commit, test.clone());
+        let store = |test_case: TestCase| {
+            let legacy_db = &legacy_db;
+            async move {
+                match legacy_db.lookup(&test_case).await.unwrap() {
+                    LookupResult::FoundResult(_) => panic!("Found result in empty database"),
+                    LookupResult::YouRunIt(output) => output
+                        .set_result(&TestResult {
+                            exit_code: 0,
+                            failed_step: None,
+                        })
+                        .await
+                        .unwrap(),
+                }
+            }
+        };
+        let ours1 = test_case(repo.commit("1").await.unwrap()).await.unwrap();
+        let ours2 = test_case(repo.commit("2").await.unwrap()).await.unwrap();
+        let theirs = test_case(Commit::arbitrary()).await.unwrap();
+        store(ours1.clone()).await;
+        let in_use = store(ours2.clone()).await;
+        store(theirs.clone()).await;
+
+        let db = Database::create_or_open(db_dir.path(), "repos/ours", Backend::Directory)
+            .await
+            .unwrap();
+        assert_eq!(db.migrate_legacy(&repo).await.unwrap(), 1);
+        assert!(matches!(
+            db.lookup(&ours1).await.unwrap(),
+            LookupResult::FoundResult(_)
+        ));
+        drop(in_use);
+        assert_eq!(db.migrate_legacy(&repo).await.unwrap(), 1);
+        assert!(matches!(
+            db.lookup(&ours2).await.unwrap(),
+            LookupResult::FoundResult(_)
+        ));
+        // The result for the commit that isn't in our repo stays where it
+        // was.
+        assert!(matches!(
+            db.lookup(&theirs).await.unwrap(),
+            LookupResult::YouRunIt(_)
+        ));
+        let legacy_dir = db_dir.path().join(LEGACY_DIR);
+        assert!(legacy_dir
+            .join(theirs.storage_hash().to_string())
+            .join("my_test/result.json")
+            .exists());
+        assert!(!legacy_dir.join(ours1.storage_hash().to_string()).exists());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_version() {
+        let db_dir = TempDir::new().unwrap();
+        let repo = TempRepo::new().await.unwrap();
+        let test_case = TestCase::new(
+            &repo,
+            repo.commit("1").await.unwrap(),
+            Arc::new(Test::arbitrary()),
+        )
+        .await
+        .unwrap();
+
+        // Fake up a version 1 database: an empty namespace gives the old
+        // layout, then get rid of the version file.
+        let old_db = Database::create_or_open(db_dir.path(), "", Backend::Directory)
+            .await
+            .unwrap();
+        match old_db.lookup(&test_case).await.unwrap() {
+            LookupResult::FoundResult(_) => panic!("Found result in empty database"),
+            LookupResult::YouRunIt(output) => output
+                .set_result(&TestResult {
+                    exit_code: 0,
+                    failed_step: None,
+                })
+                .await
+                .unwrap(),
+        };
+        fs::remove_file(db_dir.path().join("version")).unwrap();
+        let relpath = Path::new(test_case.storage_hash()).join("my_test/result.json");
+
+        // Can't migrate it while it's in use.
+        assert!(
+            Database::create_or_open(db_dir.path(), "repos/ours", Backend::Directory)
+                .await
+                .is_err()
+        );
+        drop(old_db);
+
+        let db = Database::create_or_open(db_dir.path(), "repos/ours", Backend::Directory)
+            .await
+            .unwrap();
+        assert_eq!(read_version(db_dir.path()).unwrap(), VERSION);
+        assert!(db_dir.path().join("backups/v1").join(&relpath).exists());
+        assert!(db_dir.path().join(LEGACY_DIR).join(&relpath).exists());
+        assert_eq!(db.migrate_legacy(&repo).await.unwrap(), 1);
+        assert!(matches!(
+            db.lookup(&test_case).await.unwrap(),
+            LookupResult::FoundResult(_)
+        ));
+        // The backup is still there afterwards.
+        assert!(db_dir.path().join("backups/v1").join(&relpath).exists());
+        drop(db);
+
+        // Upgrading from 2 only bumps the version, there's nothing to back up.
+        write_version(db_dir.path(), 2).unwrap();
+        let db = Database::create_or_open(db_dir.path(), "repos/ours", Backend::Directory)
+            .await
+            .unwrap();
+        assert_eq!(read_version(db_dir.path()).unwrap(), VERSION);
+        assert!(!db_dir.path().join("backups/v2").exists());
+        drop(db);
+
+        // Something from the future.
+        write_version(db_dir.path(), VERSION + 1).unwrap();
+        let err = Database::create_or_open(db_dir.path(), "repos/ours", Backend::Directory)
+            .await
+            .err()
+            .unwrap();
+        assert!(
+            format!("{err:#}").contains("newer version of Limmat"),
+            "{err:#}"
+        );
+    }
 }
 // TODO:
 // - Test behaviour on already-existing directories
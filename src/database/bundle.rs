@@ -0,0 +1,569 @@
+// Bundles of results for moving them between databases, e.g. to share results
+// from a CI machine. A bundle is a tarball with a manifest.json saying which
+// test case each entry is for, and the entries themselves under entries/.
+// Importing goes through the usual locking so it's safe while Limmat is
+// running.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, create_dir_all, File},
+    io::{ErrorKind::NotFound, Read as _, Seek as _, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use anyhow::{anyhow, bail, Context as _, Result};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+use tokio::task::spawn_blocking;
+
+use super::{
+    hostname, Database, DatabaseEntry, DatabaseOutput, LookupResult, TestResultEntry, FORGOTTEN,
+};
+use crate::{test::TestCase, util::IoResultExt as _};
+
+const MANIFEST_VERSION: u32 = 1;
+
+// Results are read into memory, this stops a dodgy bundle from making that
+// hurt.
+const MAX_RESULT_SIZE: u64 = 1 << 20;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Manifest {
+    version: u32,
+    // Host the bundle was exported from.
+    host: Option<String>,
+    created_at: SystemTime,
+    entries: Vec<BundleEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BundleEntry {
+    pub test: String,
+    pub commit: String,
+    // See TestCase::storage_hash. This is checked on import, so that e.g.
+    // a by_paths result doesn't get imported for a commit where the paths
+    // are different here.
+    storage_hash: String,
+    // Relative to the root of the bundle.
+    dir: PathBuf,
+}
+
+pub enum ImportOutcome {
+    Imported,
+    // There's already a usable result in the database.
+    AlreadyPresent,
+    // The bundled result can't be used here, the string says why.
+    Skipped(String),
+}
+
+// Whether a file at the top level of a database entry belongs in a bundle.
+// The lock and error are local business, and the result JSON is handled
+// separately.
+fn is_bundled(name: &str, artifacts: bool) -> bool {
+    !(["lock", "result.json", "error.json", FORGOTTEN].contains(&name)
+        || name.ends_with(".tmp")
+        || (name == "artifacts" && !artifacts))
+}
+
+// The files in a database entry that belong in a bundle, relative to dir.
+fn bundled_files(dir: &Path, artifacts: bool) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(reldir) = dirs.pop() {
+        let read_dir = fs::read_dir(dir.join(&reldir))
+            .with_context(|| format!("reading {}", dir.join(&reldir).display()))?;
+        for dirent in read_dir {
+            let dirent = dirent.context("reading directory entry")?;
+            let relpath = reldir.join(dirent.file_name());
+            if reldir.as_os_str().is_empty()
+                && !is_bundled(&dirent.file_name().to_string_lossy(), artifacts)
+            {
+                continue;
+            }
+            // Don't follow symlinks, artifacts might have them.
+            if dirent.file_type().context("reading file type")?.is_dir() {
+                dirs.push(relpath);
+            } else {
+                files.push(relpath);
+            }
+        }
+    }
+    Ok(files)
+}
+
+// Builds up a bundle in a tarball.
+pub(super) struct BundleWriter<W: Write> {
+    builder: tar::Builder<W>,
+    manifest: Manifest,
+}
+
+impl<W: Write> BundleWriter<W> {
+    pub(super) fn new(out: W) -> Self {
+        let mut builder = tar::Builder::new(out);
+        builder.follow_symlinks(false);
+        Self {
+            builder,
+            manifest: Manifest {
+                version: MANIFEST_VERSION,
+                host: hostname(),
+                created_at: SystemTime::now(),
+                entries: Vec::new(),
+            },
+        }
+    }
+
+    // Add the entry in result_dir as the result for test_case. The caller
+    // must hold a lock on it.
+    pub(super) fn add(
+        &mut self,
+        result_dir: &Path,
+        test_case: &TestCase,
+        artifacts: bool,
+    ) -> Result<()> {
+        let dir = Path::new("entries").join(self.manifest.entries.len().to_string());
+        self.builder
+            .append_path_with_name(result_dir.join("result.json"), dir.join("result.json"))
+            .context("adding result to bundle")?;
+        for relpath in bundled_files(result_dir, artifacts)? {
+            self.builder
+                .append_path_with_name(result_dir.join(&relpath), dir.join(&relpath))
+                .with_context(|| format!("adding {} to bundle", relpath.display()))?;
+        }
+        self.manifest.entries.push(BundleEntry {
+            test: test_case.test.name.to_string(),
+            commit: test_case.commit_hash.to_string(),
+            storage_hash: test_case.storage_hash().to_string(),
+            dir,
+        });
+        Ok(())
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.manifest.entries.len()
+    }
+
+    pub(super) fn finish(mut self) -> Result<W> {
+        let manifest_json =
+            serde_json::to_vec(&self.manifest).expect("failed to serialize Manifest");
+        let mut header = tar::Header::new_gnu();
+        header.set_size(manifest_json.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+        );
+        self.builder
+            .append_data(&mut header, "manifest.json", manifest_json.as_slice())
+            .context("adding manifest to bundle")?;
+        self.builder.into_inner().context("writing bundle")
+    }
+}
+
+// Write a bundle of the results for the test cases that have one to out.
+// Artifacts are only included if requested since they can be huge. Returns
+// how many results were exported.
+pub async fn export(
+    database: &Database,
+    test_cases: &[TestCase],
+    artifacts: bool,
+    out: &Path,
+) -> Result<usize> {
+    let out_dir = match out.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    // Write it to a temporary file first so there's never a half-written
+    // bundle at out.
+    let tmp = NamedTempFile::new_in(out_dir)
+        .with_context(|| format!("creating temporary file in {}", out_dir.display()))?;
+    let mut writer = BundleWriter::new(tmp.reopen().context("opening temporary bundle")?);
+    // Results that aren't cached by commit are shared between test cases.
+    let mut seen = HashSet::new();
+    for test_case in test_cases {
+        // Hold onto this while we archive it, so it doesn't get deleted or
+        // re-run under us.
+        let Some(entry) = database.find(test_case).await? else {
+            continue;
+        };
+        if seen.insert(entry.base_path.clone()) {
+            writer.add(&entry.base_path, test_case, artifacts)?;
+        }
+    }
+    let count = writer.len();
+    writer.finish()?.sync_all().context("syncing bundle")?;
+    tmp.persist(out)
+        .with_context(|| format!("moving bundle to {}", out.display()))?;
+    Ok(count)
+}
+
+// A bundle that's been opened for importing. Only the manifest and the results
+// get read up front, the rest of an entry is copied straight out of the
+// tarball if it gets imported.
+pub struct Bundle {
+    manifest: Manifest,
+    // The result JSON for each entry dir.
+    results: HashMap<PathBuf, Vec<u8>>,
+    file: Arc<Mutex<File>>,
+}
+
+impl Bundle {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+        Self::read(file).with_context(|| format!("reading {}", path.display()))
+    }
+
+    // Like open, for a bundle that's already open. This blocks.
+    pub fn read(mut file: File) -> Result<Self> {
+        file.seek(SeekFrom::Start(0)).context("rewinding bundle")?;
+        let mut manifest = None;
+        let mut results = HashMap::new();
+        let mut archive = tar::Archive::new(&mut file);
+        for tar_entry in archive.entries_with_seek().context("reading bundle")? {
+            let mut tar_entry = tar_entry.context("reading bundle")?;
+            let path = tar_entry.path().context("reading bundle")?.into_owned();
+            if path == Path::new("manifest.json") {
+                manifest = Some(
+                    serde_json::from_reader::<_, Manifest>(&mut tar_entry)
+                        .context("parsing bundle manifest")?,
+                );
+            } else if path.file_name() == Some("result.json".as_ref()) {
+                if tar_entry.size() > MAX_RESULT_SIZE {
+                    bail!("{} in bundle is too big", path.display());
+                }
+                let mut json = Vec::new();
+                tar_entry
+                    .read_to_end(&mut json)
+                    .with_context(|| format!("reading {} from bundle", path.display()))?;
+                results.insert(path.parent().unwrap().to_owned(), json);
+            }
+        }
+        let manifest = manifest.ok_or_else(|| anyhow!("bundle has no manifest"))?;
+        if manifest.version != MANIFEST_VERSION {
+            bail!(
+                "unsupported bundle version {} (expected {MANIFEST_VERSION})",
+                manifest.version
+            );
+        }
+        Ok(Self {
+            manifest,
+            results,
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    pub fn entries(&self) -> &[BundleEntry] {
+        &self.manifest.entries
+    }
+
+    // Import the entry as the result for test_case, which the caller must have
+    // found to match it by test name and commit. Only results from the same
+    // config as test_case get imported.
+    pub async fn import(
+        &self,
+        database: &Database,
+        entry: &BundleEntry,
+        test_case: &TestCase,
+    ) -> Result<ImportOutcome> {
+        let result = match self.usable_result(entry, test_case)? {
+            Ok(result) => result,
+            Err(reason) => return Ok(ImportOutcome::Skipped(reason)),
+        };
+        match database.lookup(test_case).await? {
+            LookupResult::FoundResult(_) => Ok(ImportOutcome::AlreadyPresent),
+            LookupResult::YouRunIt(output) => {
+                self.import_into(output, entry, result).await?;
+                Ok(ImportOutcome::Imported)
+            }
+        }
+    }
+
+    fn check_entry_dir(entry: &BundleEntry) -> Result<()> {
+        // Don't let dodgy manifests point outside the entries.
+        let mut components = entry.dir.components();
+        if components.next() != Some(Component::Normal("entries".as_ref()))
+            || !components.all(|c| matches!(c, Component::Normal(_)))
+        {
+            bail!("invalid entry path {} in bundle", entry.dir.display());
+        }
+        Ok(())
+    }
+
+    // Read the bundled result for entry. If it can't be used for test_case,
+    // the inner error says why.
+    pub(super) fn usable_result(
+        &self,
+        entry: &BundleEntry,
+        test_case: &TestCase,
+    ) -> Result<std::result::Result<TestResultEntry, String>> {
+        if test_case.storage_hash().to_string() != entry.storage_hash {
+            return Ok(Err(format!(
+                "result is for {} but here it would be {}",
+                entry.storage_hash,
+                test_case.storage_hash()
+            )));
+        }
+        if test_case.cache_hash.is_none() {
+            return Ok(Err("caching is disabled".to_owned()));
+        }
+        Self::check_entry_dir(entry)?;
+        let json = self
+            .results
+            .get(&entry.dir)
+            .ok_or_else(|| anyhow!("no result for {} in bundle", entry.dir.display()))?;
+        let result: TestResultEntry = serde_json::from_slice(json)
+            .with_context(|| format!("parsing result for {}", entry.dir.display()))?;
+        if !result.config_matches(test_case) {
+            return Ok(Err("test config differs".to_owned()));
+        }
+        if result.expired(test_case) {
+            return Ok(Err("result is too old".to_owned()));
+        }
+        Ok(Ok(result))
+    }
+
+    // Store result (from usable_result) in output, tagged with where it came
+    // from.
+    pub(super) async fn import_into(
+        &self,
+        output: DatabaseOutput,
+        entry: &BundleEntry,
+        mut result: TestResultEntry,
+    ) -> Result<DatabaseEntry> {
+        Self::check_entry_dir(entry)?;
+        result.metadata.imported_from =
+            Some(self.manifest.host.clone().unwrap_or_else(|| "?".to_owned()));
+        let file = self.file.clone();
+        let entry_dir = entry.dir.clone();
+        let dest = output.base_dir.clone();
+        let output_paths = spawn_blocking(move || extract(&mut file.lock(), &entry_dir, &dest))
+            .await
+            .context("extracting bundle entry")??;
+        output.import(output_paths, result).await
+    }
+}
+
+// Copy the bundled files for the entry at entry_dir in the bundle to dest.
+// Returns the paths of the files that were written. This blocks.
+fn extract(file: &mut File, entry_dir: &Path, dest: &Path) -> Result<Vec<PathBuf>> {
+    file.seek(SeekFrom::Start(0)).context("rewinding bundle")?;
+    let mut archive = tar::Archive::new(file);
+    let mut written = Vec::new();
+    for tar_entry in archive.entries_with_seek().context("reading bundle")? {
+        let mut tar_entry = tar_entry.context("reading bundle")?;
+        let path = tar_entry.path().context("reading bundle")?.into_owned();
+        let Ok(relpath) = path.strip_prefix(entry_dir) else {
+            continue;
+        };
+        let Some(Component::Normal(name)) = relpath.components().next() else {
+            continue;
+        };
+        if !is_bundled(&name.to_string_lossy(), true) {
+            continue;
+        }
+        if !relpath
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            bail!("invalid path {} in bundle", path.display());
+        }
+        let dest_path = dest.join(relpath);
+        // Don't let a symlink from earlier in the bundle get us writing
+        // outside of dest.
+        let mut parent = dest.to_owned();
+        for component in relpath.parent().unwrap().components() {
+            parent.push(component);
+            if parent.is_symlink() {
+                bail!("{} in bundle is under a symlink", path.display());
+            }
+        }
+        create_dir_all(dest_path.parent().unwrap())
+            .with_context(|| format!("creating {}", dest_path.parent().unwrap().display()))?;
+        // Same as in output_file, don't write through hard links.
+        fs::remove_file(&dest_path)
+            .ignore(NotFound)
+            .with_context(|| format!("removing {}", dest_path.display()))?;
+        match tar_entry.header().entry_type() {
+            tar::EntryType::Regular | tar::EntryType::Symlink => {
+                let is_file = tar_entry.header().entry_type().is_file();
+                tar_entry
+                    .unpack(&dest_path)
+                    .with_context(|| format!("extracting {}", path.display()))?;
+                if is_file {
+                    written.push(dest_path);
+                }
+            }
+            entry_type => bail!(
+                "unexpected {entry_type:?} entry {} in bundle",
+                path.display()
+            ),
+        }
+    }
+    Ok(written)
+}
+
+impl DatabaseOutput {
+    // Store a result that was produced elsewhere, whose logs and artifacts
+    // have already been written to the paths in written.
+    async fn import(
+        mut self,
+        written: Vec<PathBuf>,
+        entry: TestResultEntry,
+    ) -> Result<DatabaseEntry> {
+        assert!(!self.status_written);
+        self.status_written = true;
+        self.output_paths.extend(written);
+        self.commit(entry).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, io::Write as _, sync::Arc};
+
+    use tempfile::TempDir;
+
+    use crate::{
+        database::Backend,
+        git::{test_utils::TempRepo, Commit},
+        test::{Test, TestResult},
+    };
+
+    use super::*;
+
+    #[test_log::test(tokio::test)]
+    async fn test_round_trip() {
+        let repo = TempRepo::new().await.unwrap();
+        let test_case = |config: &str| {
+            let test = Test {
+                canonical_config: serde_json::json!({ "command": config }),
+                ..Test::arbitrary()
+            };
+            TestCase::new(&repo, Commit::arbitrary(), Arc::new(test))
+        };
+        let orig = test_case("foo").await.unwrap();
+
+        let src_dir = TempDir::new().unwrap();
+        let src = Database::create_or_open(src_dir.path(), "repo", Backend::Directory)
+            .await
+            .unwrap();
+        match src.lookup(&orig).await.unwrap() {
+            LookupResult::FoundResult(_) => panic!("Found result in empty database"),
+            LookupResult::YouRunIt(mut output) => {
+                output
+                    .stdout_file(None)
+                    .unwrap()
+                    .write_all(b"hello stdout\n")
+                    .unwrap();
+                output.start_step(None, None, BTreeMap::new()).unwrap();
+                fs::write(output.artifacts_dir().join("artifact"), b"big").unwrap();
+                output
+                    .set_result(&TestResult {
+                        exit_code: 1,
+                        failed_step: None,
+                    })
+                    .await
+                    .unwrap();
+            }
+        }
+
+        let bundle_dir = TempDir::new().unwrap();
+        let with_artifacts = bundle_dir.path().join("with.tar");
+        let without_artifacts = bundle_dir.path().join("without.tar");
+        // The same test case twice should only get exported once.
+        let test_cases = [orig, test_case("foo").await.unwrap()];
+        assert_eq!(
+            export(&src, &test_cases, true, &with_artifacts)
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            export(&src, &test_cases, false, &without_artifacts)
+                .await
+                .unwrap(),
+            1
+        );
+
+        for (bundle_path, artifacts) in [(with_artifacts, true), (without_artifacts, false)] {
+            let dest_dir = TempDir::new().unwrap();
+            let dest = Database::create_or_open(dest_dir.path(), "repo", Backend::Directory)
+                .await
+                .unwrap();
+            let bundle = Bundle::open(&bundle_path).unwrap();
+            assert_eq!(bundle.entries().len(), 1);
+            let entry = &bundle.entries()[0];
+            assert_eq!(entry.test, "my_test");
+
+            // A different config mustn't get the result.
+            let changed = test_case("bar").await.unwrap();
+            assert!(matches!(
+                bundle.import(&dest, entry, &changed).await.unwrap(),
+                ImportOutcome::Skipped(_)
+            ));
+            assert!(dest.find(&changed).await.unwrap().is_none());
+
+            assert!(matches!(
+                bundle.import(&dest, entry, &test_cases[0]).await.unwrap(),
+                ImportOutcome::Imported
+            ));
+            assert!(matches!(
+                bundle.import(&dest, entry, &test_cases[0]).await.unwrap(),
+                ImportOutcome::AlreadyPresent
+            ));
+            let db_entry = dest.find(&test_cases[0]).await.unwrap().unwrap();
+            assert_eq!(db_entry.exit_code(), 1);
+            assert_eq!(db_entry.metadata().imported_from, hostname());
+            assert_eq!(
+                fs::read_to_string(db_entry.stdout_path(None)).unwrap(),
+                "hello stdout\n"
+            );
+            assert_eq!(
+                db_entry.artifacts_dir().join("artifact").exists(),
+                artifacts
+            );
+        }
+    }
+
+    #[test_log::test]
+    fn test_extract_dodgy() {
+        let outside = TempDir::new().unwrap();
+        let mut builder = tar::Builder::new(tempfile::tempfile().unwrap());
+        let mut add = |path: &str, entry_type: tar::EntryType, link: Option<&Path>| {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(entry_type);
+            header.set_mode(0o644);
+            header.set_size(0);
+            if let Some(link) = link {
+                header.set_link_name(link).unwrap();
+            }
+            builder
+                .append_data(&mut header, path, [].as_slice())
+                .unwrap();
+        };
+        add("entries/0/stdout", tar::EntryType::Regular, None);
+        // This would mess up the locking.
+        add("entries/0/lock", tar::EntryType::Regular, None);
+        add(
+            "entries/0/artifacts/link",
+            tar::EntryType::Symlink,
+            Some(outside.path()),
+        );
+        add(
+            "entries/0/artifacts/link/file",
+            tar::EntryType::Regular,
+            None,
+        );
+        let mut file = builder.into_inner().unwrap();
+
+        let dest = TempDir::new().unwrap();
+        let err = extract(&mut file, Path::new("entries/0"), dest.path()).unwrap_err();
+        assert!(format!("{err:#}").contains("under a symlink"), "{err:#}");
+        assert!(dest.path().join("stdout").exists());
+        assert!(!dest.path().join("lock").exists());
+        assert!(!outside.path().join("file").exists());
+    }
+}
@@ -0,0 +1,469 @@
+// Remote cache of results, shared over HTTP between everyone testing the same
+// project. Results are stored on the server as bundles (see bundle) with a
+// single entry, at <url>/<storage hash>/<test name>/<config hash>. It's just
+// GET and PUT so any dumb HTTP server that supports PUT will do, serve_cache is
+// a simple implementation.
+
+use std::{
+    fs::{self, create_dir_all, File},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{bail, Context as _, Result};
+use axum::{
+    body::Body,
+    extract::{Path as PathParams, State},
+    http::{header::CONTENT_LENGTH, HeaderMap, StatusCode},
+    response::{IntoResponse as _, Response},
+    routing::get_service,
+    Router,
+};
+use futures::{
+    future::{BoxFuture, FutureExt as _},
+    StreamExt as _,
+};
+use log::{debug, error, info, warn};
+use reqwest::Url;
+use tempfile::NamedTempFile;
+use tokio::{io::AsyncWriteExt as _, net::TcpListener, select};
+use tokio_util::sync::CancellationToken;
+use tower_http::services::ServeDir;
+
+use super::{
+    bundle::{Bundle, BundleWriter},
+    open_entry_lock, DatabaseEntry, EntryQuery, Explanation, LookupResult, ResultStore,
+    StoredEntry, TestResultEntry,
+};
+use crate::{flock::SharedFlock, test::TestCase};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// If the server stops sending us anything for this long, give up on it.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+// Bundles get downloaded to a temporary file (often on tmpfs), so don't let a
+// misbehaving server fill it up. serve_cache applies the same limit to
+// uploads.
+const MAX_BUNDLE_SIZE: u64 = 4 << 30;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteCacheConfig {
+    pub url: Url,
+    // Upload successful results to the cache, not just download them.
+    pub upload: bool,
+    pub upload_artifacts: bool,
+}
+
+impl RemoteCacheConfig {
+    pub fn new(url: &str, upload: bool, upload_artifacts: bool) -> Result<Self> {
+        let url = Url::parse(url).with_context(|| format!("parsing URL {url:?}"))?;
+        // We don't have a TLS implementation.
+        if url.scheme() != "http" {
+            bail!(
+                "unsupported URL scheme {:?}, only http is supported",
+                url.scheme()
+            );
+        }
+        if url.cannot_be_a_base() {
+            bail!("{url} can't be used as a base URL");
+        }
+        Ok(Self {
+            url,
+            upload,
+            upload_artifacts,
+        })
+    }
+}
+
+// Wraps another store, consulting the remote cache when that doesn't have a
+// result, and uploading results produced locally.
+pub(super) struct RemoteStore {
+    inner: Arc<dyn ResultStore>,
+    client: reqwest::Client,
+    config: RemoteCacheConfig,
+}
+
+impl RemoteStore {
+    pub(super) fn new(inner: Arc<dyn ResultStore>, config: RemoteCacheConfig) -> Result<Self> {
+        Ok(Self {
+            inner,
+            client: reqwest::Client::builder()
+                .connect_timeout(CONNECT_TIMEOUT)
+                .read_timeout(READ_TIMEOUT)
+                .build()
+                .context("creating HTTP client")?,
+            config,
+        })
+    }
+
+    fn url(&self, test_case: &TestCase) -> Url {
+        let config_hash: String = test_case
+            .test
+            .config_hash
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        let mut url = self.config.url.clone();
+        url.path_segments_mut()
+            .expect("remote cache URL can't be a base")
+            .pop_if_empty()
+            .extend([
+                test_case.storage_hash().to_string(),
+                test_case.test.name.to_string(),
+                config_hash,
+            ]);
+        url
+    }
+
+    // Download the bundle at url and check that it has a result that's usable
+    // for test_case.
+    async fn fetch(
+        &self,
+        url: &Url,
+        test_case: &TestCase,
+    ) -> Result<Option<(Bundle, TestResultEntry)>> {
+        let response = self
+            .client
+            .get(url.clone())
+            .send()
+            .await
+            .context("sending request")?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let mut response = response.error_for_status()?;
+        if let Some(len) = response.content_length() {
+            if len > MAX_BUNDLE_SIZE {
+                bail!("bundle is too big ({len} bytes)");
+            }
+        }
+        let tmp = NamedTempFile::new().context("creating temporary file")?;
+        let mut file = tokio::fs::File::from_std(tmp.reopen().context("opening temporary file")?);
+        let mut size = 0;
+        while let Some(chunk) = response.chunk().await.context("downloading bundle")? {
+            size += chunk.len() as u64;
+            if size > MAX_BUNDLE_SIZE {
+                bail!("bundle is too big (more than {MAX_BUNDLE_SIZE} bytes)");
+            }
+            file.write_all(&chunk).await.context("writing bundle")?;
+        }
+        file.flush().await.context("writing bundle")?;
+        // The file gets deleted, but we hang onto it until the result has
+        // been imported.
+        let file = tmp.into_file();
+        let bundle = tokio::task::spawn_blocking(move || Bundle::read(file))
+            .await
+            .context("reading bundle")??;
+        let [entry] = bundle.entries() else {
+            bail!("expected 1 entry, bundle has {}", bundle.entries().len());
+        };
+        match bundle.usable_result(entry, test_case)? {
+            Ok(result) => Ok(Some((bundle, result))),
+            Err(reason) => {
+                info!("Ignoring result from {url}: {reason}");
+                Ok(None)
+            }
+        }
+    }
+
+    async fn lookup(&self, test_case: &TestCase) -> Result<LookupResult> {
+        let mut output = match self.inner.lookup(test_case).await? {
+            LookupResult::YouRunIt(output) => output,
+            found => return Ok(found),
+        };
+        if test_case.cache_hash.is_none() {
+            return Ok(LookupResult::YouRunIt(output));
+        }
+        let url = self.url(test_case);
+        // If the cache isn't working, just run the test.
+        match self.fetch(&url, test_case).await {
+            Ok(Some((bundle, result))) => {
+                debug!("Got result for {} from {url}", test_case.test.name);
+                let entry = &bundle.entries()[0];
+                return Ok(LookupResult::FoundResult(
+                    bundle.import_into(output, entry, result).await?,
+                ));
+            }
+            Ok(None) => (),
+            Err(e) => warn!("Couldn't get result from {url}: {e:#}"),
+        }
+
+        if self.config.upload {
+            let client = self.client.clone();
+            let artifacts = self.config.upload_artifacts;
+            let test_case = test_case.clone();
+            let result_dir = output.base_dir.clone();
+            output
+                .on_result
+                .push(Box::new(move |entry: &TestResultEntry| {
+                    // Results that came from somewhere else are presumably
+                    // already there.
+                    if entry.result.exit_code != 0 || entry.metadata.imported_from.is_some() {
+                        return async {}.boxed();
+                    }
+                    // Don't hold up the job while we upload, do it in the
+                    // background. The lock file gets opened now, while the
+                    // entry is definitely still there.
+                    let lock_file = open_entry_lock(&result_dir);
+                    let entry = entry.clone();
+                    tokio::spawn(async move {
+                        let result = upload(
+                            &client, &url, lock_file, result_dir, entry, test_case, artifacts,
+                        )
+                        .await;
+                        if let Err(e) = result {
+                            error!("Couldn't upload result to {url}: {e:#}");
+                        }
+                    });
+                    async {}.boxed()
+                }));
+        }
+        Ok(LookupResult::YouRunIt(output))
+    }
+}
+
+fn write_bundle(result_dir: &Path, test_case: &TestCase, artifacts: bool) -> Result<NamedTempFile> {
+    let mut tmp = NamedTempFile::new().context("creating temporary file")?;
+    let mut writer = BundleWriter::new(tmp.as_file_mut());
+    writer.add(result_dir, test_case, artifacts)?;
+    writer.finish()?;
+    Ok(tmp)
+}
+
+// Bundle up the result in result_dir and upload it. lock_file is the entry's
+// lock, which we hold while bundling so the result can't be deleted under us.
+async fn upload(
+    client: &reqwest::Client,
+    url: &Url,
+    lock_file: std::io::Result<File>,
+    result_dir: PathBuf,
+    entry: TestResultEntry,
+    test_case: TestCase,
+    artifacts: bool,
+) -> Result<()> {
+    let flock = SharedFlock::new(lock_file.context("opening database entry lock")?)
+        .await
+        .context("locking database entry")?;
+    // By now the result might have been deleted or replaced.
+    let stored = fs::read(result_dir.join("result.json"))
+        .ok()
+        .and_then(|json| serde_json::from_slice::<TestResultEntry>(&json).ok());
+    if !flock.is_at(&result_dir.join("lock"))? || stored.as_ref() != Some(&entry) {
+        debug!("Result changed before it could be uploaded to {url}");
+        return Ok(());
+    }
+    // Artifacts can be big, don't tie up the runtime tarring them up.
+    let bundle =
+        tokio::task::spawn_blocking(move || write_bundle(&result_dir, &test_case, artifacts))
+            .await
+            .context("bundling result")??;
+    drop(flock);
+    let file = tokio::fs::File::open(bundle.path())
+        .await
+        .context("opening bundle")?;
+    client
+        .put(url.clone())
+        .body(file)
+        .send()
+        .await
+        .context("sending request")?
+        .error_for_status()?;
+    Ok(())
+}
+
+impl ResultStore for RemoteStore {
+    fn lookup<'a>(&'a self, test_case: &'a TestCase) -> BoxFuture<'a, Result<LookupResult>> {
+        RemoteStore::lookup(self, test_case).boxed()
+    }
+
+    // The rest is just about what's stored locally.
+
+    fn find<'a>(&'a self, test_case: &'a TestCase) -> BoxFuture<'a, Result<Option<DatabaseEntry>>> {
+        self.inner.find(test_case)
+    }
+
+    fn explain(&self, test_case: &TestCase) -> Result<Explanation> {
+        self.inner.explain(test_case)
+    }
+
+    fn entries(&self, query: &EntryQuery) -> Result<Vec<StoredEntry>> {
+        self.inner.entries(query)
+    }
+
+    fn delete(&self, entry: &StoredEntry) -> Result<bool> {
+        self.inner.delete(entry)
+    }
+
+    fn reindex(&self) -> Result<()> {
+        self.inner.reindex()
+    }
+}
+
+// Serve a remote cache from dir until cancelled. There's no authentication,
+// anyone who can reach it can upload results.
+pub async fn serve_cache(dir: PathBuf, listener: TcpListener, ct: CancellationToken) -> Result<()> {
+    create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+    let app = Router::new()
+        .route(
+            "/:storage_hash/:test/:config_hash",
+            get_service(ServeDir::new(&dir)).put(put_bundle),
+        )
+        .with_state(Arc::new(dir));
+    select! {
+        result = axum::serve(listener, app) => result.context("serving result cache"),
+        _ = ct.cancelled() => Ok(()),
+    }
+}
+
+async fn put_bundle(
+    State(dir): State<Arc<PathBuf>>,
+    PathParams(segments): PathParams<(String, String, String)>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    let (storage_hash, test, config_hash) = segments;
+    // These get decoded, so make sure nobody sneaks a path in.
+    for segment in [&storage_hash, &test, &config_hash] {
+        if segment.is_empty() || segment.starts_with('.') || segment.contains('/') {
+            return (StatusCode::BAD_REQUEST, "invalid path").into_response();
+        }
+    }
+    // store_bundle checks the size too, this just avoids receiving the whole
+    // thing when we know we'll reject it.
+    let len = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse::<u64>().ok());
+    if len.is_some_and(|len| len > MAX_BUNDLE_SIZE) {
+        return (StatusCode::PAYLOAD_TOO_LARGE, "bundle is too big").into_response();
+    }
+    let dest_dir = dir.join(storage_hash).join(test);
+    match store_bundle(&dest_dir, &config_hash, body).await {
+        Ok(()) => StatusCode::CREATED.into_response(),
+        Err(e) => {
+            error!("Couldn't store bundle in {}: {e:#}", dest_dir.display());
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("{e:#}")).into_response()
+        }
+    }
+}
+
+async fn store_bundle(dest_dir: &Path, name: &str, body: Body) -> Result<()> {
+    create_dir_all(dest_dir).with_context(|| format!("creating {}", dest_dir.display()))?;
+    // Only replace the existing bundle once we've got the whole thing, so
+    // readers never see a partial one.
+    let tmp = NamedTempFile::new_in(dest_dir).context("creating temporary file")?;
+    let mut file = tokio::fs::File::from_std(tmp.reopen().context("opening temporary file")?);
+    // Bundles with artifacts can be big, so they get streamed to disk. There's
+    // no authentication so anyone could upload, don't let them fill the disk.
+    let mut stream = body.into_data_stream();
+    let mut size = 0;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.context("receiving bundle")?;
+        size += chunk.len() as u64;
+        if size > MAX_BUNDLE_SIZE {
+            bail!("bundle is too big (more than {MAX_BUNDLE_SIZE} bytes)");
+        }
+        file.write_all(&chunk).await.context("writing bundle")?;
+    }
+    file.sync_all().await.context("syncing bundle")?;
+    tmp.persist(dest_dir.join(name))
+        .context("moving bundle into place")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tempfile::TempDir;
+    use tokio::time::sleep;
+
+    use crate::{
+        database::{hostname, Backend, Database},
+        git::{test_utils::TempRepo, Commit},
+        test::{Test, TestName, TestResult},
+    };
+
+    use super::*;
+
+    #[test_log::test(tokio::test)]
+    async fn test_remote_cache() {
+        let cache_dir = TempDir::new().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let ct = CancellationToken::new();
+        let server = tokio::spawn(serve_cache(
+            cache_dir.path().to_owned(),
+            listener,
+            ct.clone(),
+        ));
+
+        let repo = TempRepo::new().await.unwrap();
+        let test_case = |config: &str| {
+            let test = Test {
+                name: TestName::new(config),
+                canonical_config: serde_json::json!({ "command": config }),
+                ..Test::arbitrary()
+            };
+            TestCase::new(&repo, Commit::arbitrary(), Arc::new(test))
+        };
+        let db_dirs = [TempDir::new().unwrap(), TempDir::new().unwrap()];
+        let mut dbs = Vec::new();
+        for db_dir in &db_dirs {
+            dbs.push(
+                Database::create_or_open(db_dir.path(), "repo", Backend::Directory)
+                    .await
+                    .unwrap()
+                    .with_remote_cache(RemoteCacheConfig::new(&url, true, false).unwrap())
+                    .unwrap(),
+            );
+        }
+
+        let pass = test_case("pass").await.unwrap();
+        let fail = test_case("fail").await.unwrap();
+        for (test_case, exit_code) in [(&pass, 0), (&fail, 1)] {
+            match dbs[0].lookup(test_case).await.unwrap() {
+                LookupResult::FoundResult(_) => panic!("Found result in empty cache"),
+                LookupResult::YouRunIt(output) => output
+                    .set_result(&TestResult {
+                        exit_code,
+                        failed_step: None,
+                    })
+                    .await
+                    .unwrap(),
+            };
+        }
+
+        // The successful result should come from the cache, once it's been
+        // uploaded in the background.
+        let mut found = None;
+        for _ in 0..50 {
+            match dbs[1].lookup(&pass).await.unwrap() {
+                LookupResult::FoundResult(entry) => {
+                    found = Some(entry);
+                    break;
+                }
+                LookupResult::YouRunIt(_) => sleep(Duration::from_millis(100)).await,
+            }
+        }
+        let entry = found.expect("result not found in remote cache");
+        assert_eq!(entry.exit_code(), 0);
+        assert_eq!(entry.metadata().imported_from, hostname());
+        drop(entry);
+        // Now it's stored locally.
+        assert!(dbs[1].find(&pass).await.unwrap().is_some());
+        // Failures don't get uploaded.
+        assert!(matches!(
+            dbs[1].lookup(&fail).await.unwrap(),
+            LookupResult::YouRunIt(_)
+        ));
+
+        // If the server goes away, tests just get run.
+        ct.cancel();
+        server.await.unwrap().unwrap();
+        let other = test_case("other").await.unwrap();
+        assert!(matches!(
+            dbs[1].lookup(&other).await.unwrap(),
+            LookupResult::YouRunIt(_)
+        ));
+    }
+}
@@ -0,0 +1,528 @@
+// Result store that keeps an SQLite index of the entries in the directory, so
+// that finding results doesn't mean scanning the whole thing. The results, logs
+// and locks all still live in the directory and lookup goes through DirStore,
+// so the locking between processes works exactly the same as without the
+// index. That also means the index only helps with queries over lots of
+// entries (db list and GC): looking up individual results, which is what
+// running tests and the UI do, needs the entry's lock anyway.
+//
+// SQLite calls can block for a long time (see busy_timeout) so in async code
+// they're done with spawn_blocking.
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context as _, Result};
+use futures::future::{BoxFuture, FutureExt as _};
+use parking_lot::Mutex;
+use rusqlite::{params, params_from_iter, types::Value, Connection};
+use tokio::task::spawn_blocking;
+
+use super::{
+    disk_usage, DatabaseEntry, DirStore, EntryQuery, Explanation, LookupResult, Outcome,
+    ResultStore, StoredEntry, TestResultEntry, SHARED_DIR,
+};
+use crate::{
+    git::CommitHash,
+    test::{ExitCode, TestCase},
+    util::ResultExt as _,
+};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS entries (
+        -- Relative to the base dir of the database.
+        path TEXT PRIMARY KEY,
+        -- The namespace the entry is in, or the shared dir.
+        scope TEXT NOT NULL,
+        test_name TEXT NOT NULL,
+        commit_hash TEXT,
+        -- NULL if there's no result.
+        exit_code INTEGER,
+        -- Milliseconds since the epoch.
+        finished_at INTEGER NOT NULL,
+        size INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS entries_by_test ON entries (scope, test_name, finished_at);
+    CREATE INDEX IF NOT EXISTS entries_by_commit ON entries (commit_hash);
+    -- Scopes whose entries have been indexed at least once.
+    CREATE TABLE IF NOT EXISTS indexed_scopes (scope TEXT PRIMARY KEY);
+";
+
+// What the index knows about an entry.
+struct Row {
+    path: String,
+    scope: String,
+    test_name: String,
+    commit: Option<String>,
+    exit_code: Option<ExitCode>,
+    finished_at: i64,
+    size: u64,
+}
+
+impl Row {
+    fn upsert(&self, conn: &Connection) -> rusqlite::Result<()> {
+        conn.execute(
+            "INSERT OR REPLACE INTO entries
+                (path, scope, test_name, commit_hash, exit_code, finished_at, size)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                self.path,
+                self.scope,
+                self.test_name,
+                self.commit,
+                self.exit_code,
+                self.finished_at,
+                self.size,
+            ],
+        )
+        .map(drop)
+    }
+}
+
+fn millis(t: SystemTime) -> i64 {
+    t.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64)
+}
+
+pub(super) struct SqliteStore {
+    dir: Arc<DirStore>,
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    // The index is shared by everyone using the database. The first time it's
+    // opened for a namespace, the namespace gets indexed.
+    pub(super) fn open(dir: Arc<DirStore>) -> Result<Self> {
+        let path = dir.base_dir.join("index.sqlite");
+        let conn =
+            Connection::open(&path).with_context(|| format!("opening {}", path.display()))?;
+        // Other Limmats might be writing to it at the same time, SQLite takes
+        // care of that but we need to be willing to wait.
+        conn.busy_timeout(Duration::from_secs(60))
+            .context("setting SQLite busy timeout")?;
+        conn.execute_batch(SCHEMA)
+            .context("creating result index schema")?;
+        let store = Self {
+            dir,
+            conn: Arc::new(Mutex::new(conn)),
+        };
+        let indexed: i64 = store
+            .conn
+            .lock()
+            .query_row(
+                "SELECT COUNT(*) FROM indexed_scopes WHERE scope IN (?1, ?2)",
+                params![store.namespace(), SHARED_DIR],
+                |row| row.get(0),
+            )
+            .context("querying result index")?;
+        if indexed != 2 {
+            store.reindex()?;
+        }
+        Ok(store)
+    }
+
+    fn namespace(&self) -> String {
+        self.dir.namespace.to_string_lossy().into_owned()
+    }
+
+    fn relpath<'a>(&self, path: &'a Path) -> &'a Path {
+        path.strip_prefix(&self.dir.base_dir)
+            .expect("entry outside database")
+    }
+
+    fn scope(&self, relpath: &Path) -> String {
+        if relpath.starts_with(SHARED_DIR) {
+            SHARED_DIR.to_owned()
+        } else {
+            self.namespace()
+        }
+    }
+
+    fn row(&self, entry: &StoredEntry) -> Row {
+        let relpath = self.relpath(&entry.path);
+        Row {
+            path: relpath.to_string_lossy().into_owned(),
+            scope: self.scope(relpath),
+            test_name: entry.test_name.clone(),
+            commit: entry.commit.as_ref().map(|c| c.to_string()),
+            exit_code: entry.exit_code,
+            finished_at: millis(entry.finished_at),
+            size: entry.size,
+        }
+    }
+}
+
+impl ResultStore for SqliteStore {
+    fn lookup<'a>(&'a self, test_case: &'a TestCase) -> BoxFuture<'a, Result<LookupResult>> {
+        async move {
+            let mut output = match self.dir.lookup(test_case).await? {
+                LookupResult::YouRunIt(output) => output,
+                found => return Ok(found),
+            };
+            // Index it straight away, so that it shows up (e.g. to GC) even if
+            // the test never produces a result.
+            let relpath = self.dir.result_relpath(test_case);
+            let started_at = millis(SystemTime::now());
+            let row = Row {
+                path: relpath.to_string_lossy().into_owned(),
+                scope: self.scope(&relpath),
+                test_name: test_case.test.name.to_string(),
+                commit: Some(test_case.commit_hash.to_string()),
+                exit_code: None,
+                finished_at: started_at,
+                size: 0,
+            };
+            let conn = self.conn.clone();
+            let row = spawn_blocking(move || {
+                row.upsert(&conn.lock())
+                    .context("adding entry to result index")
+                    .map(|()| row)
+            })
+            .await
+            .context("adding entry to result index")??;
+            let conn = self.conn.clone();
+            let result_dir = self.dir.base_dir.join(&relpath);
+            output
+                .on_result
+                .push(Box::new(move |entry: &TestResultEntry| {
+                    let exit_code = entry.result.exit_code;
+                    let finished_at = entry.finished_at.map_or(started_at, millis);
+                    async move {
+                        spawn_blocking(move || {
+                            let mut row = row;
+                            row.exit_code = Some(exit_code);
+                            row.finished_at = finished_at;
+                            row.size = disk_usage(&result_dir);
+                            row.upsert(&conn.lock())
+                                .or_log_error("updating result index");
+                        })
+                        .await
+                        .or_log_error("updating result index");
+                    }
+                    .boxed()
+                }));
+            Ok(LookupResult::YouRunIt(output))
+        }
+        .boxed()
+    }
+
+    fn find<'a>(&'a self, test_case: &'a TestCase) -> BoxFuture<'a, Result<Option<DatabaseEntry>>> {
+        self.dir.find(test_case).boxed()
+    }
+
+    fn explain(&self, test_case: &TestCase) -> Result<Explanation> {
+        self.dir.explain(test_case)
+    }
+
+    fn entries(&self, query: &EntryQuery) -> Result<Vec<StoredEntry>> {
+        let mut sql = "SELECT path, test_name, commit_hash, exit_code, finished_at, size
+            FROM entries WHERE scope IN (?, ?)"
+            .to_owned();
+        let mut values = vec![
+            Value::from(self.namespace()),
+            Value::from(SHARED_DIR.to_owned()),
+        ];
+        if let Some(test) = &query.test {
+            sql += " AND test_name = ?";
+            values.push(Value::from(test.clone()));
+        }
+        if let Some(commits) = &query.commits {
+            sql += " AND commit_hash IN (SELECT value FROM json_each(?))";
+            let commits: Vec<String> = commits.iter().map(|c| c.to_string()).collect();
+            values.push(Value::from(serde_json::to_string(&commits).unwrap()));
+        }
+        if let Some(since) = query.since {
+            sql += " AND finished_at >= ?";
+            values.push(Value::from(millis(since)));
+        }
+        sql += match query.outcome {
+            Some(Outcome::Success) => " AND exit_code = 0",
+            Some(Outcome::Failure) => " AND exit_code != 0",
+            Some(Outcome::Error) => " AND exit_code IS NULL",
+            None => "",
+        };
+
+        let conn = self.conn.lock();
+        let mut stmt = conn.prepare(&sql).context("querying result index")?;
+        let rows = stmt
+            .query_map(params_from_iter(values), |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    StoredEntry {
+                        path: PathBuf::new(),
+                        test_name: row.get(1)?,
+                        commit: row.get::<_, Option<String>>(2)?.map(CommitHash::new),
+                        exit_code: row.get(3)?,
+                        finished_at: SystemTime::UNIX_EPOCH
+                            + Duration::from_millis(row.get::<_, i64>(4)?.max(0) as u64),
+                        size: row.get(5)?,
+                    },
+                ))
+            })
+            .context("querying result index")?;
+        let mut entries = Vec::new();
+        let mut gone = HashSet::new();
+        for row in rows {
+            let (relpath, mut entry) = row.context("reading result index")?;
+            entry.path = self.dir.base_dir.join(&relpath);
+            // Someone might have deleted it without the index, e.g. a Limmat
+            // that isn't using it.
+            if entry.path.exists() {
+                entries.push(entry);
+            } else {
+                gone.insert(relpath);
+            }
+        }
+        for relpath in gone {
+            conn.execute("DELETE FROM entries WHERE path = ?1", [relpath])
+                .context("removing stale entry from result index")?;
+        }
+        Ok(entries)
+    }
+
+    fn delete(&self, entry: &StoredEntry) -> Result<bool> {
+        let gone = self.dir.delete(entry)?;
+        if gone {
+            self.conn
+                .lock()
+                .execute(
+                    "DELETE FROM entries WHERE path = ?1",
+                    [self.relpath(&entry.path).to_string_lossy()],
+                )
+                .context("removing entry from result index")?;
+        }
+        Ok(gone)
+    }
+
+    // Scan the directory and bring the index up to date with it.
+    fn reindex(&self) -> Result<()> {
+        let entries = self.dir.entries(&EntryQuery::default())?;
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction().context("updating result index")?;
+        for entry in &entries {
+            self.row(entry)
+                .upsert(&tx)
+                .context("updating result index")?;
+        }
+        // Rows for entries that weren't found. If a test was just started it
+        // might not have been there when we scanned, so only delete the ones
+        // that really aren't there.
+        let seen: HashSet<String> = entries
+            .iter()
+            .map(|e| self.relpath(&e.path).to_string_lossy().into_owned())
+            .collect();
+        for scope in [self.namespace(), SHARED_DIR.to_owned()] {
+            let paths: Vec<String> = tx
+                .prepare("SELECT path FROM entries WHERE scope = ?1")?
+                .query_map([&scope], |row| row.get(0))?
+                .collect::<rusqlite::Result<_>>()
+                .context("reading result index")?;
+            for path in paths {
+                if !seen.contains(&path) && !self.dir.base_dir.join(&path).exists() {
+                    tx.execute("DELETE FROM entries WHERE path = ?1", [path])?;
+                }
+            }
+            tx.execute(
+                "INSERT OR IGNORE INTO indexed_scopes (scope) VALUES (?1)",
+                [scope],
+            )?;
+        }
+        tx.commit().context("updating result index")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeSet, fs};
+
+    use tempfile::TempDir;
+
+    use crate::{
+        database::{Backend, Database, DatabaseEntry, DatabaseOutput},
+        git::{test_utils::TempRepo, test_utils::WorktreeExt as _, Commit},
+        test::{Test, TestName, TestResult},
+    };
+
+    use super::*;
+
+    async fn test_case(repo: &TempRepo, test_name: &str, commit: &Commit) -> TestCase {
+        let test = Test {
+            name: TestName::new(test_name),
+            ..Test::arbitrary()
+        };
+        TestCase::new(repo, commit.clone(), Arc::new(test))
+            .await
+            .unwrap()
+    }
+
+    async fn you_run_it(db: &Database, test_case: &TestCase) -> DatabaseOutput {
+        match db.lookup(test_case).await.unwrap() {
+            LookupResult::FoundResult(_) => panic!("result already stored"),
+            LookupResult::YouRunIt(output) => output,
+        }
+    }
+
+    async fn store(db: &Database, test_case: &TestCase, exit_code: ExitCode) -> DatabaseEntry {
+        you_run_it(db, test_case)
+            .await
+            .set_result(&TestResult {
+                exit_code,
+                failed_step: None,
+            })
+            .await
+            .unwrap()
+    }
+
+    fn query(db: &Database, query: &EntryQuery) -> BTreeSet<(String, String, ExitCode)> {
+        db.entries(query)
+            .unwrap()
+            .into_iter()
+            .map(|e| {
+                (
+                    e.test_name,
+                    e.commit.unwrap().to_string(),
+                    e.exit_code.unwrap_or(-1),
+                )
+            })
+            .collect()
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_query() {
+        let db_dir = TempDir::new().unwrap();
+        let db = Database::create_or_open(db_dir.path(), "repo", Backend::Sqlite)
+            .await
+            .unwrap();
+        let dir_db = Database::create_or_open(db_dir.path(), "repo", Backend::Directory)
+            .await
+            .unwrap();
+        let repo = TempRepo::new().await.unwrap();
+        let mut commits = Vec::new();
+        for i in 0..3 {
+            commits.push(repo.commit(format!("{i}")).await.unwrap());
+        }
+        store(&db, &test_case(&repo, "foo", &commits[0]).await, 0).await;
+        store(&db, &test_case(&repo, "foo", &commits[1]).await, 1).await;
+        store(&db, &test_case(&repo, "bar", &commits[1]).await, 0).await;
+        // Started but never finished.
+        drop(you_run_it(&db, &test_case(&repo, "bar", &commits[2]).await).await);
+
+        let hash = |i: usize| commits[i].hash.to_string();
+        for (q, want) in [
+            (
+                EntryQuery {
+                    test: Some("foo".into()),
+                    ..EntryQuery::default()
+                },
+                vec![("foo", hash(0), 0), ("foo", hash(1), 1)],
+            ),
+            (
+                EntryQuery {
+                    commits: Some([commits[1].hash.clone(), commits[2].hash.clone()].into()),
+                    ..EntryQuery::default()
+                },
+                vec![
+                    ("bar", hash(1), 0),
+                    ("bar", hash(2), -1),
+                    ("foo", hash(1), 1),
+                ],
+            ),
+            (
+                EntryQuery {
+                    outcome: Some(Outcome::Success),
+                    ..EntryQuery::default()
+                },
+                vec![("bar", hash(1), 0), ("foo", hash(0), 0)],
+            ),
+            (
+                EntryQuery {
+                    outcome: Some(Outcome::Error),
+                    ..EntryQuery::default()
+                },
+                vec![("bar", hash(2), -1)],
+            ),
+            (
+                EntryQuery {
+                    since: Some(SystemTime::now() + Duration::from_secs(60)),
+                    ..EntryQuery::default()
+                },
+                vec![],
+            ),
+        ] {
+            let want: BTreeSet<_> = want
+                .into_iter()
+                .map(|(t, c, e)| (t.to_owned(), c, e))
+                .collect();
+            assert_eq!(query(&db, &q), want, "{q:?}");
+            // Should be the same as what you get by scanning.
+            assert_eq!(query(&dir_db, &q), want, "{q:?} (directory)");
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_reindex() {
+        let db_dir = TempDir::new().unwrap();
+        let dir_db = Database::create_or_open(db_dir.path(), "repo", Backend::Directory)
+            .await
+            .unwrap();
+        let repo = TempRepo::new().await.unwrap();
+        let commit1 = repo.commit("1").await.unwrap();
+        let commit2 = repo.commit("2").await.unwrap();
+        store(&dir_db, &test_case(&repo, "foo", &commit1).await, 0).await;
+
+        // Entries that were there before the index get indexed when it's
+        // created.
+        let db = Database::create_or_open(db_dir.path(), "repo", Backend::Sqlite)
+            .await
+            .unwrap();
+        assert_eq!(db.entries(&EntryQuery::default()).unwrap().len(), 1);
+
+        // But after that, it doesn't notice ones stored without it.
+        store(&dir_db, &test_case(&repo, "foo", &commit2).await, 0).await;
+        assert_eq!(db.entries(&EntryQuery::default()).unwrap().len(), 1);
+        db.reindex().unwrap();
+        let entries = db.entries(&EntryQuery::default()).unwrap();
+        assert_eq!(entries.len(), 2);
+
+        // If they get deleted behind its back they disappear too.
+        assert!(dir_db.delete(&entries[0]).unwrap());
+        assert_eq!(db.entries(&EntryQuery::default()).unwrap().len(), 1);
+        fs::remove_dir_all(&entries[1].path).unwrap();
+        assert_eq!(db.entries(&EntryQuery::default()).unwrap().len(), 0);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_lookup_waits() {
+        let db_dir = TempDir::new().unwrap();
+        let db1 = Database::create_or_open(db_dir.path(), "repo", Backend::Sqlite)
+            .await
+            .unwrap();
+        let db2 = Database::create_or_open(db_dir.path(), "repo", Backend::Sqlite)
+            .await
+            .unwrap();
+        let repo = TempRepo::new().await.unwrap();
+        let test_case = test_case(&repo, "foo", &repo.commit("1").await.unwrap()).await;
+
+        // While one instance is running the test, the other one should wait
+        // for it and then get its result, instead of running it again.
+        let output = you_run_it(&db1, &test_case).await;
+        let (lookup_result, _entry) = tokio::join!(db2.lookup(&test_case), async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            output
+                .set_result(&TestResult {
+                    exit_code: 3,
+                    failed_step: None,
+                })
+                .await
+                .unwrap()
+        });
+        match lookup_result.unwrap() {
+            LookupResult::FoundResult(entry) => assert_eq!(entry.exit_code(), 3),
+            LookupResult::YouRunIt(_) => panic!("got to run the test while it was running"),
+        }
+    }
+}
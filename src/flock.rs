@@ -7,19 +7,24 @@
 //    https://github.com/Stock84-dev/async-file-lock/issues/3
 //
 // This is a very simple flock library that is not really generic, it serves the
-// rather specific needs of using small files kinda like "database entries".
+// rather specific needs of locking database entries and the like. The locked
+// files are just locks, nothing is stored in them.
 
 use std::{
-    fs::File,
-    io::{Read as _, Seek as _, Write as _},
-    os::fd::{AsRawFd as _, RawFd},
+    fs::{self, File},
+    io::ErrorKind::NotFound,
+    os::{
+        fd::{AsRawFd as _, RawFd},
+        unix::fs::MetadataExt as _,
+    },
+    path::Path,
 };
 
 use anyhow::{anyhow, Context as _};
 
 use nix::{
     errno::Errno,
-    libc::{self, LOCK_EX, LOCK_SH},
+    libc::{self, LOCK_EX, LOCK_NB, LOCK_SH},
 };
 use tokio::task::{self};
 
@@ -38,6 +43,17 @@ impl LockKind {
     }
 }
 
+// Whether the locked file is still the one at path, i.e. nobody deleted or
+// moved it before we got the lock.
+fn is_at(file: &File, path: &Path) -> anyhow::Result<bool> {
+    let locked = file.metadata().context("statting locked file")?;
+    match fs::metadata(path) {
+        Ok(m) => Ok(m.dev() == locked.dev() && m.ino() == locked.ino()),
+        Err(e) if e.kind() == NotFound => Ok(false),
+        Err(e) => Err(e).with_context(|| format!("statting {}", path.display())),
+    }
+}
+
 fn flock(fd: RawFd, kind: LockKind) -> anyhow::Result<()> {
     let res = unsafe { libc::flock(fd, kind.flock_arg()) };
     Errno::result(res)
@@ -57,73 +73,67 @@ async fn flock_async(fd: RawFd, kind: LockKind) -> anyhow::Result<()> {
 #[derive(Debug)]
 pub struct SharedFlock {
     file: File,
-    content: String,
 }
 
 impl SharedFlock {
-    // Lock an open file, this also immediately reads the whole content which
-    // can access via `content`. The file should be freshly-opened.
-    pub async fn new(mut file: File) -> anyhow::Result<Self> {
+    // Lock an open file.
+    pub async fn new(file: File) -> anyhow::Result<Self> {
         flock_async(file.as_raw_fd(), LockKind::Shared).await?;
-        let mut content = String::new();
-        file.read_to_string(&mut content)
-            .context("reading locked file")?;
-        Ok(Self { file, content })
+        Ok(Self { file })
+    }
+
+    // Like new, but if someone else holds an exclusive lock on the file, return
+    // None instead of waiting for it.
+    pub fn try_new(file: File) -> anyhow::Result<Option<Self>> {
+        let res = unsafe { libc::flock(file.as_raw_fd(), LOCK_SH | LOCK_NB) };
+        match Errno::result(res) {
+            Err(Errno::EWOULDBLOCK) => Ok(None),
+            Err(errno) => Err(anyhow!("flock(Shared | NB) failed: {errno}")),
+            Ok(_) => Ok(Some(Self { file })),
+        }
     }
 
-    // The content of the file.
-    // This returns a reference to reflect the fact that the validity of the
-    // content is tied to the lifetime of the lock.
-    pub fn content(&self) -> &str {
-        &self.content
+    pub fn is_at(&self, path: &Path) -> anyhow::Result<bool> {
+        is_at(&self.file, path)
     }
 
-    // Upgrade to a "write" lock. This is not an atomic operation, when you do
-    // this the content of the file can change, which is reflected by the fact
-    // that the reference returned by `content` is invalid now, so you should
-    // check the `content` of the result again.
-    pub async fn upgrade(mut self) -> anyhow::Result<ExclusiveFlock> {
-        self.file.rewind().context("rewinding locked file")?;
+    // Upgrade to a "write" lock. This is not an atomic operation, someone
+    // else might get the exclusive lock in between, so whatever the lock was
+    // protecting needs to be checked again after this.
+    pub async fn upgrade(self) -> anyhow::Result<ExclusiveFlock> {
         ExclusiveFlock::new(self.file).await
     }
 }
 
 // A simple "write" lock on a file.
+#[derive(Debug)]
 pub struct ExclusiveFlock {
     file: File,
-    content: String,
 }
 
 impl ExclusiveFlock {
-    // Even though this is a "write lock" in a sense, the file needs to be open for reading too.
-    pub async fn new(mut file: File) -> anyhow::Result<Self> {
-        debug_assert_eq!(file.stream_position().unwrap(), 0);
+    pub async fn new(file: File) -> anyhow::Result<Self> {
         flock_async(file.as_raw_fd(), LockKind::Exclusive).await?;
-        let mut content = String::new();
-        file.read_to_string(&mut content)
-            .context("reading locked")?;
-        file.rewind().context("rewinding locked file")?;
-        Ok(Self { file, content })
+        Ok(Self { file })
     }
 
-    pub fn content(&self) -> &str {
-        &self.content
+    // Like new, but if someone else holds a lock on the file, return None
+    // instead of waiting for it.
+    pub fn try_new(file: File) -> anyhow::Result<Option<Self>> {
+        let res = unsafe { libc::flock(file.as_raw_fd(), LOCK_EX | LOCK_NB) };
+        match Errno::result(res) {
+            Err(Errno::EWOULDBLOCK) => Ok(None),
+            Err(errno) => Err(anyhow!("flock(Exclusive | NB) failed: {errno}")),
+            Ok(_) => Ok(Some(Self { file })),
+        }
     }
 
-    // Replace the content of the file.
-    pub fn set_content(&mut self, content: &[u8]) -> anyhow::Result<()> {
-        debug_assert_eq!(self.file.stream_position().unwrap(), 0);
-        self.file.set_len(0).context("truncating locked file")?;
-        self.file
-            .write_all(content)
-            .context("writing locked file")?;
-        // TODO: it would be nicer if this method consumed self, then we wouldn't have to rewind.
-        self.file.rewind().context("rewinding locked file")
+    pub fn is_at(&self, path: &Path) -> anyhow::Result<bool> {
+        is_at(&self.file, path)
     }
 
     // See SharedFlock::upgrade - same limiations apply.
-    pub async fn downgrade(mut self) -> anyhow::Result<SharedFlock> {
-        self.file.rewind().context("rewinding locked file")?;
+    pub async fn downgrade(self) -> anyhow::Result<SharedFlock> {
         SharedFlock::new(self.file).await
     }
 }
@@ -0,0 +1,361 @@
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context as _};
+#[allow(unused_imports)]
+use log::{debug, info};
+use tokio::task::spawn_blocking;
+
+use crate::{
+    database::{Database, EntryQuery, StoredEntry},
+    git::{CommitHash, Worktree},
+};
+
+// Rules for deleting old results from the database. With the default,
+// nothing gets deleted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcPolicy {
+    // Delete the oldest results until the database is at most this many bytes.
+    pub max_size: Option<u64>,
+    // Delete results older than this.
+    pub max_age: Option<Duration>,
+    // Delete all but this many of the newest results for each test.
+    pub keep_per_test: Option<usize>,
+    // Revspecs. Results for commits reachable from any of these are never
+    // deleted, even if that means exceeding max_size.
+    pub keep_reachable_from: Vec<String>,
+}
+
+impl GcPolicy {
+    pub fn is_empty(&self) -> bool {
+        self.max_size.is_none() && self.max_age.is_none() && self.keep_per_test.is_none()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct GcReport {
+    // In dry-run mode, these weren't really deleted.
+    pub deleted: Vec<StoredEntry>,
+    // Entries that should have been deleted but were being used by a job or a
+    // reader.
+    pub in_use: usize,
+    // Size of the database afterwards.
+    pub remaining_size: u64,
+}
+
+impl GcReport {
+    pub fn freed(&self) -> u64 {
+        self.deleted.iter().map(|e| e.size).sum()
+    }
+}
+
+// Figures out whether results are protected by keep_reachable_from. Git is
+// only asked about entries that we actually want to delete, and only once per
+// commit.
+struct Protection<'a, W: Worktree> {
+    repo: &'a W,
+    refs: Vec<CommitHash>,
+    known: HashMap<CommitHash, bool>,
+}
+
+impl<'a, W: Worktree> Protection<'a, W> {
+    async fn new(repo: &'a W, rev_specs: &[String]) -> anyhow::Result<Self> {
+        let mut refs = Vec::new();
+        for rev_spec in rev_specs {
+            let commit = repo
+                .rev_parse(rev_spec)
+                .await
+                .with_context(|| format!("looking up {rev_spec:?}"))?
+                .ok_or_else(|| anyhow!("keep_reachable_from revision {rev_spec:?} not found"))?;
+            refs.push(commit.hash);
+        }
+        Ok(Self {
+            repo,
+            refs,
+            known: HashMap::new(),
+        })
+    }
+
+    async fn protects(&mut self, entry: &StoredEntry) -> anyhow::Result<bool> {
+        // If we can't tell what commit it was for, it can't be protected.
+        let Some(commit) = &entry.commit else {
+            return Ok(false);
+        };
+        if let Some(protected) = self.known.get(commit) {
+            return Ok(*protected);
+        }
+        let mut protected = false;
+        for r in &self.refs {
+            if self.repo.is_ancestor(commit, r).await? {
+                protected = true;
+                break;
+            }
+        }
+        self.known.insert(commit.clone(), protected);
+        Ok(protected)
+    }
+}
+
+enum Pass {
+    Unwanted,
+    Size,
+}
+
+// Delete results from the database according to the policy. Entries that are
+// in use are left alone, so it's safe to run this while tests are running.
+// repo is used to evaluate keep_reachable_from.
+pub async fn collect_garbage(
+    database: &Arc<Database>,
+    repo: &impl Worktree,
+    policy: &GcPolicy,
+    dry_run: bool,
+) -> anyhow::Result<GcReport> {
+    // Scanning the database (or waiting for the index) can take a while.
+    let db = database.clone();
+    let mut entries = spawn_blocking(move || db.entries(&EntryQuery::default()))
+        .await?
+        .context("listing database entries")?;
+    // Newest first.
+    entries.sort_by_key(|e| Reverse(e.finished_at));
+    let mut protection = Protection::new(repo, &policy.keep_reachable_from).await?;
+    let mut report = GcReport {
+        remaining_size: entries.iter().map(|e| e.size).sum(),
+        ..GcReport::default()
+    };
+
+    // First get rid of the entries that are unwanted regardless of the size
+    // limit, then go from oldest to newest deleting the rest until we're under
+    // the size limit.
+    let mut per_test: HashMap<&str, usize> = HashMap::new();
+    let unwanted: Vec<bool> = entries
+        .iter()
+        .map(|entry| {
+            let count = per_test.entry(&entry.test_name).or_default();
+            *count += 1;
+            policy.max_age.is_some_and(|max_age| entry.age() > max_age)
+                || policy.keep_per_test.is_some_and(|keep| *count > keep)
+        })
+        .collect();
+    let mut done = HashSet::new();
+    for pass in [Pass::Unwanted, Pass::Size] {
+        for (i, entry) in entries.iter().enumerate().rev() {
+            if done.contains(&i) {
+                continue;
+            }
+            let delete = match pass {
+                Pass::Unwanted => unwanted[i],
+                Pass::Size => policy
+                    .max_size
+                    .is_some_and(|max_size| report.remaining_size > max_size),
+            };
+            if !delete {
+                continue;
+            }
+            // Either way we won't look at this one again.
+            done.insert(i);
+            if protection.protects(entry).await? {
+                debug!("GC: keeping {entry:?}, it's reachable from a protected ref");
+                continue;
+            }
+            if !dry_run && !delete_entry(database, entry).await? {
+                debug!("GC: can't delete {entry:?}, it's in use");
+                report.in_use += 1;
+                continue;
+            }
+            report.remaining_size -= entry.size;
+            report.deleted.push(entry.clone());
+        }
+    }
+    Ok(report)
+}
+
+// Database::delete, but without blocking the runtime.
+async fn delete_entry(database: &Arc<Database>, entry: &StoredEntry) -> anyhow::Result<bool> {
+    let database = database.clone();
+    let entry = entry.clone();
+    spawn_blocking(move || database.delete(&entry)).await?
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, fs, sync::Arc};
+
+    use tempfile::TempDir;
+    use test_case::test_case;
+
+    use crate::{
+        database::{Backend, DatabaseEntry, LookupResult},
+        git::{test_utils::TempRepo, test_utils::WorktreeExt as _, Commit},
+        process::CommandExt as _,
+        test::{Test, TestCase, TestName, TestResult},
+    };
+
+    use super::*;
+
+    // Store a passing result for the test at the commit, with an artifact of
+    // the given size.
+    async fn store(
+        db: &Database,
+        repo: &TempRepo,
+        test_name: &str,
+        commit: &Commit,
+        size: usize,
+    ) -> DatabaseEntry {
+        let test = Test {
+            name: TestName::new(test_name),
+            ..Test::arbitrary()
+        };
+        let test_case = TestCase::new(repo, commit.clone(), Arc::new(test))
+            .await
+            .unwrap();
+        let mut output = match db.lookup(&test_case).await.unwrap() {
+            LookupResult::FoundResult(_) => panic!("result already stored"),
+            LookupResult::YouRunIt(output) => output,
+        };
+        output.start_step(None, None, BTreeMap::new()).unwrap();
+        fs::write(output.artifacts_dir().join("blob"), vec![0; size]).unwrap();
+        output
+            .set_result(&TestResult {
+                exit_code: 0,
+                failed_step: None,
+            })
+            .await
+            .unwrap()
+    }
+
+    fn remaining(db: &Database) -> Vec<(String, String)> {
+        let mut entries: Vec<_> = db
+            .entries(&EntryQuery::default())
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.test_name, e.commit.unwrap().to_string()))
+            .collect();
+        entries.sort();
+        entries
+    }
+
+    #[test_case(Backend::Directory ; "directory")]
+    #[test_case(Backend::Sqlite ; "sqlite")]
+    #[test_log::test(tokio::test)]
+    async fn test_keep_per_test(backend: Backend) {
+        let db_dir = TempDir::new().unwrap();
+        let db = Arc::new(
+            Database::create_or_open(db_dir.path(), "repo", backend)
+                .await
+                .unwrap(),
+        );
+        let repo = TempRepo::new().await.unwrap();
+        let base = repo.commit("base").await.unwrap();
+        let mut commits = Vec::new();
+        for i in 0..4 {
+            commits.push(repo.commit(format!("{i}")).await.unwrap());
+        }
+        // Only the base is protected.
+        repo.git(["branch", "protected"])
+            .arg(&base.hash)
+            .execute()
+            .await
+            .unwrap();
+
+        for commit in [&base, &commits[0], &commits[1], &commits[2]] {
+            store(&db, &repo, "foo", commit, 10).await;
+        }
+        let bar = store(&db, &repo, "bar", &commits[0], 10).await;
+        // Hold on to one of them, that shouldn't get deleted.
+        let in_use = store(&db, &repo, "foo", &commits[3], 10).await;
+        drop(bar);
+        let policy = GcPolicy {
+            keep_per_test: Some(1),
+            keep_reachable_from: vec!["protected".into()],
+            ..GcPolicy::default()
+        };
+
+        let report = collect_garbage(&db, &repo, &policy, true).await.unwrap();
+        assert_eq!(report.deleted.len(), 3);
+        assert_eq!(remaining(&db).len(), 6, "dry run deleted stuff");
+
+        let report = collect_garbage(&db, &repo, &policy, false).await.unwrap();
+        // foo at commits[3] is the newest so it's kept anyway, and the base
+        // is protected.
+        assert_eq!(report.deleted.len(), 3);
+        assert_eq!(report.in_use, 0);
+        let hash = |c: &Commit| c.hash.to_string();
+        let mut want = vec![
+            ("bar".to_owned(), hash(&commits[0])),
+            ("foo".to_owned(), hash(&base)),
+            ("foo".to_owned(), hash(&commits[3])),
+        ];
+        want.sort();
+        assert_eq!(remaining(&db), want);
+
+        // Now with a policy that wants to delete the one that's in use.
+        let policy = GcPolicy {
+            max_size: Some(0),
+            ..GcPolicy::default()
+        };
+        let report = collect_garbage(&db, &repo, &policy, false).await.unwrap();
+        assert_eq!(report.deleted.len(), 2);
+        assert_eq!(report.in_use, 1);
+        assert_eq!(remaining(&db), vec![("foo".to_owned(), hash(&commits[3]))]);
+        drop(in_use);
+        let report = collect_garbage(&db, &repo, &policy, false).await.unwrap();
+        assert_eq!(report.deleted.len(), 1);
+        assert_eq!(report.remaining_size, 0);
+        assert_eq!(remaining(&db), vec![]);
+    }
+
+    #[test_case(Backend::Directory ; "directory")]
+    #[test_case(Backend::Sqlite ; "sqlite")]
+    #[test_log::test(tokio::test)]
+    async fn test_max_size(backend: Backend) {
+        let db_dir = TempDir::new().unwrap();
+        let db = Arc::new(
+            Database::create_or_open(db_dir.path(), "repo", backend)
+                .await
+                .unwrap(),
+        );
+        let repo = TempRepo::new().await.unwrap();
+        let mut commits = Vec::new();
+        for i in 0..4 {
+            let commit = repo.commit(format!("{i}")).await.unwrap();
+            store(&db, &repo, "foo", &commit, 1000).await;
+            commits.push(commit);
+        }
+        // Just enough space for the two newest ones. The sizes aren't quite
+        // equal because of the metadata.
+        let mut entries = db.entries(&EntryQuery::default()).unwrap();
+        entries.sort_by_key(|e| Reverse(e.finished_at));
+        let max_size = entries[0].size + entries[1].size;
+        let policy = GcPolicy {
+            max_size: Some(max_size),
+            ..GcPolicy::default()
+        };
+        let report = collect_garbage(&db, &repo, &policy, false).await.unwrap();
+        assert_eq!(report.deleted.len(), 2);
+        assert_eq!(report.remaining_size, max_size);
+        let hash = |c: &Commit| c.hash.to_string();
+        let mut want = vec![
+            ("foo".to_owned(), hash(&commits[2])),
+            ("foo".to_owned(), hash(&commits[3])),
+        ];
+        want.sort();
+        assert_eq!(remaining(&db), want);
+
+        // Deleted results should just get re-run.
+        let test = Test {
+            name: TestName::new("foo"),
+            ..Test::arbitrary()
+        };
+        let test_case = TestCase::new(&repo, commits[0].clone(), Arc::new(test))
+            .await
+            .unwrap();
+        assert!(matches!(
+            db.lookup(&test_case).await.unwrap(),
+            LookupResult::YouRunIt(_)
+        ));
+    }
+}
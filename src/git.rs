@@ -1,28 +1,40 @@
 use core::fmt;
 use core::fmt::{Debug, Display};
+use std::collections::HashSet;
 use std::ffi::{OsStr, OsString};
+use std::io;
 use std::ops::Deref;
 use std::os::unix::ffi::{OsStrExt as _, OsStringExt as _};
 use std::path::{Path, PathBuf};
 use std::pin::pin;
-use std::process::Command as SyncCommand;
+use std::process::{Command as SyncCommand, Stdio};
 use std::str;
+use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::anyhow;
 use anyhow::{bail, Context};
 use async_stream::try_stream;
 use colored::control::SHOULD_COLORIZE;
-use futures::{future::Fuse, select, FutureExt, SinkExt as _, StreamExt as _};
+use futures::{
+    future::{join, Fuse},
+    select, FutureExt, SinkExt as _, StreamExt as _,
+};
 use futures_core::{stream::Stream, FusedFuture};
 #[allow(unused_imports)]
 use log::{debug, error, info, warn};
 use notify::{Config, RecommendedWatcher, RecursiveMode, Watcher};
+use parking_lot::Mutex;
+use schemars::JsonSchema;
+use serde::Deserialize;
 use tempfile::TempDir;
+use tokio::io::AsyncWriteExt as _;
 use tokio::process::Command;
 use tokio::time::sleep;
 use tokio_util::sync::CancellationToken;
 
+use crate::flock::ExclusiveFlock;
+use crate::overlay::{CommitCheckouts, Overlay};
 use crate::process::OutputExt;
 use crate::process::{CommandExt, SyncCommandExt as _};
 
@@ -182,6 +194,22 @@ impl From<Commit> for CommitHash {
     }
 }
 
+// Default implementation of Worktree::checkout, available separately so that
+// implementors that override it can still fall back to it.
+async fn checkout<W: Worktree + ?Sized>(worktree: &W, commit: &CommitHash) -> anyhow::Result<()> {
+    worktree
+        .git(["checkout"])
+        .arg(commit)
+        .output()
+        .await?
+        .ok()
+        .context(format!(
+            "checking out revision {:?} in {:?}",
+            commit,
+            worktree.path()
+        ))
+}
+
 // This is a weird kinda inheritance type thing to enable different types of worktree (with
 // different fields and drop behaviours) to share the functionality that users actually care about.
 // Not really sure if this is the Rust Way or not.
@@ -241,9 +269,9 @@ pub trait Worktree: Debug {
         let output = self
             .git(["rev-list"])
             .arg(range_spec)
-            .execute()
+            .output()
             .await
-            .context("'git rev-list' failed")?;
+            .context("failed to run 'git rev-list'")?;
         // See coment in rev_parse.
         if output.code_not_killed()? == 128 {
             return Ok(vec![]);
@@ -262,16 +290,26 @@ pub trait Worktree: Debug {
     }
 
     async fn checkout(&self, commit: &CommitHash) -> anyhow::Result<()> {
-        self.git(["checkout"])
+        checkout(self, commit).await
+    }
+
+    // The commit and up to max_count-1 of its ancestors, with children always
+    // listed before their parents. So for linear history the index is the
+    // distance from the commit.
+    async fn ancestors(
+        &self,
+        commit: &CommitHash,
+        max_count: usize,
+    ) -> anyhow::Result<Vec<CommitHash>> {
+        let output = self
+            .git(["rev-list", "--topo-order"])
+            .arg(format!("--max-count={max_count}"))
             .arg(commit)
-            .output()
-            .await?
-            .ok()
-            .context(format!(
-                "checking out revision {:?} in {:?}",
-                commit,
-                self.path()
-            ))
+            .execute()
+            .await
+            .context("'git rev-list' failed")?;
+        let out_str: &str = str::from_utf8(&output.stdout).context("non utf-8 rev-list output")?;
+        Ok(out_str.lines().map(CommitHash::new).collect())
     }
 
     async fn log_graph<S, T>(&self, range_spec: S, format_spec: T) -> anyhow::Result<OsString>
@@ -281,18 +319,23 @@ pub trait Worktree: Debug {
     {
         let mut format_arg = OsString::from("--format=");
         format_arg.push(format_spec.as_ref());
-        let stdout = self
+        let output = self
             .git(["log", "--graph"])
             .args([&format_arg, range_spec.as_ref()])
-            .execute()
+            .output()
             .await
-            .context(format!(
-                "getting graph log for {:?} with format {:?}",
-                range_spec.as_ref(),
-                format_spec.as_ref(),
-            ))?
-            .stdout;
-        Ok(OsString::from_vec(stdout))
+            .context("failed to run 'git log --graph'")?;
+        // Like rev_list, an invalid range (e.g. before the first commit) is
+        // just empty.
+        if output.code_not_killed()? == 128 {
+            return Ok(OsString::new());
+        }
+        output.ok().context(format!(
+            "getting graph log for {:?} with format {:?}",
+            range_spec.as_ref(),
+            format_spec.as_ref(),
+        ))?;
+        Ok(OsString::from_vec(output.stdout))
     }
 
     async fn log_n1<S, T>(&self, rev_spec: S, format_spec: T) -> anyhow::Result<OsString>
@@ -316,6 +359,124 @@ pub trait Worktree: Debug {
         Ok(OsString::from_vec(stdout))
     }
 
+    // Contents of the file at path (relative to the root of the repo) in the
+    // given commit, or None if the commit has no such file.
+    async fn show_file(&self, commit: &CommitHash, path: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let output = self
+            .git(["show"])
+            .arg(format!("{commit}:{path}"))
+            .output()
+            .await
+            .context("failed to run 'git show'")?;
+        // Like in rev_parse, 128 seems to mean the object doesn't exist.
+        match output.code_not_killed()? {
+            0 => Ok(Some(output.stdout)),
+            128 => Ok(None),
+            exit_code => bail!("'git show {commit}:{path}' failed with code {exit_code}"),
+        }
+    }
+
+    // ID of the object (tree or blob) at path in the given commit, or None if
+    // the commit has no such path.
+    async fn path_object(&self, commit: &CommitHash, path: &str) -> anyhow::Result<Option<Hash>> {
+        let output = self
+            .git(["rev-parse", "--verify", "--quiet"])
+            .arg(format!("{commit}:{path}"))
+            .output()
+            .await
+            .context("failed to run 'git rev-parse'")?;
+        match output.code_not_killed()? {
+            0 => {
+                let stdout =
+                    str::from_utf8(&output.stdout).context("non utf-8 rev-parse output")?;
+                Ok(Some(Hash::new(stdout.trim())))
+            }
+            // --quiet means this is how it tells us it doesn't exist.
+            1 => Ok(None),
+            exit_code => bail!("'git rev-parse {commit}:{path}' failed with code {exit_code}"),
+        }
+    }
+
+    // Whether commit is reachable from descendant (a commit counts as
+    // reachable from itself). If either of them isn't a commit in this repo,
+    // the answer is no.
+    async fn is_ancestor(
+        &self,
+        commit: &CommitHash,
+        descendant: &CommitHash,
+    ) -> anyhow::Result<bool> {
+        let output = self
+            .git(["merge-base", "--is-ancestor"])
+            .args([commit, descendant])
+            .output()
+            .await
+            .context("failed to run 'git merge-base'")?;
+        // 128 seems to mean one of them doesn't exist, like in rev_parse.
+        match output.code_not_killed()? {
+            0 => Ok(true),
+            1 | 128 => Ok(false),
+            exit_code => bail!(
+                "'git merge-base --is-ancestor {commit} {descendant}' failed with code {exit_code}"
+            ),
+        }
+    }
+
+    // One of the root commits reachable from rev, or None if rev doesn't
+    // exist (e.g. HEAD before the first commit). If there are several (e.g.
+    // unrelated histories got merged) it picks the lowest hash, so it doesn't
+    // depend on how Git happens to order them. This is a pretty stable
+    // identity for a repository, it's the same in all its clones.
+    async fn root_commit(&self, rev: &str) -> anyhow::Result<Option<CommitHash>> {
+        let output = self
+            .git(["rev-list", "--max-parents=0"])
+            .arg(rev)
+            .output()
+            .await
+            .context("failed to run 'git rev-list'")?;
+        match output.code_not_killed()? {
+            0 => {
+                let stdout = str::from_utf8(&output.stdout).context("non utf-8 rev-list output")?;
+                Ok(stdout.lines().min().map(CommitHash::new))
+            }
+            128 => Ok(None),
+            exit_code => bail!("'git rev-list --max-parents=0 {rev}' failed with code {exit_code}"),
+        }
+    }
+
+    // Which of these object IDs (commits, trees etc) the repo has.
+    async fn existing_objects(&self, ids: &[String]) -> anyhow::Result<HashSet<String>> {
+        let mut child = self
+            .git(["cat-file", "--batch-check=%(objectname)"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .context("failed to spawn 'git cat-file'")?;
+        let mut stdin = child.stdin.take().unwrap();
+        let input = ids.iter().map(|id| format!("{id}\n")).collect::<String>();
+        // Write concurrently with reading the output, otherwise if there's
+        // lots of them we could deadlock.
+        let write = async move {
+            stdin.write_all(input.as_bytes()).await?;
+            // Close stdin so it terminates.
+            drop(stdin);
+            Ok::<_, io::Error>(())
+        };
+        let (write_result, output) = join(write, child.wait_with_output()).await;
+        write_result.context("writing to 'git cat-file'")?;
+        let output = output.context("running 'git cat-file'")?;
+        if !output.status.success() {
+            bail!("'git cat-file --batch-check' failed with {}", output.status);
+        }
+        let stdout = str::from_utf8(&output.stdout).context("non utf-8 cat-file output")?;
+        // Missing objects are reported as "<id> missing", existing ones are
+        // just the ID.
+        Ok(stdout
+            .lines()
+            .filter(|l| !l.ends_with(" missing"))
+            .map(str::to_owned)
+            .collect())
+    }
+
     // Watch for events that could change the meaning of a revspec. When that happens, send an event
     // on the channel with the new resolved spec.
     fn watch_refs<'a>(
@@ -433,13 +594,71 @@ pub trait Worktree: Debug {
     }
 }
 
+// How the worktrees that test jobs run in are created.
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WorktreeMode {
+    /// Each worktree is a full checkout created with `git worktree add`.
+    #[default]
+    Worktree,
+    /// Each job gets a private copy-on-write view of a single read-only
+    /// checkout of the commit being tested. Uses overlayfs if possible,
+    /// otherwise fuse-overlayfs, otherwise a (reflink, if supported) copy. The
+    /// tree is reset to a pristine state for every job. Note there is no .git
+    /// in these trees, use $LIMMAT_ORIGIN if you need to run Git commands.
+    Overlay,
+    /// Each worktree is a standalone local clone (`git clone --shared`) of the
+    /// repository, with a real `.git` directory. New commits are fetched from
+    /// the main repository on demand. Use this if your tooling gets confused by
+    /// the `.git` file in a `git worktree`.
+    Clone,
+}
+
+// Where a TempWorktree lives. Persistent worktrees are for
+// WorktreeMode::Worktree only, they are kept around after cleanup so they can
+// be reused by later Limmat instances (see worktree_pool.rs).
+#[derive(Debug)]
+enum WorktreeDir {
+    Temp(TempDir),
+    Persistent {
+        path: PathBuf,
+        // Held for as long as we're using the worktree, so that other Limmat
+        // processes don't use it at the same time.
+        _lock: ExclusiveFlock,
+        // Set once the worktree is known to be valid, until then cleanup
+        // deletes it as if it was temporary.
+        keep: bool,
+    },
+}
+
+impl WorktreeDir {
+    fn path(&self) -> &Path {
+        match self {
+            Self::Temp(temp_dir) => temp_dir.path(),
+            Self::Persistent { path, .. } => path,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Backend {
+    GitWorktree,
+    Overlay(Overlay),
+    Clone,
+}
+
 // A worktree that is deleted when dropped. This is kind of a dumb API that just happens to fit this
 // project's exact needs. Instead probably Repo::new and this method should return a common trait or
 // something.
 #[derive(Debug)]
 pub struct TempWorktree {
     origin: PathBuf, // Path of repo this was created from.
-    temp_dir: TempDir,
+    dir: WorktreeDir,
+    backend: Backend,
+    // Stable identifier, assigned by whoever manages the set of worktrees.
+    id: usize,
+    // Most recent commit successfully checked out, if any.
+    last_checkout: Mutex<Option<CommitHash>>,
     cleaned_up: bool,
 }
 
@@ -453,6 +672,65 @@ impl TempWorktree {
         origin: &W,
         temp_dir: TempDir,
     ) -> anyhow::Result<TempWorktree>
+    where
+        W: Worktree,
+    {
+        Self::add_worktree(ct, origin, WorktreeDir::Temp(temp_dir)).await
+    }
+
+    // Like new, but creates the worktree at a path that will be kept on
+    // cleanup. The path must not exist yet, or be an empty directory. The
+    // lock should be held to ensure no other Limmat is using this path.
+    pub async fn new_persistent<W>(
+        ct: &CancellationToken,
+        origin: &W,
+        path: PathBuf,
+        lock: ExclusiveFlock,
+    ) -> anyhow::Result<TempWorktree>
+    where
+        W: Worktree,
+    {
+        let mut zelf = Self::add_worktree(
+            ct,
+            origin,
+            WorktreeDir::Persistent {
+                path,
+                _lock: lock,
+                keep: false,
+            },
+        )
+        .await?;
+        if let WorktreeDir::Persistent { keep, .. } = &mut zelf.dir {
+            *keep = true;
+        }
+        Ok(zelf)
+    }
+
+    // Re-use a persistent worktree that was previously created with
+    // new_persistent. The caller is responsible for checking that it's valid.
+    pub fn adopt_persistent<W>(origin: &W, path: PathBuf, lock: ExclusiveFlock) -> TempWorktree
+    where
+        W: Worktree,
+    {
+        Self {
+            origin: origin.path().to_owned(),
+            dir: WorktreeDir::Persistent {
+                path,
+                _lock: lock,
+                keep: true,
+            },
+            backend: Backend::GitWorktree,
+            id: 0,
+            last_checkout: Mutex::new(None),
+            cleaned_up: false,
+        }
+    }
+
+    async fn add_worktree<W>(
+        ct: &CancellationToken,
+        origin: &W,
+        dir: WorktreeDir,
+    ) -> anyhow::Result<TempWorktree>
     where
         W: Worktree,
     {
@@ -461,14 +739,17 @@ impl TempWorktree {
         // this constructor is cancelled.
         let zelf = Self {
             origin: origin.path().to_owned(),
-            temp_dir,
+            dir,
+            backend: Backend::GitWorktree,
+            id: 0,
+            last_checkout: Mutex::new(None),
             cleaned_up: false,
         };
         // Dumb workaround for https://github.com/bjackman/limmat/issues/14
         let mut attempts = 1;
         loop {
             let mut cmd = origin.git(["worktree", "add"]);
-            let cmd = cmd.arg(zelf.temp_dir.path()).arg("HEAD");
+            let cmd = cmd.arg(zelf.dir.path()).arg("HEAD");
             select! {
                 _ = ct.cancelled().fuse() => {
                     zelf.cleanup().await;
@@ -489,11 +770,99 @@ impl TempWorktree {
         }
     }
 
+    // Create an overlay worktree (see WorktreeMode::Overlay). This is cheap,
+    // nothing actually gets checked out until you call checkout.
+    pub fn new_overlay<W>(
+        origin: &W,
+        temp_dir: TempDir,
+        checkouts: Arc<CommitCheckouts>,
+    ) -> anyhow::Result<TempWorktree>
+    where
+        W: Worktree,
+    {
+        let overlay = Overlay::new(checkouts, temp_dir.path())?;
+        Ok(Self {
+            origin: origin.path().to_owned(),
+            dir: WorktreeDir::Temp(temp_dir),
+            backend: Backend::Overlay(overlay),
+            id: 0,
+            last_checkout: Mutex::new(None),
+            cleaned_up: false,
+        })
+    }
+
+    // Create a worktree that's a separate clone of the origin repo (see
+    // WorktreeMode::Clone). Same cleanup/cancellation rules as new.
+    pub async fn new_clone<W>(
+        ct: &CancellationToken,
+        origin: &W,
+        temp_dir: TempDir,
+    ) -> anyhow::Result<TempWorktree>
+    where
+        W: Worktree,
+    {
+        let zelf = Self {
+            origin: origin.path().to_owned(),
+            dir: WorktreeDir::Temp(temp_dir),
+            backend: Backend::Clone,
+            id: 0,
+            last_checkout: Mutex::new(None),
+            cleaned_up: false,
+        };
+        // --shared means we borrow the origin's object database via
+        // alternates instead of copying it, so this is cheap. It also means
+        // most new commits are visible without fetching, but not all (e.g. if
+        // the origin is itself a worktree whose objects live elsewhere), so
+        // checkout still falls back to fetching.
+        let mut cmd = origin.git(["clone", "--quiet", "--shared", "--no-checkout"]);
+        cmd.arg(origin.path()).arg(zelf.dir.path());
+        select! {
+            _ = ct.cancelled().fuse() => {
+                zelf.cleanup().await;
+                bail!("canceled")
+            },
+            res = cmd.execute().fuse() => {
+                res.context("git clone failed")?;
+                Ok(zelf)
+            },
+        }
+    }
+
+    // Make sure the commit is available in a Clone worktree, fetching it from
+    // the origin if necessary.
+    async fn fetch_if_missing(&self, commit: &CommitHash) -> anyhow::Result<()> {
+        let mut object = OsString::from(AsRef::<OsStr>::as_ref(commit));
+        object.push("^{commit}");
+        let output = self.git(["cat-file", "-e"]).arg(object).output().await?;
+        if output.status.success() {
+            return Ok(());
+        }
+        debug!("Fetching {commit} into {:?}", self.path());
+        self.git(["fetch", "--quiet", "origin"])
+            .arg(commit)
+            .execute()
+            .await
+            .with_context(|| format!("fetching {commit} from origin"))?;
+        Ok(())
+    }
+
     fn cleanup_cmd(&self) -> Option<SyncCommand> {
+        match &self.backend {
+            // Nothing to de-register, we just need to unmount before the
+            // TempDir gets deleted.
+            Backend::Overlay(overlay) => return overlay.teardown_cmd(),
+            // The clone is self-contained, deleting the TempDir is enough.
+            Backend::Clone => return None,
+            Backend::GitWorktree => (),
+        }
+        if let WorktreeDir::Persistent { keep: true, .. } = self.dir {
+            // Leave it registered for the next Limmat to pick up.
+            return None;
+        }
         if !self.origin.exists() {
             debug!(
                 "Not de-registering worktree at {:?} as origin repo ({:?}) is gone.",
-                self.temp_dir.path(),
+                self.dir.path(),
                 self.origin
             );
             return None;
@@ -508,11 +877,23 @@ impl TempWorktree {
         // Double --force means remove it even if we were in the middle of
         // creating it.
         cmd.args(["worktree", "remove", "--force", "--force"])
-            .arg(self.temp_dir.path())
+            .arg(self.dir.path())
             .current_dir(&self.origin);
         Some(cmd)
     }
 
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn set_id(&mut self, id: usize) {
+        self.id = id;
+    }
+
+    pub fn last_checkout(&self) -> Option<CommitHash> {
+        self.last_checkout.lock().clone()
+    }
+
     // Clean up asnchronously, if you don't do this it will be done
     // synchronously in drop (blocking the async runtime and with no opportunity
     // for parallelism) and you will feel like a dumb idiot and your friends
@@ -523,9 +904,9 @@ impl TempWorktree {
                 Err(e) => {
                     // This is totally normal, because the constructor creates this
                     // object before being certain the worktree was even created.
-                    debug!("Couldn't clean up worktree {:?}: {:?}", &self.temp_dir, e);
+                    debug!("Couldn't clean up worktree {:?}: {:?}", self.dir.path(), e);
                 }
-                Ok(_) => debug!("Delorted worktree at {:?}", self.temp_dir.path()),
+                Ok(_) => debug!("Delorted worktree at {:?}", self.dir.path()),
             }
         }
 
@@ -535,7 +916,25 @@ impl TempWorktree {
 
 impl Worktree for TempWorktree {
     fn path(&self) -> &Path {
-        self.temp_dir.path()
+        match &self.backend {
+            Backend::GitWorktree | Backend::Clone => self.dir.path(),
+            Backend::Overlay(overlay) => overlay.tree(),
+        }
+    }
+
+    async fn checkout(&self, commit: &CommitHash) -> anyhow::Result<()> {
+        // If this fails we don't really know what state the tree is in.
+        *self.last_checkout.lock() = None;
+        match &self.backend {
+            Backend::GitWorktree => checkout(self, commit).await?,
+            Backend::Overlay(overlay) => overlay.checkout(commit).await?,
+            Backend::Clone => {
+                self.fetch_if_missing(commit).await?;
+                checkout(self, commit).await?
+            }
+        }
+        *self.last_checkout.lock() = Some(commit.clone());
+        Ok(())
     }
 }
 
@@ -553,9 +952,9 @@ impl Drop for TempWorktree {
                 Err(e) => {
                     // This is totally normal, because the constructor creates this
                     // object before being certain the worktree was even created.
-                    debug!("Couldn't clean up worktree {:?}: {:?}", &self.temp_dir, e);
+                    debug!("Couldn't clean up worktree {:?}: {:?}", self.dir.path(), e);
                 }
-                Ok(_) => debug!("Delorted worktree at {:?}", self.temp_dir.path()),
+                Ok(_) => debug!("Delorted worktree at {:?}", self.dir.path()),
             }
         }
     }
@@ -627,11 +1026,12 @@ pub mod test_utils {
 
 #[cfg(test)]
 mod tests {
-    use std::fs::File;
+    use std::fs::{self, File};
     use std::io::Write;
 
     use tempfile::TempDir;
 
+    use super::test_utils::{TempRepo, WorktreeExt as _};
     use super::*;
 
     #[test_log::test(tokio::test)]
@@ -662,4 +1062,294 @@ mod tests {
             "opening repo with bogus .git file didn't fail"
         );
     }
+
+    #[test_log::test(tokio::test)]
+    async fn test_overlay_worktree_pristine() {
+        let repo = TempRepo::new().await.unwrap();
+        fs::write(repo.path().join("file.txt"), "hello\n").unwrap();
+        repo.git(["add", "file.txt"]).execute().await.unwrap();
+        let commit = repo.commit("1").await.unwrap();
+
+        let checkouts = Arc::new(CommitCheckouts::new(
+            repo.path(),
+            TempDir::with_prefix("checkouts-").unwrap(),
+        ));
+        let worktree =
+            TempWorktree::new_overlay(&repo, TempDir::with_prefix("worktree-").unwrap(), checkouts)
+                .unwrap();
+        worktree.checkout(&commit.hash).await.unwrap();
+        assert_eq!(
+            fs::read_to_string(worktree.path().join("file.txt")).unwrap(),
+            "hello\n"
+        );
+
+        // Mess it up, then check out again, it should be pristine.
+        fs::write(worktree.path().join("file.txt"), "goodbye\n").unwrap();
+        fs::write(worktree.path().join("junk.txt"), "junk\n").unwrap();
+        worktree.checkout(&commit.hash).await.unwrap();
+        assert_eq!(
+            fs::read_to_string(worktree.path().join("file.txt")).unwrap(),
+            "hello\n"
+        );
+        assert!(!worktree.path().join("junk.txt").exists());
+
+        worktree.cleanup().await;
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_overlay_worktree_bad_path() {
+        let repo = TempRepo::new().await.unwrap();
+        let commit = repo.commit("1").await.unwrap();
+
+        let checkouts = Arc::new(CommitCheckouts::new(
+            repo.path(),
+            TempDir::with_prefix("checkouts-").unwrap(),
+        ));
+        // The comma would end up in the overlay mount options.
+        let worktree =
+            TempWorktree::new_overlay(&repo, TempDir::with_prefix("worktree,").unwrap(), checkouts)
+                .unwrap();
+        let err = worktree.checkout(&commit.hash).await.unwrap_err();
+        assert!(format!("{err:#}").contains("contains ','"), "{err:#}");
+        worktree.cleanup().await;
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_overlay_checkouts_evicted() {
+        let repo = TempRepo::new().await.unwrap();
+        let mut commits = Vec::new();
+        for i in 0..3 {
+            fs::write(repo.path().join("file.txt"), format!("{i}\n")).unwrap();
+            repo.git(["add", "file.txt"]).execute().await.unwrap();
+            commits.push(repo.commit(&i.to_string()).await.unwrap());
+        }
+
+        let checkouts_dir = TempDir::with_prefix("checkouts-").unwrap();
+        let checkouts_path = checkouts_dir.path().to_owned();
+        let checkouts = Arc::new(CommitCheckouts::new(repo.path(), checkouts_dir).with_max_idle(1));
+        let worktree =
+            TempWorktree::new_overlay(&repo, TempDir::with_prefix("worktree-").unwrap(), checkouts)
+                .unwrap();
+        let is_extracted = |i: usize| {
+            checkouts_path
+                .join(commits[i].hash.as_ref() as &str)
+                .exists()
+        };
+        for (i, commit) in commits.iter().enumerate() {
+            worktree.checkout(&commit.hash).await.unwrap();
+            assert_eq!(
+                fs::read_to_string(worktree.path().join("file.txt")).unwrap(),
+                format!("{i}\n")
+            );
+        }
+        // The one in use and one idle one are kept.
+        assert!(!is_extracted(0));
+        assert!(is_extracted(1));
+        assert!(is_extracted(2));
+
+        // Evicted ones can come back.
+        worktree.checkout(&commits[0].hash).await.unwrap();
+        assert_eq!(
+            fs::read_to_string(worktree.path().join("file.txt")).unwrap(),
+            "0\n"
+        );
+
+        worktree.cleanup().await;
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_show_file() {
+        let repo = TempRepo::new().await.unwrap();
+        fs::write(repo.path().join("foo"), "committed").unwrap();
+        repo.git(["add", "foo"]).execute().await.unwrap();
+        let commit = repo.commit("add foo").await.unwrap();
+        // Should get the content from the commit, not the working tree.
+        fs::write(repo.path().join("foo"), "uncommitted").unwrap();
+        assert_eq!(
+            repo.show_file(&commit.hash, "foo").await.unwrap(),
+            Some(b"committed".to_vec())
+        );
+        assert_eq!(repo.show_file(&commit.hash, "bar").await.unwrap(), None);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_path_object() {
+        let repo = TempRepo::new().await.unwrap();
+        fs::create_dir_all(repo.path().join("dir/subdir")).unwrap();
+        fs::write(repo.path().join("dir/subdir/foo"), "foo").unwrap();
+        fs::write(repo.path().join("bar"), "bar").unwrap();
+        repo.git(["add", "."]).execute().await.unwrap();
+        let commit1 = repo.commit("1").await.unwrap();
+        fs::write(repo.path().join("bar"), "changed").unwrap();
+        repo.git(["add", "."]).execute().await.unwrap();
+        let commit2 = repo.commit("2").await.unwrap();
+
+        let object = |commit: Commit, path: &'static str| {
+            let repo = &repo;
+            async move { repo.path_object(&commit.hash, path).await.unwrap() }
+        };
+        let dir1 = object(commit1.clone(), "dir/subdir").await;
+        assert!(dir1.is_some());
+        // Only bar changed.
+        assert_eq!(dir1, object(commit2.clone(), "dir/subdir").await);
+        assert_ne!(
+            object(commit1.clone(), "bar").await,
+            object(commit2.clone(), "bar").await
+        );
+        assert_eq!(object(commit1, "nonexistent").await, None);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_is_ancestor() {
+        let repo = TempRepo::new().await.unwrap();
+        let base = repo.commit("base").await.unwrap();
+        let child = repo.commit("child").await.unwrap();
+        repo.checkout(&base.hash).await.unwrap();
+        let sibling = repo.commit("sibling").await.unwrap();
+
+        assert!(repo.is_ancestor(&base.hash, &child.hash).await.unwrap());
+        assert!(repo.is_ancestor(&child.hash, &child.hash).await.unwrap());
+        assert!(!repo.is_ancestor(&child.hash, &base.hash).await.unwrap());
+        assert!(!repo.is_ancestor(&child.hash, &sibling.hash).await.unwrap());
+        // Not a commit, e.g. from a different repo.
+        let bogus = CommitHash::new("0123456789abcdef0123456789abcdef01234567");
+        assert!(!repo.is_ancestor(&bogus, &child.hash).await.unwrap());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_root_commit() {
+        let repo = TempRepo::new().await.unwrap();
+        assert_eq!(repo.root_commit("HEAD").await.unwrap(), None);
+        let root = repo.commit("root").await.unwrap();
+        let child = repo.commit("child").await.unwrap();
+        assert_eq!(
+            repo.root_commit("HEAD").await.unwrap(),
+            Some(root.hash.clone())
+        );
+        // Merge in an unrelated history, whichever root has the lowest hash
+        // wins, wherever it is.
+        repo.git(["checkout", "--quiet", "--orphan", "other"])
+            .execute()
+            .await
+            .unwrap();
+        let other_root = repo.commit("other root").await.unwrap();
+        repo.git([
+            "merge",
+            "--quiet",
+            "--allow-unrelated-histories",
+            "-m",
+            "merge",
+        ])
+        .arg(&child.hash)
+        .execute()
+        .await
+        .unwrap();
+        let lowest = if root.hash.to_string() < other_root.hash.to_string() {
+            &root
+        } else {
+            &other_root
+        };
+        assert_eq!(
+            repo.root_commit("HEAD").await.unwrap(),
+            Some(lowest.hash.clone())
+        );
+        assert_eq!(
+            repo.root_commit(child.hash.as_ref()).await.unwrap(),
+            Some(root.hash.clone())
+        );
+        assert_eq!(repo.root_commit("nonexistent").await.unwrap(), None);
+        let ids = [
+            root.hash.to_string(),
+            child.tree.to_string(),
+            "0123456789abcdef0123456789abcdef01234567".to_owned(),
+            "my_test".to_owned(),
+        ];
+        assert_eq!(
+            repo.existing_objects(&ids).await.unwrap(),
+            HashSet::from([root.hash.to_string(), child.tree.to_string()])
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_clone_worktree() {
+        let repo = TempRepo::new().await.unwrap();
+        repo.commit("1").await.unwrap();
+
+        let worktree = TempWorktree::new_clone(
+            &CancellationToken::new(),
+            &repo,
+            TempDir::with_prefix("worktree-").unwrap(),
+        )
+        .await
+        .unwrap();
+        // The point of this mode is that there's a real .git dir.
+        assert!(worktree.path().join(".git").is_dir());
+
+        // Commits created after the clone should still be available.
+        fs::write(repo.path().join("file.txt"), "hello\n").unwrap();
+        repo.git(["add", "file.txt"]).execute().await.unwrap();
+        let commit = repo.commit("2").await.unwrap();
+        worktree.checkout(&commit.hash).await.unwrap();
+        assert_eq!(
+            fs::read_to_string(worktree.path().join("file.txt")).unwrap(),
+            "hello\n"
+        );
+
+        worktree.cleanup().await;
+    }
+
+    // Usually new commits are visible to the clone via alternates, here they
+    // aren't, so the commit has to be fetched.
+    #[test_log::test(tokio::test)]
+    async fn test_clone_worktree_fetch() {
+        let repo = TempRepo::new().await.unwrap();
+        repo.commit("1").await.unwrap();
+
+        let worktree = TempWorktree::new_clone(
+            &CancellationToken::new(),
+            &repo,
+            TempDir::with_prefix("worktree-").unwrap(),
+        )
+        .await
+        .unwrap();
+        // Point the clone at a snapshot of the origin's objects, so it can't
+        // see anything created after this.
+        let snapshot = TempDir::with_prefix("objects-").unwrap();
+        Command::new("cp")
+            .arg("-r")
+            .arg(repo.path().join(".git").join("objects"))
+            .arg(snapshot.path())
+            .execute()
+            .await
+            .unwrap();
+        fs::write(
+            worktree
+                .path()
+                .join(".git")
+                .join("objects")
+                .join("info")
+                .join("alternates"),
+            format!("{}\n", snapshot.path().join("objects").display()),
+        )
+        .unwrap();
+
+        fs::write(repo.path().join("file.txt"), "hello\n").unwrap();
+        repo.git(["add", "file.txt"]).execute().await.unwrap();
+        let commit = repo.commit("2").await.unwrap();
+        let exists = |worktree: &TempWorktree| {
+            worktree
+                .git(["cat-file", "-e"])
+                .arg(format!("{}^{{commit}}", commit.hash))
+                .output()
+        };
+        assert!(!exists(&worktree).await.unwrap().status.success());
+        worktree.checkout(&commit.hash).await.unwrap();
+        assert!(exists(&worktree).await.unwrap().status.success());
+        assert_eq!(
+            fs::read_to_string(worktree.path().join("file.txt")).unwrap(),
+            "hello\n"
+        );
+
+        worktree.cleanup().await;
+    }
 }
@@ -0,0 +1,334 @@
+// Tests can declare "inputs", files outside of the repo that affect their
+// results. The contents of those files are hashed into the test's config hash,
+// and in watch mode they are watched so that tests can be re-run when they
+// change.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    env, fs,
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context as _};
+use futures::{channel::mpsc, SinkExt as _, StreamExt as _};
+use glob::Pattern;
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+use notify::{Config, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use sha3::{Digest as _, Sha3_256};
+use tokio::{
+    select,
+    time::{sleep_until, Instant},
+};
+
+use crate::util::ResultExt as _;
+
+// Expand ~ and environment variables ($FOO or ${FOO}) in an input glob.
+// $LIMMAT_ORIGIN is the path of the main repo worktree.
+pub fn expand(pattern: &str, origin: &Path) -> anyhow::Result<String> {
+    let lookup = |name: &str| -> anyhow::Result<String> {
+        if name == "LIMMAT_ORIGIN" {
+            return Ok(origin.to_string_lossy().into_owned());
+        }
+        env::var(name).with_context(|| format!("expanding ${name} in input {pattern:?}"))
+    };
+
+    let mut expanded = String::new();
+    let mut rest = pattern;
+    if let Some(after) = rest.strip_prefix('~') {
+        if after.is_empty() || after.starts_with('/') {
+            let home = directories::BaseDirs::new()
+                .ok_or_else(|| anyhow!("couldn't find home directory"))?;
+            expanded.push_str(&home.home_dir().to_string_lossy());
+            rest = after;
+        }
+    }
+    while let Some(idx) = rest.find('$') {
+        expanded.push_str(&rest[..idx]);
+        rest = &rest[idx + 1..];
+        let name = if let Some(braced) = rest.strip_prefix('{') {
+            let end = braced
+                .find('}')
+                .ok_or_else(|| anyhow!("unterminated ${{ in input {pattern:?}"))?;
+            rest = &braced[end + 1..];
+            &braced[..end]
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            let name = &rest[..end];
+            rest = &rest[end..];
+            name
+        };
+        if name.is_empty() {
+            bail!("bad variable reference in input {pattern:?}");
+        }
+        expanded.push_str(&lookup(name)?);
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+// Make a relative glob relative to dir instead of the current directory.
+pub fn resolve(pattern: &str, dir: &Path) -> String {
+    if Path::new(pattern).is_absolute() {
+        return pattern.to_owned();
+    }
+    format!("{}/{pattern}", Pattern::escape(&dir.to_string_lossy()))
+}
+
+// Hash the contents of each file matching the (already expanded) globs,
+// returning a hex digest per path. It's fine for a glob not to match anything.
+pub fn input_digests(patterns: &[String]) -> anyhow::Result<BTreeMap<String, String>> {
+    let mut digests = BTreeMap::new();
+    for pattern in patterns {
+        let paths = glob::glob(pattern)
+            .with_context(|| format!("bad input glob {pattern:?}"))?
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("expanding input glob {pattern:?}"))?;
+        for path in paths {
+            // Directories aren't hashed, if you want their contents use a
+            // glob.
+            if path.is_dir() {
+                continue;
+            }
+            let content = fs::read(&path).with_context(|| format!("reading input {path:?}"))?;
+            digests.insert(
+                path.to_string_lossy().into_owned(),
+                format!("{:x}", Sha3_256::digest(&content)),
+            );
+        }
+    }
+    Ok(digests)
+}
+
+// The directory to watch to find out about changes to the files matching a
+// glob, and whether it needs to be watched recursively.
+fn watch_dir(pattern: &str) -> (PathBuf, RecursiveMode) {
+    let path = Path::new(pattern);
+    let mut dir = PathBuf::new();
+    let mut mode = RecursiveMode::NonRecursive;
+    let mut components = path.components().peekable();
+    while let Some(component) = components.next() {
+        let is_glob = matches!(component, Component::Normal(c)
+            if c.to_string_lossy().contains(['*', '?', '[']));
+        if is_glob {
+            // If the glob is in the last component, the files are directly in
+            // dir, otherwise they could be anywhere under it.
+            if components.peek().is_some() {
+                mode = RecursiveMode::Recursive;
+            }
+            break;
+        }
+        if components.peek().is_none() {
+            // No glob at all, it's just a file. Watch the directory it's in,
+            // in case it gets replaced instead of modified in place.
+            break;
+        }
+        dir.push(component);
+    }
+    // Happens for relative patterns without a directory, e.g. "*.config".
+    if dir.as_os_str().is_empty() {
+        dir.push(".");
+    }
+    (dir, mode)
+}
+
+// Watches the files matching a set of input globs.
+pub struct InputWatcher {
+    watcher: RecommendedWatcher,
+    rx: mpsc::UnboundedReceiver<notify::Result<Event>>,
+    patterns: Vec<Pattern>,
+    watched: HashSet<(PathBuf, bool)>,
+    // Set when we've seen a change but are waiting for things to settle down.
+    debounce_deadline: Option<Instant>,
+}
+
+impl InputWatcher {
+    pub fn new() -> anyhow::Result<Self> {
+        // Same approach as Worktree::watch_refs.
+        let (mut tx, rx) = mpsc::unbounded();
+        let watcher = RecommendedWatcher::new(
+            move |res| {
+                futures::executor::block_on(async {
+                    // Receiver dropped means we're shutting down.
+                    let _ = tx.send(res).await;
+                })
+            },
+            Config::default(),
+        )?;
+        Ok(Self {
+            watcher,
+            rx,
+            patterns: Vec::new(),
+            watched: HashSet::new(),
+            debounce_deadline: None,
+        })
+    }
+
+    // Set the (already expanded) globs to watch. Directories that don't exist
+    // are ignored, so if one is created later we won't notice files appearing
+    // in it.
+    pub fn set_patterns(&mut self, patterns: impl IntoIterator<Item = String>) {
+        let patterns: Vec<String> = patterns.into_iter().collect();
+        self.patterns = patterns
+            .iter()
+            .filter_map(|p| Pattern::new(p).ok())
+            .collect();
+        let want: HashSet<(PathBuf, bool)> = patterns
+            .iter()
+            .map(|p| {
+                let (dir, mode) = watch_dir(p);
+                (dir, mode == RecursiveMode::Recursive)
+            })
+            .collect();
+        for (dir, recursive) in self.watched.difference(&want) {
+            debug!("No longer watching inputs in {dir:?} (recursive: {recursive})");
+            self.watcher.unwatch(dir).or_log_error("unwatching inputs");
+        }
+        self.watched.retain(|w| want.contains(w));
+        for (dir, recursive) in want {
+            if self.watched.contains(&(dir.clone(), recursive)) {
+                continue;
+            }
+            debug!("Watching inputs in {dir:?} (recursive: {recursive})");
+            let mode = if recursive {
+                RecursiveMode::Recursive
+            } else {
+                RecursiveMode::NonRecursive
+            };
+            match self.watcher.watch(&dir, mode) {
+                Ok(()) => {
+                    self.watched.insert((dir, recursive));
+                }
+                Err(e) => warn!("Couldn't watch {dir:?} for input changes: {e}"),
+            }
+        }
+    }
+
+    fn is_relevant(&self, event: &notify::Result<Event>) -> bool {
+        match event {
+            Ok(event) => {
+                // We read the files ourselves when hashing them, so we'd
+                // loop forever if we noticed that.
+                !matches!(event.kind, EventKind::Access(_))
+                    && event
+                        .paths
+                        .iter()
+                        .any(|path| self.patterns.iter().any(|p| p.matches_path(path)))
+            }
+            Err(e) => {
+                warn!("Error watching inputs: {e}");
+                false
+            }
+        }
+    }
+
+    // Completes when any of the input files have changed. This waits for
+    // things to settle down for a second before returning, so that editing a
+    // bunch of files at once only produces one change. Cancel-safe.
+    pub async fn changed(&mut self) {
+        loop {
+            let event = match self.debounce_deadline {
+                None => self.rx.next().await,
+                Some(deadline) => select! {
+                    event = self.rx.next() => event,
+                    _ = sleep_until(deadline) => {
+                        self.debounce_deadline = None;
+                        return;
+                    }
+                },
+            };
+            let event = event.expect("input watcher channel closed");
+            if self.is_relevant(&event) {
+                self.debounce_deadline = Some(Instant::now() + Duration::from_secs(1));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use googletest::{expect_that, prelude::*};
+    use tempfile::TempDir;
+
+    use crate::test_utils::timeout_5s;
+
+    use super::*;
+
+    #[googletest::test]
+    fn test_expand() {
+        let origin = Path::new("/my/repo");
+        expect_that!(
+            expand("$LIMMAT_ORIGIN/../tools/run.sh", origin),
+            ok(eq("/my/repo/../tools/run.sh"))
+        );
+        expect_that!(
+            expand("${LIMMAT_ORIGIN}foo/*.c", origin),
+            ok(eq("/my/repofoo/*.c"))
+        );
+        expect_that!(expand("/etc/*.conf", origin), ok(eq("/etc/*.conf")));
+        expect_that!(expand("~foo", origin), ok(eq("~foo")));
+        expect_that!(
+            expand("$LIMMAT_DEFINITELY_NOT_SET_VAR/foo", origin),
+            err(anything())
+        );
+        expect_that!(expand("${LIMMAT_ORIGIN", origin), err(anything()));
+    }
+
+    #[googletest::test]
+    fn test_watch_dir() {
+        expect_that!(
+            &watch_dir("/a/b/*.config"),
+            eq(&(PathBuf::from("/a/b"), RecursiveMode::NonRecursive))
+        );
+        expect_that!(
+            &watch_dir("/a/**/*.config"),
+            eq(&(PathBuf::from("/a"), RecursiveMode::Recursive))
+        );
+        expect_that!(
+            &watch_dir("/a/b/run.sh"),
+            eq(&(PathBuf::from("/a/b"), RecursiveMode::NonRecursive))
+        );
+        expect_that!(
+            &watch_dir("run.sh"),
+            eq(&(PathBuf::from("."), RecursiveMode::NonRecursive))
+        );
+        expect_that!(
+            &watch_dir("*.config"),
+            eq(&(PathBuf::from("."), RecursiveMode::NonRecursive))
+        );
+    }
+
+    fn hash(patterns: &[String]) -> BTreeMap<String, String> {
+        input_digests(patterns).unwrap()
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_inputs_change() {
+        let dir = TempDir::new().unwrap();
+        let patterns = vec![format!("{}/*.config", dir.path().display())];
+        fs::write(dir.path().join("a.config"), "foo").unwrap();
+        fs::write(dir.path().join("unrelated"), "foo").unwrap();
+        let orig_hash = hash(&patterns);
+
+        let mut watcher = InputWatcher::new().unwrap();
+        watcher.set_patterns(patterns.clone());
+
+        // Files that don't match shouldn't count.
+        fs::write(dir.path().join("unrelated"), "bar").unwrap();
+        assert_eq!(hash(&patterns), orig_hash);
+        // Reading the file shouldn't count either.
+        hash(&patterns);
+        tokio::time::timeout(Duration::from_millis(1500), watcher.changed())
+            .await
+            .expect_err("noticed irrelevant change");
+
+        fs::write(dir.path().join("a.config"), "bar").unwrap();
+        timeout_5s(watcher.changed())
+            .await
+            .expect("didn't notice input change");
+        assert_ne!(hash(&patterns), orig_hash);
+    }
+}
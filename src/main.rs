@@ -1,51 +1,76 @@
 use anyhow::{anyhow, bail, Context};
-use clap::{arg, Parser as _, Subcommand, ValueEnum};
-use config::{Config, ParsedConfig};
+use clap::{Parser as _, Subcommand, ValueEnum};
+use config::ParsedConfig;
+use config_layers::ConfigLayers;
+use config_repo::{ConfigRepoRevs, ConfigRepos};
 use dag::{Dag, GraphNode as _};
-use database::{Database, DatabaseEntry, DatabaseOutput, LookupResult};
-use futures::future::join_all;
-use futures::StreamExt;
-use git::{Commit, PersistentWorktree, TempWorktree};
+use database::{
+    bundle::{self, Bundle, ImportOutcome},
+    remote::serve_cache,
+    Backend, Database, DatabaseEntry, DatabaseOutput, EntryQuery, Explanation, ForgetOutcome,
+    LookupResult, Outcome, Repair,
+};
+use futures::future::{join_all, try_join_all, BoxFuture};
+use futures::{FutureExt as _, StreamExt};
+use gc::GcPolicy;
+use git::{Commit, CommitHash, PersistentWorktree, TempWorktree, WorktreeMode};
 use http::Ui;
-use log::{debug, info};
+use inputs::InputWatcher;
+use log::{debug, error, info};
 use nix::sys::utsname::uname;
-use resource::Pools;
-use resource::{Resource, ResourceKey};
-use std::borrow::Borrow as _;
-use std::cmp::min;
-use std::collections::HashMap;
+use overlay::CommitCheckouts;
+use resource::{Pools, WorktreeFactory};
+use service::Services;
+use sha3::{Digest as _, Sha3_256};
+use std::borrow::{Borrow as _, Cow};
+use std::cmp::{min, Reverse};
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsString;
 use std::fmt::Display;
+use std::fs;
 use std::io::{stdout, Stdout};
-use std::path::{absolute, PathBuf};
+use std::path::{absolute, Path, PathBuf};
 use std::pin::pin;
 use std::process::Stdio;
 use std::sync::{Arc, Mutex};
-use std::{env, fmt, fs, str};
+use std::time::{Duration, SystemTime};
+use std::{env, fmt, str};
 use tempfile::TempDir;
 use test::{base_job_env, Manager, TestCase, TestCaseId, TestJob, TestJobBuilder, TestName};
-use test::{DepDatabaseEntries, Test};
+use test::{ConfigHash, DepDatabaseEntries, Test, TestDag};
 use tokio::select;
 use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::sleep;
 use tokio_util::sync::CancellationToken;
-use util::{DisplayablePathBuf, ErrGroup};
+use util::{
+    format_age, format_size, parse_duration, parse_size, DisplayablePathBuf, ErrGroup,
+    ResultExt as _,
+};
+use worktree_pool::PersistentPool;
 
 use crate::git::Worktree;
 use crate::terminal::TerminalSizeWatcher;
 
 mod config;
+mod config_layers;
+mod config_repo;
 mod dag;
 mod database;
 mod flock;
+mod gc;
 mod git;
 mod http;
+mod inputs;
+mod overlay;
 mod process;
 mod resource;
+mod service;
 mod terminal;
 mod test;
 mod text;
 mod ui;
 mod util;
+mod worktree_pool;
 
 #[cfg(test)]
 mod test_utils;
@@ -58,18 +83,39 @@ struct Args {
     #[arg(short, long, default_value_t = {".".to_string()}, global = true)]
     repo: String,
     /// Path to TOML config file. Default is $LIMMAT_CONFIG if non-empty,
-    /// or ./limmat.toml if it exists, or ./.limmat.toml if it exists
+    /// or ./limmat.toml if it exists, or ./.limmat.toml if it exists. If there's
+    /// a limmat.local.toml next to it, that overrides it.
     #[arg(short, long, global = true)]
     config: Option<PathBuf>,
+    /// Path to TOML config file for settings specific to this machine, which
+    /// the config file overrides. Default is $LIMMAT_HOST_CONFIG if set, or
+    /// config.toml in the user's config directory (e.g.
+    /// ~/.config/limmat/config.toml) if it exists.
+    #[arg(long, global = true)]
+    host_config: Option<PathBuf>,
     /// Directory where results will be stored.
     #[arg(long, default_value_t = default_result_db(), global = true)]
     result_db: DisplayablePathBuf,
+    /// How to find results in the result database. "sqlite" keeps an index of
+    /// the results, which makes "db list" and GC faster. If other Limmats use "directory" on the
+    /// same database, run "limmat db reindex" to pick up their results.
+    #[arg(long, value_enum, default_value_t = Backend::Directory, global = true)]
+    result_db_backend: Backend,
     /// Filename prefix for temporary worktrees.
     #[arg(long, default_value_t = {"limmat-worktree".to_string()}, global = true)]
     worktree_prefix: String,
-    /// Directory (must exist) to create temporary worktrees in.
-    #[arg(long, default_value_t = {env::temp_dir().to_string_lossy().into_owned()}, global = true)]
-    worktree_dir: String,
+    /// Directory (must exist) to create temporary worktrees in. Default is
+    /// worktree_dir from the config, or the system temporary directory.
+    #[arg(long, global = true)]
+    worktree_dir: Option<PathBuf>,
+    /// Keep worktrees around after exiting, and reuse them next time, instead
+    /// of creating temporary ones. They are stored under --state-dir. Only
+    /// supported with the default worktree_mode.
+    #[arg(long, default_value_t = false, global = true)]
+    persistent_worktrees: bool,
+    /// Directory for long-lived state such as persistent worktrees.
+    #[arg(long, default_value_t = default_state_dir(), global = true)]
+    state_dir: DisplayablePathBuf,
     #[command(subcommand)]
     command: Command,
 }
@@ -99,6 +145,16 @@ fn default_result_db() -> DisplayablePathBuf {
     )
 }
 
+fn default_state_dir() -> DisplayablePathBuf {
+    let dirs = directories::ProjectDirs::from("", "", "limmat").expect("couldn't find user dirs");
+    // There's no state dir on some platforms.
+    DisplayablePathBuf(
+        dirs.state_dir()
+            .map(|d| d.to_owned())
+            .unwrap_or_else(|| dirs.data_local_dir().join("state")),
+    )
+}
+
 fn default_hostname() -> String {
     uname()
         .expect("couldn't get nodename")
@@ -129,6 +185,53 @@ fn find_config(config_arg: &Option<PathBuf>) -> anyhow::Result<PathBuf> {
     bail!("Neither config nor $LIMMAT_CONFIG were set. No ./limmat.toml or ./.limmat.toml found");
 }
 
+// Where this repo's results go in the database, see Database::project_namespace
+// and Database::root_commit_namespace. The root commit is found from rev (for
+// watch, the base of the range being tested, otherwise HEAD), or None if the
+// repo doesn't have any commits yet. Finding it is slow for big repos so it's
+// cached in the state dir, under repo_id.
+async fn repo_namespace(
+    repo: &PersistentWorktree,
+    repo_id: &str,
+    state_dir: &Path,
+    project: Option<&str>,
+    rev: &str,
+) -> anyhow::Result<Option<PathBuf>> {
+    if let Some(project) = project {
+        return Ok(Some(Database::project_namespace(project)));
+    }
+    // The base might not exist yet, e.g. HEAD^ when there's only one commit.
+    let commit = match repo.rev_parse(rev).await? {
+        Some(commit) => commit,
+        None => match repo.rev_parse("HEAD").await? {
+            Some(commit) => commit,
+            None => return Ok(None),
+        },
+    };
+    let cache_path = state_dir.join("root_commits").join(repo_id);
+    // If the repo got replaced with a different one, or we're now testing an
+    // unrelated history, the cached commit won't be an ancestor. Otherwise it
+    // sticks, even if a root with a lower hash gets merged in later.
+    let cached = match fs::read_to_string(&cache_path) {
+        Ok(hash) => Some(CommitHash::new(hash)),
+        Err(_) => None,
+    };
+    let root_commit = match cached {
+        Some(root_commit) if repo.is_ancestor(&root_commit, &commit.hash).await? => root_commit,
+        _ => {
+            let root_commit = repo
+                .root_commit(commit.hash.as_ref())
+                .await?
+                .ok_or_else(|| anyhow!("no root commit found for {}", commit.hash))?;
+            fs::create_dir_all(cache_path.parent().unwrap())
+                .and_then(|()| fs::write(&cache_path, root_commit.to_string()))
+                .or_log_error("caching root commit");
+            root_commit
+        }
+    };
+    Ok(Some(Database::root_commit_namespace(&root_commit)))
+}
+
 #[derive(clap::Args, Debug)]
 struct TestArgs {
     /// Name of the test to run, per the "name" field in the config file.
@@ -154,6 +257,31 @@ struct GetArgs {
     /// Which output from the job do we want?
     #[arg(default_value_t = GetOutput::Stdout)]
     output: GetOutput,
+    /// For tests with multiple steps, which step's output to get. Defaults to
+    /// the step that failed, if there was one.
+    #[arg(long)]
+    step: Option<String>,
+    /// Instead of a path, print everything known about the result (exit code,
+    /// timing, host, resources etc) as JSON.
+    #[arg(long, default_value_t = false)]
+    json: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct ExplainArgs {
+    /// Name of the test, per the "name" field in the config file.
+    test: String,
+    /// Revision to explain. Any git revspec is fine.
+    rev: String,
+}
+
+#[derive(clap::Args, Debug)]
+struct ForgetArgs {
+    /// Name of the test, per the "name" field in the config file.
+    test: String,
+    /// Revisions to forget the result for. Any git revspec is fine.
+    #[arg(required = true)]
+    revs: Vec<String>,
 }
 
 #[derive(Clone, ValueEnum, Debug)]
@@ -175,6 +303,137 @@ impl Display for GetOutput {
     }
 }
 
+#[derive(Subcommand, Debug)]
+enum WorktreesCommand {
+    /// Delete the persistent worktrees for the repo (see
+    /// --persistent-worktrees). Worktrees in use by a running Limmat are left
+    /// alone.
+    Prune,
+}
+
+#[derive(clap::Args, Debug)]
+struct ConfigShowArgs {
+    /// Show which file each setting came from.
+    #[arg(long, default_value_t = false)]
+    origin: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Print the config that Limmat would use, after merging the host, repo
+    /// and local config files.
+    Show(ConfigShowArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct GcArgs {
+    /// Delete the oldest results until the database is at most this big, for
+    /// example "20G". Overrides gc.max_size from the config.
+    #[arg(long, value_parser = parse_size)]
+    max_size: Option<u64>,
+    /// Delete results older than this, for example "30d". Overrides
+    /// gc.max_age from the config.
+    #[arg(long, value_parser = parse_duration)]
+    max_age: Option<Duration>,
+    /// Only keep this many of the newest results for each test. Overrides
+    /// gc.keep_per_test from the config.
+    #[arg(long)]
+    keep_per_test: Option<usize>,
+    /// Never delete results for commits reachable from this revision. Can be
+    /// given multiple times, these are added to gc.keep_reachable_from from
+    /// the config.
+    #[arg(long)]
+    keep_reachable_from: Vec<String>,
+    /// Just print what would be deleted.
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct ListArgs {
+    /// Only list results for this test.
+    #[arg(long)]
+    test: Option<String>,
+    /// Only list results newer than this, for example "2d".
+    #[arg(long, value_parser = parse_duration)]
+    newer_than: Option<Duration>,
+    /// Only list results with this outcome. "error" means the test didn't
+    /// produce a result.
+    #[arg(long, value_enum)]
+    outcome: Option<Outcome>,
+    /// Only list results for commits in this range, for example
+    /// "origin/main..HEAD". Any range git rev-list understands is fine.
+    range: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct ExportArgs {
+    /// Export results for commits in this range, for example
+    /// "origin/main..HEAD".
+    range: String,
+    /// Only export results for these tests. By default, results for all tests
+    /// are exported.
+    #[arg(long, num_args = 1..)]
+    tests: Vec<String>,
+    /// Include the tests' artifacts. These can be large.
+    #[arg(long, default_value_t = false)]
+    artifacts: bool,
+    /// Path to write the bundle to.
+    #[arg(short, long)]
+    output: PathBuf,
+}
+
+#[derive(clap::Args, Debug)]
+struct ImportArgs {
+    /// Bundle written by "db export". Results are only imported for tests
+    /// whose config here matches the one that produced them.
+    bundle: PathBuf,
+}
+
+#[derive(clap::Args, Debug)]
+struct FsckArgs {
+    /// Move the broken entries into the "quarantine" directory in the result
+    /// database, so the tests get re-run.
+    #[arg(long, default_value_t = false)]
+    repair: bool,
+    /// With --repair, delete the broken entries instead of quarantining them.
+    #[arg(long, default_value_t = false, requires = "repair")]
+    delete: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct ServeCacheArgs {
+    /// Directory to store the results in. Created if it doesn't exist.
+    dir: PathBuf,
+    /// Address to listen on. There's no authentication, so think twice
+    /// before making this reachable from untrusted networks.
+    #[arg(long, default_value_t = {"127.0.0.1:8421".to_string()})]
+    listen: String,
+}
+
+#[derive(Subcommand, Debug)]
+enum DbCommand {
+    /// Delete old results according to the [gc] section of the config and the
+    /// options given here. Results that are in use by a running Limmat are
+    /// left alone.
+    Gc(GcArgs),
+    /// List the results stored for this repository, newest first.
+    List(ListArgs),
+    /// Rebuild the index used by --result-db-backend=sqlite from the
+    /// directory.
+    Reindex,
+    /// Write the results (and logs) for a range of commits into a tarball,
+    /// for importing into another result database.
+    Export(ExportArgs),
+    /// Import results from a bundle written by "db export".
+    Import(ImportArgs),
+    /// Check this repository's results for damage: corrupt or partial
+    /// entries, directories with no result, and (unless tests come from the
+    /// commits, see config_source) results from configs that no test has any
+    /// more. Entries that are in use are left alone.
+    Fsck(FsckArgs),
+}
+
 #[derive(Subcommand, Debug)]
 enum Command {
     /// The main command. Watch a repository and run tests whenever the revision
@@ -186,29 +445,152 @@ enum Command {
     Get(GetArgs),
     /// Get the path to the artifacts for a given test
     Artifacts(DatabaseLookupArgs),
+    /// Explain whether a test's result at a revision is cached, and if it was
+    /// invalidated, how the config changed.
+    Explain(ExplainArgs),
+    /// Delete a test's stored results so that it gets run again. If a watch
+    /// is running for the revisions, it re-runs the test straight away.
+    Forget(ForgetArgs),
+    /// Forget a test's stored results, then run it again (along with its
+    /// dependencies, if they don't have results).
+    Rerun(ForgetArgs),
+    /// Manage persistent worktrees.
+    #[command(subcommand)]
+    Worktrees(WorktreesCommand),
+    /// Inspect the configuration.
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Manage the result database.
+    #[command(subcommand)]
+    Db(DbCommand),
+    /// Run a server for the remote_cache config, storing results in a
+    /// directory.
+    ServeCache(ServeCacheArgs),
 }
 
 // Kitchen-sink object for global shit.
 struct Env {
     config: ParsedConfig,
+    // Kept around so the config can be reparsed, see watch_loop.
+    config_layers: ConfigLayers,
+    config_repos: Arc<ConfigRepos>,
     repo: Arc<git::PersistentWorktree>,
     database: Arc<Database>,
-    worktree_builder: WorktreeBuilder,
+    worktree_builder: Arc<WorktreeBuilder>,
+}
+
+// The tests that apply to a commit. That's just the ones in the config,
+// unless they come from a file in the commit being tested.
+async fn tests_for_commit<'a>(env: &'a Env, commit: &Commit) -> anyhow::Result<Cow<'a, TestDag>> {
+    Ok(match &env.config.commit_config {
+        Some(commit_config) => {
+            Cow::Owned(commit_config.load(env.repo.as_ref(), &commit.hash).await?)
+        }
+        None => Cow::Borrowed(&env.config.tests),
+    })
 }
 
 // Fallback instead of https://github.com/Stebalien/tempfile/pull/308
 struct WorktreeBuilder {
     prefix: OsString,
     parent_dir: PathBuf,
+    repo: Arc<PersistentWorktree>,
+    mode: WorktreeMode,
+    // Only set for WorktreeMode::Overlay.
+    checkouts: Option<Arc<CommitCheckouts>>,
+    // Only set if using persistent worktrees.
+    persistent_pool: Option<Arc<PersistentPool>>,
 }
 
 impl WorktreeBuilder {
+    fn new(
+        prefix: OsString,
+        parent_dir: PathBuf,
+        repo: Arc<PersistentWorktree>,
+        mode: WorktreeMode,
+        persistent_pool: Option<PersistentPool>,
+    ) -> anyhow::Result<Self> {
+        if persistent_pool.is_some() && mode != WorktreeMode::Worktree {
+            bail!("persistent worktrees are not supported with worktree_mode {mode:?}");
+        }
+        let mut zelf = Self {
+            prefix,
+            parent_dir,
+            repo,
+            mode,
+            checkouts: None,
+            persistent_pool: persistent_pool.map(Arc::new),
+        };
+        if mode == WorktreeMode::Overlay {
+            let mut prefix = zelf.prefix.clone();
+            prefix.push("checkouts-");
+            let dir = tempfile::Builder::new()
+                .prefix(&prefix)
+                .tempdir_in(&zelf.parent_dir)
+                .context("creating temp dir for commit checkouts")?;
+            zelf.checkouts = Some(Arc::new(CommitCheckouts::new(zelf.repo.path(), dir)));
+        }
+        Ok(zelf)
+    }
+
     pub fn build(&self) -> anyhow::Result<TempDir> {
         tempfile::Builder::new()
             .prefix(&self.prefix)
             .tempdir_in(&self.parent_dir)
             .context("creating temp dir for worktree")
     }
+
+    // Returns a future that creates a new worktree in the configured mode, for
+    // the named worktree pool. id is the worktree's stable ID within that
+    // pool, see Pools::set_worktree_factory.
+    pub fn create(
+        &self,
+        ct: CancellationToken,
+        worktree_pool: &str,
+        id: usize,
+    ) -> anyhow::Result<BoxFuture<'static, anyhow::Result<TempWorktree>>> {
+        let repo = self.repo.clone();
+        if let Some(pool) = &self.persistent_pool {
+            let pool = pool.clone();
+            let worktree_pool = worktree_pool.to_owned();
+            return Ok(
+                async move { pool.claim(&ct, repo.as_ref(), &worktree_pool, id).await }.boxed(),
+            );
+        }
+        let dir = self.build()?;
+        Ok(match self.mode {
+            WorktreeMode::Worktree => {
+                async move { TempWorktree::new(&ct, repo.as_ref(), dir).await }.boxed()
+            }
+            WorktreeMode::Overlay => {
+                let checkouts = self.checkouts.clone().unwrap();
+                async move { TempWorktree::new_overlay(repo.as_ref(), dir, checkouts) }.boxed()
+            }
+            WorktreeMode::Clone => {
+                async move { TempWorktree::new_clone(&ct, repo.as_ref(), dir).await }.boxed()
+            }
+        })
+    }
+
+    // For creating worktrees on demand in the resource pools.
+    fn factory(self: &Arc<Self>, ct: CancellationToken, worktree_pool: &str) -> WorktreeFactory {
+        let zelf = self.clone();
+        let worktree_pool = worktree_pool.to_owned();
+        Box::new(move |id| zelf.create(ct.child_token(), &worktree_pool, id))
+    }
+
+    // Set up on-demand worktree creation for each of the worktree pools, with
+    // the given maximum sizes.
+    fn set_factories(
+        self: &Arc<Self>,
+        ct: &CancellationToken,
+        pools: &Pools,
+        worktree_pools: &HashMap<String, usize>,
+    ) {
+        for (name, max) in worktree_pools {
+            pools.set_worktree_factory(name, *max, self.factory(ct.child_token(), name));
+        }
+    }
 }
 
 // This is the main loop of the program. Take notifications from the Git tree,
@@ -220,9 +602,16 @@ async fn watch_loop(
     mut status_tracker: ui::StatusTracker<PersistentWorktree, Stdout>,
     range_spec: OsString,
     repo: Arc<PersistentWorktree>,
+    config_repos: Arc<ConfigRepos>,
+    // Reparses the config, used when the tests' inputs or config repos change.
+    parse_config: impl Fn(&ConfigRepoRevs) -> anyhow::Result<ParsedConfig>,
 ) -> anyhow::Result<()> {
     let mut revs_stream = pin!(repo.watch_refs(&range_spec)?);
+    let mut config_repo_changes = pin!(config_repos.changes()?);
     let mut notifs = test_manager.results();
+    let mut expired = test_manager.expired();
+    let mut forget_watcher = test_manager.result_db().forget_watcher()?;
+    let mut input_watcher = InputWatcher::new()?;
 
     let size_watcher = TerminalSizeWatcher::new()?;
     let mut resizes = pin!(size_watcher.resizes());
@@ -238,6 +627,7 @@ async fn watch_loop(
                 // (mostly just kicks off background stuff) before awaiting the
                 // status tracker reset (does synchronhous work).
                 test_manager.set_revisions(revs.clone()).await.context("setting revisions to test")?;
+                input_watcher.set_patterns(test_manager.input_patterns());
                 status_tracker.set_range(&range_spec).await.context("resetting status tracker")?;
                 status_tracker.repaint(&size_watcher.size()).context("error painting status to stdout")?;
             },
@@ -248,9 +638,30 @@ async fn watch_loop(
                 status_tracker.update(notif);
                 status_tracker.repaint(&size_watcher.size()).context("error painting status to stdout")?;
             },
+            id = expired.recv() => {
+                let id = id.expect("expired result stream terminated");
+                info!("Result for {id:?} expired, re-running");
+                test_manager.rerun(&id).await.context("re-running expired test")?;
+            },
+            _ = forget_watcher.changed() => {
+                for id in status_tracker.take_forgotten() {
+                    info!("Result for {id:?} forgotten, re-running");
+                    test_manager.rerun(&id).await.context("re-running forgotten test")?;
+                }
+                status_tracker.repaint(&size_watcher.size()).context("error painting status to stdout")?;
+            },
             _ = resizes.next() => {
                 status_tracker.repaint(&size_watcher.size()).context("error painting status to stdout")?;
             },
+            _ = input_watcher.changed() => {
+                info!("Test inputs changed, reloading tests");
+                reload_tests(&test_manager, &config_repos, &mut input_watcher, &parse_config).await?;
+            },
+            change = config_repo_changes.next() => {
+                change.expect("config repo change stream terminated").context("watching config repos")?;
+                info!("Config repos changed, reloading tests");
+                reload_tests(&test_manager, &config_repos, &mut input_watcher, &parse_config).await?;
+            },
             _ =  cancellation_token.cancelled() => {
                 info!("Got shutdown signal, terminating jobs and waiting");
                 test_manager.cancel_running().await.context("cancelling tests")?;
@@ -263,6 +674,34 @@ async fn watch_loop(
     Ok(())
 }
 
+// Reparse the tests. Tests whose inputs or config repos changed will have a
+// new config hash, so they get re-run. Only fails if the tests couldn't be
+// updated, if the config can't be parsed we just carry on with the old one.
+async fn reload_tests(
+    test_manager: &test::Manager<PersistentWorktree>,
+    config_repos: &ConfigRepos,
+    input_watcher: &mut InputWatcher,
+    parse_config: impl Fn(&ConfigRepoRevs) -> anyhow::Result<ParsedConfig>,
+) -> anyhow::Result<()> {
+    let config = match config_repos
+        .resolve()
+        .await
+        .and_then(|revs| parse_config(&revs))
+    {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Couldn't reload tests: {e:#}");
+            return Ok(());
+        }
+    };
+    test_manager
+        .set_tests(config.tests, config.commit_config)
+        .await
+        .context("setting tests")?;
+    input_watcher.set_patterns(test_manager.input_patterns());
+    Ok(())
+}
+
 async fn watch(
     env: Env,
     cancellation_token: CancellationToken,
@@ -293,70 +732,144 @@ async fn watch(
     let ui_state = ui.state();
     eg.spawn(ui.serve(cancellation_token.child_token()));
 
+    if let Some(interval) = env.config.gc_interval {
+        eg.spawn(collect_garbage_periodically(
+            cancellation_token.child_token(),
+            env.database.clone(),
+            env.repo.clone(),
+            env.config.gc_policy.clone(),
+            interval,
+        ));
+    }
+
     // Set up the test manager, which is the weirdly-scoped god-object that
     // orchestrates test jobs.
-    let test_manager = Arc::new(Manager::new(
-        env.repo.clone(),
-        env.database,
-        env.config.resource_pools.clone(),
-        env.config.tests,
-    ));
+    let test_manager = Arc::new(
+        Manager::new(
+            env.repo.clone(),
+            env.database.clone(),
+            env.config.resource_pools.clone(),
+            env.config.tests,
+        )
+        .with_services(env.config.services.clone())
+        .with_config_repos(env.config_repos.clone())
+        .with_commit_config(env.config.commit_config.clone()),
+    );
 
     // Set up the status tracker, which shows the user what's going on in the terminal.
     let status_tracker = ui::StatusTracker::new(
         env.repo.clone(),
         stdout(),
         ui_state,
+        env.database.clone(),
         result_url_base,
         home_url,
     );
 
-    // Kick off creation of the worktrees that the test manager will run jobs in.
+    // Worktrees will be created on demand when jobs need them.
     //
     // Once we've done this, we can no longer return from this function until
     // we've also cleaned the worktrees up. This is stinky and gross. AFAICT
     // async Rust just doesn't have a solution for that at all.
-    //
-    // TODO: This doesn't work if there are no commits in the repository. Not sure I care about
-    // this, but the solution would be to create the worktrees ondemand, when we have a revision we
-    // are actually trying to test. That might be a good idea anyway, so probably it's preferable to
-    // just do that for its own sake and leave the empty-repo problem as a nice freebie.
-    for _ in 0..env.config.num_worktrees {
-        let repo = env.repo.clone();
-        let ct = cancellation_token.child_token();
-        let resource_pools = env.config.resource_pools.clone();
-        let dir = env.worktree_builder.build()?;
-        eg.spawn(async move {
-            let worktree = TempWorktree::new::<PersistentWorktree>(&ct, repo.as_ref(), dir).await?;
-            resource_pools.add([(ResourceKey::Worktree, Resource::Worktree(worktree))]);
-            Ok(())
-        });
+    env.worktree_builder.set_factories(
+        &cancellation_token,
+        &env.config.resource_pools,
+        &env.config.worktree_pools,
+    );
+    if let Some(idle_timeout) = env.config.worktree_idle_timeout {
+        eg.spawn(reap_idle_worktrees(
+            cancellation_token.child_token(),
+            env.config.resource_pools.clone(),
+            env.config.services.clone(),
+            idle_timeout,
+        ));
     }
 
     // DO THE THING.
+    let config_layers = env.config_layers;
+    let origin = absolute(env.repo.path()).context("getting absolute path of repo")?;
     eg.spawn(watch_loop(
         cancellation_token.child_token(),
         test_manager.clone(),
         status_tracker,
         format!("{}..HEAD", watch_args.base).into(),
         env.repo,
+        env.config_repos,
+        move |revs| ParsedConfig::from(config_layers.config()?, &origin, revs),
     ));
 
     let end_result = eg.wait().await;
 
     // Now we have to remember to clean up before returning the result :/
-    join_all(
-        Arc::into_inner(test_manager)
-            .expect("leaked test manager reference")
-            .into_resource_pools()
-            .try_remove_worktrees()
-            .map(|w| w.cleanup()),
-    )
-    .await;
+    let worktrees = Arc::into_inner(test_manager)
+        .expect("leaked test manager reference")
+        .into_resource_pools()
+        .remove_worktrees()
+        .await;
+    cleanup_worktrees(&env.config.services, worktrees).await;
 
     end_result
 }
 
+// Shut down any per-worktree services using the worktrees, then delete them.
+async fn cleanup_worktrees(services: &Services, worktrees: Vec<TempWorktree>) {
+    join_all(worktrees.into_iter().map(|w| async move {
+        services.stop_worktree(w.path()).await;
+        w.cleanup().await
+    }))
+    .await;
+}
+
+// Periodically clean up worktrees that haven't been used for a while.
+async fn reap_idle_worktrees(
+    ct: CancellationToken,
+    resource_pools: Arc<Pools>,
+    services: Arc<Services>,
+    idle_timeout: Duration,
+) -> anyhow::Result<()> {
+    let period = idle_timeout.max(Duration::from_secs(2)) / 2;
+    loop {
+        select! {
+            _ = ct.cancelled() => return Ok(()),
+            _ = sleep(period) => {
+                let reaped = resource_pools.reap_idle_worktrees(idle_timeout);
+                if !reaped.is_empty() {
+                    info!("Cleaning up {} idle worktrees", reaped.len());
+                }
+                cleanup_worktrees(&services, reaped).await;
+            }
+        }
+    }
+}
+
+// Collect garbage from the result database now, then every interval. Results
+// that are being used (including the ones currently being displayed) are never
+// deleted.
+async fn collect_garbage_periodically(
+    ct: CancellationToken,
+    database: Arc<Database>,
+    repo: Arc<PersistentWorktree>,
+    policy: GcPolicy,
+    interval: Duration,
+) -> anyhow::Result<()> {
+    loop {
+        match gc::collect_garbage(&database, repo.as_ref(), &policy, false).await {
+            Ok(report) if !report.deleted.is_empty() => info!(
+                "Deleted {} old results ({})",
+                report.deleted.len(),
+                format_size(report.freed())
+            ),
+            Ok(_) => (),
+            // Not worth taking the whole thing down for.
+            Err(e) => error!("Collecting garbage from result database: {e:#}"),
+        }
+        select! {
+            _ = ct.cancelled() => return Ok(()),
+            _ = sleep(interval) => (),
+        }
+    }
+}
+
 async fn ensure_job_success(
     database: Arc<Database>,
     resource_pools: Arc<Pools>,
@@ -386,10 +899,15 @@ async fn ensure_tests_run(
     rev: &Commit,
 ) -> anyhow::Result<DepDatabaseEntries> {
     let tests = tests.into_iter();
-    let num_worktrees = min(
-        env.config.num_worktrees,
-        tests.clone().filter(|t| t.needs_worktree()).count(),
-    );
+    let worktree_pools: HashMap<String, usize> = env
+        .config
+        .worktree_pools
+        .iter()
+        .map(|(name, max)| {
+            let num_tests = tests.clone().filter(|t| t.uses_worktree_pool(name)).count();
+            (name.clone(), min(*max, num_tests))
+        })
+        .collect();
 
     let job_env = Arc::new(base_job_env(env.repo.path()));
 
@@ -402,52 +920,49 @@ async fn ensure_tests_run(
     //    validation
     // 2. We could build the subset graph in place, i.e. totally skip making a
     //    new graph and instead just logicall remove the nodes we don't need.
-    let jobs = Dag::new(
+    let test_cases = try_join_all(
         // TODO: Would be nice to have an _into thing so we can avoid this clone.
-        tests.map(|t| TestCase::new(rev.clone(), t.clone())),
+        tests.map(|t| TestCase::new(env.repo.as_ref(), rev.clone(), t.clone())),
     )
-    .context("setting up dependency test graph")?
-    .bottom_up()
-    .try_fold(
-        HashMap::new(),
-        |mut jobs, test_case| -> anyhow::Result<HashMap<TestCaseId, TestJob>> {
-            let wait_for = test_case
-                .child_ids() // This gives the TestCaseIds of dependency jobs.
-                .iter()
-                .map(|tc_id| {
-                    let dep_job = &jobs[tc_id.borrow()];
-                    (dep_job.test_name().clone(), dep_job.subscribe_completion())
-                })
-                .collect();
-            let job = TestJobBuilder::new(
-                cancellation_token.clone(),
-                // TODO: it would be nice if we had an into_ variant of
-                // the bottom_up so we didn't need this clone.
-                test_case.clone(),
-                job_env.clone(),
-                wait_for,
-            )
-            .build();
-            jobs.insert(test_case.id().borrow().to_owned(), job);
-            Ok(jobs)
-        },
-    )?;
+    .await?;
+    let jobs = Dag::new(test_cases)
+        .context("setting up dependency test graph")?
+        .bottom_up()
+        .try_fold(
+            HashMap::new(),
+            |mut jobs, test_case| -> anyhow::Result<HashMap<TestCaseId, TestJob>> {
+                let wait_for = test_case
+                    .child_ids() // This gives the TestCaseIds of dependency jobs.
+                    .iter()
+                    .map(|tc_id| {
+                        let dep_job = &jobs[tc_id.borrow()];
+                        (dep_job.test_name().clone(), dep_job.subscribe_completion())
+                    })
+                    .collect();
+                let job = TestJobBuilder::new(
+                    cancellation_token.clone(),
+                    // TODO: it would be nice if we had an into_ variant of
+                    // the bottom_up so we didn't need this clone.
+                    test_case.clone(),
+                    job_env.clone(),
+                    wait_for,
+                )
+                .with_services(Some(env.config.services.clone()))
+                .with_config_repos(Some(env.config_repos.clone()))
+                .build();
+                jobs.insert(test_case.id().borrow().to_owned(), job);
+                Ok(jobs)
+            },
+        )?;
+
+    // Worktrees for the dep jobs will be created on demand.
+    env.worktree_builder.set_factories(
+        &cancellation_token,
+        &env.config.resource_pools,
+        &worktree_pools,
+    );
 
-    // Kick off creation of the worktrees that the dep jobs will run in.
-    // This is horribly copy-pasted from watch. I dunno, I can't figure out how
-    // to fix that without insanely complex async stuff.
     let mut eg = ErrGroup::new(cancellation_token.clone());
-    for _ in 0..num_worktrees {
-        let repo = env.repo.clone();
-        let ct = cancellation_token.child_token();
-        let resource_pools = env.config.resource_pools.clone();
-        let dir = env.worktree_builder.build()?;
-        eg.spawn(async move {
-            let worktree = TempWorktree::new::<PersistentWorktree>(&ct, repo.as_ref(), dir).await?;
-            resource_pools.add([(ResourceKey::Worktree, Resource::Worktree(worktree))]);
-            Ok(())
-        });
-    }
 
     let dep_db_entries = Arc::new(Mutex::new(HashMap::new()));
     for (_, job) in jobs {
@@ -466,11 +981,9 @@ async fn ensure_tests_run(
     let result = eg.wait().await;
 
     // Now we have to remember to clean up before returning the result :/
-    join_all(
-        env.config
-            .resource_pools
-            .try_remove_worktrees()
-            .map(|w| w.cleanup()),
+    cleanup_worktrees(
+        &env.config.services,
+        env.config.resource_pools.remove_worktrees().await,
     )
     .await;
 
@@ -514,26 +1027,30 @@ async fn test(
     }
 
     let test = env.config.tests.node(&test_name).unwrap();
-    let test_case = TestCase::new(head.clone(), test.clone());
-    let mut needs_resources = test_case.test.needs_resources.clone();
+    let test_case = TestCase::new(env.repo.as_ref(), head.clone(), test.clone()).await?;
     let job = TestJobBuilder::new(
         cancellation_token.clone(),
         test_case,
         Arc::new(base_job_env(env.repo.path())),
         Vec::new(), // wait_for
     )
+    .with_services(Some(env.config.services.clone()))
+    .with_config_repos(Some(env.config_repos.clone()))
     .build();
-    // Doesn't need a worktree, it's gonna do it live and direct in the main tree.
-    needs_resources.remove(&ResourceKey::Worktree);
-    let resources = env.config.resource_pools.get(needs_resources).await;
     let output_dir = TempDir::with_prefix("limmat-output-")?.into_path();
     eprintln!(
         "Test artifacts will be stored under {}",
         output_dir.display()
     );
-    let output = DatabaseOutput::ephemeral(output_dir, Stdio::inherit(), Stdio::inherit()).await?;
+    let output = DatabaseOutput::ephemeral(output_dir, Stdio::inherit).await?;
+    // Doesn't need a worktree, it's gonna do it live and direct in the main tree.
     let db_entry = job
-        .run_with(env.repo.path(), &resources, output, dep_db_entries)
+        .run_with(
+            env.repo.path(),
+            &env.config.resource_pools,
+            output,
+            dep_db_entries,
+        )
         .await?;
     eprintln!("Finished: {}", db_entry.result());
     Ok(())
@@ -551,11 +1068,10 @@ async fn lookup(
         .await
         .context("error looking up commit")?
         .ok_or_else(|| anyhow!("revision {:?} not found", lookup_args.test))?;
+    let tests = tests_for_commit(&env, &rev).await?;
 
     if lookup_args.run {
-        let tests: Vec<&Arc<Test>> = env
-            .config
-            .tests
+        let tests: Vec<&Arc<Test>> = tests
             .top_down_from(&test_name)
             .ok_or(anyhow!("no such test {:?}", test_name.to_string()))?
             .collect();
@@ -566,14 +1082,12 @@ async fn lookup(
         eprintln!("Tests complete");
     }
 
-    let test = env
-        .config
-        .tests
+    let test = tests
         .node(&test_name)
         .ok_or(anyhow!("no such test {:?}", test_name.to_string()))?;
     match env
         .database
-        .lookup(&TestCase::new(rev.clone(), test.clone()))
+        .lookup(&TestCase::new(env.repo.as_ref(), rev.clone(), test.clone()).await?)
         .await
         .context("database lookup")?
     {
@@ -593,9 +1107,17 @@ async fn get(
     get_args: GetArgs,
 ) -> anyhow::Result<()> {
     let db_entry = lookup(env, cancellation_token, &get_args.lookup_args).await?;
+    if get_args.json {
+        println!("{:#}", db_entry.to_json());
+        return Ok(());
+    }
+    let step = get_args
+        .step
+        .as_deref()
+        .or(db_entry.result().failed_step.as_deref());
     match get_args.output {
-        GetOutput::Stdout => println!("{}", db_entry.stdout_path().display()),
-        GetOutput::Stderr => println!("{}", db_entry.stderr_path().display()),
+        GetOutput::Stdout => println!("{}", db_entry.stdout_path(step).display()),
+        GetOutput::Stderr => println!("{}", db_entry.stderr_path(step).display()),
     }
     Ok(())
 }
@@ -610,6 +1132,302 @@ async fn artifacts(
     Ok(())
 }
 
+async fn explain(env: Env, explain_args: ExplainArgs) -> anyhow::Result<()> {
+    let test_name = TestName::new(explain_args.test.clone());
+    let rev = env
+        .repo
+        .rev_parse(&explain_args.rev)
+        .await
+        .context("error looking up commit")?
+        .ok_or_else(|| anyhow!("revision {:?} not found", explain_args.rev))?;
+    let tests = tests_for_commit(&env, &rev).await?;
+    let test = tests
+        .node(&test_name)
+        .ok_or(anyhow!("no such test {:?}", test_name.to_string()))?;
+    let test_case = TestCase::new(env.repo.as_ref(), rev.clone(), test.clone()).await?;
+    match env.database.explain(&test_case)? {
+        Explanation::CacheDisabled => println!("cache disabled for test {test_name}"),
+        Explanation::NoResult => println!("no result for {test_name} at {}", rev.hash),
+        Explanation::Error { message } => {
+            println!(
+                "result was an error: {test_name} at {} was run but didn't produce a result",
+                rev.hash
+            );
+            if let Some(message) = message {
+                println!("{message}");
+            }
+        }
+        Explanation::Cached(result) => println!("result is cached ({result})"),
+        Explanation::Forgotten => {
+            println!("result was forgotten, it's being kept until whoever's using it lets go")
+        }
+        Explanation::Running => println!("{test_name} is running at {}", rev.hash),
+        Explanation::Expired { age: Some(age) } => {
+            println!("result expired, it's {} old", format_age(age))
+        }
+        Explanation::Expired { age: None } => {
+            println!("result expired, it was stored without a timestamp")
+        }
+        Explanation::ConfigChanged { stored: None } => println!(
+            "config changed, but the stored result predates storing configs so there's no diff"
+        ),
+        Explanation::ConfigChanged {
+            stored: Some(stored),
+        } => {
+            // Pretty-printing puts each field on its own line so the diff is
+            // readable.
+            let stored = serde_json::to_string_pretty(&stored).unwrap() + "\n";
+            let current =
+                serde_json::to_string_pretty(&test_case.test.canonical_config).unwrap() + "\n";
+            println!("config changed:");
+            print!(
+                "{}",
+                similar::TextDiff::from_lines(&stored, &current)
+                    .unified_diff()
+                    .header("stored", "current")
+            );
+        }
+    }
+    Ok(())
+}
+
+fn forget_test_case(env: &Env, test_case: &TestCase) -> anyhow::Result<()> {
+    let name = &test_case.test.name;
+    let hash = &test_case.commit_hash;
+    match env.database.forget(test_case)? {
+        ForgetOutcome::NotFound => println!("no result for {name} at {hash}"),
+        ForgetOutcome::Deleted => println!("forgot result for {name} at {hash}"),
+        ForgetOutcome::Marked => println!(
+            "forgot result for {name} at {hash} (it's in use, it'll be replaced when the test is re-run)"
+        ),
+    }
+    Ok(())
+}
+
+// Implements both forget and rerun.
+async fn forget(
+    env: Env,
+    cancellation_token: CancellationToken,
+    forget_args: ForgetArgs,
+    rerun: bool,
+) -> anyhow::Result<()> {
+    let test_name = TestName::new(forget_args.test);
+    for rev in &forget_args.revs {
+        let rev = env
+            .repo
+            .rev_parse(rev)
+            .await
+            .context("error looking up commit")?
+            .ok_or_else(|| anyhow!("revision {rev:?} not found"))?;
+        let tests = tests_for_commit(&env, &rev).await?;
+        let test = tests
+            .node(&test_name)
+            .ok_or(anyhow!("no such test {:?}", test_name.to_string()))?;
+        let test_case = TestCase::new(env.repo.as_ref(), rev.clone(), test.clone()).await?;
+        forget_test_case(&env, &test_case)?;
+        if !rerun {
+            continue;
+        }
+
+        // If a watch is testing this revision it will also be re-running the
+        // test now. Whichever of us gets there second just waits for the
+        // other's result.
+        let tests: Vec<&Arc<Test>> = tests.top_down_from(&test_name).unwrap().collect();
+        eprintln!("Running {} tests...", tests.len());
+        let db_entries =
+            ensure_tests_run(&env, cancellation_token.child_token(), tests, &rev).await?;
+        println!(
+            "{test_name} at {}: {}",
+            rev.hash,
+            db_entries[&test_name].result()
+        );
+    }
+    Ok(())
+}
+
+async fn db_gc(env: Env, gc_args: GcArgs) -> anyhow::Result<()> {
+    let mut policy = env.config.gc_policy.clone();
+    policy.max_size = gc_args.max_size.or(policy.max_size);
+    policy.max_age = gc_args.max_age.or(policy.max_age);
+    policy.keep_per_test = gc_args.keep_per_test.or(policy.keep_per_test);
+    policy
+        .keep_reachable_from
+        .extend(gc_args.keep_reachable_from);
+    if policy.is_empty() {
+        bail!("nothing to collect, set max_size, max_age or keep_per_test in the [gc] section of the config, or pass them as options");
+    }
+    let report =
+        gc::collect_garbage(&env.database, env.repo.as_ref(), &policy, gc_args.dry_run).await?;
+    if gc_args.dry_run {
+        for entry in &report.deleted {
+            println!("would delete {}", entry.path.display());
+        }
+    }
+    println!(
+        "{} {} results ({}), database is now {}",
+        if gc_args.dry_run {
+            "would delete"
+        } else {
+            "deleted"
+        },
+        report.deleted.len(),
+        format_size(report.freed()),
+        format_size(report.remaining_size),
+    );
+    if report.in_use != 0 {
+        println!(
+            "{} results were in use so they weren't deleted",
+            report.in_use
+        );
+    }
+    Ok(())
+}
+
+async fn db_list(env: Env, list_args: ListArgs) -> anyhow::Result<()> {
+    let commits = match &list_args.range {
+        Some(range) => Some(
+            env.repo
+                .rev_list(range)
+                .await
+                .with_context(|| format!("listing commits in {range:?}"))?
+                .into_iter()
+                .collect(),
+        ),
+        None => None,
+    };
+    let query = EntryQuery {
+        test: list_args.test,
+        commits,
+        since: list_args
+            .newer_than
+            .and_then(|d| SystemTime::now().checked_sub(d)),
+        outcome: list_args.outcome,
+    };
+    let mut entries = env.database.entries(&query)?;
+    entries.sort_by_key(|e| Reverse(e.finished_at));
+    for entry in entries {
+        let outcome = match entry.exit_code {
+            Some(0) => "success".to_owned(),
+            Some(code) => format!("failed (status {code})"),
+            None => "no result".to_owned(),
+        };
+        println!(
+            "{} {} {} ({} old) {}",
+            entry
+                .commit
+                .as_ref()
+                .map_or("?".to_owned(), |c| c.to_string()),
+            entry.test_name,
+            outcome,
+            format_age(entry.age()),
+            entry.path.display()
+        );
+    }
+    Ok(())
+}
+
+async fn db_export(env: Env, export_args: ExportArgs) -> anyhow::Result<()> {
+    let range = &export_args.range;
+    let commits = env
+        .repo
+        .rev_list(range)
+        .await
+        .with_context(|| format!("listing commits in {range:?}"))?;
+    let mut test_cases = Vec::new();
+    for hash in commits {
+        let commit = env
+            .repo
+            .rev_parse(hash.to_string())
+            .await?
+            .ok_or_else(|| anyhow!("commit {hash} disappeared"))?;
+        let tests = tests_for_commit(&env, &commit).await?;
+        for test in tests.nodes() {
+            if !export_args.tests.is_empty()
+                && !export_args
+                    .tests
+                    .iter()
+                    .any(|t| *t == test.name.to_string())
+            {
+                continue;
+            }
+            test_cases.push(TestCase::new(env.repo.as_ref(), commit.clone(), test.clone()).await?);
+        }
+    }
+    let count = bundle::export(
+        &env.database,
+        &test_cases,
+        export_args.artifacts,
+        &export_args.output,
+    )
+    .await?;
+    eprintln!(
+        "Exported {count} results to {}",
+        export_args.output.display()
+    );
+    Ok(())
+}
+
+async fn db_import(env: Env, import_args: ImportArgs) -> anyhow::Result<()> {
+    let bundle = Bundle::open(&import_args.bundle)?;
+    let mut imported = 0;
+    for entry in bundle.entries() {
+        let skip =
+            |reason: &str| eprintln!("Skipping {} at {}: {reason}", entry.test, entry.commit);
+        let Some(commit) = env.repo.rev_parse(&entry.commit).await? else {
+            skip("commit not found");
+            continue;
+        };
+        let tests = tests_for_commit(&env, &commit).await?;
+        let Some(test) = tests.node(&TestName::new(entry.test.clone())) else {
+            skip("no such test");
+            continue;
+        };
+        let test_case = TestCase::new(env.repo.as_ref(), commit, test.clone()).await?;
+        match bundle.import(&env.database, entry, &test_case).await? {
+            ImportOutcome::Imported => imported += 1,
+            ImportOutcome::AlreadyPresent => (),
+            ImportOutcome::Skipped(reason) => skip(&reason),
+        }
+    }
+    eprintln!("Imported {imported} of {} results", bundle.entries().len());
+    Ok(())
+}
+
+fn db_fsck(env: Env, fsck_args: FsckArgs) -> anyhow::Result<()> {
+    // In ConfigSource::Commit mode, we don't know every config that's in use.
+    let known_configs = env.config.commit_config.is_none().then(|| {
+        let mut configs: HashMap<String, HashSet<ConfigHash>> = HashMap::new();
+        for test in env.config.tests.nodes() {
+            configs
+                .entry(test.name.to_string())
+                .or_default()
+                .insert(test.config_hash.clone());
+        }
+        configs
+    });
+    let repair = match (fsck_args.repair, fsck_args.delete) {
+        (false, _) => None,
+        (true, false) => Some(Repair::Quarantine),
+        (true, true) => Some(Repair::Delete),
+    };
+    let issues = env.database.fsck(known_configs.as_ref(), repair)?;
+    for issue in &issues {
+        let action = match repair {
+            None => "",
+            Some(Repair::Quarantine) => " (quarantined)",
+            Some(Repair::Delete) => " (deleted)",
+        };
+        println!("{}: {}{action}", issue.path.display(), issue.problem);
+    }
+    if repair.is_none() && !issues.is_empty() {
+        bail!(
+            "found {} broken entries, run with --repair to fix",
+            issues.len()
+        );
+    }
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     env_logger::init();
@@ -630,34 +1448,143 @@ async fn main() -> anyhow::Result<()> {
 
     let args = Args::parse();
     debug!("args: {:?}", &args);
-    let config_content =
-        fs::read_to_string(&find_config(&args.config)?).context("couldn't read config")?;
-    debug!("config:\n{}", &config_content);
-    let config: Config = toml::from_str(&config_content).context("couldn't parse config")?;
-    let config = ParsedConfig::from(config)?;
+
+    // This one doesn't need a repo.
+    if let Command::ServeCache(serve_args) = args.command {
+        let listener = tokio::net::TcpListener::bind(&serve_args.listen)
+            .await
+            .context("setting up HTTP server")?;
+        eprintln!(
+            "Serving result cache from {} at http://{}",
+            serve_args.dir.display(),
+            listener.local_addr().context("getting local socket addr")?
+        );
+        return serve_cache(serve_args.dir, listener, cancellation_token).await;
+    }
 
     let repo = git::PersistentWorktree {
         path: args.repo.to_owned().into(),
     };
     // Check repo is valid.
-    repo.git_common_dir()
+    let git_common_dir = repo
+        .git_common_dir()
         .await
         .context(format!("opening repo {}", args.repo))?;
 
+    // This one doesn't need a config.
+    if let Command::Worktrees(WorktreesCommand::Prune) = args.command {
+        return PersistentPool::new(&args.state_dir, &git_common_dir)?
+            .prune(&repo)
+            .await;
+    }
+
+    let config_layers = ConfigLayers::load(&args.host_config, &find_config(&args.config)?)?;
+    if let Command::Config(ConfigCommand::Show(show_args)) = args.command {
+        print!("{}", config_layers.show(show_args.origin));
+        return Ok(());
+    }
+    let origin = absolute(repo.path()).context("getting absolute path of repo")?;
+    let config = config_layers.config()?;
+    let config_repos = Arc::new(
+        ConfigRepos::open(
+            config.parse_config_repos(&origin)?,
+            &origin,
+            &args.state_dir,
+            // Other commands just need a result for the revision we had last
+            // time.
+            matches!(
+                args.command,
+                Command::Watch(_) | Command::Test(_) | Command::Rerun(_)
+            ),
+        )
+        .await?,
+    );
+    let config = ParsedConfig::from(config, &origin, &config_repos.resolve().await?)?;
+
+    let git_dir_digest = Sha3_256::digest(
+        git_common_dir
+            .canonicalize()
+            .context("canonicalizing Git dir")?
+            .as_os_str()
+            .as_encoded_bytes(),
+    );
+    let repo_id = format!("{git_dir_digest:x}");
+    let namespace = repo_namespace(
+        &repo,
+        &repo_id,
+        &args.state_dir,
+        config.project.as_deref(),
+        match &args.command {
+            Command::Watch(watch_args) => &watch_args.base,
+            _ => "HEAD",
+        },
+    )
+    .await?;
+    let unborn = namespace.is_none();
+    let namespace = namespace.unwrap_or_else(|| Database::unborn_namespace(&repo_id));
+    let mut database =
+        Database::create_or_open(&args.result_db, namespace, args.result_db_backend).await?;
+    if let Some(remote_cache) = &config.remote_cache {
+        database = database.with_remote_cache(remote_cache.clone())?;
+    }
+    let migrated = database
+        .migrate_legacy(&repo)
+        .await
+        .context("migrating results from old database layout")?;
+    if migrated != 0 {
+        info!("Moved {migrated} results from the old database layout");
+    }
+    if !unborn {
+        let adopted = database
+            .adopt_unborn(&repo_id)
+            .context("moving results from before the repo had any commits")?;
+        if adopted != 0 {
+            info!("Moved {adopted} results from before the repo had any commits");
+        }
+    }
+
+    let persistent_pool = if args.persistent_worktrees {
+        Some(PersistentPool::new(&args.state_dir, &git_common_dir)?)
+    } else {
+        None
+    };
+    let repo = Arc::new(repo);
     let env = Env {
+        worktree_builder: Arc::new(WorktreeBuilder::new(
+            args.worktree_prefix.into(),
+            args.worktree_dir
+                .or(config.worktree_dir.clone())
+                .unwrap_or_else(env::temp_dir),
+            repo.clone(),
+            config.worktree_mode,
+            persistent_pool,
+        )?),
         config,
-        repo: Arc::new(repo),
-        database: Arc::new(Database::create_or_open(&args.result_db)?),
-        worktree_builder: WorktreeBuilder {
-            prefix: args.worktree_prefix.into(),
-            parent_dir: args.worktree_dir.into(),
-        },
+        config_layers,
+        config_repos: config_repos.clone(),
+        repo,
+        database: Arc::new(database),
     };
 
-    match args.command {
+    let services = env.config.services.clone();
+    let result = match args.command {
         Command::Watch(watch_args) => watch(env, cancellation_token, watch_args).await,
         Command::Test(ref test_args) => test(env, cancellation_token, test_args).await,
         Command::Get(get_args) => get(env, cancellation_token, get_args).await,
         Command::Artifacts(lookup_args) => artifacts(env, cancellation_token, lookup_args).await,
-    }
+        Command::Explain(explain_args) => explain(env, explain_args).await,
+        Command::Forget(forget_args) => forget(env, cancellation_token, forget_args, false).await,
+        Command::Rerun(rerun_args) => forget(env, cancellation_token, rerun_args, true).await,
+        Command::Db(DbCommand::Gc(gc_args)) => db_gc(env, gc_args).await,
+        Command::Db(DbCommand::List(list_args)) => db_list(env, list_args).await,
+        Command::Db(DbCommand::Reindex) => env.database.reindex(),
+        Command::Db(DbCommand::Export(export_args)) => db_export(env, export_args).await,
+        Command::Db(DbCommand::Import(import_args)) => db_import(env, import_args).await,
+        Command::Db(DbCommand::Fsck(fsck_args)) => db_fsck(env, fsck_args),
+        Command::Worktrees(_) | Command::Config(_) | Command::ServeCache(_) => unreachable!(),
+    };
+    // Services are torn down alongside the worktrees.
+    services.shutdown().await;
+    config_repos.shutdown().await;
+    result
 }
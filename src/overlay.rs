@@ -0,0 +1,405 @@
+// Support for "overlay" worktrees. Instead of each worktree being a full Git
+// checkout, we keep a single read-only extraction of each commit that gets
+// tested (a "lower" dir) and give each job a private copy-on-write view of it.
+//
+// The cheapest way to get that view is a kernel overlayfs mount, but that
+// needs privileges most users don't have. So we fall back to fuse-overlayfs,
+// and if that isn't installed either we just copy the tree, using reflinks if
+// the filesystem supports them.
+
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::{create_dir, create_dir_all, remove_dir_all, remove_file, rename},
+    io::ErrorKind::NotFound,
+    path::{Path, PathBuf},
+    process::Command as SyncCommand,
+    sync::Arc,
+    time::Instant,
+};
+
+use anyhow::{bail, Context as _};
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+use parking_lot::Mutex;
+use tempfile::TempDir;
+use tokio::{process::Command, task::spawn_blocking};
+
+use crate::{
+    git::{CommitHash, PersistentWorktree, Worktree as _},
+    process::CommandExt as _,
+    util::{IoResultExt as _, ResultExt as _},
+};
+
+// Ways we know how to create a copy-on-write tree, in order of preference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverlayMethod {
+    Kernel,
+    Fuse,
+    Copy,
+}
+
+impl OverlayMethod {
+    const ALL: [Self; 3] = [Self::Kernel, Self::Fuse, Self::Copy];
+
+    fn setup_cmd(&self, lower: &Path, upper: &Path, work: &Path, tree: &Path) -> Command {
+        let opts = format!(
+            "lowerdir={},upperdir={},workdir={}",
+            lower.display(),
+            upper.display(),
+            work.display()
+        );
+        let mut cmd;
+        match self {
+            Self::Kernel => {
+                cmd = Command::new("mount");
+                cmd.args(["-t", "overlay", "overlay", "-o", &opts])
+                    .arg(tree);
+            }
+            Self::Fuse => {
+                cmd = Command::new("fuse-overlayfs");
+                cmd.args(["-o", &opts]).arg(tree);
+            }
+            Self::Copy => {
+                // The trailing /. means "copy the contents", including dotfiles.
+                cmd = Command::new("cp");
+                cmd.args(["-a", "--reflink=auto"])
+                    .arg(lower.join("."))
+                    .arg(tree);
+            }
+        }
+        cmd
+    }
+
+    // Command to tear down a tree set up by this method, if there's anything to
+    // do beyond deleting the files.
+    pub fn teardown_cmd(&self, tree: &Path) -> Option<SyncCommand> {
+        let mut cmd = match self {
+            Self::Kernel => SyncCommand::new("umount"),
+            Self::Fuse => {
+                let mut cmd = SyncCommand::new("fusermount");
+                cmd.arg("-u");
+                cmd
+            }
+            Self::Copy => return None,
+        };
+        cmd.arg(tree);
+        Some(cmd)
+    }
+}
+
+// How many extracted commits to keep around when no worktree is using them.
+// Jobs for the same commit usually run close together, so this is mostly about
+// not re-extracting a commit for each of its tests.
+const MAX_IDLE_CHECKOUTS: usize = 8;
+
+// Read-only extractions of commits, shared between all the overlay worktrees of
+// a repo. These are never modified once created. Ones no worktree is using get
+// evicted once there are too many of them, and the rest are deleted on drop.
+#[derive(Debug)]
+pub struct CommitCheckouts {
+    origin: PathBuf,
+    dir: TempDir,
+    checkouts: Mutex<HashMap<CommitHash, CheckoutState>>,
+    max_idle: usize,
+    // Counter for picking unique names for evicted checkouts.
+    evictions: Mutex<u64>,
+    // Once we've figured out which OverlayMethod works on this system, we
+    // stick with it.
+    method: Mutex<Option<OverlayMethod>>,
+}
+
+#[derive(Debug)]
+struct CheckoutState {
+    // Held while extracting. The bool says whether that's been done.
+    ready: Arc<tokio::sync::Mutex<bool>>,
+    // Number of CheckoutGuards for it.
+    users: usize,
+    last_used: Instant,
+}
+
+// A checkout that's in use, it won't be evicted while this exists.
+#[derive(Debug)]
+pub struct CheckoutGuard {
+    checkouts: Arc<CommitCheckouts>,
+    commit: CommitHash,
+}
+
+impl CheckoutGuard {
+    pub fn path(&self) -> PathBuf {
+        self.checkouts.path(&self.commit)
+    }
+}
+
+impl Drop for CheckoutGuard {
+    fn drop(&mut self) {
+        let mut checkouts = self.checkouts.checkouts.lock();
+        let state = checkouts
+            .get_mut(&self.commit)
+            .expect("checkout in use got evicted");
+        state.users -= 1;
+        state.last_used = Instant::now();
+    }
+}
+
+impl CommitCheckouts {
+    pub fn new(origin: &Path, dir: TempDir) -> Self {
+        Self {
+            origin: origin.to_owned(),
+            dir,
+            checkouts: Mutex::new(HashMap::new()),
+            max_idle: MAX_IDLE_CHECKOUTS,
+            evictions: Mutex::new(0),
+            method: Mutex::new(None),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_max_idle(mut self, max_idle: usize) -> Self {
+        self.max_idle = max_idle;
+        self
+    }
+
+    fn path(&self, commit: &CommitHash) -> PathBuf {
+        self.dir.path().join::<&str>(commit.as_ref())
+    }
+
+    // Returns a read-only tree containing the content of the commit, creating
+    // it if necessary. It stays there at least until the guard is dropped.
+    pub async fn get(self: &Arc<Self>, commit: &CommitHash) -> anyhow::Result<CheckoutGuard> {
+        let evicted;
+        let ready = {
+            let mut checkouts = self.checkouts.lock();
+            let state = checkouts
+                .entry(commit.clone())
+                .or_insert_with(|| CheckoutState {
+                    ready: Arc::new(tokio::sync::Mutex::new(false)),
+                    users: 0,
+                    last_used: Instant::now(),
+                });
+            state.users += 1;
+            let ready = state.ready.clone();
+            evicted = self.evict(&mut checkouts);
+            ready
+        };
+        let guard = CheckoutGuard {
+            checkouts: self.clone(),
+            commit: commit.clone(),
+        };
+        if !evicted.is_empty() {
+            spawn_blocking(move || {
+                for path in evicted {
+                    remove_dir_all(&path)
+                        .or_log_error(&format!("removing old checkout {}", path.display()));
+                }
+            });
+        }
+
+        // Only this commit's lock is held while extracting, other commits
+        // can be extracted in parallel.
+        let mut ready = ready.lock().await;
+        if !*ready {
+            self.extract(commit, &guard.path()).await?;
+            *ready = true;
+        }
+        Ok(guard)
+    }
+
+    // Forget the least recently used checkouts that aren't in use, if there
+    // are too many. Returns the paths to delete, they have already been
+    // moved out of the way so the commits can be extracted again straight
+    // away.
+    fn evict(&self, checkouts: &mut HashMap<CommitHash, CheckoutState>) -> Vec<PathBuf> {
+        let mut idle: Vec<(CommitHash, Instant)> = checkouts
+            .iter()
+            .filter(|(_, state)| state.users == 0)
+            .map(|(commit, state)| (commit.clone(), state.last_used))
+            .collect();
+        if idle.len() <= self.max_idle {
+            return Vec::new();
+        }
+        idle.sort_by_key(|(_, last_used)| *last_used);
+        let mut evicted = Vec::new();
+        for (commit, _) in &idle[..idle.len() - self.max_idle] {
+            checkouts.remove(commit);
+            let path = self.path(commit);
+            let mut evictions = self.evictions.lock();
+            *evictions += 1;
+            let dest = self
+                .dir
+                .path()
+                .join(format!("{commit}.evicted.{evictions}"));
+            match rename(&path, &dest) {
+                Ok(()) => {
+                    debug!("Evicting checkout of {commit}");
+                    evicted.push(dest);
+                }
+                // Extraction must have failed.
+                Err(e) if e.kind() == NotFound => (),
+                Err(e) => warn!("Couldn't evict checkout of {commit}: {e}"),
+            }
+        }
+        evicted
+    }
+
+    async fn extract(&self, commit: &CommitHash, path: &Path) -> anyhow::Result<()> {
+        // Extract via a private index file so we don't touch the origin's
+        // index, and via a temporary path so that if we get interrupted we
+        // don't leave a partial tree lying around where it might be used.
+        let tmp_path = self.dir.path().join(format!("{commit}.tmp"));
+        tokio::fs::remove_dir_all(&tmp_path)
+            .await
+            .ignore(NotFound)
+            .context("removing stale partial checkout")?;
+        create_dir(&tmp_path).context("creating commit checkout dir")?;
+        let index_path = self.dir.path().join(format!("{commit}.index"));
+        let origin = PersistentWorktree {
+            path: self.origin.clone(),
+        };
+        origin
+            .git(["read-tree"])
+            .arg(commit)
+            .env("GIT_INDEX_FILE", &index_path)
+            .execute()
+            .await
+            .with_context(|| format!("reading tree for {commit}"))?;
+        let mut prefix_arg = tmp_path.clone().into_os_string();
+        prefix_arg.push("/");
+        let mut prefix = OsString::from("--prefix=");
+        prefix.push(prefix_arg);
+        origin
+            .git(["checkout-index", "--all"])
+            .arg(prefix)
+            .env("GIT_INDEX_FILE", &index_path)
+            .execute()
+            .await
+            .with_context(|| format!("extracting tree for {commit}"))?;
+        remove_file(&index_path).context("removing temporary index")?;
+        rename(&tmp_path, path).context("moving commit checkout into place")?;
+        debug!("Extracted {commit} to {path:?}");
+        Ok(())
+    }
+
+    // Set up tree as a copy-on-write view of lower, using upper and work as
+    // scratch space. All of these must be empty directories, except lower.
+    pub async fn setup_tree(
+        &self,
+        lower: &Path,
+        upper: &Path,
+        work: &Path,
+        tree: &Path,
+    ) -> anyhow::Result<OverlayMethod> {
+        // The mount options are comma-separated and lowerdir can have several
+        // colon-separated layers. overlayfs has its own escaping for these
+        // but fuse-overlayfs doesn't necessarily agree, so just refuse.
+        for path in [lower, upper, work] {
+            if path
+                .as_os_str()
+                .as_encoded_bytes()
+                .iter()
+                .any(|b| b",:\\".contains(b))
+            {
+                bail!(
+                    "can't use overlay worktrees at {}, the path contains ',', ':' or '\\' \
+                     (try setting worktree_dir)",
+                    path.display()
+                );
+            }
+        }
+        let known_method = *self.method.lock();
+        let candidates = match known_method {
+            Some(m) => vec![m],
+            None => OverlayMethod::ALL.to_vec(),
+        };
+        for method in candidates {
+            match method.setup_cmd(lower, upper, work, tree).execute().await {
+                Ok(_) => {
+                    if known_method.is_none() {
+                        info!("Using {method:?} method for overlay worktrees");
+                        *self.method.lock() = Some(method);
+                    }
+                    return Ok(method);
+                }
+                // If we already figured out the method, errors are real errors.
+                Err(e) if known_method.is_some() => {
+                    return Err(e).context(format!("setting up {method:?} overlay"));
+                }
+                Err(e) => debug!("{method:?} overlay not available: {e:#}"),
+            }
+        }
+        bail!("no overlay method worked (not even a plain copy)")
+    }
+}
+
+// Per-worktree state for an overlay worktree. The worktree's root directory
+// contains the scratch dirs as well as the mount point, so that it all gets
+// deleted together.
+#[derive(Debug)]
+pub struct Overlay {
+    checkouts: Arc<CommitCheckouts>,
+    root: PathBuf,
+    tree: PathBuf,
+    // How the tree is currently set up, if it is.
+    active: Mutex<Option<OverlayMethod>>,
+    // The checkout the tree is currently a view of. This has to outlive the
+    // overlay, so whoever owns this must tear it down before dropping it.
+    lower: Mutex<Option<CheckoutGuard>>,
+}
+
+impl Overlay {
+    pub fn new(checkouts: Arc<CommitCheckouts>, root: &Path) -> anyhow::Result<Self> {
+        let tree = root.join("tree");
+        create_dir_all(&tree).context("creating overlay mount point")?;
+        Ok(Self {
+            checkouts,
+            root: root.to_owned(),
+            tree,
+            active: Mutex::new(None),
+            lower: Mutex::new(None),
+        })
+    }
+
+    // Where the job should run.
+    pub fn tree(&self) -> &Path {
+        &self.tree
+    }
+
+    pub fn teardown_cmd(&self) -> Option<SyncCommand> {
+        self.active
+            .lock()
+            .and_then(|method| method.teardown_cmd(&self.tree))
+    }
+
+    // Replace the content of the tree with a pristine view of the commit.
+    pub async fn checkout(&self, commit: &CommitHash) -> anyhow::Result<()> {
+        if let Some(cmd) = self.teardown_cmd() {
+            Command::from(cmd)
+                .execute()
+                .await
+                .context("tearing down previous overlay")?;
+        }
+        *self.active.lock() = None;
+        *self.lower.lock() = None;
+
+        let upper = self.root.join("upper");
+        let work = self.root.join("work");
+        // The upper dir is basically a whole build tree, so this can take a
+        // while.
+        for dir in [&upper, &work, &self.tree] {
+            tokio::fs::remove_dir_all(dir)
+                .await
+                .ignore(NotFound)
+                .with_context(|| format!("cleaning up {}", dir.display()))?;
+            create_dir(dir).with_context(|| format!("creating {}", dir.display()))?;
+        }
+
+        let lower = self.checkouts.get(commit).await?;
+        let method = self
+            .checkouts
+            .setup_tree(&lower.path(), &upper, &work, &self.tree)
+            .await?;
+        *self.active.lock() = Some(method);
+        *self.lower.lock() = Some(lower);
+        Ok(())
+    }
+}
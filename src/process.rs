@@ -1,4 +1,5 @@
 use anyhow::{anyhow, Context};
+use nix::sys::signal::Signal;
 use std::ffi::OsStr;
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::process::ExitStatusExt as _;
@@ -13,15 +14,22 @@ pub trait ExitStatusExt {
 impl ExitStatusExt for ExitStatus {
     fn code_not_killed(&self) -> anyhow::Result<i32> {
         self.code().ok_or_else(|| {
-            anyhow!(
-                "terminated by signal {}",
+            killed_error(
                 self.signal()
-                    .expect("ExitStatus::code() and ExitStatus::signal() both None")
+                    .expect("ExitStatus::code() and ExitStatus::signal() both None"),
             )
         })
     }
 }
 
+// Error for a process that got killed, with the signal name if we know it.
+fn killed_error(signal: i32) -> anyhow::Error {
+    match Signal::try_from(signal) {
+        Ok(name) => anyhow!("terminated by signal {signal} ({name})"),
+        Err(_) => anyhow!("terminated by signal {signal}"),
+    }
+}
+
 pub trait OutputExt {
     // Returns exit code, fails verbosely if the process was killed by a signal.
     fn code_not_killed(&self) -> anyhow::Result<i32>;
@@ -32,11 +40,10 @@ pub trait OutputExt {
 impl OutputExt for Output {
     fn code_not_killed(&self) -> anyhow::Result<i32> {
         self.status.code().ok_or_else(|| {
-            anyhow!(
-                "terminated by signal {}",
+            killed_error(
                 self.status
                     .signal()
-                    .expect("ExitStatus::code() and ExitStatus::signal() both None")
+                    .expect("ExitStatus::code() and ExitStatus::signal() both None"),
             )
         })
     }
@@ -1,23 +1,43 @@
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
 use std::mem::ManuallyDrop;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use anyhow::anyhow;
 use async_condvar_fair::Condvar;
+use futures::future::BoxFuture;
 #[allow(unused_imports)]
 use log::debug;
 use parking_lot::Mutex;
 
-use crate::git::TempWorktree;
+use crate::git::{CommitHash, TempWorktree, Worktree as _};
+
+// Name of the worktree pool used by tests that don't specify one.
+pub const DEFAULT_WORKTREE_POOL: &str = "default";
 
 // Key to identify the type of resource that can be put into the pool.
 #[derive(Debug, Hash, PartialEq, Eq, Clone)]
 pub enum ResourceKey {
-    // Special type of resource representing worktrees. This code doesn't
-    // actually care about worktrees so probably we should actually just be
-    // generic over the key type.
-    Worktree,
+    // Special type of resource representing worktrees, there's one of these
+    // per named worktree pool. This code doesn't actually care about worktrees
+    // so probably we should actually just be generic over the key type.
+    Worktree(String),
     UserToken(String), // Resource defined by the user.
 }
 
+impl ResourceKey {
+    pub fn default_worktree() -> Self {
+        Self::Worktree(DEFAULT_WORKTREE_POOL.to_owned())
+    }
+
+    pub fn is_worktree(&self) -> bool {
+        matches!(self, Self::Worktree(_))
+    }
+}
+
 // Resource that can be put in the pool. This is another thing where we leak the
 // details of the user into this code, we probably shouldn't know about
 // TempWorktree in here.
@@ -37,6 +57,83 @@ impl Resource {
     }
 }
 
+// Returns a future that creates a worktree. The argument is the ID that will be
+// assigned to it, which the factory can use to make its choices stable.
+pub type WorktreeFactory = Box<
+    dyn Fn(usize) -> anyhow::Result<BoxFuture<'static, anyhow::Result<TempWorktree>>> + Send + Sync,
+>;
+
+// State for creating worktrees on demand for a single worktree pool, see
+// Pools::set_worktree_factory.
+struct LazyWorktrees {
+    factory: WorktreeFactory,
+    max: usize,
+    // Number of worktrees that exist or are being created.
+    total: usize,
+    // Number of worktrees currently being created.
+    creating: usize,
+    // If creating a worktree failed, this is reported to the next getter that
+    // wants one.
+    error: Option<anyhow::Error>,
+}
+
+impl fmt::Debug for LazyWorktrees {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LazyWorktrees")
+            .field("max", &self.max)
+            .field("total", &self.total)
+            .field("creating", &self.creating)
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    resources: HashMap<ResourceKey, Vec<Resource>>,
+    // Keyed by worktree pool name.
+    lazy_worktrees: HashMap<String, LazyWorktrees>,
+    // When each available worktree was last put back in the pool, keyed by
+    // path.
+    worktree_idle_since: HashMap<PathBuf, Instant>,
+    // IDs of worktrees that exist or are being created, keyed by worktree pool
+    // name.
+    worktree_ids: HashMap<String, BTreeSet<usize>>,
+}
+
+impl State {
+    // Worktree IDs are kept as small as possible, so that they're the same
+    // each time Limmat runs. They're only unique within a worktree pool.
+    fn alloc_worktree_id(&mut self, pool: &str) -> usize {
+        let ids = self.worktree_ids.entry(pool.to_owned()).or_default();
+        let id = (0..).find(|id| !ids.contains(id)).unwrap();
+        ids.insert(id);
+        id
+    }
+
+    fn free_worktree_id(&mut self, pool: &str, id: usize) {
+        if let Some(ids) = self.worktree_ids.get_mut(pool) {
+            ids.remove(&id);
+        }
+    }
+
+    fn add_worktree(&mut self, pool: &str, worktree: TempWorktree) {
+        self.worktree_idle_since
+            .insert(worktree.path().to_owned(), Instant::now());
+        self.resources
+            .entry(ResourceKey::Worktree(pool.to_owned()))
+            .or_default()
+            .push(Resource::Worktree(worktree));
+    }
+}
+
+// The bits of Pools that background tasks need access to.
+#[derive(Debug)]
+struct Shared {
+    cond: Condvar,
+    state: Mutex<State>,
+}
+
 // Collection of shared resources, consisting of pools of resources. The
 // user can block until an arbitrary combination of numbers of different tokens
 // becomes available, without any underutilization or deadlocking. Tokens are
@@ -44,8 +141,7 @@ impl Resource {
 // probably "should" be generic over.
 #[derive(Debug)]
 pub struct Pools {
-    cond: Condvar,
-    resources: Mutex<HashMap<ResourceKey, Vec<Resource>>>,
+    shared: Arc<Shared>,
 }
 
 impl Pools {
@@ -54,35 +150,133 @@ impl Pools {
     // TODO: this key/val tuple approach is kinda annoying, maybe we should have
     // a trait object that implements Into<Resource> or something?
     pub fn new(resources: impl IntoIterator<Item = (ResourceKey, Vec<Resource>)>) -> Self {
+        let mut state = State::default();
+        for (key, key_resources) in resources {
+            state.resources.entry(key.clone()).or_default();
+            for resource in key_resources {
+                match (&key, resource) {
+                    (ResourceKey::Worktree(pool), Resource::Worktree(mut w)) => {
+                        w.set_id(state.alloc_worktree_id(pool));
+                        state.add_worktree(pool, w);
+                    }
+                    (_, resource) => state.resources.get_mut(&key).unwrap().push(resource),
+                }
+            }
+        }
         Self {
-            cond: Condvar::new(),
-            resources: Mutex::new(resources.into_iter().collect()),
+            shared: Arc::new(Shared {
+                cond: Condvar::new(),
+                state: Mutex::new(state),
+            }),
         }
     }
 
-    // TODO: As well as being annoying in a similar way to new, this is
-    // inconsistent with it for no good reason.
-    pub fn add(&self, new_resources: impl IntoIterator<Item = (ResourceKey, Resource)>) {
-        // Don't need the condvar since we have a mutable reference to self. We
-        // only take the mutex out of a misguided sense of decorum.
-        let mut resources = self.resources.lock();
-        for (key, resource) in new_resources.into_iter() {
-            (*resources).entry(key).or_default().push(resource);
-        }
-        self.cond.notify_all();
+    // Instead of the user adding worktrees up-front, create them on demand
+    // for the named worktree pool, when a getter would otherwise block waiting
+    // for one, up to the given maximum. The factory must be usable from any
+    // tokio task.
+    pub fn set_worktree_factory(&self, pool: &str, max: usize, factory: WorktreeFactory) {
+        let mut state = self.shared.state.lock();
+        let total = state
+            .resources
+            .get(&ResourceKey::Worktree(pool.to_owned()))
+            .map_or(0, |w| w.len());
+        state.lazy_worktrees.insert(
+            pool.to_owned(),
+            LazyWorktrees {
+                factory,
+                max,
+                total,
+                creating: 0,
+                error: None,
+            },
+        );
+    }
+
+    // Kick off creation of a worktree in the background. When it's done it
+    // will be put in the pool and getters will be woken up.
+    fn spawn_create_worktree(&self, state: &mut State, pool: &str) -> anyhow::Result<()> {
+        let id = state.alloc_worktree_id(pool);
+        let lazy = state.lazy_worktrees.get_mut(pool).unwrap();
+        let create = match (lazy.factory)(id) {
+            Ok(create) => create,
+            Err(e) => {
+                state.free_worktree_id(pool, id);
+                return Err(e);
+            }
+        };
+        lazy.total += 1;
+        lazy.creating += 1;
+        let shared = self.shared.clone();
+        let pool = pool.to_owned();
+        tokio::spawn(async move {
+            let result = create.await;
+            let mut state = shared.state.lock();
+            // remove_worktrees waits for creation to finish so this must still
+            // be set.
+            let lazy = state.lazy_worktrees.get_mut(&pool).unwrap();
+            lazy.creating -= 1;
+            match result {
+                Ok(mut worktree) => {
+                    worktree.set_id(id);
+                    state.add_worktree(&pool, worktree);
+                }
+                Err(e) => {
+                    lazy.total -= 1;
+                    lazy.error = Some(e);
+                    state.free_worktree_id(&pool, id);
+                }
+            }
+            shared.cond.notify_all();
+        });
+        Ok(())
     }
 
     // Get the specified number of tokens from each of the pools, keys match
     // the keys used in new (or this panics).
     // The tokens are held until you drop the returned value.
+    // This only fails if the resources have to be created and that fails.
+    #[cfg(test)]
+    pub async fn get(
+        &self,
+        wants: impl IntoIterator<Item = (ResourceKey, usize)>,
+    ) -> anyhow::Result<Resources<'_>> {
+        self.get_with_affinity(wants, &HashMap::new()).await
+    }
+
+    // Like get, but if there's a choice of worktrees, prefer the ones whose
+    // last checked-out commit has the lowest value in affinity. Worktrees
+    // whose commit isn't in there are the last choice.
     //
     // https://github.com/rust-lang/rust-clippy/issues/13075
     #[expect(clippy::await_holding_lock)]
-    pub async fn get(&self, wants: impl IntoIterator<Item = (ResourceKey, usize)>) -> Resources {
+    pub async fn get_with_affinity(
+        &self,
+        wants: impl IntoIterator<Item = (ResourceKey, usize)>,
+        affinity: &HashMap<CommitHash, usize>,
+    ) -> anyhow::Result<Resources<'_>> {
         let wants: Vec<(ResourceKey, usize)> = wants.into_iter().collect();
-        let mut guard = self.resources.lock();
+        // Number of worktrees wanted from each pool.
+        let want_worktrees: Vec<(&str, usize)> = wants
+            .iter()
+            .filter_map(|(key, n)| match key {
+                ResourceKey::Worktree(pool) if *n > 0 => Some((pool.as_str(), *n)),
+                _ => None,
+            })
+            .collect();
+        let mut guard = self.shared.state.lock();
         loop {
-            let avail_tokens = &mut (*guard);
+            let state = &mut (*guard);
+            for (pool, _) in &want_worktrees {
+                if let Some(e) = state
+                    .lazy_worktrees
+                    .get_mut(*pool)
+                    .and_then(|l| l.error.take())
+                {
+                    return Err(e.context(format!("creating worktree for pool {pool:?}")));
+                }
+            }
+            let avail_tokens = &mut state.resources;
             // For simplicity we first iterate to check if all the resources we
             // need are available, then if they are we take them out in a
             // separate operation.
@@ -90,13 +284,26 @@ impl Pools {
                 .iter()
                 .all(|(key, want)| avail_tokens.get(key).unwrap_or(&vec![]).len() >= *want)
             {
-                return Resources {
+                return Ok(Resources {
                     resources: ManuallyDrop::new(
                         wants
                             .into_iter()
                             .map(|(key, want_count)| {
                                 let avail =
                                     avail_tokens.get_mut(&key).expect("invalid resource key");
+                                if key.is_worktree() && !affinity.is_empty() {
+                                    // Stable sort, so among equally good
+                                    // worktrees the most recently used stays
+                                    // at the end.
+                                    avail.sort_by_key(|r| {
+                                        Reverse(
+                                            r.as_worktree()
+                                                .last_checkout()
+                                                .and_then(|c| affinity.get(&c).copied())
+                                                .unwrap_or(usize::MAX),
+                                        )
+                                    });
+                                }
                                 // Take the last n tokens out of the Vec and
                                 // associated them with the key.
                                 (key, avail.drain((avail.len() - want_count)..).collect())
@@ -104,40 +311,124 @@ impl Pools {
                             .collect(),
                     ),
                     pools: self,
+                });
+            }
+
+            // We're gonna block. If that's (partly) because of a lack of
+            // worktrees, and we're allowed to make more, make more. Note this
+            // can create a worktree even if we end up not being the getter
+            // that uses it, that's fine, someone else will.
+            for (pool, want) in &want_worktrees {
+                let Some(lazy) = state.lazy_worktrees.get(*pool) else {
+                    continue;
                 };
+                if *want > lazy.max {
+                    return Err(anyhow!(
+                        "want {want} worktrees from pool {pool:?} but only {} allowed",
+                        lazy.max
+                    ));
+                }
+                let avail = state
+                    .resources
+                    .get(&ResourceKey::Worktree(pool.to_string()))
+                    .map_or(0, |w| w.len());
+                let shortfall = want.saturating_sub(avail + lazy.creating);
+                let headroom = lazy.max.saturating_sub(lazy.total);
+                for _ in 0..shortfall.min(headroom) {
+                    self.spawn_create_worktree(state, pool)?;
+                }
             }
 
-            guard = self.cond.wait(guard).await;
+            guard = self.shared.cond.wait(guard).await;
         }
     }
 
-    // Without blocking, permanently remove all the worktrees that are currently available.
-    // specified type that are currently available, up to the specified number.
-    pub fn try_remove_worktrees(&self) -> impl Iterator<Item = TempWorktree> {
-        let mut guard = self.resources.lock();
-        let avail = &mut (*guard);
-        avail
-            .remove(&ResourceKey::Worktree)
-            .unwrap_or_default()
-            .into_iter()
+    // Permanently remove all the worktrees that are currently available, and
+    // stop creating new ones. If any are currently being created, this waits
+    // for that to finish so that they can be removed too.
+    //
+    // https://github.com/rust-lang/rust-clippy/issues/13075
+    #[expect(clippy::await_holding_lock)]
+    pub async fn remove_worktrees(&self) -> Vec<TempWorktree> {
+        let mut guard = self.shared.state.lock();
+        while guard.lazy_worktrees.values().any(|l| l.creating > 0) {
+            guard = self.shared.cond.wait(guard).await;
+        }
+        let state = &mut (*guard);
+        state.lazy_worktrees.clear();
+        state.worktree_idle_since.clear();
+        state.worktree_ids.clear();
+        let keys: Vec<ResourceKey> = state
+            .resources
+            .keys()
+            .filter(|k| k.is_worktree())
+            .cloned()
+            .collect();
+        keys.into_iter()
+            .flat_map(|key| state.resources.remove(&key).unwrap())
             .map(|resource| match resource {
                 Resource::Worktree(w) => w,
                 _ => panic!("wrong resource type in worktree pool"),
             })
+            .collect()
+    }
+
+    // Without blocking, remove the lazily-created worktrees that have been
+    // sitting unused for at least the given duration. They'll be recreated if
+    // they're needed again.
+    pub fn reap_idle_worktrees(&self, idle_for: Duration) -> Vec<TempWorktree> {
+        let mut guard = self.shared.state.lock();
+        let state = &mut (*guard);
+        let mut reaped = Vec::new();
+        for (pool, lazy) in state.lazy_worktrees.iter_mut() {
+            let Some(avail) = state
+                .resources
+                .get_mut(&ResourceKey::Worktree(pool.clone()))
+            else {
+                continue;
+            };
+            let idle_since = &mut state.worktree_idle_since;
+            let (reap, keep): (Vec<_>, Vec<_>) = avail.drain(..).partition(|r| {
+                idle_since
+                    .get(r.as_worktree().path())
+                    .is_some_and(|t| t.elapsed() >= idle_for)
+            });
+            *avail = keep;
+            lazy.total -= reap.len();
+            for resource in reap {
+                let Resource::Worktree(w) = resource else {
+                    panic!("wrong resource type in worktree pool");
+                };
+                idle_since.remove(w.path());
+                if let Some(ids) = state.worktree_ids.get_mut(pool) {
+                    ids.remove(&w.id());
+                }
+                reaped.push(w);
+            }
+        }
+        reaped
     }
 
     fn put(&self, resources: HashMap<ResourceKey, Vec<Resource>>) {
-        let mut guard = self.resources.lock();
-        let avail_tokens = &mut (*guard);
+        let mut guard = self.shared.state.lock();
+        let state = &mut (*guard);
         for (key, mut key_resources) in resources.into_iter() {
-            avail_tokens
+            if key.is_worktree() {
+                for r in &key_resources {
+                    state
+                        .worktree_idle_since
+                        .insert(r.as_worktree().path().to_owned(), Instant::now());
+                }
+            }
+            state
+                .resources
                 .get_mut(&key)
                 .expect("invalid resource key")
                 .append(&mut key_resources);
         }
         // Note this is pretty inefficient, we are waking up every getter even though we can satisfy
         // at most one of them.
-        self.cond.notify_all();
+        self.shared.cond.notify_all();
     }
 }
 
@@ -149,9 +440,17 @@ pub struct Resources<'a> {
 }
 
 impl Resources<'_> {
-    // Get access to the resources with the given key.
-    pub fn resources(&self, key: &ResourceKey) -> Option<&Vec<Resource>> {
-        self.resources.get(key)
+    // Get the worktree, if there is one, and the name of the pool it came
+    // from. If there are several you get an arbitrary one.
+    pub fn worktree(&self) -> Option<(&str, &TempWorktree)> {
+        self.resources
+            .iter()
+            .find_map(|(key, resources)| match key {
+                ResourceKey::Worktree(pool) => {
+                    resources.first().map(|r| (pool.as_str(), r.as_worktree()))
+                }
+                _ => None,
+            })
     }
 
     // Get all the user-configured token values
@@ -188,10 +487,15 @@ mod tests {
     use anyhow::bail;
     use std::task::{Context, Poll};
 
-    use futures::{pin_mut, task::noop_waker, Future};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::{pin_mut, task::noop_waker, Future, FutureExt as _};
+    use tempfile::TempDir;
     use test_case::test_case;
+    use tokio_util::sync::CancellationToken;
 
     use super::*;
+    use crate::git::test_utils::{TempRepo, WorktreeExt as _};
 
     // Assert that a future is blocked. Note that panicking directly in assertion helpers like this
     // is unhelpful because you lose line number debug. It seems the proper solution for that is to
@@ -266,10 +570,206 @@ mod tests {
                     (ResourceKey::UserToken("foo".into()), 2),
                     (ResourceKey::UserToken("bar".into()), 2),
                 ])
-                .await;
+                .await
+                .unwrap();
             check_pending(pools.get([(ResourceKey::UserToken("foo".into()), 3)]))
                 .expect("returned too many tokens");
         }
-        pools.get([(ResourceKey::UserToken("foo".into()), 3)]).await;
+        pools
+            .get([(ResourceKey::UserToken("foo".into()), 3)])
+            .await
+            .unwrap();
+    }
+
+    // Factory that creates real worktrees and counts how many times it was called.
+    fn counting_factory(repo: Arc<TempRepo>, count: Arc<AtomicUsize>) -> WorktreeFactory {
+        Box::new(move |_id| {
+            count.fetch_add(1, Ordering::SeqCst);
+            let repo = repo.clone();
+            Ok(async move {
+                TempWorktree::new(
+                    &CancellationToken::new(),
+                    repo.as_ref(),
+                    TempDir::with_prefix("worktree-")?,
+                )
+                .await
+            }
+            .boxed())
+        })
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_pools_lazy_worktrees() {
+        let repo = Arc::new(TempRepo::new().await.unwrap());
+        repo.commit("1").await.unwrap();
+        let count = Arc::new(AtomicUsize::new(0));
+        let pools = Pools::new([]);
+        pools.set_worktree_factory(
+            DEFAULT_WORKTREE_POOL,
+            2,
+            counting_factory(repo.clone(), count.clone()),
+        );
+        assert_eq!(count.load(Ordering::SeqCst), 0, "created worktree eagerly");
+
+        {
+            let _wt1 = pools
+                .get([(ResourceKey::default_worktree(), 1)])
+                .await
+                .unwrap();
+            let _wt2 = pools
+                .get([(ResourceKey::default_worktree(), 1)])
+                .await
+                .unwrap();
+            assert_eq!(count.load(Ordering::SeqCst), 2);
+            check_pending(pools.get([(ResourceKey::default_worktree(), 1)]))
+                .expect("returned more worktrees than allowed");
+            assert_eq!(count.load(Ordering::SeqCst), 2);
+        }
+        // Now they're back in the pool they should get reused.
+        pools
+            .get([(ResourceKey::default_worktree(), 2)])
+            .await
+            .unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        // Reaping them should mean they get recreated when needed again.
+        let reaped = pools.reap_idle_worktrees(Duration::ZERO);
+        assert_eq!(reaped.len(), 2);
+        for w in reaped {
+            w.cleanup().await;
+        }
+        pools
+            .get([(ResourceKey::default_worktree(), 1)])
+            .await
+            .unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+        assert!(pools
+            .reap_idle_worktrees(Duration::from_secs(3600))
+            .is_empty());
+
+        for w in pools.remove_worktrees().await {
+            w.cleanup().await;
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_pools_separate_worktree_pools() {
+        let repo = Arc::new(TempRepo::new().await.unwrap());
+        repo.commit("1").await.unwrap();
+        let default_count = Arc::new(AtomicUsize::new(0));
+        let kbuild_count = Arc::new(AtomicUsize::new(0));
+        let pools = Pools::new([]);
+        pools.set_worktree_factory(
+            DEFAULT_WORKTREE_POOL,
+            1,
+            counting_factory(repo.clone(), default_count.clone()),
+        );
+        pools.set_worktree_factory(
+            "kbuild",
+            1,
+            counting_factory(repo.clone(), kbuild_count.clone()),
+        );
+        let kbuild = ResourceKey::Worktree("kbuild".into());
+
+        // Exhausting one pool shouldn't block the other.
+        let kbuild_resources = pools.get([(kbuild.clone(), 1)]).await.unwrap();
+        check_pending(pools.get([(kbuild.clone(), 1)]))
+            .expect("returned more worktrees than allowed");
+        let default_resources = pools
+            .get([(ResourceKey::default_worktree(), 1)])
+            .await
+            .unwrap();
+        assert_eq!(default_count.load(Ordering::SeqCst), 1);
+        assert_eq!(kbuild_count.load(Ordering::SeqCst), 1);
+        assert_eq!(kbuild_resources.worktree().unwrap().0, "kbuild");
+        assert_eq!(
+            default_resources.worktree().unwrap().0,
+            DEFAULT_WORKTREE_POOL
+        );
+        // IDs are per-pool.
+        assert_eq!(kbuild_resources.worktree().unwrap().1.id(), 0);
+        assert_eq!(default_resources.worktree().unwrap().1.id(), 0);
+        drop(kbuild_resources);
+        drop(default_resources);
+
+        let worktrees = pools.remove_worktrees().await;
+        assert_eq!(worktrees.len(), 2);
+        for w in worktrees {
+            w.cleanup().await;
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_pools_lazy_worktree_error() {
+        let pools = Pools::new([(
+            ResourceKey::UserToken("foo".into()),
+            vec![Resource::UserToken("foo1".into())],
+        )]);
+        pools.set_worktree_factory(
+            DEFAULT_WORKTREE_POOL,
+            1,
+            Box::new(|_id| Ok(async { Err(anyhow!("oh no")) }.boxed())),
+        );
+        pools
+            .get([(ResourceKey::default_worktree(), 1)])
+            .await
+            .expect_err("worktree creation failure not reported");
+        // Getters that don't need a worktree shouldn't be affected.
+        pools
+            .get([(ResourceKey::UserToken("foo".into()), 1)])
+            .await
+            .unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_pools_worktree_affinity() {
+        let repo = TempRepo::new().await.unwrap();
+        let commit1 = repo.commit("1").await.unwrap();
+        let commit2 = repo.commit("2").await.unwrap();
+        let commit3 = repo.commit("3").await.unwrap();
+        let ct = CancellationToken::new();
+        let mut worktrees = Vec::new();
+        for commit in [&commit1, &commit3] {
+            let worktree =
+                TempWorktree::new(&ct, &repo, TempDir::with_prefix("worktree-").unwrap())
+                    .await
+                    .unwrap();
+            worktree.checkout(&commit.hash).await.unwrap();
+            worktrees.push(Resource::Worktree(worktree));
+        }
+        let pools = Pools::new([(ResourceKey::default_worktree(), worktrees)]);
+
+        // If we're testing commit2, the worktree that has its parent checked
+        // out is the best choice, even though it's not the one that would
+        // otherwise be picked.
+        let affinity = repo
+            .ancestors(&commit2.hash, 10)
+            .await
+            .unwrap()
+            .into_iter()
+            .enumerate()
+            .map(|(i, c)| (c, i))
+            .collect();
+        {
+            let resources = pools
+                .get_with_affinity([(ResourceKey::default_worktree(), 1)], &affinity)
+                .await
+                .unwrap();
+            let worktree = resources.worktree().unwrap().1;
+            assert_eq!(worktree.last_checkout(), Some(commit1.hash.clone()));
+            assert_eq!(worktree.id(), 0);
+        }
+        {
+            let resources = pools
+                .get([(ResourceKey::default_worktree(), 1)])
+                .await
+                .unwrap();
+            let worktree = resources.worktree().unwrap().1;
+            assert_eq!(worktree.last_checkout(), Some(commit1.hash.clone()));
+        }
+
+        for w in pools.remove_worktrees().await {
+            w.cleanup().await;
+        }
     }
 }
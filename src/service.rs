@@ -0,0 +1,594 @@
+// Long-running processes (like a database server) that tests can use. Rather
+// than having every test job start and stop its own, Limmat starts a service
+// the first time a test needs it and keeps it running until shutdown,
+// restarting it if it dies. Each instance of a service gets its own port.
+
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::File,
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context as _};
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+use nix::{
+    sys::signal::{killpg, Signal},
+    unistd::Pid,
+};
+use parking_lot::Mutex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    process::{Child, Command},
+    select,
+    sync::watch,
+    task::JoinHandle,
+    time::{sleep, sleep_until, Instant},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::util::ResultExt as _;
+
+// How many instances of a service there are.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, Default, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ServiceScope {
+    /// One instance, shared by all tests. It runs in the main worktree.
+    #[default]
+    Session,
+    /// One instance for each worktree, running in that worktree. Only tests
+    /// that use a worktree can use these services.
+    Worktree,
+}
+
+// How to run a service. Construct via config::Service.
+#[derive(Debug)]
+pub struct ServiceConfig {
+    pub name: String,
+    pub program: OsString,
+    pub args: Vec<OsString>,
+    // If set, the service isn't considered ready until this succeeds.
+    pub ready_command: Option<(OsString, Vec<OsString>)>,
+    pub scope: ServiceScope,
+    pub ready_timeout: Duration,
+    pub shutdown_grace_period: Duration,
+}
+
+impl ServiceConfig {
+    fn command(&self, program: &OsString, args: &[OsString], cwd: &Path, port: u16) -> Command {
+        let mut cmd = Command::new(program);
+        cmd.args(args)
+            .current_dir(cwd)
+            .env("LIMMAT_SERVICE_PORT", port.to_string())
+            .stdin(Stdio::null())
+            // Like for tests, we want to be able to killpg the whole thing,
+            // and it shouldn't get the user's SIGINT.
+            .process_group(0);
+        cmd
+    }
+}
+
+#[derive(Debug, Clone)]
+enum InstanceState {
+    Starting,
+    Ready,
+    // The service couldn't be started, the message says why.
+    Failed(String),
+}
+
+#[derive(Debug)]
+struct Instance {
+    port: u16,
+    state: watch::Receiver<InstanceState>,
+    ct: CancellationToken,
+    task: JoinHandle<()>,
+}
+
+// Instances are identified by the service name, and the worktree for
+// per-worktree services.
+type InstanceKey = (String, Option<PathBuf>);
+
+#[derive(Debug)]
+pub struct Services {
+    configs: HashMap<String, Arc<ServiceConfig>>,
+    instances: Mutex<HashMap<InstanceKey, Instance>>,
+    // Where service output goes. Created the first time a service starts.
+    log_dir: Mutex<Option<PathBuf>>,
+    ct: CancellationToken,
+}
+
+impl Services {
+    pub fn new(configs: impl IntoIterator<Item = ServiceConfig>) -> Self {
+        Self {
+            configs: configs
+                .into_iter()
+                .map(|c| (c.name.clone(), Arc::new(c)))
+                .collect(),
+            instances: Mutex::new(HashMap::new()),
+            log_dir: Mutex::new(None),
+            ct: CancellationToken::new(),
+        }
+    }
+
+    fn log_path(&self, name: &str, port: u16) -> anyhow::Result<PathBuf> {
+        let mut log_dir = self.log_dir.lock();
+        if log_dir.is_none() {
+            let dir = tempfile::Builder::new()
+                .prefix("limmat-services-")
+                .tempdir()
+                .context("creating service log dir")?
+                .into_path();
+            info!("Service logs will be stored under {dir:?}");
+            *log_dir = Some(dir);
+        }
+        Ok(log_dir.as_ref().unwrap().join(format!("{name}-{port}.log")))
+    }
+
+    // Make sure the named service is running, starting it if necessary, and
+    // wait for it to be ready. Returns the port it's listening on. Session
+    // services run in origin, per-worktree services run in worktree, which
+    // must be set for those.
+    pub async fn ensure(
+        &self,
+        name: &str,
+        origin: &Path,
+        worktree: Option<&Path>,
+    ) -> anyhow::Result<u16> {
+        let config = self
+            .configs
+            .get(name)
+            .ok_or_else(|| anyhow!("no such service {name:?}"))?;
+        let (cwd, key) = match config.scope {
+            ServiceScope::Session => (origin, (name.to_owned(), None)),
+            ServiceScope::Worktree => {
+                let worktree = worktree.ok_or_else(|| {
+                    anyhow!("service {name:?} is per-worktree but there's no worktree")
+                })?;
+                (worktree, (name.to_owned(), Some(worktree.to_owned())))
+            }
+        };
+
+        let (port, mut state) = {
+            let mut instances = self.instances.lock();
+            if self.ct.is_cancelled() {
+                bail!("services are shut down");
+            }
+            // If it failed to start before, have another go.
+            let failed = instances
+                .get(&key)
+                .is_some_and(|i| matches!(*i.state.borrow(), InstanceState::Failed(_)));
+            if failed {
+                instances.remove(&key);
+            }
+            if !instances.contains_key(&key) {
+                let instance = self.start(config.clone(), cwd, origin)?;
+                instances.insert(key.clone(), instance);
+            }
+            let instance = &instances[&key];
+            (instance.port, instance.state.clone())
+        };
+
+        let state = state
+            .wait_for(|s| !matches!(s, InstanceState::Starting))
+            .await
+            .map_err(|_| anyhow!("service {name:?} was shut down"))?
+            .clone();
+        match state {
+            InstanceState::Ready => Ok(port),
+            InstanceState::Failed(msg) => bail!("service {name:?} failed to start: {msg}"),
+            InstanceState::Starting => unreachable!(),
+        }
+    }
+
+    fn start(
+        &self,
+        config: Arc<ServiceConfig>,
+        cwd: &Path,
+        origin: &Path,
+    ) -> anyhow::Result<Instance> {
+        // There's a race here, something else could grab the port before the
+        // service does. Oh well.
+        let port = TcpListener::bind("127.0.0.1:0")
+            .and_then(|l| l.local_addr())
+            .context("allocating port for service")?
+            .port();
+        let log_path = self.log_path(&config.name, port)?;
+        info!("Starting service {:?} on port {port}", config.name);
+        let (tx, rx) = watch::channel(InstanceState::Starting);
+        let ct = self.ct.child_token();
+        let task = tokio::spawn(supervise(
+            config,
+            cwd.to_owned(),
+            origin.to_owned(),
+            port,
+            log_path,
+            tx,
+            ct.clone(),
+        ));
+        Ok(Instance {
+            port,
+            state: rx,
+            ct,
+            task,
+        })
+    }
+
+    // Shut down the instances of per-worktree services for the given worktree,
+    // e.g. because the worktree is about to be deleted.
+    pub async fn stop_worktree(&self, worktree: &Path) {
+        let instances: Vec<Instance> = {
+            let mut instances = self.instances.lock();
+            let keys: Vec<InstanceKey> = instances
+                .keys()
+                .filter(|(_, w)| w.as_deref() == Some(worktree))
+                .cloned()
+                .collect();
+            keys.iter().map(|k| instances.remove(k).unwrap()).collect()
+        };
+        for instance in instances {
+            instance.ct.cancel();
+            instance.task.await.or_log_error("joining service task");
+        }
+    }
+
+    // Shut down all the services. They can't be started again after this.
+    pub async fn shutdown(&self) {
+        let instances: Vec<Instance> = {
+            let mut instances = self.instances.lock();
+            self.ct.cancel();
+            instances.drain().map(|(_, i)| i).collect()
+        };
+        for instance in instances {
+            instance.task.await.or_log_error("joining service task");
+        }
+    }
+}
+
+// SIGTERM the process group and wait for the child to exit, SIGKILLing it if
+// that takes too long.
+async fn terminate(child: &mut Child, grace_period: Duration) {
+    let Some(pid) = child.id() else {
+        return; // Already dead.
+    };
+    let pid = Pid::from_raw(pid.try_into().unwrap());
+    killpg(pid, Signal::SIGTERM).or_log_error("SIGTERMing service");
+    select! {
+        _ = child.wait() => (),
+        _ = sleep(grace_period) => {
+            warn!("Service didn't shut down in time, SIGKILLing process group");
+            killpg(pid, Signal::SIGKILL).or_log_error("SIGKILLing service");
+            child.wait().await.or_log_error("waiting for SIGKILLed service");
+        }
+    }
+}
+
+// A running readiness command. If we stop waiting for it (cancellation, timeout,
+// service death) the whole process group gets killed so that nothing it spawned
+// is left behind.
+struct ReadyCheck(Child);
+
+impl Drop for ReadyCheck {
+    fn drop(&mut self) {
+        if let Some(pid) = self.0.id() {
+            let pid = Pid::from_raw(pid.try_into().unwrap());
+            killpg(pid, Signal::SIGKILL).or_log_error("SIGKILLing readiness command");
+        }
+    }
+}
+
+// Start the service and wait for it to be ready. Returns None if cancelled.
+async fn start_once(
+    config: &ServiceConfig,
+    cwd: &Path,
+    origin: &Path,
+    port: u16,
+    log_path: &Path,
+    ct: &CancellationToken,
+) -> anyhow::Result<Option<Child>> {
+    let log = File::options()
+        .create(true)
+        .append(true)
+        .open(log_path)
+        .with_context(|| format!("opening service log {log_path:?}"))?;
+    let mut child = config
+        .command(&config.program, &config.args, cwd, port)
+        .env("LIMMAT_ORIGIN", origin)
+        .stdout(log.try_clone().context("duplicating log file")?)
+        .stderr(log)
+        .spawn()
+        .context("spawning service command")?;
+
+    // However we stop waiting for it, if it isn't ready it mustn't be left
+    // running. It's in its own process group so nothing else would kill it.
+    match wait_ready(config, cwd, origin, port, log_path, ct, &mut child).await {
+        Ok(true) => Ok(Some(child)),
+        Ok(false) => {
+            terminate(&mut child, config.shutdown_grace_period).await;
+            Ok(None)
+        }
+        Err(e) => {
+            terminate(&mut child, config.shutdown_grace_period).await;
+            Err(e)
+        }
+    }
+}
+
+// Poll the readiness command until it succeeds. Returns false if cancelled.
+async fn wait_ready(
+    config: &ServiceConfig,
+    cwd: &Path,
+    origin: &Path,
+    port: u16,
+    log_path: &Path,
+    ct: &CancellationToken,
+    child: &mut Child,
+) -> anyhow::Result<bool> {
+    let Some((program, args)) = &config.ready_command else {
+        return Ok(true);
+    };
+    let deadline = Instant::now() + config.ready_timeout;
+    loop {
+        let mut ready_child = ReadyCheck(
+            config
+                .command(program, args, cwd, port)
+                .env("LIMMAT_ORIGIN", origin)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .kill_on_drop(true)
+                .spawn()
+                .context("spawning readiness command")?,
+        );
+        select! {
+            _ = ct.cancelled() => return Ok(false),
+            status = child.wait() => {
+                bail!("exited during startup ({}), see {log_path:?}", status.context("awaiting service")?);
+            }
+            _ = sleep_until(deadline) => {
+                bail!("not ready after {:?}, see {log_path:?}", config.ready_timeout);
+            }
+            status = ready_child.0.wait() => {
+                if status.context("running readiness command")?.success() {
+                    return Ok(true);
+                }
+            }
+        }
+        select! {
+            _ = ct.cancelled() => (),
+            _ = sleep(Duration::from_millis(100)) => (),
+        }
+    }
+}
+
+// Runs the service until ct is cancelled, restarting it if it dies.
+async fn supervise(
+    config: Arc<ServiceConfig>,
+    cwd: PathBuf,
+    origin: PathBuf,
+    port: u16,
+    log_path: PathBuf,
+    state: watch::Sender<InstanceState>,
+    ct: CancellationToken,
+) {
+    loop {
+        let mut child = match start_once(&config, &cwd, &origin, port, &log_path, &ct).await {
+            Ok(Some(child)) => child,
+            Ok(None) => return,
+            Err(e) => {
+                // Don't retry here, if it couldn't start once it probably
+                // won't start next time either. The next user will try again.
+                warn!("Service {:?} failed to start: {e:#}", config.name);
+                state.send_replace(InstanceState::Failed(format!("{e:#}")));
+                return;
+            }
+        };
+        debug!("Service {:?} on port {port} is ready", config.name);
+        state.send_replace(InstanceState::Ready);
+        select! {
+            _ = ct.cancelled() => {
+                terminate(&mut child, config.shutdown_grace_period).await;
+                return;
+            }
+            status = child.wait() => {
+                warn!(
+                    "Service {:?} died ({}), restarting. See {log_path:?}",
+                    config.name,
+                    status.map_or_else(|e| e.to_string(), |s| s.to_string())
+                );
+                state.send_replace(InstanceState::Starting);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt as _};
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::test_utils::timeout_5s;
+
+    fn bash(script: &str) -> (OsString, Vec<OsString>) {
+        ("bash".into(), vec!["-c".into(), script.into()])
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_service_restart() {
+        let dir = TempDir::new().unwrap();
+        let starts_path = dir.path().join("starts");
+        // The service records that it started, then runs until it's killed.
+        // It's "ready" once it's written its PID.
+        let (program, args) = bash(&format!(
+            "echo $LIMMAT_SERVICE_PORT >> {starts_path:?}; echo $$ > pid; sleep infinity & wait"
+        ));
+        let services = Services::new([ServiceConfig {
+            name: "my_service".into(),
+            program,
+            args,
+            ready_command: Some(bash("test -s pid")),
+            scope: ServiceScope::Session,
+            ready_timeout: Duration::from_secs(5),
+            shutdown_grace_period: Duration::from_secs(1),
+        }]);
+
+        let port = timeout_5s(services.ensure("my_service", dir.path(), None))
+            .await
+            .unwrap()
+            .unwrap();
+        // Second user gets the same instance.
+        assert_eq!(
+            timeout_5s(services.ensure("my_service", dir.path(), None))
+                .await
+                .unwrap()
+                .unwrap(),
+            port
+        );
+        assert_eq!(
+            fs::read_to_string(&starts_path).unwrap(),
+            format!("{port}\n")
+        );
+
+        // Kill it, it should come back.
+        let pid: i32 = fs::read_to_string(dir.path().join("pid"))
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        fs::remove_file(dir.path().join("pid")).unwrap();
+        killpg(Pid::from_raw(pid), Signal::SIGKILL).unwrap();
+        timeout_5s(async {
+            while fs::read_to_string(&starts_path).unwrap().lines().count() < 2 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("service not restarted");
+        assert_eq!(
+            timeout_5s(services.ensure("my_service", dir.path(), None))
+                .await
+                .unwrap()
+                .unwrap(),
+            port
+        );
+
+        timeout_5s(services.shutdown()).await.unwrap();
+        assert!(services
+            .ensure("my_service", dir.path(), None)
+            .await
+            .is_err());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_service_not_ready() {
+        let dir = TempDir::new().unwrap();
+        let (program, args) = bash("sleep infinity & wait");
+        let services = Services::new([ServiceConfig {
+            name: "my_service".into(),
+            program,
+            args,
+            ready_command: Some(bash("false")),
+            scope: ServiceScope::Worktree,
+            ready_timeout: Duration::from_millis(500),
+            shutdown_grace_period: Duration::from_secs(1),
+        }]);
+        // Per-worktree services need a worktree.
+        assert!(services
+            .ensure("my_service", dir.path(), None)
+            .await
+            .is_err());
+        let err = timeout_5s(services.ensure("my_service", dir.path(), Some(dir.path())))
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("not ready"), "{err:#}");
+        timeout_5s(services.shutdown()).await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_service_ready_command_killed() {
+        let dir = TempDir::new().unwrap();
+        let pid_path = dir.path().join("ready_pid");
+        let (program, args) = bash("sleep infinity & wait");
+        let services = Services::new([ServiceConfig {
+            name: "my_service".into(),
+            program,
+            args,
+            // Readiness command that never finishes, and has a child of its own.
+            ready_command: Some(bash(&format!(
+                "sleep infinity & echo $! > {pid_path:?}; wait"
+            ))),
+            scope: ServiceScope::Session,
+            ready_timeout: Duration::from_millis(500),
+            shutdown_grace_period: Duration::from_secs(1),
+        }]);
+        let err = timeout_5s(services.ensure("my_service", dir.path(), None))
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("not ready"), "{err:#}");
+        let pid: i32 = fs::read_to_string(&pid_path)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        timeout_5s(async {
+            while nix::sys::signal::kill(Pid::from_raw(pid), None).is_ok() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("readiness command's child left running");
+        timeout_5s(services.shutdown()).await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_service_killed_on_ready_error() {
+        let dir = TempDir::new().unwrap();
+        let pid_path = dir.path().join("pid");
+        let ready_path = dir.path().join("ready.sh");
+        fs::write(&ready_path, "#!/bin/bash\nexit 1\n").unwrap();
+        fs::set_permissions(&ready_path, fs::Permissions::from_mode(0o755)).unwrap();
+        // Once the service has started, the readiness command disappears so
+        // the next attempt to run it fails.
+        let (program, args) = bash(&format!(
+            "sleep infinity & echo $! > {pid_path:?}; rm {ready_path:?}; wait"
+        ));
+        let services = Services::new([ServiceConfig {
+            name: "my_service".into(),
+            program,
+            args,
+            ready_command: Some((ready_path.clone().into(), vec![])),
+            scope: ServiceScope::Session,
+            ready_timeout: Duration::from_secs(5),
+            shutdown_grace_period: Duration::from_secs(1),
+        }]);
+        let err = timeout_5s(services.ensure("my_service", dir.path(), None))
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("spawning readiness command"),
+            "{err:#}"
+        );
+        let pid: i32 = fs::read_to_string(&pid_path)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        timeout_5s(async {
+            while nix::sys::signal::kill(Pid::from_raw(pid), None).is_ok() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("service left running");
+        timeout_5s(services.shutdown()).await.unwrap();
+    }
+}
@@ -1,9 +1,10 @@
 use core::{error::Error, fmt, fmt::Display};
 use std::{
     borrow::Borrow,
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     ffi::{OsStr, OsString},
     fmt::{Debug, Formatter},
+    os::unix::process::ExitStatusExt as _,
     path::Path,
     pin::pin,
     process::Stdio,
@@ -12,8 +13,10 @@ use std::{
 };
 
 use anyhow::{anyhow, Context};
-use futures::future::{self, select_all, try_join_all, Either, FutureExt};
-use itertools::Itertools;
+use futures::{
+    future::{self, join_all, select_all, try_join_all, Either, FutureExt},
+    stream, StreamExt as _, TryStreamExt as _,
+};
 #[allow(unused_imports)]
 use log::{debug, error, info, warn};
 use nix::sys::signal::{killpg, Signal};
@@ -21,43 +24,89 @@ use nix::unistd::Pid;
 use parking_lot::Mutex;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use sha3::{Digest as _, Sha3_256};
 use tokio::{
     process::{Child, Command},
     select,
-    sync::{broadcast, watch},
+    sync::{broadcast, watch, OnceCell},
     time::sleep,
 };
 use tokio_util::sync::CancellationToken;
 
 use crate::{
+    config::CommitConfigParser,
+    config_repo::{ConfigRepoCheckout, ConfigRepos},
     dag::{Dag, GraphNode},
     database::{Database, DatabaseEntry, DatabaseOutput, LookupResult},
-    git::{Commit, CommitHash, Hash, Worktree},
+    git::{Commit, CommitHash, Hash, PersistentWorktree, Worktree},
     process::ExitStatusExt as _,
     resource::{Pools, ResourceKey, Resources},
+    service::Services,
     util::ResultExt,
 };
 
-#[derive(Deserialize, JsonSchema, Serialize, Debug, Clone, Copy, Hash, PartialEq, Eq)]
+#[derive(Deserialize, JsonSchema, Serialize, Debug, Clone, Hash, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum CachePolicy {
     NoCaching,
     ByCommit,
     ByTree,
+    /// Only re-run the test when the contents of these paths (relative to the
+    /// root of the repository) change.
+    ByPaths(Vec<String>),
 }
 
 impl CachePolicy {
     // Figure out the hash that should be used to store a result in the
     // database, if it should be stored at all
-    pub fn cache_hash(&self, commit: &Commit) -> Option<Hash> {
+    pub async fn cache_hash(
+        &self,
+        repo: &(impl Worktree + ?Sized),
+        commit: &Commit,
+    ) -> anyhow::Result<Option<Hash>> {
+        let objects = match self {
+            CachePolicy::ByPaths(paths) => try_join_all(
+                paths
+                    .iter()
+                    .map(|path| repo.path_object(&commit.hash, path)),
+            )
+            .await
+            .with_context(|| format!("looking up paths in {}", commit.hash))?,
+            _ => Vec::new(),
+        };
+        Ok(self.cache_hash_with_objects(commit, objects))
+    }
+
+    // Like cache_hash, but with the result of Worktree::path_object for each
+    // of the ByPaths paths already looked up.
+    fn cache_hash_with_objects(&self, commit: &Commit, objects: Vec<Option<Hash>>) -> Option<Hash> {
         match self {
             CachePolicy::NoCaching => None::<Hash>,
             CachePolicy::ByCommit => Some(commit.hash.clone().into()),
             CachePolicy::ByTree => Some(commit.tree.clone().into()),
+            CachePolicy::ByPaths(paths) => {
+                // This isn't a Git object ID, but it goes in the database in
+                // the same way. Paths that don't exist still need to
+                // contribute something, otherwise adding one wouldn't change
+                // the hash.
+                let mut digest = Sha3_256::new();
+                for (path, object) in paths.iter().zip(objects) {
+                    digest.update(path);
+                    digest.update([0]);
+                    if let Some(object) = object {
+                        digest.update(AsRef::<str>::as_ref(&object));
+                    }
+                    digest.update([0]);
+                }
+                Some(Hash::new(format!("{:x}", digest.finalize())))
+            }
         }
     }
 }
 
+// How many git processes set_revisions runs at once to look stuff up.
+const MAX_GIT_LOOKUPS: usize = 16;
+
 // Some unspecified hash, don't care too much about stability across builds.
 pub type ConfigHash = Vec<u8>;
 
@@ -88,28 +137,37 @@ impl Debug for TestName {
     }
 }
 
-// A test task that will need to be repeated for each commit.
-// TODO: this struct is too complex for the plain-old-data (pub fields)
-// approach, it should be constructed with a builder.
+// One command that's run as part of a test. Steps get their resources
+// separately, so that a test doesn't hold onto stuff like a worktree while
+// it's running a step that doesn't need it.
+#[derive(Debug)]
 #[cfg_attr(test, derive(PartialEq, Eq))]
-pub struct Test {
-    pub name: TestName,
-    // Hash of the configuration that created this Test.
-    pub config_hash: ConfigHash,
+pub struct TestStep {
+    // None if the test just has a single command, otherwise this identifies
+    // where the step's output is stored.
+    pub name: Option<String>,
     pub program: OsString,
     pub args: Vec<OsString>,
-    // Counts of the resource tokens this test needs a resource-token before it
+    // Counts of the resource tokens this step needs a resource-token before it
     // can begin.
     pub needs_resources: HashMap<ResourceKey, usize>,
-    pub shutdown_grace_period: Duration,
-    pub cache_policy: CachePolicy,
-    // This tests shoudln't start until these other tests have finished.
-    // Manager setup will fail if there are cycles in this graph or named tests
-    // do not exist.
-    pub depends_on: Vec<TestName>,
 }
 
-impl Test {
+impl TestStep {
+    // Shorthand for the step of a test that just has a single command.
+    pub fn single(
+        program: OsString,
+        args: Vec<OsString>,
+        needs_resources: HashMap<ResourceKey, usize>,
+    ) -> Self {
+        Self {
+            name: None,
+            program,
+            args,
+            needs_resources,
+        }
+    }
+
     fn command(&self) -> Command {
         let mut cmd = Command::new(&self.program);
         cmd.args(&self.args);
@@ -134,23 +192,83 @@ impl Test {
         cmd
     }
 
-    pub fn needs_worktree(&self) -> bool {
-        self.needs_resources
-            .get(&ResourceKey::Worktree)
-            .unwrap_or(&0)
-            != &0
+    // Name of the worktree pool this step takes its worktree from, if it needs
+    // one.
+    pub fn worktree_pool(&self) -> Option<&str> {
+        self.needs_resources.iter().find_map(|(key, n)| match key {
+            ResourceKey::Worktree(pool) if *n != 0 => Some(pool.as_str()),
+            _ => None,
+        })
+    }
+}
+
+// A test task that will need to be repeated for each commit.
+// TODO: this struct is too complex for the plain-old-data (pub fields)
+// approach, it should be constructed with a builder.
+#[cfg_attr(test, derive(PartialEq, Eq))]
+pub struct Test {
+    pub name: TestName,
+    // Hash of the configuration that created this Test.
+    pub config_hash: ConfigHash,
+    // Serialized form of everything that went into config_hash.
+    pub canonical_config: serde_json::Value,
+    // Run in order, stopping at the first one that fails. Never empty.
+    pub steps: Vec<TestStep>,
+    // Names of the services that need to be running for this test.
+    pub services: Vec<String>,
+    // Globs (with variables already expanded) for files outside the repo that
+    // the test depends on.
+    pub inputs: Vec<String>,
+    // Config repos the test uses, and the commit it uses each one at.
+    pub config_repos: Vec<(String, CommitHash)>,
+    pub shutdown_grace_period: Duration,
+    pub cache_policy: CachePolicy,
+    // Store results outside of the repo's namespace in the database, see
+    // Database::result_relpath.
+    pub share_results: bool,
+    pub cache_ttl: Option<Duration>,
+    // Only used in watch mode, see Manager::expired.
+    pub rerun_every: Option<Duration>,
+    // This tests shoudln't start until these other tests have finished.
+    // Manager setup will fail if there are cycles in this graph or named tests
+    // do not exist.
+    pub depends_on: Vec<TestName>,
+}
+
+impl Test {
+    // Does any step of this test take a worktree from the named pool?
+    pub fn uses_worktree_pool(&self, pool: &str) -> bool {
+        self.steps.iter().any(|s| s.worktree_pool() == Some(pool))
+    }
+
+    // Results older than this shouldn't be used. There's no point keeping a
+    // result around for longer than we'd wait before re-running the test.
+    pub fn max_result_age(&self) -> Option<Duration> {
+        [self.cache_ttl, self.rerun_every]
+            .into_iter()
+            .flatten()
+            .min()
     }
 
     #[cfg(test)]
     pub fn arbitrary() -> Self {
         Test {
             name: TestName::new("my_test"),
-            program: OsString::from("bash"),
-            args: vec!["yer".into()],
-            needs_resources: [].into(),
+            steps: vec![TestStep::single(
+                OsString::from("bash"),
+                vec!["yer".into()],
+                [].into(),
+            )],
+            services: vec![],
+            inputs: vec![],
+            config_repos: vec![],
             shutdown_grace_period: Duration::from_secs(5),
             cache_policy: CachePolicy::ByCommit,
             config_hash: vec![1, 2, 3],
+            canonical_config: serde_json::Value::Null,
+            share_results: false,
+            cache_ttl: None,
+            rerun_every: None,
             depends_on: vec![],
         }
     }
@@ -195,22 +313,91 @@ pub fn base_job_env(repo_origin: &Path) -> JobEnv {
     )]
 }
 
+// How far back in history to look for a worktree that last tested an ancestor
+// of the commit we're about to test.
+const AFFINITY_MAX_ANCESTORS: usize = 1000;
+
+// Figure out which worktrees would be good to test the commit in. If a worktree
+// last tested a recent ancestor of the commit, there's a good chance that
+// incremental build state in it will be reusable. The result maps commits to
+// how far back they are, see Pools::get_with_affinity.
+async fn worktree_affinity(origin: &Path, commit: &CommitHash) -> HashMap<CommitHash, usize> {
+    let origin = PersistentWorktree {
+        path: origin.to_owned(),
+    };
+    match origin.ancestors(commit, AFFINITY_MAX_ANCESTORS).await {
+        Ok(ancestors) => ancestors
+            .into_iter()
+            .enumerate()
+            .map(|(i, c)| (c, i))
+            .collect(),
+        Err(e) => {
+            // It's just an optimisation, not worth failing over.
+            warn!("Couldn't look up ancestors of {commit}: {e:#}");
+            HashMap::new()
+        }
+    }
+}
+
+type Affinity = Arc<HashMap<CommitHash, usize>>;
+
+// Remembers worktree_affinity for each commit, so that the jobs (and steps)
+// for a commit don't each have to run git rev-list.
+#[derive(Default)]
+struct AffinityCache {
+    affinity: Mutex<HashMap<CommitHash, Arc<OnceCell<Affinity>>>>,
+}
+
+impl AffinityCache {
+    async fn get(&self, origin: &Path, commit: &CommitHash) -> Affinity {
+        let cell = self
+            .affinity
+            .lock()
+            .entry(commit.clone())
+            .or_default()
+            .clone();
+        cell.get_or_init(|| async { Arc::new(worktree_affinity(origin, commit).await) })
+            .await
+            .clone()
+    }
+
+    // Forget about the commits that aren't in commits.
+    fn retain(&self, commits: &[Commit]) {
+        let commits: HashSet<&CommitHash> = commits.iter().map(|c| &c.hash).collect();
+        self.affinity.lock().retain(|c, _| commits.contains(c));
+    }
+}
+
 // Manages a bunch of worker threads that run tests for the current set of revisions.
 pub struct Manager<W: Worktree> {
     // We hardly need this field, it should be quite easy to remove it.
     repo: Arc<W>,
     // Oops, be extremely careful about mutating this. set_revisions has some
-    // pretty strong implicit assumptions about this field.
-    job_cts: Mutex<HashMap<TestCaseId, CancellationToken>>,
+    // pretty strong implicit assumptions about this field. Alongside the
+    // cancellation token for each running job is the config hash of its test,
+    // so we can tell if the test has changed.
+    job_cts: Mutex<HashMap<TestCaseId, (CancellationToken, ConfigHash)>>,
     job_counter: JobCounter,
     notif_tx: broadcast::Sender<Arc<Notification>>,
-    tests: TestDag,
+    // Test cases whose tests have rerun_every, once their results get too old.
+    expired_tx: broadcast::Sender<TestCaseId>,
+    tests: Mutex<Arc<TestDag>>,
+    // The commits from the last set_revisions.
+    commits: Mutex<Vec<Commit>>,
+    // Set if the tests come from the config in each commit instead of from
+    // tests.
+    commit_config: Mutex<Option<Arc<CommitConfigParser>>>,
+    // Tests loaded from commit_config for the commits we're currently testing.
+    commit_tests: Mutex<HashMap<CommitHash, Arc<TestDag>>>,
     // Pools contains sets of intangible arbitrary "resources" that can be used to throttle test
     // jobs, and also tracks access to reused worktrees. The indices of the token-type resources
     // will be referenced by Test::needs_resource_idx values.
     resource_pools: Arc<Pools>,
+    services: Option<Arc<Services>>,
+    config_repos: Option<Arc<ConfigRepos>>,
     result_db: Arc<Database>,
     job_env: Arc<Vec<(String, String)>>,
+    affinity_cache: Arc<AffinityCache>,
 }
 
 // We need to specify 'static here. Just because we have an Arc over the
@@ -233,26 +420,70 @@ impl<W: Worktree + Sync + Send + 'static> Manager<W> {
         // TODO: If this capacity gets exhausted, data gets lost and we get an error which this code
         // probably doesn't handle very gracefully. We should instead just block the sender.
         let (result_tx, _) = broadcast::channel(4096);
+        let (expired_tx, _) = broadcast::channel(4096);
         Self {
             job_env: Arc::new(base_job_env(repo.path())),
             repo,
             notif_tx: result_tx,
+            expired_tx,
             job_cts: Mutex::new(HashMap::new()),
             job_counter: JobCounter::new(),
-            tests,
+            tests: Mutex::new(Arc::new(tests)),
+            commits: Mutex::new(Vec::new()),
+            commit_config: Mutex::new(None),
+            commit_tests: Mutex::new(HashMap::new()),
             resource_pools,
+            services: None,
+            config_repos: None,
             result_db,
+            affinity_cache: Arc::new(AffinityCache::default()),
         }
     }
 
+    // Have the jobs start services for the tests that need them.
+    pub fn with_services(mut self, services: Arc<Services>) -> Self {
+        self.services = Some(services);
+        self
+    }
+
+    // If set, test each commit with the tests from its own config, instead of
+    // the tests passed to new.
+    pub fn with_commit_config(mut self, commit_config: Option<Arc<CommitConfigParser>>) -> Self {
+        *self.commit_config.get_mut() = commit_config;
+        self
+    }
+
+    // Have the jobs check out the config repos their tests use.
+    pub fn with_config_repos(mut self, config_repos: Arc<ConfigRepos>) -> Self {
+        self.config_repos = Some(config_repos);
+        self
+    }
+
     fn spawn_job(&self, job: TestJob) {
         job.notifier.notify(&TestStatus::Enqueued);
 
         let pools = self.resource_pools.clone();
         let origin_worktree = self.repo.clone();
         let db = self.result_db.clone();
+        let ct = job.ct.clone();
+        let id = job.test_case.id();
+        let rerun_every = job.test_case.test.rerun_every;
+        let expired_tx = self.expired_tx.clone();
         tokio::spawn(async move {
-            let _ = job.run(db, &pools, origin_worktree.path()).await;
+            let outcome = job.run(db, &pools, origin_worktree.path()).await;
+            let (Some(rerun_every), Ok(db_entry)) = (rerun_every, outcome) else {
+                return;
+            };
+            let wait = rerun_every.saturating_sub(db_entry.age().unwrap_or(Duration::ZERO));
+            // Holding onto the entry would stop it from being re-run.
+            drop(db_entry);
+            select! {
+                _ = ct.cancelled() => (),
+                _ = sleep(wait) => {
+                    // Failure means nobody is listening, so they don't want re-runs.
+                    let _ = expired_tx.send(id);
+                }
+            }
         });
     }
 
@@ -265,57 +496,169 @@ impl<W: Worktree + Sync + Send + 'static> Manager<W> {
         I: IntoIterator<Item = R>,
         R: Into<CommitHash> + Debug,
     {
-        // Build the set test cases we need to kick off.
-        // TODO: this is kinda silly, TestCase::new looks up the tree from the
-        // hash for every indiviual test, we only need to do that at most once
-        // per revision. But doing this efficiently makes for code that's
-        // probably more complex than necessary.
-        let commits = try_join_all(revs.into_iter().map(|rev| {
-            let commit_hash = rev.into();
-            self.repo
-                .rev_parse(commit_hash.clone())
-                .map(move |result| result?.ok_or(anyhow!("no such revision {commit_hash:?}")))
-        }))
-        .await?;
+        let commits = stream::iter(revs)
+            .map(|rev| {
+                let commit_hash = rev.into();
+                self.repo
+                    .rev_parse(commit_hash.clone())
+                    .map(move |result| result?.ok_or(anyhow!("no such revision {commit_hash:?}")))
+            })
+            .buffered(MAX_GIT_LOOKUPS)
+            .try_collect()
+            .await?;
 
-        self.set_commits(commits)
+        self.test_commits(commits).await
     }
 
-    // Inner non-async helper for set_revisions.
-    pub fn set_commits(&self, commits: impl IntoIterator<Item = Commit>) -> anyhow::Result<()> {
-        let mut job_cts = self.job_cts.lock();
+    // Replace the tests, for example because their inputs have changed. Jobs
+    // for tests whose config hash has changed are restarted. If the tests come
+    // from the commits, they get reloaded with the new commit_config.
+    pub async fn set_tests(
+        &self,
+        tests: TestDag,
+        commit_config: Option<Arc<CommitConfigParser>>,
+    ) -> anyhow::Result<()> {
+        *self.tests.lock() = Arc::new(tests);
+        *self.commit_config.lock() = commit_config;
+        self.commit_tests.lock().clear();
+        let commits = self.commits.lock().clone();
+        self.test_commits(commits).await
+    }
+
+    // The input globs of all the tests we're currently running.
+    pub fn input_patterns(&self) -> HashSet<String> {
+        let commit_tests = self.commit_tests.lock();
+        let tests = self.tests.lock();
+        commit_tests
+            .values()
+            .chain([&*tests])
+            .flat_map(|dag| dag.nodes())
+            .flat_map(|test| test.inputs.iter().cloned())
+            .collect()
+    }
 
-        let test_cases: HashMap<TestCaseId, TestCase> = commits
-            .into_iter()
-            .cartesian_product(self.tests.nodes())
-            .map(|(commit, test)| {
-                let tc = TestCase::new(commit, test.clone());
-                (tc.id(), tc)
+    async fn test_commits(&self, commits: Vec<Commit>) -> anyhow::Result<()> {
+        self.affinity_cache.retain(&commits);
+        *self.commits.lock() = commits.clone();
+        let commit_config = self.commit_config.lock().clone();
+        let commits = match commit_config {
+            Some(commit_config) => self.load_commit_tests(&commit_config, commits).await,
+            None => {
+                let tests = self.tests.lock().clone();
+                commits.into_iter().map(|c| (c, tests.clone())).collect()
+            }
+        };
+        // Tests that cache by paths often share them, so look up each path once
+        // per commit instead of once per test.
+        let lookups: HashSet<(CommitHash, String)> = commits
+            .iter()
+            .flat_map(|(commit, tests)| {
+                tests.nodes().flat_map(|test| match &test.cache_policy {
+                    CachePolicy::ByPaths(paths) => paths
+                        .iter()
+                        .map(|path| (commit.hash.clone(), path.clone()))
+                        .collect(),
+                    _ => Vec::new(),
+                })
             })
             .collect();
-
-        // Cancel jobs for test cases that we don't care about any more.
-        // https://github.com/rust-lang/rust/issues/59618 would make this more convenient.
-        *job_cts = job_cts
-            .drain()
-            .filter(|(id, cancellation_token)| {
-                if !test_cases.contains_key(id) {
-                    cancellation_token.cancel();
-                    return false;
-                }
-                true
+        let path_objects: HashMap<(CommitHash, String), Option<Hash>> = stream::iter(lookups)
+            .map(|(commit_hash, path)| async move {
+                let object = self
+                    .repo
+                    .path_object(&commit_hash, &path)
+                    .await
+                    .with_context(|| format!("looking up paths in {commit_hash}"))?;
+                anyhow::Ok(((commit_hash, path), object))
             })
-            .collect::<HashMap<_, _>>();
+            .buffer_unordered(MAX_GIT_LOOKUPS)
+            .try_collect()
+            .await?;
+        // Each commit is tested with the tests it's paired with.
+        let test_cases = commits
+            .iter()
+            .flat_map(|(commit, tests)| {
+                tests.nodes().map(|test| {
+                    TestCase::with_path_objects(commit.clone(), test.clone(), &path_objects)
+                })
+            })
+            .collect();
+        self.set_test_cases(test_cases)
+    }
 
-        // Don't start new jobs for test cases that are already running
-        let test_cases = test_cases.into_iter().filter_map(|(tc_id, tc)| {
-            if job_cts.contains_key(&tc_id) {
-                None
-            } else {
-                Some(tc)
+    // Get the tests for each commit from its own config. The configs can't
+    // change so we hang onto them for as long as the commits are being tested.
+    async fn load_commit_tests(
+        &self,
+        commit_config: &CommitConfigParser,
+        commits: Vec<Commit>,
+    ) -> Vec<(Commit, Arc<TestDag>)> {
+        let cached = self.commit_tests.lock().clone();
+        let commits = join_all(commits.into_iter().map(|commit| async {
+            if let Some(tests) = cached.get(&commit.hash) {
+                return (commit, tests.clone());
             }
+            let tests = commit_config
+                .load(self.repo.as_ref(), &commit.hash)
+                .await
+                .unwrap_or_else(|e| {
+                    // Not much we can do other than skip the commit.
+                    error!("Couldn't load config for {}: {e:#}", commit.hash);
+                    Dag::empty()
+                });
+            (commit, Arc::new(tests))
+        }))
+        .await;
+        *self.commit_tests.lock() = commits
+            .iter()
+            .map(|(c, tests)| (c.hash.clone(), tests.clone()))
+            .collect();
+        commits
+    }
+
+    // Inner non-async helper for set_revisions.
+    fn set_test_cases(&self, test_cases: Vec<TestCase>) -> anyhow::Result<()> {
+        let mut job_cts = self.job_cts.lock();
+
+        let mut test_cases: HashMap<TestCaseId, TestCase> =
+            test_cases.into_iter().map(|tc| (tc.id(), tc)).collect();
+
+        // Cancel jobs for test cases that we don't care about any more, or
+        // whose test config has changed.
+        job_cts.retain(|id, (cancellation_token, config_hash)| {
+            let keep = test_cases
+                .get(id)
+                .is_some_and(|tc| tc.test.config_hash == *config_hash);
+            if !keep {
+                cancellation_token.cancel();
+            }
+            keep
         });
 
+        // Don't start new jobs for test cases that are already running. But
+        // new jobs can only wait for dependency jobs that are started along
+        // with them, so if one of those is already running it has to be
+        // restarted. If it had already finished, it will just find its result
+        // in the database.
+        let mut to_start: Vec<TestCaseId> = test_cases
+            .keys()
+            .filter(|id| !job_cts.contains_key(id))
+            .cloned()
+            .collect();
+        let mut i = 0;
+        while i < to_start.len() {
+            for dep_id in test_cases[&to_start[i]].child_ids() {
+                if let Some((cancellation_token, _)) = job_cts.remove(dep_id.borrow()) {
+                    cancellation_token.cancel();
+                    to_start.push(dep_id.borrow().clone());
+                }
+            }
+            i += 1;
+        }
+        let test_cases = to_start
+            .into_iter()
+            .map(|id| test_cases.remove(&id).unwrap());
+
         // Build the jobs. We do this bottom-up so that depending jobs can refer
         // to the notifier of the jobs they depend on (which we can therefore
         // trust has been constructed already).
@@ -344,6 +687,9 @@ impl<W: Worktree + Sync + Send + 'static> Manager<W> {
                 )
                 .with_token(self.job_counter.get())
                 .with_global_notif(self.notif_tx.clone())
+                .with_services(self.services.clone())
+                .with_config_repos(self.config_repos.clone())
+                .with_affinity_cache(self.affinity_cache.clone())
                 .build();
                 jobs.insert(test_case.id(), job);
                 Ok(jobs)
@@ -351,12 +697,27 @@ impl<W: Worktree + Sync + Send + 'static> Manager<W> {
         )?;
 
         for (tc_id, job) in jobs.into_iter() {
-            job_cts.insert(tc_id.clone(), job.ct.clone());
+            job_cts.insert(
+                tc_id.clone(),
+                (job.ct.clone(), job.test_case.test.config_hash.clone()),
+            );
             self.spawn_job(job);
         }
         Ok(())
     }
 
+    // Re-run a test case whose result has expired (see expired), if we're
+    // still testing it.
+    pub async fn rerun(&self, id: &TestCaseId) -> anyhow::Result<()> {
+        if self.job_cts.lock().remove(id).is_none() {
+            return Ok(());
+        }
+        // The job has finished so there's nothing to cancel, and when it's
+        // restarted the database lookup will find that the result is too old.
+        let commits = self.commits.lock().clone();
+        self.test_commits(commits).await
+    }
+
     pub async fn cancel_running(&self) -> anyhow::Result<()> {
         self.set_revisions::<_, CommitHash>([]).await
     }
@@ -369,6 +730,17 @@ impl<W: Worktree + Sync + Send + 'static> Manager<W> {
         self.notif_tx.subscribe()
     }
 
+    // Streams test cases whose tests have rerun_every once their result is
+    // old enough to be re-run, pass them to rerun. Like results, you need to
+    // call this before the results get produced.
+    pub fn expired(&self) -> broadcast::Receiver<TestCaseId> {
+        self.expired_tx.subscribe()
+    }
+
+    pub fn result_db(&self) -> &Database {
+        &self.result_db
+    }
+
     // Completes once there are no pending jobs or results.
     pub async fn settled(&self) {
         self.job_counter.zero().await;
@@ -518,6 +890,9 @@ pub struct TestJobBuilder {
     env: Arc<Vec<(String, String)>>,
     wait_for: Vec<(TestName, broadcast::Receiver<TestOutcome>)>,
     global_tx: Option<broadcast::Sender<Arc<Notification>>>,
+    services: Option<Arc<Services>>,
+    config_repos: Option<Arc<ConfigRepos>>,
+    affinity_cache: Option<Arc<AffinityCache>>,
 }
 
 impl TestJobBuilder {
@@ -536,6 +911,9 @@ impl TestJobBuilder {
             wait_for,
             token: None,
             global_tx: None,
+            services: None,
+            config_repos: None,
+            affinity_cache: None,
         }
     }
 
@@ -552,9 +930,32 @@ impl TestJobBuilder {
         self
     }
 
+    // Have this job start the services its test needs. If the test needs
+    // services and this isn't set, the job fails.
+    pub fn with_services(mut self, services: Option<Arc<Services>>) -> Self {
+        self.services = services;
+        self
+    }
+
+    // Have this job check out the config repos its test uses. If the test
+    // uses config repos and this isn't set, the job fails.
+    pub fn with_config_repos(mut self, config_repos: Option<Arc<ConfigRepos>>) -> Self {
+        self.config_repos = config_repos;
+        self
+    }
+
+    // Share worktree affinity lookups with other jobs.
+    fn with_affinity_cache(mut self, affinity_cache: Arc<AffinityCache>) -> Self {
+        self.affinity_cache = Some(affinity_cache);
+        self
+    }
+
     pub fn build(self) -> TestJob {
         TestJob {
             ct: self.ct,
+            services: self.services,
+            config_repos: self.config_repos,
+            affinity_cache: self.affinity_cache,
             test_case: self.test_case.clone(),
             _token: self.token,
             base_env: self.env,
@@ -579,6 +980,9 @@ pub struct TestJob {
     // is unsuccessful it should abort.
     wait_for: Vec<(TestName, broadcast::Receiver<TestOutcome>)>,
     notifier: TestStatusNotifier,
+    services: Option<Arc<Services>>,
+    config_repos: Option<Arc<ConfigRepos>>,
+    affinity_cache: Option<Arc<AffinityCache>>,
 }
 
 pub type DepDatabaseEntries = HashMap<TestName, Arc<DatabaseEntry>>;
@@ -632,29 +1036,155 @@ impl<'a> TestJob {
             LookupResult::YouRunIt(output) => output,
         };
 
-        select! {
-            // This "biased" is here because otherwise when we cancel a bunch of jobs all at once,
-            // and some of those jobs are blocking on resources held by others,
-            // we want the former jobs to observe their own cancellation before
-            // they see the resources get freed up by the latter. I don't think
-            // this totally eliminates that case, which probably means tests
-            // will be flaky. Not sure what to do about that.
+        self.run_steps(pools, origin_worktree_path, true, output, &dep_db_entries)
+            .await
+    }
+
+    // Run each step of the test in turn, getting each one's resources from
+    // the pools separately, then store the result. If use_worktrees is false,
+    // steps run directly in origin_worktree_path even if they'd normally get a
+    // worktree.
+    async fn run_steps(
+        &self,
+        pools: &Pools,
+        origin_worktree_path: &Path,
+        use_worktrees: bool,
+        output: DatabaseOutput,
+        dep_db_entries: &DepDatabaseEntries,
+    ) -> TestOutcome {
+        // The config repo checkouts are shared by all the steps.
+        let checkouts = select! {
             biased;
 
-            _ = self.ct.cancelled() => Err(TestInconclusive::Canceled),
-            resources = pools.get(self.test_case.test.needs_resources.clone()) =>  {
-                self.notifier.notify(&TestStatus::Started);
-                if let Some(worktrees) = resources.resources(&ResourceKey::Worktree) {
-                    // We "own" this worktree.
-                    let worktree = worktrees[0].as_worktree();
-                    worktree.checkout(&self.test_case.commit_hash).await.context("failed to check out revision")?;
-                    self.execute_child(worktree.path(), &resources, output, dep_db_entries).await
-                } else {
-                    // We don't "own" the "main" worktree so the job shouldn't mess with it.
-                    self.execute_child(origin_worktree_path, &resources, output, dep_db_entries).await
+            _ = self.ct.cancelled() => return Err(TestInconclusive::Canceled),
+            checkouts = self.config_repo_checkouts() => checkouts?,
+        };
+        let config_repo_env: JobEnv = checkouts
+            .iter()
+            .map(|(name, checkout)| {
+                (
+                    format!("LIMMAT_CONFIG_REPO_{name}"),
+                    checkout.path().to_string_lossy().into_owned(),
+                )
+            })
+            .collect();
+        let outcome = self
+            .run_steps_with_env(
+                pools,
+                origin_worktree_path,
+                use_worktrees,
+                output,
+                dep_db_entries,
+                &config_repo_env,
+            )
+            .await;
+        join_all(checkouts.into_iter().map(|(_, c)| c.cleanup())).await;
+        outcome
+    }
+
+    async fn run_steps_with_env(
+        &self,
+        pools: &Pools,
+        origin_worktree_path: &Path,
+        use_worktrees: bool,
+        mut output: DatabaseOutput,
+        dep_db_entries: &DepDatabaseEntries,
+        config_repo_env: &JobEnv,
+    ) -> TestOutcome {
+        let result = self
+            .execute_steps(
+                pools,
+                origin_worktree_path,
+                use_worktrees,
+                &mut output,
+                dep_db_entries,
+                config_repo_env,
+            )
+            .await;
+        match result {
+            Ok(result) => Ok(Arc::new(output.set_result(&result).await?)),
+            Err(TestInconclusive::Error(msg)) => {
+                output.set_error(&msg).or_log_error("recording test error");
+                Err(TestInconclusive::Error(msg))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn execute_steps(
+        &self,
+        pools: &Pools,
+        origin_worktree_path: &Path,
+        use_worktrees: bool,
+        output: &mut DatabaseOutput,
+        dep_db_entries: &DepDatabaseEntries,
+        config_repo_env: &JobEnv,
+    ) -> Result<TestResult, TestInconclusive> {
+        let test = self.test_case.test.clone();
+        let mut result = TestResult {
+            exit_code: 0,
+            failed_step: None,
+        };
+        for step in &test.steps {
+            let mut needs_resources = step.needs_resources.clone();
+            if !use_worktrees {
+                needs_resources.retain(|key, _| !key.is_worktree());
+            }
+            let commit_hash = &self.test_case.commit_hash;
+            let affinity = match &self.affinity_cache {
+                _ if !use_worktrees || step.worktree_pool().is_none() => Affinity::default(),
+                Some(cache) => cache.get(origin_worktree_path, commit_hash).await,
+                None => Arc::new(worktree_affinity(origin_worktree_path, commit_hash).await),
+            };
+
+            let exit_code = select! {
+                // This "biased" is here because otherwise when we cancel a bunch of jobs all at once,
+                // and some of those jobs are blocking on resources held by others,
+                // we want the former jobs to observe their own cancellation before
+                // they see the resources get freed up by the latter. I don't think
+                // this totally eliminates that case, which probably means tests
+                // will be flaky. Not sure what to do about that.
+                biased;
+
+                _ = self.ct.cancelled() => return Err(TestInconclusive::Canceled),
+                resources = pools.get_with_affinity(needs_resources, &affinity) =>  {
+                    let resources = resources.context("getting resources")?;
+                    self.notifier.notify(&match &step.name {
+                        Some(name) => TestStatus::StepStarted(name.clone()),
+                        None => TestStatus::Started,
+                    });
+                    let worktree = resources.worktree().map(|(_, w)| w);
+                    if let Some(worktree) = worktree {
+                        // We "own" this worktree.
+                        worktree.checkout(&self.test_case.commit_hash).await.context("failed to check out revision")?;
+                    }
+                    // If we don't have a worktree, run in the "main" worktree.
+                    // We don't "own" that so the job shouldn't mess with it.
+                    let current_dir = worktree.map_or(origin_worktree_path, |w| w.path());
+                    // When not using worktrees, per-worktree services get
+                    // the main worktree instead.
+                    let service_worktree = if use_worktrees { worktree.map(|w| w.path()) } else { Some(current_dir) };
+                    let service_env = select! {
+                        biased;
+
+                        _ = self.ct.cancelled() => return Err(TestInconclusive::Canceled),
+                        env = self.service_env(origin_worktree_path, service_worktree) => env?,
+                    };
+                    let extra_env: JobEnv = config_repo_env.iter().cloned().chain(service_env).collect();
+                    self.execute_step(step, current_dir, &resources, &extra_env, output, dep_db_entries).await?
                 }
+            };
+            // The resources were dropped at the end of the select, so the next
+            // step doesn't hold onto them.
+            if exit_code != 0 {
+                result = TestResult {
+                    exit_code,
+                    failed_step: step.name.clone(),
+                };
+                break;
             }
         }
+        Ok(result)
     }
 
     // Blocks until all dependency jobs have succeeded and returns all the
@@ -701,12 +1231,20 @@ impl<'a> TestJob {
     fn set_env(
         &self,
         cmd: &mut Command,
+        step: &TestStep,
         resources: &Resources<'a>,
         artifacts_dir: &Path,
         dep_db_entries: &DepDatabaseEntries,
     ) {
         cmd.env("LIMMAT_COMMIT", &self.test_case.commit_hash);
         cmd.env("LIMMAT_ARTIFACTS", artifacts_dir);
+        if let Some(name) = &step.name {
+            cmd.env("LIMMAT_STEP", name);
+        }
+        if let Some((pool, worktree)) = resources.worktree() {
+            cmd.env("LIMMAT_WORKTREE_ID", worktree.id().to_string());
+            cmd.env("LIMMAT_WORKTREE_POOL", pool);
+        }
         for (k, v) in self.base_env.iter() {
             cmd.env(k, v);
         }
@@ -727,21 +1265,96 @@ impl<'a> TestJob {
         }
     }
 
-    // The core part of the job - runs the actual process and returns its result.
-    async fn execute_child(
-        &mut self,
+    // Make sure the services the test needs are running, and return the
+    // environment variables for connecting to them.
+    async fn service_env(
+        &self,
+        origin_worktree_path: &Path,
+        worktree: Option<&Path>,
+    ) -> anyhow::Result<JobEnv> {
+        let test = &self.test_case.test;
+        if test.services.is_empty() {
+            return Ok(Vec::new());
+        }
+        let services = self
+            .services
+            .as_ref()
+            .ok_or_else(|| anyhow!("test needs services but none are available"))?;
+        let mut env = Vec::new();
+        for name in &test.services {
+            let port = services
+                .ensure(name, origin_worktree_path, worktree)
+                .await
+                .with_context(|| format!("starting service {name:?}"))?;
+            env.push((format!("LIMMAT_SERVICE_{name}_PORT"), port.to_string()));
+        }
+        Ok(env)
+    }
+
+    // Check out the config repos the test needs, returning each one with its
+    // name.
+    async fn config_repo_checkouts(&self) -> anyhow::Result<Vec<(String, ConfigRepoCheckout)>> {
+        let test = &self.test_case.test;
+        if test.config_repos.is_empty() {
+            return Ok(Vec::new());
+        }
+        let config_repos = self
+            .config_repos
+            .as_ref()
+            .ok_or_else(|| anyhow!("test needs config repos but none are available"))?;
+        let mut checkouts = Vec::new();
+        for (name, commit) in &test.config_repos {
+            match config_repos.checkout(&self.ct, name, commit).await {
+                Ok(checkout) => checkouts.push((name.clone(), checkout)),
+                Err(e) => {
+                    join_all(checkouts.into_iter().map(|(_, c)| c.cleanup())).await;
+                    return Err(e);
+                }
+            }
+        }
+        Ok(checkouts)
+    }
+
+    // The core part of the job - runs the actual process for a step and
+    // returns its exit code. extra_env is set on top of the usual
+    // environment.
+    async fn execute_step(
+        &self,
+        step: &TestStep,
         current_dir: &Path,
         resources: &Resources<'a>,
-        mut output: DatabaseOutput,
-        dep_db_entries: DepDatabaseEntries,
-    ) -> TestOutcome {
-        info!("Starting {:?}", self.test_case);
+        extra_env: &JobEnv,
+        output: &mut DatabaseOutput,
+        dep_db_entries: &DepDatabaseEntries,
+    ) -> Result<ExitCode, TestInconclusive> {
+        info!("Starting {:?} (step {:?})", self.test_case, step.name);
 
-        let mut cmd = self.test_case.test.command();
+        let step_name = step.name.as_deref();
+        let mut cmd = step.command();
         cmd.current_dir(current_dir)
-            .stdout(output.stdout().context("no stdout handle available")?)
-            .stderr(output.stderr().context("no stdout handle available")?);
-        self.set_env(&mut cmd, resources, output.artifacts_dir(), &dep_db_entries);
+            .stdout(
+                output
+                    .stdout(step_name)
+                    .context("no stdout handle available")?,
+            )
+            .stderr(
+                output
+                    .stderr(step_name)
+                    .context("no stdout handle available")?,
+            );
+        self.set_env(
+            &mut cmd,
+            step,
+            resources,
+            output.artifacts_dir(),
+            dep_db_entries,
+        );
+        cmd.envs(extra_env.iter().cloned());
+        output.start_step(
+            step_name,
+            resources.worktree().map(|(_, w)| w.path()),
+            resources.tokens().into_iter().collect(),
+        )?;
         // It would be really confusing and annoying if we exited this function
         // without ensuring the child is dead. So we wrap it in this sketchy
         // drop guard thing.
@@ -763,10 +1376,12 @@ impl<'a> TestJob {
             // Test completed, figure out the result. I think maybe a true Rustacean would
             // write this block as a single chain of methods? But it seems ridiculous to me.
             {
-                let exit_code = wait_result.context("awaiting child")?.code_not_killed()?;
-                Ok(Arc::new(
-                    output.set_result(&TestResult { exit_code }).await?,
-                ))
+                let status = wait_result.context("awaiting child")?;
+                output.finish_step(status.signal());
+                Ok(status.code_not_killed().with_context(|| match step_name {
+                    Some(name) => format!("step {name}"),
+                    None => "test command".to_owned(),
+                })?)
             }
             Either::Right((_, child_fut)) => {
                 // Canceled. Shut down the process if necessary.
@@ -798,20 +1413,20 @@ impl<'a> TestJob {
         }
     }
 
-    // This is a specialised entry point for when you already have the necessary
-    // resources from the pools and you need direct control over where the job
-    // runs and where its output goes. This API is wack and I hate it but I
-    // can't quite seem to figure out the right design in snatched moments on
-    // weeknights.
+    // This is a specialised entry point for when you need direct control over
+    // where the job runs and where its output goes. The steps don't get
+    // worktrees, they all run in current_dir. This API is wack and I hate it
+    // but I can't quite seem to figure out the right design in snatched
+    // moments on weeknights.
     pub async fn run_with(
-        mut self,
+        self,
         current_dir: &Path,
-        resources: &Resources<'a>,
+        pools: &Pools,
         output: DatabaseOutput,
         dep_db_entries: DepDatabaseEntries,
     ) -> TestOutcome {
         let outcome = self
-            .execute_child(current_dir, resources, output, dep_db_entries)
+            .run_steps(pools, current_dir, false, output, &dep_db_entries)
             .await;
         self.notifier.notify_completion(outcome.clone());
         outcome
@@ -855,9 +1470,35 @@ impl Debug for TestCase {
 }
 
 impl TestCase {
-    pub fn new(commit: Commit, test: Arc<Test>) -> Self {
+    // The repo is needed to work out the cache hash for some cache policies.
+    pub async fn new(
+        repo: &(impl Worktree + ?Sized),
+        commit: Commit,
+        test: Arc<Test>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            cache_hash: test.cache_policy.cache_hash(repo, &commit).await?,
+            test,
+            commit_hash: commit.hash,
+        })
+    }
+
+    // Like new, but the paths for CachePolicy::ByPaths have already been looked
+    // up. path_objects must have an entry for each of them.
+    fn with_path_objects(
+        commit: Commit,
+        test: Arc<Test>,
+        path_objects: &HashMap<(CommitHash, String), Option<Hash>>,
+    ) -> Self {
+        let objects = match &test.cache_policy {
+            CachePolicy::ByPaths(paths) => paths
+                .iter()
+                .map(|path| path_objects[&(commit.hash.clone(), path.clone())].clone())
+                .collect(),
+            _ => Vec::new(),
+        };
         Self {
-            cache_hash: test.cache_policy.cache_hash(&commit),
+            cache_hash: test.cache_policy.cache_hash_with_objects(&commit, objects),
             test,
             commit_hash: commit.hash,
         }
@@ -872,7 +1513,7 @@ impl TestCase {
 
     // TODO: this is always getting built on-demand all over the place, it
     // doesn't really need to be.
-    fn id(&self) -> TestCaseId {
+    pub fn id(&self) -> TestCaseId {
         // The hash_cache is redundant information here so we don't need to include it.
         TestCaseId::new(&self.commit_hash, &self.test.name)
     }
@@ -900,6 +1541,8 @@ pub type ExitCode = i32;
 pub enum TestStatus {
     Enqueued,
     Started,
+    // A named step of the test started.
+    StepStarted(String),
     Finished(TestOutcome),
 }
 
@@ -908,6 +1551,7 @@ impl Display for TestStatus {
         match self {
             Self::Enqueued => write!(f, "Enqueued"),
             Self::Started => write!(f, "Started"),
+            Self::StepStarted(name) => write!(f, "Running {name}"),
             Self::Finished(Err(inconclusive)) => write!(f, "{}", inconclusive),
             Self::Finished(Ok(db_entry)) => write!(f, "{}", db_entry.result()),
         }
@@ -947,11 +1591,18 @@ pub struct TestResult {
     // Note this is called "exit_code" instead of "return_code" because it really
     // only gets set when the child process exits.
     pub exit_code: ExitCode,
+    // If a named step failed, this is it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failed_step: Option<String>,
 }
 
 impl Display for TestResult {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "exit code {}", self.exit_code)
+        write!(f, "exit code {}", self.exit_code)?;
+        if let Some(step) = &self.failed_step {
+            write!(f, " (step {step})")?;
+        }
+        Ok(())
     }
 }
 
@@ -989,15 +1640,20 @@ mod tests {
     use test_case::test_case;
     use tokio::{
         select,
-        time::{sleep, sleep_until, Instant},
+        time::{sleep, sleep_until, timeout, Instant},
     };
 
     use crate::{
+        config::ParsedConfig,
+        config_repo::{CheckoutMode, ConfigRepoConfig},
+        database::{Backend, Explanation},
         git::{
             test_utils::{TempRepo, WorktreeExt},
             CommitHash, TempWorktree,
         },
+        process::CommandExt as _,
         resource::Resource,
+        service::{ServiceConfig, ServiceScope},
         test_utils::{path_exists, timeout_5s},
     };
 
@@ -1148,7 +1804,7 @@ mod tests {
         }
 
         // Blocks until the script is started for the given commit hash.
-        pub async fn started(&self, hash: &CommitHash) -> StartedTestScript {
+        pub async fn started(&self, hash: &CommitHash) -> StartedTestScript<'_> {
             let pid_path = self.signalling_path(Self::PID_FILENAME_PREFIX, hash);
             path_exists(&pid_path).await;
             let content = fs::read_to_string(pid_path).expect("couldn't read PID file");
@@ -1183,16 +1839,25 @@ mod tests {
         ) -> Test {
             Test {
                 name: self.test_name.clone(),
-                program: self.program(),
-                args: self.args(),
-                needs_resources: if needs_worktree {
-                    [(ResourceKey::Worktree, 1)].into()
-                } else {
-                    [].into()
-                },
+                steps: vec![TestStep::single(
+                    self.program(),
+                    self.args(),
+                    if needs_worktree {
+                        [(ResourceKey::default_worktree(), 1)].into()
+                    } else {
+                        [].into()
+                    },
+                )],
+                services: vec![],
+                inputs: vec![],
+                config_repos: vec![],
                 shutdown_grace_period: Duration::from_secs(5),
                 cache_policy,
                 config_hash: vec![0],
+                canonical_config: serde_json::Value::Null,
+                share_results: false,
+                cache_ttl: None,
+                rerun_every: None,
                 depends_on: depends_on.into_iter().collect(),
             }
         }
@@ -1452,21 +2117,23 @@ mod tests {
                 &self.cache_policies,
                 &self.needs_worktree
             )
-            .map(|(i, script, &cache_policy, &needs_worktree)| {
+            .map(|(i, script, cache_policy, &needs_worktree)| {
                 let dep_names = self
                     .dependencies
                     .iter()
                     .filter(|(from_idx, _)| *from_idx == i)
                     .map(|(_, to_idx)| TestName::new(format!("test_{to_idx}")));
-                script.as_test(cache_policy, needs_worktree, dep_names)
+                script.as_test(cache_policy.clone(), needs_worktree, dep_names)
             });
             let manager = Manager::new(
                 repo.clone(),
                 Arc::new(
-                    Database::create_or_open(db_dir.path()).expect("couldn't setup result DB"),
+                    Database::create_or_open(db_dir.path(), "repo", Backend::Directory)
+                        .await
+                        .expect("couldn't setup result DB"),
                 ),
                 Arc::new(Pools::new([(
-                    ResourceKey::Worktree,
+                    ResourceKey::default_worktree(),
                     worktree_resources(&repo, self.num_worktrees).await,
                 )])),
                 Dag::new(tests.map(Arc::new)).expect("couldn't build test DAG"),
@@ -1493,16 +2160,18 @@ mod tests {
 
         // Convenience helper to construct a TestCase referring to this fixture's configuration.
         // yes this function is O(test_idx). you got a porblem with that?? is that a porblem?
-        fn test_case(&self, commit: impl Borrow<Commit>, test_idx: usize) -> TestCase {
-            TestCase::new(
-                commit.borrow().to_owned(),
-                self.manager
-                    .tests
-                    .nodes()
-                    .nth(test_idx)
-                    .expect("bad test idx")
-                    .clone(),
-            )
+        async fn test_case(&self, commit: impl Borrow<Commit>, test_idx: usize) -> TestCase {
+            let test = self
+                .manager
+                .tests
+                .lock()
+                .nodes()
+                .nth(test_idx)
+                .expect("bad test idx")
+                .clone();
+            TestCase::new(self.repo.as_ref(), commit.borrow().to_owned(), test)
+                .await
+                .unwrap()
         }
     }
 
@@ -1517,6 +2186,73 @@ mod tests {
         }
     }
 
+    // A test with a single bash command that doesn't need any resources.
+    fn bash_test(name: &str, script: impl Into<OsString>) -> Test {
+        Test {
+            name: TestName::new(name),
+            steps: vec![TestStep::single(
+                "bash".into(),
+                vec!["-c".into(), script.into()],
+                [].into(),
+            )],
+            ..Test::arbitrary()
+        }
+    }
+
+    // For tests that define their own Tests instead of using TestScript. The
+    // result database lives in the returned TempDir.
+    async fn new_manager(
+        repo: &Arc<TempRepo>,
+        pools: Pools,
+        tests: TestDag,
+    ) -> (Manager<TempRepo>, TempDir) {
+        let db_dir = TempDir::with_prefix("result-db-").unwrap();
+        let db = Database::create_or_open(db_dir.path(), "repo", Backend::Directory)
+            .await
+            .expect("couldn't setup result DB");
+        let m = Manager::new(repo.clone(), Arc::new(db), Arc::new(pools), tests);
+        (m, db_dir)
+    }
+
+    // The results of the tests that have finished, per the notifications
+    // received so far.
+    fn finished_results(
+        results: &mut broadcast::Receiver<Arc<Notification>>,
+    ) -> Vec<(TestCase, Arc<DatabaseEntry>)> {
+        let mut finished = Vec::new();
+        while let Ok(notif) = results.try_recv() {
+            if let TestStatus::Finished(outcome) = &notif.status {
+                finished.push((notif.test_case.clone(), outcome.clone().unwrap()));
+            }
+        }
+        finished
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn should_cache_affinity() {
+        let repo = TempRepo::new().await.unwrap();
+        let commit1 = repo.commit("1").await.unwrap();
+        let commit2 = repo.commit("2").await.unwrap();
+        let cache = AffinityCache::default();
+
+        let affinity = cache.get(repo.path(), &commit2.hash).await;
+        assert_eq!(
+            *affinity,
+            HashMap::from([(commit2.hash.clone(), 0), (commit1.hash.clone(), 1)])
+        );
+        // Second time it doesn't get looked up again.
+        assert!(Arc::ptr_eq(
+            &affinity,
+            &cache.get(repo.path(), &commit2.hash).await
+        ));
+        // Until the commit isn't being tested any more.
+        cache.retain(&[commit1]);
+        assert!(!Arc::ptr_eq(
+            &affinity,
+            &cache.get(repo.path(), &commit2.hash).await
+        ));
+    }
+
     #[test_log::test(tokio::test)]
     async fn should_run_single() {
         let f = TestScriptFixture::builder().num_tests(1).build().await;
@@ -1531,7 +2267,7 @@ mod tests {
         expect_notifs_20s(
             &mut results,
             [(
-                f.test_case(&commit, 0),
+                f.test_case(&commit, 0).await,
                 vec![
                     TestStatusMatcher::Enqueued,
                     TestStatusMatcher::Started,
@@ -1590,7 +2326,7 @@ mod tests {
             // awu weh, weh mah
             [
                 (
-                    f.test_case(&commit1, 0),
+                    f.test_case(&commit1, 0).await,
                     vec![
                         TestStatusMatcher::Enqueued,
                         TestStatusMatcher::Started,
@@ -1599,7 +2335,7 @@ mod tests {
                     .into(),
                 ),
                 (
-                    f.test_case(&commit1, 1),
+                    f.test_case(&commit1, 1).await,
                     vec![
                         TestStatusMatcher::Enqueued,
                         TestStatusMatcher::Started,
@@ -1610,7 +2346,7 @@ mod tests {
                 // This isn't what we're testing here but we need to assert that it comes in so we can
                 // check below that nothing else comes in.
                 (
-                    f.test_case(&commit2, 0),
+                    f.test_case(&commit2, 0).await,
                     vec![
                         TestStatusMatcher::Enqueued,
                         TestStatusMatcher::Started,
@@ -1619,7 +2355,7 @@ mod tests {
                     .into(),
                 ),
                 (
-                    f.test_case(&commit2, 1),
+                    f.test_case(&commit2, 1).await,
                     vec![
                         TestStatusMatcher::Enqueued,
                         TestStatusMatcher::Started,
@@ -1744,7 +2480,7 @@ mod tests {
                 .expect("couldn't create test commit");
             for j in 0..num_tests {
                 want_results.push((
-                    f.test_case(&commit, j),
+                    f.test_case(&commit, j).await,
                     vec![
                         TestStatusMatcher::Enqueued,
                         TestStatusMatcher::Started,
@@ -1785,25 +2521,41 @@ mod tests {
         // And a test that requires one of those tokens.
         let tests = [Test {
             name: TestName::new("my_test"),
-            program: script.program(),
-            args: script.args(),
-            needs_resources: HashMap::from([
-                (ResourceKey::Worktree, 1),
-                (ResourceKey::UserToken("foo".into()), 1),
-            ]),
+            steps: vec![TestStep::single(
+                script.program(),
+                script.args(),
+                HashMap::from([
+                    (ResourceKey::default_worktree(), 1),
+                    (ResourceKey::UserToken("foo".into()), 1),
+                ]),
+            )],
+            services: vec![],
+            inputs: vec![],
+            config_repos: vec![],
             shutdown_grace_period: Duration::from_secs(5),
             cache_policy: CachePolicy::ByCommit,
             config_hash: vec![0],
+            canonical_config: serde_json::Value::Null,
+            share_results: false,
+            cache_ttl: None,
+            rerun_every: None,
             depends_on: vec![],
         }];
         let db_dir = TempDir::new().expect("couldn't make temp dir for result DB");
         let m = Manager::new(
             repo.clone(),
-            Arc::new(Database::create_or_open(db_dir.path()).expect("couldn't setup result DB")),
+            Arc::new(
+                Database::create_or_open(db_dir.path(), "repo", Backend::Directory)
+                    .await
+                    .expect("couldn't setup result DB"),
+            ),
             Arc::new(Pools::new(
-                [(ResourceKey::Worktree, worktree_resources(&repo, 4).await)]
-                    .into_iter()
-                    .chain(resource_tokens.into_iter()),
+                [(
+                    ResourceKey::default_worktree(),
+                    worktree_resources(&repo, 4).await,
+                )]
+                .into_iter()
+                .chain(resource_tokens.into_iter()),
             )),
             Dag::new(tests.map(Arc::new)).expect("couldn't build test DAG"),
         );
@@ -1847,82 +2599,116 @@ mod tests {
         let tests = Dag::new([
             Arc::new(Test {
                 name: TestName::new("dep"),
-                program: OsString::from("bash"),
-                args: vec!["-c".into(), OsString::from("true")],
-                needs_resources: [
-                    (ResourceKey::Worktree, 1),
-                    (ResourceKey::UserToken("my_resource".into()), 2),
-                ]
-                .into(),
+                steps: vec![TestStep::single(
+                    OsString::from("bash"),
+                    vec!["-c".into(), OsString::from("true")],
+                    [
+                        (ResourceKey::default_worktree(), 1),
+                        (ResourceKey::UserToken("my_resource".into()), 2),
+                    ]
+                    .into(),
+                )],
+                services: vec![],
+                inputs: vec![],
+                config_repos: vec![],
                 shutdown_grace_period: Duration::from_secs(5),
                 cache_policy: CachePolicy::ByCommit,
                 config_hash: vec![0],
+                canonical_config: serde_json::Value::Null,
+                share_results: false,
+                cache_ttl: None,
+                rerun_every: None,
                 depends_on: vec![],
             }),
             Arc::new(Test {
                 name: TestName::new("not_dep"),
-                program: OsString::from("bash"),
-                args: vec!["-c".into(), OsString::from("true")],
-                needs_resources: [
-                    (ResourceKey::Worktree, 1),
-                    (ResourceKey::UserToken("my_resource".into()), 2),
-                ]
-                .into(),
+                steps: vec![TestStep::single(
+                    OsString::from("bash"),
+                    vec!["-c".into(), OsString::from("true")],
+                    [
+                        (ResourceKey::default_worktree(), 1),
+                        (ResourceKey::UserToken("my_resource".into()), 2),
+                    ]
+                    .into(),
+                )],
+                services: vec![],
+                inputs: vec![],
+                config_repos: vec![],
                 shutdown_grace_period: Duration::from_secs(5),
                 cache_policy: CachePolicy::ByCommit,
                 config_hash: vec![0],
+                canonical_config: serde_json::Value::Null,
+                share_results: false,
+                cache_ttl: None,
+                rerun_every: None,
                 depends_on: vec![],
             }),
             Arc::new(Test {
                 name: TestName::new("my_test"),
-                program: OsString::from("bash"),
-                args: vec![
-                    "-c".into(),
-                    OsString::from(format!(
-                        "env >> {0:?}/${{LIMMAT_COMMIT}}_env.txt",
-                        temp_dir.path()
-                    )),
-                ],
-                needs_resources: [
-                    (ResourceKey::Worktree, 1),
-                    (ResourceKey::UserToken("my_resource".into()), 2),
-                ]
-                .into(),
+                steps: vec![TestStep::single(
+                    OsString::from("bash"),
+                    vec![
+                        "-c".into(),
+                        OsString::from(format!(
+                            "env >> {0:?}/${{LIMMAT_COMMIT}}_env.txt",
+                            temp_dir.path()
+                        )),
+                    ],
+                    [
+                        (ResourceKey::default_worktree(), 1),
+                        (ResourceKey::UserToken("my_resource".into()), 2),
+                    ]
+                    .into(),
+                )],
+                services: vec![],
+                inputs: vec![],
+                config_repos: vec![],
                 shutdown_grace_period: Duration::from_secs(5),
                 cache_policy: CachePolicy::ByCommit,
                 config_hash: vec![0],
+                canonical_config: serde_json::Value::Null,
+                share_results: false,
+                cache_ttl: None,
+                rerun_every: None,
                 depends_on: vec![TestName("dep".into())],
             }),
         ])
         .expect("couldn't build test DAG");
         let resource_pools = Pools::new(
-            [(ResourceKey::Worktree, worktree_resources(&repo, 1).await)]
-                .into_iter()
-                .chain(
-                    [
-                        (
-                            ResourceKey::UserToken("my_resource".into()),
-                            vec![
-                                Resource::UserToken("thing1".into()),
-                                Resource::UserToken("thing2".into()),
-                                Resource::UserToken("thing3".into()),
-                            ],
-                        ),
-                        (
-                            ResourceKey::UserToken("other_resource".into()),
-                            vec![
-                                Resource::UserToken("whing1".into()),
-                                Resource::UserToken("whing2".into()),
-                                Resource::UserToken("whing3".into()),
-                            ],
-                        ),
-                    ]
-                    .into_iter(),
-                ),
+            [(
+                ResourceKey::default_worktree(),
+                worktree_resources(&repo, 1).await,
+            )]
+            .into_iter()
+            .chain(
+                [
+                    (
+                        ResourceKey::UserToken("my_resource".into()),
+                        vec![
+                            Resource::UserToken("thing1".into()),
+                            Resource::UserToken("thing2".into()),
+                            Resource::UserToken("thing3".into()),
+                        ],
+                    ),
+                    (
+                        ResourceKey::UserToken("other_resource".into()),
+                        vec![
+                            Resource::UserToken("whing1".into()),
+                            Resource::UserToken("whing2".into()),
+                            Resource::UserToken("whing3".into()),
+                        ],
+                    ),
+                ]
+                .into_iter(),
+            ),
         );
         let m = Manager::new(
             repo.clone(),
-            Arc::new(Database::create_or_open(db_dir.path()).expect("couldn't setup result DB")),
+            Arc::new(
+                Database::create_or_open(db_dir.path(), "repo", Backend::Directory)
+                    .await
+                    .expect("couldn't setup result DB"),
+            ),
             Arc::new(resource_pools),
             tests,
         );
@@ -1960,6 +2746,9 @@ mod tests {
             env.get("LIMMAT_COMMIT").map(|t| CommitHash::new(*t)),
             Some(&commit2.hash).cloned()
         );
+        // There's only one worktree.
+        assert_eq!(env.get("LIMMAT_WORKTREE_ID"), Some(&"0"));
+        assert_eq!(env.get("LIMMAT_WORKTREE_POOL"), Some(&"default"));
         let resource0 = env
             .get("LIMMAT_RESOURCE_my_resource_0")
             .expect("didn't get resource0");
@@ -1981,6 +2770,7 @@ mod tests {
             Path::new(env.get("LIMMAT_ARTIFACTS").expect("no LIMMAT_ARTIFACTS")),
             &db_dir
                 .path()
+                .join("repo")
                 .join(commit2.hash.to_string())
                 .join("my_test/artifacts")
         );
@@ -1991,6 +2781,7 @@ mod tests {
             ),
             &db_dir
                 .path()
+                .join("repo")
                 .join(commit2.hash.to_string())
                 .join("dep/artifacts")
         );
@@ -2026,7 +2817,7 @@ mod tests {
         expect_notifs_20s(
             &mut results,
             [(
-                f.test_case(&commits[0], 0),
+                f.test_case(&commits[0], 0).await,
                 vec![TestStatusMatcher::Enqueued, TestStatusMatcher::Started].into(),
             )],
         )
@@ -2041,7 +2832,7 @@ mod tests {
         expect_notifs_20s(
             &mut results,
             [(
-                f.test_case(&commits[1], 0),
+                f.test_case(&commits[1], 0).await,
                 vec![TestStatusMatcher::Enqueued].into(),
             )],
         )
@@ -2057,11 +2848,11 @@ mod tests {
             &mut results,
             [
                 (
-                    f.test_case(&commits[0], 0),
+                    f.test_case(&commits[0], 0).await,
                     vec![TestStatusMatcher::Inconclusive(TestInconclusive::Canceled)].into(),
                 ),
                 (
-                    f.test_case(&commits[1], 0),
+                    f.test_case(&commits[1], 0).await,
                     vec![TestStatusMatcher::Inconclusive(TestInconclusive::Canceled)].into(),
                 ),
             ],
@@ -2112,19 +2903,19 @@ mod tests {
             &mut results,
             [
                 (
-                    f.test_case(&with_error, 0),
+                    f.test_case(&with_error, 0).await,
                     vec![TestStatusMatcher::Enqueued, TestStatusMatcher::Started].into(),
                 ),
                 (
-                    f.test_case(&with_error, 1),
+                    f.test_case(&with_error, 1).await,
                     vec![TestStatusMatcher::Enqueued, TestStatusMatcher::Started].into(),
                 ),
                 (
-                    f.test_case(&with_fail, 0),
+                    f.test_case(&with_fail, 0).await,
                     vec![TestStatusMatcher::Enqueued, TestStatusMatcher::Started].into(),
                 ),
                 (
-                    f.test_case(&with_fail, 1),
+                    f.test_case(&with_fail, 1).await,
                     vec![TestStatusMatcher::Enqueued, TestStatusMatcher::Started].into(),
                 ),
             ],
@@ -2140,9 +2931,9 @@ mod tests {
         expect_notifs_20s(
             &mut results,
             [(
-                f.test_case(&with_error, 0),
+                f.test_case(&with_error, 0).await,
                 vec![TestStatusMatcher::Inconclusive(TestInconclusive::Error(
-                    String::from("terminated by signal 10"),
+                    String::from("test command: terminated by signal 10 (SIGUSR1)"),
                 ))]
                 .into(),
             )],
@@ -2157,15 +2948,15 @@ mod tests {
             &mut results,
             [
                 (
-                    f.test_case(&with_error, 1),
+                    f.test_case(&with_error, 1).await,
                     vec![TestStatusMatcher::Inconclusive(TestInconclusive::Canceled)].into(),
                 ),
                 (
-                    f.test_case(&with_fail, 0),
+                    f.test_case(&with_fail, 0).await,
                     vec![TestStatusMatcher::Inconclusive(TestInconclusive::Canceled)].into(),
                 ),
                 (
-                    f.test_case(&with_fail, 1),
+                    f.test_case(&with_fail, 1).await,
                     vec![TestStatusMatcher::Inconclusive(TestInconclusive::Canceled)].into(),
                 ),
             ],
@@ -2232,6 +3023,414 @@ mod tests {
         );
     }
 
+    #[test_log::test(tokio::test)]
+    async fn should_release_resources_between_steps() {
+        let repo = Arc::new(TempRepo::new().await.unwrap());
+        let commit = repo.commit("hello").await.unwrap();
+        let temp_dir = TempDir::new().unwrap();
+        let go_path = temp_dir.path().join("go");
+        let bash = |name: Option<&str>, script: String, needs_worktree: bool| TestStep {
+            name: name.map(|n| n.to_owned()),
+            program: OsString::from("bash"),
+            args: vec!["-c".into(), script.into()],
+            needs_resources: if needs_worktree {
+                [(ResourceKey::default_worktree(), 1)].into()
+            } else {
+                [].into()
+            },
+        };
+        // The second step of this test doesn't finish until the other test has
+        // run, and there's only one worktree, so this only works if the
+        // worktree is released after the first step.
+        let tests = Dag::new([
+            Arc::new(Test {
+                name: TestName::new("steps"),
+                steps: vec![
+                    bash(Some("build"), "echo building".into(), true),
+                    bash(
+                        Some("wait"),
+                        format!("while [ ! -e {go_path:?} ]; do sleep 0.1; done; exit 3"),
+                        false,
+                    ),
+                    bash(Some("never"), "echo should not run".into(), false),
+                ],
+                ..Test::arbitrary()
+            }),
+            Arc::new(Test {
+                name: TestName::new("other"),
+                steps: vec![bash(None, format!("touch {go_path:?}"), true)],
+                ..Test::arbitrary()
+            }),
+        ])
+        .unwrap();
+        let pools = Pools::new([(
+            ResourceKey::default_worktree(),
+            worktree_resources(&repo, 1).await,
+        )]);
+        let (m, _db_dir) = new_manager(&repo, pools, tests).await;
+        let mut results = m.results();
+        m.set_revisions([commit.hash.clone()]).await.unwrap();
+        timeout_5s(m.settled())
+            .await
+            .expect("steps didn't release worktree");
+
+        let mut steps_started = Vec::new();
+        let mut db_entry = None;
+        while let Ok(notif) = results.try_recv() {
+            if notif.test_case.test.name != TestName::new("steps") {
+                continue;
+            }
+            match &notif.status {
+                TestStatus::StepStarted(step) => steps_started.push(step.clone()),
+                TestStatus::Finished(outcome) => db_entry = Some(outcome.clone().unwrap()),
+                _ => (),
+            }
+        }
+        assert_eq!(steps_started, vec!["build", "wait"]);
+        let db_entry = db_entry.expect("no result for test with steps");
+        assert_eq!(
+            db_entry.result(),
+            &TestResult {
+                exit_code: 3,
+                failed_step: Some("wait".into()),
+            }
+        );
+        assert_eq!(
+            fs::read_to_string(db_entry.stdout_path(Some("build"))).unwrap(),
+            "building\n"
+        );
+        assert!(!db_entry.stdout_path(Some("never")).exists());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn should_pass_service_port() {
+        let repo = Arc::new(TempRepo::new().await.unwrap());
+        let commit = repo.commit("hello").await.unwrap();
+        let temp_dir = TempDir::new().unwrap();
+        let port_path = temp_dir.path().join("port");
+        let services = Arc::new(Services::new([ServiceConfig {
+            name: "db".into(),
+            program: "bash".into(),
+            args: vec![
+                "-c".into(),
+                format!("echo $LIMMAT_SERVICE_PORT > {port_path:?}; sleep infinity & wait").into(),
+            ],
+            ready_command: Some((
+                "bash".into(),
+                vec!["-c".into(), format!("test -s {port_path:?}").into()],
+            )),
+            scope: ServiceScope::Session,
+            ready_timeout: Duration::from_secs(5),
+            shutdown_grace_period: Duration::from_secs(1),
+        }]));
+        let tests = Dag::new([Arc::new(Test {
+            services: vec!["db".into()],
+            ..bash_test("query", "echo $LIMMAT_SERVICE_db_PORT")
+        })])
+        .unwrap();
+        let (m, _db_dir) = new_manager(&repo, Pools::new([]), tests).await;
+        let m = m.with_services(services.clone());
+        let mut results = m.results();
+        m.set_revisions([commit.hash.clone()]).await.unwrap();
+        timeout_5s(m.settled()).await.expect("test didn't finish");
+
+        let (_, db_entry) = finished_results(&mut results)
+            .pop()
+            .expect("no result for test");
+        assert_eq!(db_entry.exit_code(), 0);
+        assert_eq!(
+            fs::read_to_string(db_entry.stdout_path(None)).unwrap(),
+            fs::read_to_string(&port_path).unwrap()
+        );
+        services.shutdown().await;
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn should_check_out_config_repo() {
+        let repo = Arc::new(TempRepo::new().await.unwrap());
+        let commit = repo.commit("hello").await.unwrap();
+        let scripts_repo = TempRepo::new().await.unwrap();
+        fs::write(scripts_repo.path().join("script.sh"), "echo from script").unwrap();
+        scripts_repo
+            .git(["add", "script.sh"])
+            .execute()
+            .await
+            .unwrap();
+        let scripts_commit = scripts_repo.commit("script").await.unwrap();
+        let state_dir = TempDir::new().unwrap();
+        let config_repos = Arc::new(
+            ConfigRepos::open(
+                [ConfigRepoConfig {
+                    name: "scripts".into(),
+                    path: scripts_repo.path().to_string_lossy().into_owned(),
+                    rev: "HEAD".into(),
+                    checkout: CheckoutMode::Exclusive,
+                    watch: false,
+                }],
+                repo.path(),
+                state_dir.path(),
+                true,
+            )
+            .await
+            .unwrap(),
+        );
+        let tests = Dag::new([Arc::new(Test {
+            config_repos: vec![("scripts".into(), scripts_commit.hash.clone())],
+            ..bash_test("scripted", "bash $LIMMAT_CONFIG_REPO_scripts/script.sh")
+        })])
+        .unwrap();
+        let (m, _db_dir) = new_manager(&repo, Pools::new([]), tests).await;
+        let m = m.with_config_repos(config_repos.clone());
+        let mut results = m.results();
+        m.set_revisions([commit.hash.clone()]).await.unwrap();
+        timeout_5s(m.settled()).await.expect("test didn't finish");
+
+        let (_, db_entry) = finished_results(&mut results)
+            .pop()
+            .expect("no result for test");
+        assert_eq!(db_entry.exit_code(), 0);
+        assert_eq!(
+            fs::read_to_string(db_entry.stdout_path(None)).unwrap(),
+            "from script\n"
+        );
+        config_repos.shutdown().await;
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn should_use_commit_config() {
+        let repo = Arc::new(TempRepo::new().await.unwrap());
+        let mut commits = Vec::new();
+        for config in [
+            r#"
+            [[tests]]
+            name = "foo"
+            requires_worktree = false
+            command = "echo one"
+            "#,
+            r#"
+            [[tests]]
+            name = "foo"
+            requires_worktree = false
+            command = "echo two"
+
+            [[tests]]
+            name = "bar"
+            requires_worktree = false
+            command = "echo three"
+            "#,
+        ] {
+            fs::write(repo.path().join("limmat.toml"), config).unwrap();
+            repo.git(["add", "limmat.toml"]).execute().await.unwrap();
+            commits.push(repo.commit("update config").await.unwrap());
+        }
+        // No config in this one, it shouldn't get tested.
+        repo.git(["rm", "limmat.toml"]).execute().await.unwrap();
+        commits.push(repo.commit("remove config").await.unwrap());
+
+        let config = ParsedConfig::from(
+            toml::from_str(r#"config_source = "commit""#).unwrap(),
+            repo.path(),
+            &HashMap::new(),
+        )
+        .unwrap();
+        let (m, _db_dir) = new_manager(&repo, Pools::new([]), Dag::empty()).await;
+        let m = m.with_commit_config(config.commit_config);
+        let mut results = m.results();
+        m.set_revisions(commits.iter().map(|c| c.hash.clone()))
+            .await
+            .unwrap();
+        timeout_5s(m.settled()).await.expect("tests didn't finish");
+
+        let outputs: HashMap<_, String> = finished_results(&mut results)
+            .into_iter()
+            .map(|(test_case, db_entry)| {
+                (
+                    (test_case.commit_hash, test_case.test.name.to_string()),
+                    fs::read_to_string(db_entry.stdout_path(None)).unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            outputs,
+            HashMap::from([
+                ((commits[0].hash.clone(), "foo".into()), "one\n".into()),
+                ((commits[1].hash.clone(), "foo".into()), "two\n".into()),
+                ((commits[1].hash.clone(), "bar".into()), "three\n".into()),
+            ])
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn should_cache_by_paths() {
+        let repo = Arc::new(TempRepo::new().await.unwrap());
+        let mut commits = Vec::new();
+        for (path, content) in [
+            ("src/code.c", "one"),
+            // Doesn't affect the result.
+            ("README", "docs"),
+            ("src/code.c", "two"),
+            // Paths that don't exist yet still count.
+            ("include/header.h", "three"),
+        ] {
+            let path = repo.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+            repo.git(["add", "."]).execute().await.unwrap();
+            commits.push(repo.commit("commit").await.unwrap());
+        }
+        let temp_dir = TempDir::new().unwrap();
+        let runs_path = temp_dir.path().join("runs");
+        let tests = Dag::new([Arc::new(Test {
+            cache_policy: CachePolicy::ByPaths(vec!["src".into(), "include".into()]),
+            ..bash_test("build", format!("echo >> {runs_path:?}"))
+        })])
+        .unwrap();
+        let (m, _db_dir) = new_manager(&repo, Pools::new([]), tests).await;
+        // One at a time so that the commits that share a result don't race.
+        for commit in &commits {
+            m.set_revisions([commit.hash.clone()]).await.unwrap();
+            timeout_5s(m.settled()).await.expect("tests didn't finish");
+        }
+        assert_eq!(fs::read_to_string(runs_path).unwrap(), "\n\n\n");
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn should_record_metadata() {
+        let repo = nonempty_temp_repo().await;
+        let commit = repo.commit("hello").await.unwrap();
+        let tests = Dag::new(
+            [("ok", "true"), ("killed", "kill -9 $$")].map(|(name, cmd)| {
+                Arc::new(Test {
+                    name: TestName::new(name),
+                    steps: vec![TestStep::single(
+                        "bash".into(),
+                        vec!["-c".into(), cmd.into()],
+                        [
+                            (ResourceKey::default_worktree(), 1),
+                            (ResourceKey::UserToken("board".into()), 1),
+                        ]
+                        .into(),
+                    )],
+                    ..Test::arbitrary()
+                })
+            }),
+        )
+        .unwrap();
+        let pools = Pools::new([
+            (
+                ResourceKey::default_worktree(),
+                worktree_resources(&repo, 1).await,
+            ),
+            (
+                ResourceKey::UserToken("board".into()),
+                vec![Resource::UserToken("board1".into())],
+            ),
+        ]);
+        let (m, _db_dir) = new_manager(&repo, pools, tests).await;
+        let db = m.result_db.clone();
+        m.set_revisions([commit.hash.clone()]).await.unwrap();
+        timeout_5s(m.settled()).await.expect("tests didn't finish");
+
+        let test_case = |name: &str| {
+            let test = m.tests.lock().node(&TestName::new(name)).unwrap().clone();
+            TestCase::new(repo.as_ref(), commit.clone(), test)
+        };
+        let LookupResult::FoundResult(entry) =
+            db.lookup(&test_case("ok").await.unwrap()).await.unwrap()
+        else {
+            panic!("no result for successful test");
+        };
+        let metadata = entry.metadata();
+        assert!(metadata.started_at.is_some());
+        assert!(metadata.duration.is_some());
+        assert!(metadata.hostname.is_some());
+        assert_eq!(
+            metadata.limmat_version.as_deref(),
+            Some(env!("CARGO_PKG_VERSION"))
+        );
+        assert_eq!(metadata.steps.len(), 1);
+        assert!(metadata.steps[0].worktree.is_some());
+        assert_eq!(
+            metadata.steps[0].resources,
+            [("board".to_owned(), vec!["board1".to_owned()])].into()
+        );
+        assert_eq!(metadata.steps[0].signal, None);
+
+        match db.explain(&test_case("killed").await.unwrap()).unwrap() {
+            Explanation::Error {
+                message: Some(message),
+            } => assert!(message.contains("signal 9"), "{message}"),
+            _ => panic!("killed test didn't record an error"),
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn should_rerun_expired_results() {
+        let repo = Arc::new(TempRepo::new().await.unwrap());
+        let commit = repo.commit("hello").await.unwrap();
+        let temp_dir = TempDir::new().unwrap();
+        let runs_path = |name: &str| temp_dir.path().join(name);
+        let tests = Dag::new(
+            [
+                ("expiring", Some(Duration::from_millis(500))),
+                ("stable", None),
+            ]
+            .map(|(name, rerun_every)| {
+                Arc::new(Test {
+                    rerun_every,
+                    ..bash_test(name, format!("echo >> {:?}", runs_path(name)))
+                })
+            }),
+        )
+        .unwrap();
+        let (m, _db_dir) = new_manager(&repo, Pools::new([]), tests).await;
+        let mut expired = m.expired();
+        m.set_revisions([commit.hash.clone()]).await.unwrap();
+        timeout_5s(m.settled()).await.expect("tests didn't finish");
+        for _ in 0..2 {
+            let id = timeout_5s(expired.recv())
+                .await
+                .expect("result didn't expire")
+                .unwrap();
+            m.rerun(&id).await.unwrap();
+            timeout_5s(m.settled()).await.expect("tests didn't finish");
+        }
+        assert_eq!(fs::read_to_string(runs_path("expiring")).unwrap(), "\n\n\n");
+        assert_eq!(fs::read_to_string(runs_path("stable")).unwrap(), "\n");
+
+        // Once we stop testing the commit it shouldn't expire any more.
+        m.cancel_running().await.unwrap();
+        timeout(Duration::from_secs(1), expired.recv())
+            .await
+            .expect_err("result expired after commit was dropped");
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn should_rerun_changed_tests() {
+        let repo = Arc::new(TempRepo::new().await.unwrap());
+        let commit = repo.commit("hello").await.unwrap();
+        let temp_dir = TempDir::new().unwrap();
+        // Each test appends to a file every time it runs.
+        let runs_path = |name: &str| temp_dir.path().join(name);
+        let tests = |changed_hash: u8| {
+            Dag::new(["changed", "same"].map(|name| {
+                Arc::new(Test {
+                    config_hash: vec![if name == "changed" { changed_hash } else { 0 }],
+                    ..bash_test(name, format!("echo >> {:?}", runs_path(name)))
+                })
+            }))
+            .unwrap()
+        };
+        let (m, _db_dir) = new_manager(&repo, Pools::new([]), tests(0)).await;
+        m.set_revisions([commit.hash.clone()]).await.unwrap();
+        timeout_5s(m.settled()).await.expect("tests didn't finish");
+
+        m.set_tests(tests(1), None).await.unwrap();
+        timeout_5s(m.settled()).await.expect("tests didn't finish");
+        assert_eq!(fs::read_to_string(runs_path("changed")).unwrap(), "\n\n");
+        assert_eq!(fs::read_to_string(runs_path("same")).unwrap(), "\n");
+    }
+
     #[test_case(OsStr::new(TestScript::BLOCK_COMMIT_MSG_TAG), false ; "blocked¸ shouldn't start")]
     #[test_case(&TestScript::exit_code_tag(1), false ; "failed¸ shouldn't start")]
     #[test_case(&TestScript::exit_code_tag(0), true ; "succeeded¸ should start")]
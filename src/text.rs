@@ -48,12 +48,12 @@ impl<'a, T: Into<Line<'a>>> From<T> for Text<'a> {
 impl<'a> Text<'a> {
     // Render the text with style applied using ANSI commands. Use Display on the returned value
     // to write it out.
-    pub fn ansi(&self) -> RenderAnsi {
+    pub fn ansi(&self) -> RenderAnsi<'_> {
         RenderAnsi { text: self }
     }
 
     // Render to an HTML <pre> element.
-    pub fn html_pre(&self) -> RenderHtmlPre {
+    pub fn html_pre(&self) -> RenderHtmlPre<'_> {
         RenderHtmlPre { text: self }
     }
 
@@ -191,6 +191,8 @@ pub struct Span<'a> {
     // be generic across ownership or reference.
     pub content: Cow<'a, str>,
     pub url: Option<Cow<'a, str>>,
+    // Only rendered in HTML, terminals don't have tooltips.
+    pub tooltip: Option<Cow<'a, str>>,
 }
 
 impl<'a, T: Into<Cow<'a, str>>> From<T> for Span<'a> {
@@ -205,6 +207,7 @@ impl<'a> Span<'a> {
             content: content.into(),
             class: None,
             url: None,
+            tooltip: None,
         }
     }
 
@@ -218,6 +221,11 @@ impl<'a> Span<'a> {
         self
     }
 
+    pub fn with_tooltip(mut self, tooltip: impl Into<Cow<'a, str>>) -> Self {
+        self.tooltip = Some(tooltip.into());
+        self
+    }
+
     fn num_graphemes(&self) -> usize {
         self.content.graphemes(true).count()
     }
@@ -272,9 +280,19 @@ impl Display for RenderHtmlSpan<'_> {
         if let Some(ref url) = &self.span.url {
             write!(f, r#"<a href="{}">"#, url)?;
         }
+        write!(f, r#"<span "#)?;
+        if let Some(ref tooltip) = &self.span.tooltip {
+            // Unlike the content, this is free text, so escape it.
+            let tooltip = tooltip
+                .replace('&', "&amp;")
+                .replace('"', "&quot;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;");
+            write!(f, r#"title="{tooltip}" "#)?;
+        }
         write!(
             f,
-            r#"<span class="{}">{}</span>"#,
+            r#"class="{}">{}</span>"#,
             match self.span.class {
                 None => "",
                 Some(Class::Error) => "error",
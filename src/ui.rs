@@ -7,12 +7,12 @@ use lazy_static::lazy_static;
 use regex::Regex;
 
 use crate::{
-    database::Database,
+    database::{Database, RunMetadata},
     git::{CommitHash, Worktree},
     http::UiState,
-    test::{Notification, TestCase, TestInconclusive, TestName, TestStatus},
+    test::{Notification, TestCase, TestCaseId, TestInconclusive, TestName, TestStatus},
     text::{Class, Line, Span, Text},
-    util::{Rect, ResultExt as _},
+    util::{format_age, Rect, ResultExt as _},
 };
 
 struct TrackedTestCase {
@@ -20,6 +20,27 @@ struct TrackedTestCase {
     status: TestStatus,
 }
 
+impl TrackedTestCase {
+    // Which step's output the UI should link to: the one that's running, or
+    // the one that failed. Otherwise just the last one.
+    fn output_step(&self) -> Option<&str> {
+        match &self.status {
+            TestStatus::StepStarted(step) => return Some(step),
+            TestStatus::Finished(Ok(db_entry)) => {
+                if let Some(step) = &db_entry.result().failed_step {
+                    return Some(step);
+                }
+            }
+            _ => (),
+        }
+        self.test_case
+            .test
+            .steps
+            .last()
+            .and_then(|s| s.name.as_deref())
+    }
+}
+
 // Inner string key is test name. Here we awkwardly store this as a
 // two-level map instead of a flat one by TestCaseId, because that
 // conveniently lets us grab all the TestCases for a given commit when
@@ -49,6 +70,8 @@ pub struct StatusTracker<W: Worktree, O: Write> {
     output_buf: OutputBuffer,
     output: O,
     web_ui: Arc<UiState>,
+    // Just for figuring out the URLs of results.
+    database: Arc<Database>,
     result_url_base: String,
     home_url: String,
 }
@@ -66,6 +89,7 @@ impl<W: Worktree, O: Write> StatusTracker<W, O> {
         repo: Arc<W>,
         output: O,
         web_ui: Arc<UiState>,
+        database: Arc<Database>,
         result_url_base: impl Into<String>,
         home_url: impl Into<String>,
     ) -> Self {
@@ -75,6 +99,7 @@ impl<W: Worktree, O: Write> StatusTracker<W, O> {
             output_buf: OutputBuffer::empty(),
             output,
             web_ui,
+            database,
             result_url_base: result_url_base.into(),
             home_url: home_url.into(),
         }
@@ -95,12 +120,29 @@ impl<W: Worktree, O: Write> StatusTracker<W, O> {
         update_tracked_cases(&mut self.tracked_cases, notif);
     }
 
+    // Find the results we're displaying that someone asked to forget, and stop
+    // holding onto them so they can be re-run. Returns the test cases that
+    // need re-running.
+    pub fn take_forgotten(&mut self) -> Vec<TestCaseId> {
+        let mut ids = Vec::new();
+        for tracked_case in self.tracked_cases.values_mut().flat_map(|m| m.values_mut()) {
+            if let TestStatus::Finished(Ok(db_entry)) = &tracked_case.status {
+                if db_entry.forgotten() {
+                    // It'll get re-enqueued, may as well say so already.
+                    tracked_case.status = TestStatus::Enqueued;
+                    ids.push(tracked_case.test_case.id());
+                }
+            }
+        }
+        ids
+    }
+
     // Update the UI by writing it to the output with fancy terminal escape
     // codes to overwrite what was previously written.
     pub fn repaint(&mut self, term_size: &Rect) -> anyhow::Result<()> {
-        let render = self
-            .output_buf
-            .render(&self.tracked_cases, &self.result_url_base)?;
+        let render =
+            self.output_buf
+                .render(&self.tracked_cases, &self.database, &self.result_url_base)?;
 
         self.web_ui.set_log_buf(render.html_pre());
 
@@ -293,6 +335,7 @@ impl OutputBuffer {
     fn render<'a>(
         &'a self,
         statuses: &'a HashMap<CommitHash, HashMap<TestName, TrackedTestCase>>,
+        database: &Database,
         result_url_base: &str,
     ) -> anyhow::Result<Text<'a>> {
         if self.lines.is_empty() {
@@ -305,7 +348,11 @@ impl OutputBuffer {
                 let mut spans = vec![Span::from(log_line)];
                 if let Some(hash) = self.status_commits.get(&i) {
                     if let Some(tracked_cases) = statuses.get(hash) {
-                        spans.extend(self.render_cases(tracked_cases, result_url_base)?);
+                        spans.extend(self.render_cases(
+                            tracked_cases,
+                            database,
+                            result_url_base,
+                        )?);
                     }
                 }
                 Ok(Line::from_iter(spans))
@@ -316,15 +363,15 @@ impl OutputBuffer {
     fn render_cases<'a>(
         &self,
         tracked_cases: &'a HashMap<TestName, TrackedTestCase>,
+        database: &Database,
         result_url_base: &str,
     ) -> anyhow::Result<Vec<Span<'a>>> {
         let mut tracked_cases: Vec<(&TestName, &TrackedTestCase)> = tracked_cases.iter().collect();
-        // Sort by test case name. Would like sort_by_key here but
-        // there's lifetime pain.
-        tracked_cases.sort_by(|(name1, _), (name2, _)| name1.cmp(name2));
+        // Sort by test case name.
+        tracked_cases.sort_by_key(|(name, _)| *name);
         let mut spans = Vec::new();
         for (name, tracked_case) in tracked_cases {
-            let status_part = match &tracked_case.status {
+            let mut status_part = match &tracked_case.status {
                 // Note - cancellation is an "error" in the type system but we
                 // don't treat it as an error in the UI.
                 TestStatus::Finished(Err(TestInconclusive::Error(msg))) => {
@@ -333,6 +380,9 @@ impl OutputBuffer {
                 TestStatus::Finished(Ok(db_entry)) => {
                     if db_entry.exit_code() == 0 {
                         Span::new("success").with_class(Class::Success)
+                    } else if let Some(step) = &db_entry.result().failed_step {
+                        Span::new(format!("{step} failed (status {})", db_entry.exit_code()))
+                            .with_class(Class::Failure)
                     } else {
                         Span::new(format!("failed (status {})", db_entry.exit_code()))
                             .with_class(Class::Failure)
@@ -343,33 +393,90 @@ impl OutputBuffer {
             .with_url(format!(
                 "{}/{}/stdout.txt",
                 result_url_base,
-                Database::result_relpath(&tracked_case.test_case).to_string_lossy()
+                database
+                    .result_relpath(&tracked_case.test_case)
+                    .join(Database::step_relpath(tracked_case.output_step()))
+                    .to_string_lossy()
             ));
+            if let TestStatus::Finished(Ok(db_entry)) = &tracked_case.status {
+                if let Some(tooltip) = metadata_tooltip(db_entry.metadata()) {
+                    status_part = status_part.with_tooltip(tooltip);
+                }
+            }
             spans.extend([
                 Span::new(name.to_string()).with_class(Class::TestName),
                 Span::new(": "),
                 status_part,
-                Span::new(" "),
-            ])
+            ]);
+            // Results might have come from the database, so show how old they
+            // are.
+            if let TestStatus::Finished(Ok(db_entry)) = &tracked_case.status {
+                if let Some(age) = db_entry.age() {
+                    spans.push(Span::new(format!(" ({} old)", format_age(age))));
+                }
+            }
+            spans.push(Span::new(" "));
         }
         Ok(spans)
     }
 }
 
+// Summary of how a result was produced, or None if we don't know.
+fn metadata_tooltip(metadata: &RunMetadata) -> Option<String> {
+    let mut lines = Vec::new();
+    let mut summary = Vec::new();
+    if let Some(duration) = metadata.duration {
+        summary.push(format!("took {}", format_age(duration)));
+    }
+    if let Some(hostname) = &metadata.hostname {
+        summary.push(format!("on {hostname}"));
+    }
+    if let Some(version) = &metadata.limmat_version {
+        summary.push(format!("(limmat {version})"));
+    }
+    if !summary.is_empty() {
+        lines.push(summary.join(" "));
+    }
+    if let Some(host) = &metadata.imported_from {
+        lines.push(format!("imported from {host}"));
+    }
+    for step in &metadata.steps {
+        let mut details = Vec::new();
+        if let Some(worktree) = &step.worktree {
+            details.push(format!("worktree {}", worktree.display()));
+        }
+        for (name, tokens) in &step.resources {
+            details.push(format!("{name}={}", tokens.join(",")));
+        }
+        if let Some(signal) = step.signal {
+            details.push(format!("killed by signal {signal}"));
+        }
+        if details.is_empty() {
+            continue;
+        }
+        match &step.name {
+            Some(name) => lines.push(format!("{name}: {}", details.join(", "))),
+            None => lines.push(details.join(", ")),
+        }
+    }
+    (!lines.is_empty()).then(|| lines.join("\n"))
+}
+
 #[cfg(test)]
 mod tests {
     use core::str;
     use std::{sync::Arc, time::Duration};
 
     use googletest::{expect_that, prelude::eq};
+    use tempfile::TempDir;
 
     use crate::{
-        database::DatabaseEntry,
+        database::{Backend, DatabaseEntry},
         git::{
             test_utils::{TempRepo, WorktreeExt},
             Commit,
         },
-        test::{CachePolicy, ExitCode, Test, TestName, TestResult},
+        test::{CachePolicy, ExitCode, Test, TestName, TestResult, TestStep},
     };
 
     use super::*;
@@ -380,9 +487,14 @@ mod tests {
             cache_policy,
             // Don't care abou any of the other fields in these tests
             config_hash: vec![0],
-            program: "".into(),
-            args: vec![],
-            needs_resources: [].into(),
+            canonical_config: serde_json::Value::Null,
+            share_results: false,
+            cache_ttl: None,
+            rerun_every: None,
+            steps: vec![TestStep::single("".into(), vec![], [].into())],
+            services: vec![],
+            inputs: vec![],
+            config_repos: vec![],
             shutdown_grace_period: Duration::from_secs(1),
             depends_on: vec![],
         })
@@ -399,6 +511,13 @@ mod tests {
         }
     }
 
+    async fn fake_database() -> Database {
+        let db_dir = TempDir::new().unwrap();
+        Database::create_or_open(db_dir.path(), "repo", Backend::Directory)
+            .await
+            .unwrap()
+    }
+
     // Abbreviate a commit message.
     fn abbrev(commit: &Commit) -> &str {
         // TODO: The degree of abbreviation here probably depends on git
@@ -408,7 +527,11 @@ mod tests {
 
     async fn fake_completion(exit_code: ExitCode) -> TestStatus {
         TestStatus::Finished(Ok(Arc::new(
-            DatabaseEntry::fake(TestResult { exit_code }).await,
+            DatabaseEntry::fake(TestResult {
+                exit_code,
+                failed_step: None,
+            })
+            .await,
         )))
     }
 
@@ -439,7 +562,12 @@ mod tests {
             update_tracked_cases(&mut tracked_cases, Arc::new(notif));
         }
 
-        let buf = format!("{}", ob.render(&tracked_cases, "myhost").unwrap().ansi());
+        let buf = format!(
+            "{}",
+            ob.render(&tracked_cases, &fake_database().await, "myhost")
+                .unwrap()
+                .ansi()
+        );
         expect_that!(
             // The colored crate does not have any useful way to disable it from
             // this test code, only globally. This clashes with parallel testing.
@@ -499,7 +627,12 @@ mod tests {
             update_tracked_cases(&mut tracked_cases, Arc::new(notif));
         }
 
-        let buf = format!("{}", ob.render(&tracked_cases, "myhost").unwrap().ansi());
+        let buf = format!(
+            "{}",
+            ob.render(&tracked_cases, &fake_database().await, "myhost")
+                .unwrap()
+                .ansi()
+        );
 
         // Note this is a kinda weird log. We excluded the common ancestor of all the commits.
         // Also note it's a kinda weird input because we haven't provided any
@@ -561,7 +694,12 @@ mod tests {
             update_tracked_cases(&mut tracked_cases, Arc::new(notif));
         }
 
-        let buf = format!("{}", ob.render(&tracked_cases, "myhost").unwrap().ansi());
+        let buf = format!(
+            "{}",
+            ob.render(&tracked_cases, &fake_database().await, "myhost")
+                .unwrap()
+                .ansi()
+        );
         expect_that!(
             *strip_ansi_escapes::strip_str(str::from_utf8(buf.as_bytes()).unwrap()),
             eq("[range empty]\n".to_owned())
@@ -6,8 +6,10 @@ use std::{
     ops::Deref,
     path::PathBuf,
     str::FromStr,
+    time::Duration,
 };
 
+use anyhow::{bail, Context as _};
 #[allow(unused_imports)]
 use log::{debug, error};
 use sha3::digest;
@@ -152,3 +154,77 @@ impl<D: digest::Digest> std::hash::Hasher for DigestHasher<D> {
         panic!("don't call this");
     }
 }
+
+// Very rough human-readable duration, like "3h". Only the biggest unit is
+// shown.
+pub fn format_age(age: Duration) -> String {
+    let s = age.as_secs();
+    if s < 60 {
+        format!("{s}s")
+    } else if s < 60 * 60 {
+        format!("{}m", s / 60)
+    } else if s < 24 * 60 * 60 {
+        format!("{}h", s / (60 * 60))
+    } else {
+        format!("{}d", s / (24 * 60 * 60))
+    }
+}
+
+// Parse durations like "30s", "24h" or "7d".
+pub fn parse_duration(s: &str) -> anyhow::Result<Duration> {
+    let (count, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let count: u64 = count
+        .parse()
+        .with_context(|| format!("invalid duration {s:?}"))?;
+    let unit_s = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => bail!("invalid duration {s:?}, unit must be one of s, m, h, d or w"),
+    };
+    if count == 0 {
+        bail!("invalid duration {s:?}, must be nonzero");
+    }
+    count
+        .checked_mul(unit_s)
+        .map(Duration::from_secs)
+        .ok_or_else(|| anyhow::anyhow!("invalid duration {s:?}, too long"))
+}
+
+// Parse sizes in bytes like "500M" or "20G". The units are powers of 1024, no
+// unit means bytes.
+pub fn parse_size(s: &str) -> anyhow::Result<u64> {
+    let (count, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let count: u64 = count
+        .parse()
+        .with_context(|| format!("invalid size {s:?}"))?;
+    let shift = match unit {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => bail!("invalid size {s:?}, unit must be one of K, M, G or T"),
+    };
+    count
+        .checked_mul(1 << shift)
+        .ok_or_else(|| anyhow::anyhow!("invalid size {s:?}, too big"))
+}
+
+// Rough human-readable size like "1.5G", the opposite of parse_size.
+pub fn format_size(bytes: u64) -> String {
+    let mut size = bytes as f64;
+    for unit in ["", "K", "M", "G"] {
+        if size < 1024.0 {
+            return if unit.is_empty() {
+                format!("{bytes}B")
+            } else {
+                format!("{size:.1}{unit}")
+            };
+        }
+        size /= 1024.0;
+    }
+    format!("{size:.1}T")
+}
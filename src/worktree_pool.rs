@@ -0,0 +1,282 @@
+// Persistent worktrees, that survive across Limmat restarts so that build
+// directories etc don't need to be recreated every time.
+//
+// These live under the state dir, in a subdirectory specific to the repo
+// they belong to. Each worktree has a "slot" in that directory, named after
+// the worktree pool and a number within it, plus a lock file for the slot
+// that's held by the Limmat process using it. That means if multiple Limmats
+// are running for the same repo they'll just end up using different slots.
+
+use std::{
+    collections::HashSet,
+    fs::{self, create_dir_all, remove_file, OpenOptions},
+    io::ErrorKind::NotFound,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context as _;
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+use sha3::{Digest as _, Sha3_256};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    flock::ExclusiveFlock,
+    git::{TempWorktree, Worktree},
+    process::CommandExt as _,
+    resource::DEFAULT_WORKTREE_POOL,
+    util::IoResultExt as _,
+};
+
+#[derive(Debug)]
+pub struct PersistentPool {
+    dir: PathBuf,
+}
+
+impl PersistentPool {
+    // git_common_dir identifies the repo, so that worktrees belonging to
+    // different repos don't get mixed up.
+    pub fn new(state_dir: &Path, git_common_dir: &Path) -> anyhow::Result<Self> {
+        let git_common_dir = git_common_dir
+            .canonicalize()
+            .context("canonicalizing Git dir")?;
+        let digest = Sha3_256::digest(git_common_dir.as_os_str().as_encoded_bytes());
+        // Include the repo's name just to make the directory easier to
+        // identify for humans.
+        let repo_name = git_common_dir
+            .parent()
+            .and_then(|p| p.file_name())
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let dir = state_dir
+            .join("worktrees")
+            .join(format!("{repo_name}-{}", &format!("{digest:x}")[..16]));
+        create_dir_all(&dir).context("creating persistent worktree dir")?;
+        // Canonicalize so that paths can be compared with what Git reports.
+        let dir = dir.canonicalize().context("canonicalizing worktree dir")?;
+        // Record which repo this is for, again just for humans.
+        fs::write(
+            dir.join("repo"),
+            git_common_dir.as_os_str().as_encoded_bytes(),
+        )
+        .context("writing persistent worktree repo file")?;
+        Ok(Self { dir })
+    }
+
+    fn slot_name(pool: &str, index: usize) -> String {
+        // The default pool's slots are just numbered, that's what all slots
+        // were called before there were multiple pools. This way existing
+        // worktrees (and their build directories) keep getting used.
+        if pool == DEFAULT_WORKTREE_POOL {
+            index.to_string()
+        } else {
+            format!("{pool}-{index}")
+        }
+    }
+
+    fn slot_path(&self, slot: &str) -> PathBuf {
+        self.dir.join(slot)
+    }
+
+    fn lock_path(&self, slot: &str) -> PathBuf {
+        self.dir.join(format!("{slot}.lock"))
+    }
+
+    fn try_lock_slot(&self, slot: &str) -> anyhow::Result<Option<ExclusiveFlock>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.lock_path(slot))
+            .context("opening worktree slot lock")?;
+        ExclusiveFlock::try_new(file)
+    }
+
+    // Slots that exist on disk (whether or not they are valid worktrees).
+    fn existing_slots(&self) -> anyhow::Result<Vec<String>> {
+        let mut slots = Vec::new();
+        for entry in fs::read_dir(&self.dir).context("listing persistent worktree dir")? {
+            let entry = entry.context("listing persistent worktree dir")?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            let name = name.strip_suffix(".lock").unwrap_or(&name);
+            // Slot names always end in the index within the pool.
+            let index = name.rsplit_once('-').map_or(name, |(_, index)| index);
+            if index.parse::<usize>().is_ok() {
+                slots.push(name.to_owned());
+            }
+        }
+        slots.sort();
+        slots.dedup();
+        Ok(slots)
+    }
+
+    // Get a worktree for the given worktree pool. This uses the slot at
+    // preferred_index if it isn't in use by someone else, otherwise the first
+    // slot that isn't. It adopts the worktree that's already there if it's
+    // valid, otherwise creates it.
+    pub async fn claim<W: Worktree>(
+        &self,
+        ct: &CancellationToken,
+        origin: &W,
+        pool: &str,
+        preferred_index: usize,
+    ) -> anyhow::Result<TempWorktree> {
+        let preferred_slot = Self::slot_name(pool, preferred_index);
+        let (slot, lock) = match self.try_lock_slot(&preferred_slot)? {
+            Some(lock) => (preferred_slot, lock),
+            None => {
+                let mut index = 0;
+                loop {
+                    let slot = Self::slot_name(pool, index);
+                    if let Some(lock) = self.try_lock_slot(&slot)? {
+                        break (slot, lock);
+                    }
+                    index += 1;
+                }
+            }
+        };
+        let path = self.slot_path(&slot);
+
+        if registered_worktrees(origin).await?.contains(&path) {
+            debug!("Adopting persistent worktree at {path:?}");
+            return Ok(TempWorktree::adopt_persistent(origin, path, lock));
+        }
+
+        // Whatever's there (if anything) isn't a worktree Git knows about, so
+        // it must be a leftover from something that went wrong.
+        tokio::fs::remove_dir_all(&path)
+            .await
+            .ignore(NotFound)
+            .context("removing invalid persistent worktree")?;
+        origin
+            .git(["worktree", "prune"])
+            .execute()
+            .await
+            .context("pruning worktrees")?;
+        info!("Creating persistent worktree at {path:?}");
+        TempWorktree::new_persistent(ct, origin, path, lock).await
+    }
+
+    // Delete all the worktrees that aren't currently in use.
+    pub async fn prune<W: Worktree>(&self, origin: &W) -> anyhow::Result<()> {
+        let registered = registered_worktrees(origin).await?;
+        for slot in self.existing_slots()? {
+            let path = self.slot_path(&slot);
+            let Some(_lock) = self.try_lock_slot(&slot)? else {
+                warn!("Not removing worktree at {path:?}, it's in use");
+                continue;
+            };
+            if registered.contains(&path) {
+                origin
+                    .git(["worktree", "remove", "--force", "--force"])
+                    .arg(&path)
+                    .execute()
+                    .await
+                    .with_context(|| format!("removing worktree at {path:?}"))?;
+            }
+            tokio::fs::remove_dir_all(&path)
+                .await
+                .ignore(NotFound)
+                .with_context(|| format!("removing {path:?}"))?;
+            remove_file(self.lock_path(&slot))
+                .ignore(NotFound)
+                .context("removing slot lock file")?;
+            info!("Removed worktree at {path:?}");
+        }
+        origin
+            .git(["worktree", "prune"])
+            .execute()
+            .await
+            .context("pruning worktrees")?;
+        if self.existing_slots()?.is_empty() {
+            tokio::fs::remove_dir_all(&self.dir)
+                .await
+                .context("removing persistent worktree dir")?;
+        }
+        Ok(())
+    }
+}
+
+// Canonical paths of the worktrees Git knows about for this repo.
+async fn registered_worktrees<W: Worktree>(origin: &W) -> anyhow::Result<HashSet<PathBuf>> {
+    let output = origin
+        .git(["worktree", "list", "--porcelain", "-z"])
+        .execute()
+        .await
+        .context("listing worktrees")?;
+    Ok(output
+        .stdout
+        .split(|b| *b == 0)
+        .filter_map(|attr| attr.strip_prefix(b"worktree "))
+        .filter_map(|path| {
+            // Worktrees that don't exist any more will fail to canonicalize,
+            // that's fine, they aren't valid.
+            Path::new(&*String::from_utf8_lossy(path))
+                .canonicalize()
+                .ok()
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::git::test_utils::{TempRepo, WorktreeExt as _};
+
+    #[test_log::test(tokio::test)]
+    async fn test_persistent_pool() {
+        let repo = TempRepo::new().await.unwrap();
+        repo.commit("1").await.unwrap();
+        let state_dir = TempDir::with_prefix("state-").unwrap();
+        let pool =
+            PersistentPool::new(state_dir.path(), &repo.git_common_dir().await.unwrap()).unwrap();
+        let ct = CancellationToken::new();
+
+        // Two worktrees claimed at once should be different.
+        let wt1 = pool.claim(&ct, &repo, "default", 0).await.unwrap();
+        let wt2 = pool.claim(&ct, &repo, "default", 0).await.unwrap();
+        assert_ne!(wt1.path(), wt2.path());
+        let path1 = wt1.path().to_owned();
+        fs::write(path1.join("build-output"), "foo").unwrap();
+
+        // Once released, the worktree should be kept and get adopted by the
+        // next claimant, and prune shouldn't touch the one in use.
+        wt1.cleanup().await;
+        assert!(path1.join("build-output").exists());
+        let wt1 = pool.claim(&ct, &repo, "default", 0).await.unwrap();
+        assert_eq!(wt1.path(), path1);
+        assert!(path1.join("build-output").exists());
+        wt2.cleanup().await;
+        pool.prune(&repo).await.unwrap();
+        assert!(path1.exists());
+        // The default pool's slots keep the names they had before there were
+        // multiple pools.
+        assert_eq!(path1, pool.slot_path("0"));
+        let path2 = pool.slot_path("1");
+        assert!(!path2.exists());
+
+        // A garbage directory in a slot should get replaced.
+        fs::create_dir(&path2).unwrap();
+        fs::write(path2.join("junk"), "junk").unwrap();
+        let wt2 = pool.claim(&ct, &repo, "default", 1).await.unwrap();
+        assert_eq!(wt2.path(), path2);
+        assert!(!path2.join("junk").exists());
+        assert!(path2.join(".git").exists());
+
+        // Other pools get their own slots.
+        let wt3 = pool.claim(&ct, &repo, "kbuild", 0).await.unwrap();
+        assert_eq!(wt3.path(), pool.slot_path("kbuild-0"));
+
+        wt1.cleanup().await;
+        wt2.cleanup().await;
+        wt3.cleanup().await;
+        pool.prune(&repo).await.unwrap();
+        assert!(!path1.exists());
+        assert!(!path2.exists());
+        assert!(!registered_worktrees(&repo).await.unwrap().contains(&path1));
+    }
+}
@@ -8,7 +8,7 @@ use std::{
     result,
     str::FromStr,
     thread::panicking,
-    time::Duration,
+    time::{Duration, SystemTime},
 };
 
 use anyhow::{anyhow, bail, Context as _};
@@ -83,7 +83,11 @@ impl LimmatChildBuilder {
             existing_repo_dir: None,
             db_dir,
             dump_output_on_panic: true,
-            env: HashMap::from([("RUST_LOG".into(), "debug".into())]),
+            env: HashMap::from([
+                ("RUST_LOG".into(), "debug".into()),
+                // Don't pick up the host config of whoever's running the tests.
+                ("LIMMAT_HOST_CONFIG".into(), "/dev/null".into()),
+            ]),
         })
     }
 
@@ -163,6 +167,8 @@ impl LimmatChildBuilder {
                 worktree_dir.to_str().unwrap(),
                 "--worktree-prefix",
                 "test-worktree-",
+                "--state-dir",
+                self.temp_dir.path().join("state").to_str().unwrap(),
             ])
             .args(args)
             .stdin(Stdio::piped())
@@ -653,6 +659,566 @@ async fn should_find_output(want_stdout: &str, want_stderr: &str) {
     expect_true!(Path::new(child.stdout().unwrap().trim())
         .join("my_artifact")
         .exists());
+
+    let mut child = LimmatChildBuilder::new()
+        .await
+        .unwrap()
+        .db_dir(db_dir.path().to_owned())
+        .existing_repo_dir(repo_dir.path().to_owned())
+        .start(config, ["get", "--json", "my_test", "HEAD^"])
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(5), child.expect_success())
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    let json: serde_json::Value = serde_json::from_str(&child.stdout().unwrap()).unwrap();
+    expect_that!(json["result"]["exit_code"].as_i64(), some(eq(0)));
+    expect_true!(json["metadata"]["hostname"].is_string());
+    expect_that!(json["metadata"]["duration_s"].as_f64(), some(lt(5.0)));
+    expect_that!(
+        json["metadata"]["steps"][0]["duration_s"].as_f64(),
+        some(lt(5.0))
+    );
+    expect_true!(json["metadata"]["steps"][0]["signal"].is_null());
+    // RFC 3339, and recent.
+    for timestamp in [
+        &json["finished_at"],
+        &json["metadata"]["started_at"],
+        &json["metadata"]["steps"][0]["started_at"],
+    ] {
+        let timestamp = humantime::parse_rfc3339(timestamp.as_str().unwrap()).unwrap();
+        expect_that!(
+            SystemTime::now().duration_since(timestamp).unwrap(),
+            lt(Duration::from_secs(60))
+        );
+    }
+    expect_true!(Path::new(json["artifacts_dir"].as_str().unwrap())
+        .join("my_artifact")
+        .exists());
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn explain_cmd() {
+    let repo_dir = TempDir::with_prefix("repo").unwrap();
+    LimmatChildBuilder::init_test_repo(repo_dir.path())
+        .await
+        .unwrap();
+    let db_dir = TempDir::with_prefix("result-db").unwrap();
+    let config = |command: &str| {
+        format!(
+            r##"
+            num_worktrees = 1
+            [[tests]]
+            name = "my_test"
+            command = "{command}"
+        "##
+        )
+    };
+    let run = |config: String, args: &'static [&'static str]| {
+        let db_dir = db_dir.path().to_owned();
+        let repo_dir = repo_dir.path().to_owned();
+        async move {
+            let mut child = LimmatChildBuilder::new()
+                .await
+                .unwrap()
+                .db_dir(db_dir)
+                .existing_repo_dir(repo_dir)
+                .start(&config, args.iter().copied())
+                .await
+                .unwrap();
+            timeout(Duration::from_secs(5), child.expect_success())
+                .await
+                .expect("child didn't shut down")
+                .unwrap();
+            child.stdout().unwrap()
+        }
+    };
+
+    run(config("echo foo"), &["get", "--run", "my_test", "HEAD"]).await;
+    expect_that!(
+        run(config("echo foo"), &["explain", "my_test", "HEAD"]).await,
+        starts_with("result is cached")
+    );
+    expect_that!(
+        run(config("echo foo"), &["explain", "my_test", "HEAD^"]).await,
+        starts_with("no result")
+    );
+    let output = run(config("echo bar"), &["explain", "my_test", "HEAD"]).await;
+    expect_that!(output, starts_with("config changed"));
+    expect_that!(output, contains_substring("-    \"command\": \"echo foo\""));
+    expect_that!(output, contains_substring("+    \"command\": \"echo bar\""));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn db_gc_cmd() {
+    let repo_dir = TempDir::with_prefix("repo").unwrap();
+    LimmatChildBuilder::init_test_repo(repo_dir.path())
+        .await
+        .unwrap();
+    let db_dir = TempDir::with_prefix("result-db").unwrap();
+    let config = r##"
+        num_worktrees = 1
+        [[tests]]
+        name = "my_test"
+        command = "echo foo"
+        [gc]
+        keep_per_test = 1
+    "##;
+    let run = |args: &'static [&'static str]| {
+        let db_dir = db_dir.path().to_owned();
+        let repo_dir = repo_dir.path().to_owned();
+        async move {
+            let mut child = LimmatChildBuilder::new()
+                .await
+                .unwrap()
+                .db_dir(db_dir)
+                .existing_repo_dir(repo_dir)
+                .start(config, args.iter().copied())
+                .await
+                .unwrap();
+            timeout(Duration::from_secs(5), child.expect_success())
+                .await
+                .expect("child didn't shut down")
+                .unwrap();
+            child.stdout().unwrap()
+        }
+    };
+
+    run(&["get", "--run", "my_test", "HEAD^"]).await;
+    run(&["get", "--run", "my_test", "HEAD"]).await;
+    expect_that!(
+        run(&["db", "gc", "--dry-run"]).await,
+        contains_substring("would delete 1 results")
+    );
+    expect_that!(
+        run(&["explain", "my_test", "HEAD^"]).await,
+        starts_with("result is cached")
+    );
+    expect_that!(
+        run(&["db", "gc"]).await,
+        contains_substring("deleted 1 results")
+    );
+    // Only the newest result is kept.
+    expect_that!(
+        run(&["explain", "my_test", "HEAD^"]).await,
+        starts_with("no result")
+    );
+    expect_that!(
+        run(&["explain", "my_test", "HEAD"]).await,
+        starts_with("result is cached")
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn db_list_cmd() {
+    let repo_dir = TempDir::with_prefix("repo").unwrap();
+    LimmatChildBuilder::init_test_repo(repo_dir.path())
+        .await
+        .unwrap();
+    let db_dir = TempDir::with_prefix("result-db").unwrap();
+    let config = r##"
+        num_worktrees = 1
+        [[tests]]
+        name = "pass"
+        command = "true"
+        [[tests]]
+        name = "fail"
+        command = "false"
+    "##;
+    let run = |args: &'static [&'static str]| {
+        let db_dir = db_dir.path().to_owned();
+        let repo_dir = repo_dir.path().to_owned();
+        async move {
+            let mut child = LimmatChildBuilder::new()
+                .await
+                .unwrap()
+                .db_dir(db_dir)
+                .existing_repo_dir(repo_dir)
+                .start(
+                    config,
+                    ["--result-db-backend", "sqlite"]
+                        .iter()
+                        .chain(args)
+                        .copied(),
+                )
+                .await
+                .unwrap();
+            timeout(Duration::from_secs(5), child.expect_success())
+                .await
+                .expect("child didn't shut down")
+                .unwrap();
+            child.stdout().unwrap()
+        }
+    };
+
+    run(&["get", "--run", "pass", "HEAD^"]).await;
+    run(&["get", "--run", "pass", "HEAD"]).await;
+    // This fails, but the result still gets stored.
+    let mut child = LimmatChildBuilder::new()
+        .await
+        .unwrap()
+        .db_dir(db_dir.path().to_owned())
+        .existing_repo_dir(repo_dir.path().to_owned())
+        .start(
+            config,
+            [
+                "--result-db-backend",
+                "sqlite",
+                "get",
+                "--run",
+                "fail",
+                "HEAD",
+            ],
+        )
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(5), child.child.wait())
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+
+    expect_that!(
+        run(&["db", "list"]).await.lines().collect::<Vec<_>>(),
+        elements_are![
+            contains_substring("fail failed (status 1)"),
+            contains_substring("pass success"),
+            contains_substring("pass success"),
+        ]
+    );
+    expect_that!(
+        run(&["db", "list", "--outcome", "success", "HEAD^..HEAD"])
+            .await
+            .lines()
+            .collect::<Vec<_>>(),
+        elements_are![contains_substring("pass success")]
+    );
+    expect_that!(
+        run(&["db", "list", "--test", "fail", "--outcome", "success"]).await,
+        eq("")
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn db_export_import_cmd() {
+    let repo_dir = TempDir::with_prefix("repo").unwrap();
+    LimmatChildBuilder::init_test_repo(repo_dir.path())
+        .await
+        .unwrap();
+    let src_db_dir = TempDir::with_prefix("src-result-db").unwrap();
+    let dest_db_dir = TempDir::with_prefix("dest-result-db").unwrap();
+    let bundle_dir = TempDir::with_prefix("bundle").unwrap();
+    let bundle_path = bundle_dir.path().join("results.tar");
+    let config = r##"
+        num_worktrees = 1
+        [[tests]]
+        name = "pass"
+        command = "echo hello"
+    "##;
+    let run = |db_dir: &Path, args: &[&str]| {
+        let db_dir = db_dir.to_owned();
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        let repo_dir = repo_dir.path().to_owned();
+        async move {
+            let mut child = LimmatChildBuilder::new()
+                .await
+                .unwrap()
+                .db_dir(db_dir)
+                .existing_repo_dir(repo_dir)
+                .start(config, args.iter().map(|a| a.as_str()))
+                .await
+                .unwrap();
+            timeout(Duration::from_secs(5), child.expect_success())
+                .await
+                .expect("child didn't shut down")
+                .unwrap();
+            child.stdout().unwrap()
+        }
+    };
+
+    run(src_db_dir.path(), &["get", "--run", "pass", "HEAD"]).await;
+    run(
+        src_db_dir.path(),
+        &["db", "export", "HEAD", "-o", bundle_path.to_str().unwrap()],
+    )
+    .await;
+    run(
+        dest_db_dir.path(),
+        &["db", "import", bundle_path.to_str().unwrap()],
+    )
+    .await;
+    // Now the result is there without running the test.
+    let stdout_path = run(dest_db_dir.path(), &["get", "pass", "HEAD"]).await;
+    expect_that!(
+        fs::read_to_string(stdout_path.trim()).unwrap(),
+        eq("hello\n")
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn forget_and_rerun_cmds() {
+    let repo_dir = TempDir::with_prefix("repo").unwrap();
+    LimmatChildBuilder::init_test_repo(repo_dir.path())
+        .await
+        .unwrap();
+    let db_dir = TempDir::with_prefix("result-db").unwrap();
+    let counter_dir = TempDir::with_prefix("counter").unwrap();
+    let counter_path = counter_dir.path().join("runs");
+    let config = format!(
+        r##"
+        num_worktrees = 1
+        [[tests]]
+        name = "flaky"
+        command = "echo run >> {}"
+    "##,
+        counter_path.display()
+    );
+    let run = |args: &'static [&'static str]| {
+        let config = config.clone();
+        let db_dir = db_dir.path().to_owned();
+        let repo_dir = repo_dir.path().to_owned();
+        async move {
+            let mut child = LimmatChildBuilder::new()
+                .await
+                .unwrap()
+                .db_dir(db_dir)
+                .existing_repo_dir(repo_dir)
+                .start(config, args.iter().copied())
+                .await
+                .unwrap();
+            timeout(Duration::from_secs(5), child.expect_success())
+                .await
+                .expect("child didn't shut down")
+                .unwrap();
+        }
+    };
+    let num_runs = || fs::read_to_string(&counter_path).unwrap().lines().count();
+
+    run(&["get", "--run", "flaky", "HEAD"]).await;
+    run(&["get", "--run", "flaky", "HEAD"]).await;
+    expect_that!(num_runs(), eq(1));
+    run(&["rerun", "flaky", "HEAD"]).await;
+    expect_that!(num_runs(), eq(2));
+    run(&["forget", "flaky", "HEAD"]).await;
+    run(&["get", "--run", "flaky", "HEAD"]).await;
+    expect_that!(num_runs(), eq(3));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn should_share_results_between_clones() {
+    let repo_dir = TempDir::with_prefix("repo").unwrap();
+    LimmatChildBuilder::init_test_repo(repo_dir.path())
+        .await
+        .unwrap();
+    let clone_dir = TempDir::with_prefix("clone").unwrap();
+    Command::new("git")
+        .args(["clone", "--quiet"])
+        .arg(repo_dir.path())
+        .arg(clone_dir.path())
+        .status()
+        .await
+        .unwrap()
+        .check_exit_ok()
+        .unwrap();
+    // Unrelated history, but the same (empty) tree.
+    let other_repo_dir = TempDir::with_prefix("other-repo").unwrap();
+    for args in [
+        &["init", "--quiet"][..],
+        &["commit", "--quiet", "--allow-empty", "-m", "something else"],
+    ] {
+        Command::new("git")
+            .args(args)
+            .current_dir(other_repo_dir.path())
+            .status()
+            .await
+            .unwrap()
+            .check_exit_ok()
+            .unwrap();
+    }
+    let db_dir = TempDir::with_prefix("result-db").unwrap();
+    let run = |repo_dir: &Path, config: &'static str, args: &'static [&'static str]| {
+        let db_dir = db_dir.path().to_owned();
+        let repo_dir = repo_dir.to_owned();
+        async move {
+            let mut child = LimmatChildBuilder::new()
+                .await
+                .unwrap()
+                .db_dir(db_dir)
+                .existing_repo_dir(repo_dir)
+                .start(config, args.iter().copied())
+                .await
+                .unwrap();
+            timeout(Duration::from_secs(5), child.expect_success())
+                .await
+                .expect("child didn't shut down")
+                .unwrap();
+            child.stdout().unwrap()
+        }
+    };
+    // With by_tree caching the results can be shared with the other repo if
+    // we ask for it.
+    let config = r##"
+        [[tests]]
+        name = "my_test"
+        command = "true"
+        cache = "by_tree"
+    "##;
+    let shared_config = r##"
+        [[tests]]
+        name = "my_test"
+        command = "true"
+        cache = "by_tree"
+        share_results = true
+    "##;
+
+    run(
+        repo_dir.path(),
+        config,
+        &["get", "--run", "my_test", "HEAD"],
+    )
+    .await;
+    run(
+        repo_dir.path(),
+        shared_config,
+        &["get", "--run", "my_test", "HEAD"],
+    )
+    .await;
+    expect_that!(
+        run(clone_dir.path(), config, &["explain", "my_test", "HEAD"]).await,
+        starts_with("result is cached")
+    );
+    expect_that!(
+        run(
+            other_repo_dir.path(),
+            config,
+            &["explain", "my_test", "HEAD"]
+        )
+        .await,
+        starts_with("no result")
+    );
+    expect_that!(
+        run(
+            other_repo_dir.path(),
+            shared_config,
+            &["explain", "my_test", "HEAD"]
+        )
+        .await,
+        starts_with("result is cached")
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn should_watch_empty_repo() {
+    let repo_dir = TempDir::with_prefix("repo").unwrap();
+    Command::new("git")
+        .args(["init", "--quiet"])
+        .current_dir(repo_dir.path())
+        .status()
+        .await
+        .unwrap()
+        .check_exit_ok()
+        .unwrap();
+    let db_dir = TempDir::with_prefix("result-db").unwrap();
+    let config = r##"
+        [[tests]]
+        name = "my_test"
+        command = "true"
+    "##;
+    let mut limmat = LimmatChildBuilder::new()
+        .await
+        .unwrap()
+        .db_dir(db_dir.path().to_owned())
+        .existing_repo_dir(repo_dir.path().to_owned())
+        .start(config, ["watch", "HEAD^"])
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(500)).await;
+    assert_that!(limmat.child.try_wait(), ok(none()));
+
+    // Once there's something in the range, it gets tested.
+    for message in ["first", "second"] {
+        Command::new("git")
+            .args(["commit", "--quiet", "--allow-empty", "-m", message])
+            .current_dir(repo_dir.path())
+            .status()
+            .await
+            .unwrap()
+            .check_exit_ok()
+            .unwrap();
+    }
+    // It's stored separately until the next time Limmat runs.
+    let result_pattern = db_dir.path().join("unborn/*/*/my_test/result.json");
+    wait_for(
+        || Ok(glob(result_pattern.to_str().unwrap())?.next().is_some()),
+        Duration::from_secs(5),
+    )
+    .await
+    .expect("test didn't run after committing");
+    limmat.terminate().await.unwrap();
+
+    // The next time Limmat runs, the result is found under the root commit.
+    let mut child = LimmatChildBuilder::new()
+        .await
+        .unwrap()
+        .db_dir(db_dir.path().to_owned())
+        .existing_repo_dir(repo_dir.path().to_owned())
+        .start(config, ["get", "my_test", "HEAD"])
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(5), child.expect_success())
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    expect_that!(
+        child.stdout().unwrap(),
+        starts_with(db_dir.path().join("repos").to_str().unwrap())
+    );
+    expect_false!(db_dir.path().join("unborn").exists());
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn should_find_step_output() {
+    let repo_dir = TempDir::with_prefix("repo").unwrap();
+    LimmatChildBuilder::init_test_repo(repo_dir.path())
+        .await
+        .unwrap();
+
+    let config = r##"
+            num_worktrees = 1
+            [[tests]]
+            name = "my_test"
+            steps = [
+                { name = "build", command = "echo built" },
+                { name = "run", command = "echo ran $LIMMAT_STEP", requires_worktree = false },
+            ]
+        "##;
+    let db_dir = TempDir::with_prefix("result-db").unwrap();
+    for (step, want_stdout) in [("build", "built\n"), ("run", "ran run\n")] {
+        let mut child = LimmatChildBuilder::new()
+            .await
+            .unwrap()
+            .db_dir(db_dir.path().to_owned())
+            .existing_repo_dir(repo_dir.path().to_owned())
+            .start(config, ["get", "--run", "my_test", "HEAD", "--step", step])
+            .await
+            .unwrap();
+        timeout(Duration::from_secs(5), child.expect_success())
+            .await
+            .expect("child didn't shut down")
+            .unwrap();
+        expect_that!(
+            fs::read_to_string(child.stdout().unwrap().trim()),
+            ok(eq(want_stdout))
+        );
+    }
 }
 
 #[googletest::test]